@@ -22,7 +22,8 @@ interface ICoreProvider {
             initLogging = true,
             inMemory = false,
             projectDirs = null,
-            transcodePolicy = AppSettings.transcodePolicy
+            transcodePolicy = AppSettings.transcodePolicy,
+            transcodeWorkerCount = null,
         )
     }
 }
@@ -186,13 +186,34 @@ fun TransferJob(job: TransferJobModel) {
             ) {
                 val progress = job.progress
                 when (progress) {
-                    is TransferJobProgressModel.Requested, is TransferJobProgressModel.Transcoding, is TransferJobProgressModel.Ready -> {
+                    is TransferJobProgressModel.Requested, is TransferJobProgressModel.Ready -> {
                         Icon(
                             painter = painterResource(Res.drawable.pending_24px),
                             contentDescription = null,
                         )
                     }
 
+                    is TransferJobProgressModel.Transcoding -> {
+                        var targetProgress by remember { mutableFloatStateOf(0f) }
+                        val animatedProgress by animateFloatAsState(
+                            targetValue = targetProgress,
+                            animationSpec = ProgressIndicatorDefaults.ProgressAnimationSpec,
+                        )
+
+                        LaunchedEffect(true) {
+                            while (isActive) {
+                                targetProgress = progress.progress.get()
+                                delay(100)
+                            }
+                        }
+
+                        CircularProgressIndicator(
+                            progress = {
+                                animatedProgress
+                            },
+                        )
+                    }
+
                     is TransferJobProgressModel.InProgress -> {
                         var targetProgress by remember { mutableFloatStateOf(0f) }
                         val animatedProgress by animateFloatAsState(
@@ -321,7 +342,8 @@ internal fun formatJobSubtitle(job: TransferJobModel): String {
         }
 
         is TransferJobProgressModel.Transcoding -> {
-            "Transcoding..."
+            val percent = (progress.progress.get() * 100f).toInt()
+            if (percent > 0) "Transcoding... $percent%" else "Transcoding..."
         }
 
         is TransferJobProgressModel.Ready -> {
@@ -16,9 +16,17 @@ import uniffi.musicopy.IndexItemModel
 import uniffi.musicopy.LibraryModel
 import uniffi.musicopy.LibraryRootModel
 import uniffi.musicopy.NodeModel
+import uniffi.musicopy.ProgressModel
 import uniffi.musicopy.ServerModel
 import uniffi.musicopy.ServerStateModel
+import uniffi.musicopy.TranscodeApplication
+import uniffi.musicopy.TranscodeArtFormat
+import uniffi.musicopy.TranscodeArtOptions
+import uniffi.musicopy.TranscodeBitrateMode
+import uniffi.musicopy.TranscodeFormat
+import uniffi.musicopy.TranscodeFrameDuration
 import uniffi.musicopy.TranscodePolicy
+import uniffi.musicopy.TranscodeProfile
 import uniffi.musicopy.TransferJobModel
 import uniffi.musicopy.TransferJobProgressModel
 
@@ -309,7 +317,11 @@ fun mockTransferJobModel(
 
 fun mockTransferJobProgressModelRequested() = TransferJobProgressModel.Requested
 
-fun mockTransferJobProgressModelTranscoding() = TransferJobProgressModel.Transcoding
+fun mockTransferJobProgressModelTranscoding(
+    progress: Float = 0.4f,
+) = TransferJobProgressModel.Transcoding(
+    progress = ProgressModel(progress)
+)
 
 fun mockTransferJobProgressModelReady() = TransferJobProgressModel.Ready
 
@@ -336,12 +348,39 @@ fun mockLibraryModel(
         localRoots = localRoots,
         transcodesDir = "~/.cache/musicopy/transcodes",
         transcodesDirSize = FileSizeModel.Actual(534_000_000uL),
+        transcodeCacheSize = 534_000_000uL,
+        transcodeCacheSizeLimit = null,
         transcodeCountWaiting = if (transcoding) CounterModel(27uL + 8uL) else CounterModel(0uL),
         transcodeCountQueued = if (transcoding) CounterModel(27uL) else CounterModel(0uL),
         transcodeCountInprogress = if (transcoding) CounterModel(8uL) else CounterModel(0uL),
         transcodeCountReady = if (transcoding) CounterModel(143uL) else CounterModel(0uL),
         transcodeCountFailed = CounterModel(0uL),
-        transcodePolicy = TranscodePolicy.IF_REQUESTED
+        panicCount = CounterModel(0uL),
+        transcodeWorkerRestarts = CounterModel(0uL),
+        transcodeFailures = emptyList(),
+        transcodePolicy = TranscodePolicy.IF_REQUESTED,
+        transcodeProfile = TranscodeProfile(
+            format = TranscodeFormat.OPUS,
+            bitrate = 128000u,
+            bitrateMode = TranscodeBitrateMode.CONSTRAINED_VBR,
+            complexity = 10u,
+            frameDuration = TranscodeFrameDuration.MS20,
+            application = TranscodeApplication.AUDIO,
+            downmixStereo = false,
+            downmixMono = false,
+            normalizeLoudness = false,
+            art = TranscodeArtOptions(
+                embed = true,
+                maxSize = 500u,
+                format = TranscodeArtFormat.JPEG,
+                quality = 90u,
+                keepSmall = false,
+            ),
+        ),
+        transcodeWorkerCount = 8u,
+        transcodeBackground = false,
+        transcodeVerify = false,
+        transcodesPaused = false,
     )
 }
 
@@ -102,12 +102,17 @@ fun SettingsWidget(
                                     libraryModel.transcodeCountInprogress.get() +
                                     libraryModel.transcodeCountReady.get()
                         }
+                        val limit = libraryModel.transcodeCacheSizeLimit
                         Text(
                             text = "$count files, ${
                                 formatSize(
                                     libraryModel.transcodesDirSize
                                 )
-                            }",
+                            }" + if (limit != null) {
+                                " (${formatSize(libraryModel.transcodeCacheSize)} of ${formatSize(limit)} cached)"
+                            } else {
+                                ""
+                            },
                             style = MaterialTheme.typography.labelMedium,
                             maxLines = 1,
                             overflow = TextOverflow.Ellipsis,
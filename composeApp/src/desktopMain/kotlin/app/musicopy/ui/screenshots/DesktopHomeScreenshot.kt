@@ -11,6 +11,7 @@ import app.musicopy.mockTransferJobProgressModelReady
 import app.musicopy.mockTransferJobProgressModelTranscoding
 import app.musicopy.ui.DesktopHome
 import uniffi.musicopy.LibraryRootModel
+import uniffi.musicopy.TranscodeContent
 
 @Composable
 fun DesktopHomeScreenshot() {
@@ -38,12 +39,14 @@ fun DesktopHomeScreenshot() {
             LibraryRootModel(
                 name = "Favorites",
                 path = "~/music/fav2025",
-                numFiles = 83u
+                numFiles = 83u,
+                contentType = TranscodeContent.MUSIC
             ),
             LibraryRootModel(
                 name = "Backlog",
                 path = "~/music/backlog",
-                numFiles = 427u
+                numFiles = 427u,
+                contentType = TranscodeContent.MUSIC
             ),
         ),
         transcoding = true,
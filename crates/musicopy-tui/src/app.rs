@@ -5,7 +5,10 @@ use crate::{
 use anyhow::Context;
 use musicopy::{
     Core, CoreOptions,
-    library::{LibraryModel, transcode::TranscodePolicy},
+    library::{
+        LibraryModel,
+        transcode::{TranscodeArtFormat, TranscodeContent, TranscodeFormat, TranscodePolicy},
+    },
     node::{ClientStateModel, DownloadPartialItemModel, NodeModel, ServerStateModel},
 };
 use ratatui::{
@@ -85,6 +88,7 @@ impl<'a> App<'a> {
                 in_memory,
                 project_dirs: None,
                 transcode_policy: TranscodePolicy::IfRequested,
+                transcode_worker_count: None,
             },
         )
         .await?;
@@ -264,6 +268,21 @@ impl<'a> App<'a> {
                 self.core.remove_library_root(name)?;
             }
 
+            "librarytype" => {
+                if parts.len() < 3 {
+                    anyhow::bail!("usage: librarytype <name> <music|speech>");
+                }
+
+                let name = parts[1].to_string();
+                let content_type = match parts[2] {
+                    "music" => TranscodeContent::Music,
+                    "speech" => TranscodeContent::Speech,
+                    _ => anyhow::bail!("unknown content type: {}", parts[2]),
+                };
+                self.core
+                    .set_library_root_content_type(name, content_type)?;
+            }
+
             "resetdb" => {
                 self.core.reset_database()?;
                 self.core.rescan_library()?;
@@ -273,6 +292,39 @@ impl<'a> App<'a> {
                 self.core.rescan_library()?;
             }
 
+            "gc" => {
+                self.core.collect_transcode_garbage()?;
+            }
+
+            "retry" => {
+                app_log!(
+                    "retrying {} failed transcodes",
+                    self.library_model.transcode_failures.len()
+                );
+
+                self.core.retry_failed_transcodes(None)?;
+            }
+
+            "pause" => {
+                app_log!("pausing transcodes");
+                self.core.pause_transcodes()?;
+            }
+
+            "resume" => {
+                app_log!("resuming transcodes");
+                self.core.resume_transcodes()?;
+            }
+
+            "cancel" => {
+                if parts.len() < 2 {
+                    anyhow::bail!("usage: cancel <path>");
+                }
+
+                let local_path = parts[1..].join(" ");
+                app_log!("cancelling transcode: {local_path}");
+                self.core.cancel_transcodes(vec![local_path])?;
+            }
+
             "a" | "accept" => {
                 app_log!("accepting pending servers");
 
@@ -429,6 +481,166 @@ impl<'a> App<'a> {
                 }
             }
 
+            "tb" => {
+                if parts.len() < 2 {
+                    anyhow::bail!("usage: tb <bitrate in kbps>");
+                }
+
+                let kbps = parts[1].parse::<u32>().context("failed to parse bitrate")?;
+
+                let mut profile = self.library_model.transcode_profile;
+                profile.bitrate = kbps * 1000;
+
+                if let Err(e) = self.core.set_transcode_profile(profile) {
+                    anyhow::bail!("failed to set transcode profile: {e:#}");
+                }
+            }
+
+            "tf" => {
+                if parts.len() < 2 {
+                    anyhow::bail!("usage: tf <opus|flac>");
+                }
+
+                let format = match parts[1] {
+                    "opus" => TranscodeFormat::Opus,
+                    "flac" => TranscodeFormat::Flac,
+                    _ => anyhow::bail!("unknown transcode format: {}", parts[1]),
+                };
+
+                let mut profile = self.library_model.transcode_profile;
+                profile.format = format;
+
+                if let Err(e) = self.core.set_transcode_profile(profile) {
+                    anyhow::bail!("failed to set transcode profile: {e:#}");
+                }
+            }
+
+            "ta" => {
+                if parts.len() < 2 {
+                    anyhow::bail!("usage: ta <off|size> [jpeg|png] [quality] [keepsmall]");
+                }
+
+                let mut profile = self.library_model.transcode_profile;
+                if parts[1] == "off" {
+                    profile.art.embed = false;
+                } else {
+                    profile.art.embed = true;
+                    profile.art.max_size = parts[1]
+                        .parse::<u32>()
+                        .context("failed to parse art size")?;
+                    profile.art.keep_small = false;
+
+                    for part in &parts[2..] {
+                        match *part {
+                            "jpeg" => profile.art.format = TranscodeArtFormat::Jpeg,
+                            "png" => profile.art.format = TranscodeArtFormat::Png,
+                            "keepsmall" => profile.art.keep_small = true,
+                            _ => {
+                                profile.art.quality =
+                                    part.parse::<u8>().context("failed to parse art quality")?;
+                            }
+                        }
+                    }
+                }
+
+                if let Err(e) = self.core.set_transcode_profile(profile) {
+                    anyhow::bail!("failed to set transcode profile: {e:#}");
+                }
+            }
+
+            "tn" => {
+                let mut profile = self.library_model.transcode_profile;
+                profile.normalize_loudness = !profile.normalize_loudness;
+
+                if let Err(e) = self.core.set_transcode_profile(profile) {
+                    anyhow::bail!("failed to set transcode profile: {e:#}");
+                }
+            }
+
+            "tc" => {
+                if parts.len() < 2 {
+                    anyhow::bail!("usage: tc <cache size limit in MB, or 0 for no limit>");
+                }
+
+                let mb = parts[1]
+                    .parse::<u64>()
+                    .context("failed to parse cache size limit")?;
+                let limit = (mb > 0).then_some(mb * 1_000_000);
+
+                if let Err(e) = self.core.set_transcode_cache_size_limit(limit) {
+                    anyhow::bail!("failed to set transcode cache size limit: {e:#}");
+                }
+            }
+
+            "tw" => {
+                if parts.len() < 2 {
+                    anyhow::bail!("usage: tw <worker count, or 0 for default>");
+                }
+
+                let count = parts[1]
+                    .parse::<u32>()
+                    .context("failed to parse worker count")?;
+                let count = (count > 0).then_some(count);
+
+                if let Err(e) = self.core.set_transcode_worker_count(count) {
+                    anyhow::bail!("failed to set transcode worker count: {e:#}");
+                }
+            }
+
+            "tbg" => {
+                let enabled = !self.library_model.transcode_background;
+
+                if let Err(e) = self.core.set_transcode_background(enabled) {
+                    anyhow::bail!("failed to set transcode background: {e:#}");
+                }
+            }
+
+            "tverify" => {
+                let enabled = !self.library_model.transcode_verify;
+
+                if let Err(e) = self.core.set_transcode_verify(enabled) {
+                    anyhow::bail!("failed to set transcode verify: {e:#}");
+                }
+            }
+
+            "tp" | "tps" => {
+                if parts.len() < 3 {
+                    anyhow::bail!("usage: {} <budget in MB> <path>", parts[0]);
+                }
+
+                let mb = parts[1].parse::<u64>().context("failed to parse budget")?;
+                let local_path = parts[2..].join(" ");
+                let schedule = parts[0] == "tps";
+
+                app_log!("planning bitrate for {mb} MB: {local_path}");
+
+                let core = self.core.clone();
+                tokio::spawn(async move {
+                    match core
+                        .plan_transcodes(vec![local_path], mb * 1_000_000, schedule)
+                        .await
+                    {
+                        Ok(plan) => {
+                            match plan.bitrate {
+                                Some(bitrate) => app_log!(
+                                    "planned {} kbps, estimated {} MB",
+                                    bitrate / 1000,
+                                    plan.estimated_size / 1_000_000
+                                ),
+                                None => app_log!(
+                                    "doesn't fit, estimated {} MB at the lowest bitrate",
+                                    plan.estimated_size / 1_000_000
+                                ),
+                            }
+                            for path in plan.unestimated_paths {
+                                app_log!("couldn't estimate: {path}");
+                            }
+                        }
+                        Err(e) => app_log!("error planning bitrate: {e:#}"),
+                    }
+                });
+            }
+
             "help" | "h" | "?" => {
                 app_send!(AppEvent::Screen(AppScreen::Help));
             }
@@ -4,7 +4,7 @@ pub mod log;
 
 use crate::app::{App, AppMode, AppScreen};
 use musicopy::{
-    library::transcode::TranscodePolicy,
+    library::transcode::{TranscodeArtFormat, TranscodeFormat, TranscodePolicy},
     node::{ClientStateModel, ServerStateModel, TransferJobProgressModel},
 };
 use ratatui::{
@@ -246,6 +246,8 @@ impl<'a> App<'a> {
                     root.path.clone().blue(),
                     " (".green(),
                     root.num_files.to_string().green(),
+                    ", ".green(),
+                    root.content_type.key().green(),
                     ")".green(),
                 ])
             }));
@@ -254,6 +256,20 @@ impl<'a> App<'a> {
                 TranscodePolicy::IfRequested => "IfRequested",
                 TranscodePolicy::Always => "Always",
             };
+            let transcode_profile = &self.library_model.transcode_profile;
+            let transcode_format = match transcode_profile.format {
+                TranscodeFormat::Opus => format!("opus {}k", transcode_profile.bitrate / 1000),
+                TranscodeFormat::Flac => "flac".to_string(),
+            };
+            let transcode_art = if transcode_profile.art.embed {
+                let art_format = match transcode_profile.art.format {
+                    TranscodeArtFormat::Jpeg => format!("jpeg q{}", transcode_profile.art.quality),
+                    TranscodeArtFormat::Png => "png".to_string(),
+                };
+                format!("{}px {}", transcode_profile.art.max_size, art_format)
+            } else {
+                "off".to_string()
+            };
 
             lines.extend(vec![
                 Line::from(""),
@@ -290,8 +306,56 @@ impl<'a> App<'a> {
                         .green(),
                     " failed (policy: ".into(),
                     transcode_policy.green(),
+                    ", format: ".into(),
+                    transcode_format.green(),
+                    ", art: ".into(),
+                    transcode_art.green(),
+                    ")".into(),
+                ]),
+                Line::from(vec![
+                    "Cache: ".into(),
+                    format!("{} MB", self.library_model.transcode_cache_size / 1_000_000).green(),
+                    " / ".into(),
+                    match self.library_model.transcode_cache_size_limit {
+                        Some(limit) => format!("{} MB", limit / 1_000_000).green(),
+                        None => "unlimited".green(),
+                    },
+                    " (workers: ".into(),
+                    self.library_model
+                        .transcode_worker_count
+                        .to_string()
+                        .green(),
+                    ", background: ".into(),
+                    if self.library_model.transcode_background {
+                        "on".green()
+                    } else {
+                        "off".green()
+                    },
+                    ", verify: ".into(),
+                    if self.library_model.transcode_verify {
+                        "on".green()
+                    } else {
+                        "off".green()
+                    },
+                    ", paused: ".into(),
+                    if self.library_model.transcodes_paused {
+                        "yes".green()
+                    } else {
+                        "no".green()
+                    },
                     ")".into(),
                 ]),
+                Line::from(vec![
+                    "Health: ".into(),
+                    self.library_model.panic_count.get().to_string().green(),
+                    " panics / ".into(),
+                    self.library_model
+                        .transcode_worker_restarts
+                        .get()
+                        .to_string()
+                        .green(),
+                    " worker restarts".into(),
+                ]),
             ])
         }
 
@@ -319,7 +383,7 @@ impl<'a> App<'a> {
                 for job in &server.transfer_jobs {
                     match &job.progress {
                         TransferJobProgressModel::Requested => {}
-                        TransferJobProgressModel::Transcoding => count_transcoding += 1,
+                        TransferJobProgressModel::Transcoding { .. } => count_transcoding += 1,
                         TransferJobProgressModel::Ready => count_ready += 1,
                         TransferJobProgressModel::InProgress { .. } => count_inprogress += 1,
                         TransferJobProgressModel::Finished { .. } => count_finished += 1,
@@ -416,7 +480,7 @@ impl<'a> App<'a> {
                 for job in &client.transfer_jobs {
                     match &job.progress {
                         TransferJobProgressModel::Requested => count_requested += 1,
-                        TransferJobProgressModel::Transcoding => count_transcoding += 1,
+                        TransferJobProgressModel::Transcoding { .. } => count_transcoding += 1,
                         TransferJobProgressModel::Ready => count_ready += 1,
                         TransferJobProgressModel::InProgress { .. } => count_inprogress += 1,
                         TransferJobProgressModel::Finished { .. } => count_finished += 1,
@@ -442,6 +506,24 @@ impl<'a> App<'a> {
                     " failed".into(),
                 ])];
 
+                // add lines for jobs that started transcoding
+                for job in &client.transfer_jobs {
+                    if let TransferJobProgressModel::Transcoding { progress } = &job.progress {
+                        let progress_percent = progress.get() * 100.0;
+                        if progress_percent > 0.0 {
+                            job_lines.push(Line::from(vec![
+                                "   - ".into(),
+                                job.file_root.clone().blue(),
+                                "/".blue(),
+                                job.file_path.clone().blue(),
+                                " [transcoding ".green(),
+                                format!("{progress_percent:.0}").green(),
+                                "%]".green(),
+                            ]));
+                        }
+                    }
+                }
+
                 // add lines for in-progress jobs
                 for job in &client.transfer_jobs {
                     if let TransferJobProgressModel::InProgress { started_at, bytes } =
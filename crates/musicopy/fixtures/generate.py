@@ -0,0 +1,477 @@
+#!/usr/bin/env python3
+"""Generates the test fixtures in this directory.
+
+The fixtures are tiny files with the same set of tags, written by hand
+so that no encoders are needed. The gapless fixtures are silent MP3 and
+M4A files with known encoder delay and padding, and the corrupt fixtures
+are malformed files that must fail cleanly. Run from any directory:
+
+    python3 crates/musicopy/fixtures/generate.py
+"""
+
+import math
+import os
+import struct
+import zlib
+
+OUT_DIR = os.path.dirname(os.path.abspath(__file__))
+
+SAMPLE_RATE = 44100
+CHANNELS = 2
+BLOCK_SIZE = 4096
+
+TITLE = "Tag Test = Title"
+ARTISTS = ["Artist A", "Artist B"]
+ALBUM = "Fixture Album"
+ALBUM_ARTIST = "Various Artists"
+TRACK = (3, 12)
+DISC = (2, 2)
+DATE = "2024"
+GENRE = "Electronic"
+COMPOSER = "Composer C"
+ISRC = "USABC2400001"
+MB_ALBUM_ID = "0f6b2a3e-6f6e-4a53-9b5c-6c0f3b0d2d1e"
+LYRICS = "First line\nSecond line"
+
+# width and height of the cover art in art.mp3
+ART_SIZE = 64
+
+# encoder delay and padding in the gapless fixtures, in samples
+GAPLESS_DELAY = 576
+GAPLESS_AAC_DELAY = 2112
+GAPLESS_PADDING = 1000
+
+
+def write(name, data):
+    with open(os.path.join(OUT_DIR, name), "wb") as f:
+        f.write(data)
+
+
+# --- FLAC ---------------------------------------------------------------------
+
+
+def crc8(data):
+    crc = 0
+    for byte in data:
+        crc ^= byte
+        for _ in range(8):
+            crc = ((crc << 1) ^ 0x07) & 0xFF if crc & 0x80 else (crc << 1) & 0xFF
+    return crc
+
+
+def crc16(data):
+    crc = 0
+    for byte in data:
+        crc ^= byte << 8
+        for _ in range(8):
+            crc = ((crc << 1) ^ 0x8005) & 0xFFFF if crc & 0x8000 else (crc << 1) & 0xFFFF
+    return crc
+
+
+def flac_block(block_type, data, last):
+    return bytes([(0x80 if last else 0) | block_type]) + len(data).to_bytes(3, "big") + data
+
+
+def flac():
+    # STREAMINFO
+    streaminfo = struct.pack(">HH", BLOCK_SIZE, BLOCK_SIZE)
+    streaminfo += (0).to_bytes(3, "big") + (0).to_bytes(3, "big")  # frame sizes unknown
+    # 20 bits sample rate, 3 bits channels - 1, 5 bits bps - 1, 36 bits total samples
+    packed = (SAMPLE_RATE << 44) | ((CHANNELS - 1) << 41) | (15 << 36) | BLOCK_SIZE
+    streaminfo += packed.to_bytes(8, "big")
+    streaminfo += bytes(16)  # md5 unknown
+
+    # VORBIS_COMMENT
+    comments = [
+        f"TITLE={TITLE}",
+        *[f"ARTIST={artist}" for artist in ARTISTS],
+        f"ALBUM={ALBUM}",
+        f"ALBUMARTIST={ALBUM_ARTIST}",
+        f"TRACKNUMBER={TRACK[0]}",
+        f"TRACKTOTAL={TRACK[1]}",
+        f"DISCNUMBER={DISC[0]}",
+        f"DISCTOTAL={DISC[1]}",
+        f"DATE={DATE}",
+        f"GENRE={GENRE}",
+        f"COMPOSER={COMPOSER}",
+        f"ISRC={ISRC}",
+        f"MUSICBRAINZ_ALBUMID={MB_ALBUM_ID}",
+    ]
+    vendor = b"fixture"
+    vorbis_comment = struct.pack("<I", len(vendor)) + vendor + struct.pack("<I", len(comments))
+    for comment in comments:
+        encoded = comment.encode()
+        vorbis_comment += struct.pack("<I", len(encoded)) + encoded
+
+    # one frame of silence using constant subframes
+    header = bytes(
+        [
+            0xFF,
+            0xF8,  # sync code, fixed block size
+            0xC9,  # block size 4096, sample rate 44.1 kHz
+            0x18,  # independent stereo, 16 bits per sample
+            0x00,  # frame number 0
+        ]
+    )
+    header += bytes([crc8(header)])
+    # each subframe: padding bit, constant type, no wasted bits, then a 16-bit value
+    frame = header + bytes([0x00, 0x00, 0x00]) * CHANNELS
+    frame += crc16(frame).to_bytes(2, "big")
+
+    data = b"fLaC"
+    data += flac_block(0, streaminfo, False)
+    data += flac_block(4, vorbis_comment, True)
+    data += frame
+    write("tags.flac", data)
+
+
+# --- MP3 ----------------------------------------------------------------------
+
+
+def synchsafe(n):
+    return bytes([(n >> 21) & 0x7F, (n >> 14) & 0x7F, (n >> 7) & 0x7F, n & 0x7F])
+
+
+def id3_frame(frame_id, data):
+    return frame_id.encode() + synchsafe(len(data)) + b"\x00\x00" + data
+
+
+def id3_text(frame_id, *values):
+    # encoding 3 is UTF-8, multiple values are separated with null
+    return id3_frame(frame_id, b"\x03" + "\x00".join(values).encode())
+
+
+def id3_txxx(description, value):
+    return id3_frame("TXXX", b"\x03" + description.encode() + b"\x00" + value.encode())
+
+
+def id3_uslt(lyrics):
+    # UTF-8, English, with an empty content descriptor
+    return id3_frame("USLT", b"\x03eng\x00" + lyrics.encode())
+
+
+def mp3_frames():
+    # silent MPEG-1 layer III frames, 128 kbps, 44.1 kHz, joint stereo
+    # with zeroed side info, every granule decodes to silence
+    frame_len = 144 * 128000 // SAMPLE_RATE
+    frame = b"\xff\xfb\x90\x40" + bytes(frame_len - 4)
+    return frame * 16
+
+
+def mp3_tag(*extra_frames):
+    frames = b"".join(
+        [
+            id3_text("TIT2", TITLE),
+            id3_text("TPE1", *ARTISTS),
+            id3_text("TALB", ALBUM),
+            id3_text("TPE2", ALBUM_ARTIST),
+            id3_text("TRCK", f"{TRACK[0]}/{TRACK[1]}"),
+            id3_text("TPOS", f"{DISC[0]}/{DISC[1]}"),
+            id3_text("TDRC", DATE),
+            id3_text("TCON", GENRE),
+            id3_text("TCOM", COMPOSER),
+            id3_text("TSRC", ISRC),
+            id3_txxx("MusicBrainz Album Id", MB_ALBUM_ID),
+            *extra_frames,
+        ]
+    )
+    return b"ID3\x04\x00\x00" + synchsafe(len(frames)) + frames
+
+
+def mp3():
+    write("tags.mp3", mp3_tag() + mp3_frames())
+
+
+def lyrics_mp3():
+    # the same tags with embedded unsynchronized lyrics
+    write("lyrics.mp3", mp3_tag(id3_uslt(LYRICS)) + mp3_frames())
+
+
+def png(width, height):
+    # grayscale gradient, each row starts with filter type 0
+    def chunk(kind, data):
+        return struct.pack(">I", len(data)) + kind + data + struct.pack(">I", zlib.crc32(kind + data))
+
+    rows = b"".join(b"\x00" + bytes((x + y) % 256 for x in range(width)) for y in range(height))
+    ihdr = struct.pack(">IIBBBBB", width, height, 8, 0, 0, 0, 0)
+    return (
+        b"\x89PNG\r\n\x1a\n"
+        + chunk(b"IHDR", ihdr)
+        + chunk(b"IDAT", zlib.compress(rows, 9))
+        + chunk(b"IEND", b"")
+    )
+
+
+def id3_apic(image):
+    # UTF-8, PNG front cover with an empty description
+    return id3_frame("APIC", b"\x03image/png\x00\x03\x00" + image)
+
+
+def art_mp3():
+    # the same tags with an embedded front cover
+    write("art.mp3", mp3_tag(id3_apic(png(ART_SIZE, ART_SIZE))) + mp3_frames())
+
+
+def gapless_mp3():
+    # the same silent frames, preceded by an Info frame with a LAME tag
+    # the LAME tag stores 12 bits of encoder delay and 12 bits of padding
+    frame_len = 144 * 128000 // SAMPLE_RATE
+    frame = b"\xff\xfb\x90\x40" + bytes(frame_len - 4)
+    frame_count = 16
+
+    info = b"Info" + struct.pack(">III", 0x03, frame_count, (frame_count + 1) * frame_len)
+    lame = b"LAME3.100" + bytes(12)
+    delay_padding = GAPLESS_DELAY << 12 | GAPLESS_PADDING
+    lame += delay_padding.to_bytes(3, "big")
+    # the side info for joint stereo is 32 bytes
+    info_frame = b"\xff\xfb\x90\x40" + bytes(32) + info + lame
+    info_frame += bytes(frame_len - len(info_frame))
+
+    write("gapless.mp3", info_frame + frame * frame_count)
+
+
+# --- WAV ----------------------------------------------------------------------
+
+
+def riff_chunk(kind, data):
+    # chunks are padded to an even length
+    return kind + struct.pack("<I", len(data)) + data + (b"\x00" if len(data) % 2 else b"")
+
+
+def info_text(kind, value):
+    return riff_chunk(kind, value.encode() + b"\x00")
+
+
+def wav():
+    # PCM, 16 bits per sample
+    block_align = CHANNELS * 2
+    fmt = struct.pack(
+        "<HHIIHH", 1, CHANNELS, SAMPLE_RATE, SAMPLE_RATE * block_align, block_align, 16
+    )
+
+    info = b"INFO" + b"".join(
+        [
+            info_text(b"INAM", TITLE),
+            info_text(b"IART", ARTISTS[0]),
+            info_text(b"IPRD", ALBUM),
+            info_text(b"ITRK", str(TRACK[0])),
+            info_text(b"ICRD", DATE),
+            info_text(b"IGNR", GENRE),
+        ]
+    )
+
+    # a quarter second of a 440 Hz sine with a different phase per channel, so
+    # that lossless round trips are checked with real samples
+    frames = SAMPLE_RATE // 4
+    samples = b""
+    for i in range(frames):
+        for channel in range(CHANNELS):
+            t = i / SAMPLE_RATE + channel / 1000
+            samples += struct.pack("<h", int(16000 * math.sin(2 * math.pi * 440 * t)))
+
+    data = b"WAVE" + riff_chunk(b"fmt ", fmt) + riff_chunk(b"LIST", info)
+    data += riff_chunk(b"data", samples)
+    write("tags.wav", b"RIFF" + struct.pack("<I", len(data)) + data)
+
+
+# --- M4A ----------------------------------------------------------------------
+
+
+def atom(kind, *children):
+    data = b"".join(children)
+    return struct.pack(">I", 8 + len(data)) + kind + data
+
+
+def full_atom(kind, version, flags, *children):
+    return atom(kind, bytes([version]) + flags.to_bytes(3, "big"), *children)
+
+
+def descriptor(tag, data):
+    return bytes([tag, len(data)]) + data
+
+
+def ilst_text(kind, value):
+    return atom(kind, full_atom(b"data", 0, 1, b"\x00\x00\x00\x00", value.encode()))
+
+
+def ilst_pair(kind, number, total):
+    data = struct.pack(">HHHH", 0, number, total, 0)
+    return atom(kind, full_atom(b"data", 0, 0, b"\x00\x00\x00\x00", data))
+
+
+def ilst_freeform(name, value):
+    return atom(
+        b"----",
+        full_atom(b"mean", 0, 0, b"com.apple.iTunes"),
+        full_atom(b"name", 0, 0, name.encode()),
+        full_atom(b"data", 0, 1, b"\x00\x00\x00\x00", value.encode()),
+    )
+
+
+def mp4(samples, channels, audio_specific_config, ilst):
+    # each sample is one AAC frame
+    duration = 1024 * len(samples)
+
+    decoder_config = descriptor(
+        0x04,
+        bytes([0x40, 0x15]) + (0).to_bytes(3, "big") + struct.pack(">II", 128000, 128000)
+        + descriptor(0x05, audio_specific_config),
+    )
+    es_descriptor = descriptor(0x03, b"\x00\x01\x00" + decoder_config + descriptor(0x06, b"\x02"))
+
+    mp4a = atom(
+        b"mp4a",
+        bytes(6) + struct.pack(">H", 1),  # reserved, data reference index
+        bytes(8),  # reserved
+        struct.pack(">HHHH", channels, 16, 0, 0),
+        struct.pack(">I", SAMPLE_RATE << 16),
+        full_atom(b"esds", 0, 0, es_descriptor),
+    )
+
+    def stbl(chunk_offset):
+        return atom(
+            b"stbl",
+            full_atom(b"stsd", 0, 0, struct.pack(">I", 1), mp4a),
+            full_atom(b"stts", 0, 0, struct.pack(">III", 1, len(samples), 1024)),
+            full_atom(b"stsc", 0, 0, struct.pack(">IIII", 1, 1, len(samples), 1)),
+            full_atom(
+                b"stsz",
+                0,
+                0,
+                struct.pack(">II", 0, len(samples)),
+                *[struct.pack(">I", len(sample)) for sample in samples],
+            ),
+            full_atom(b"stco", 0, 0, struct.pack(">II", 1, chunk_offset)),
+        )
+
+    udta = atom(
+        b"udta",
+        full_atom(
+            b"meta",
+            0,
+            0,
+            full_atom(b"hdlr", 0, 0, bytes(4), b"mdir", b"appl", bytes(8), b"\x00"),
+            ilst,
+        ),
+    )
+
+    identity = struct.pack(">9I", 0x10000, 0, 0, 0, 0x10000, 0, 0, 0, 0x40000000)
+
+    def moov(chunk_offset):
+        mvhd = full_atom(
+            b"mvhd",
+            0,
+            0,
+            struct.pack(">IIII", 0, 0, SAMPLE_RATE, duration),
+            struct.pack(">IH", 0x10000, 0x100),
+            bytes(10),
+            identity,
+            bytes(24),
+            struct.pack(">I", 2),
+        )
+        tkhd = full_atom(
+            b"tkhd",
+            0,
+            7,
+            struct.pack(">IIIII", 0, 0, 1, 0, duration),
+            bytes(8),
+            struct.pack(">HHHH", 0, 0, 0x100, 0),
+            identity,
+            struct.pack(">II", 0, 0),
+        )
+        mdhd = full_atom(
+            b"mdhd", 0, 0, struct.pack(">IIIIHH", 0, 0, SAMPLE_RATE, duration, 0x55C4, 0)
+        )
+        hdlr = full_atom(b"hdlr", 0, 0, bytes(4), b"soun", bytes(12), b"SoundHandler\x00")
+        minf = atom(
+            b"minf",
+            full_atom(b"smhd", 0, 0, bytes(4)),
+            atom(b"dinf", full_atom(b"dref", 0, 0, struct.pack(">I", 1), full_atom(b"url ", 0, 1))),
+            stbl(chunk_offset),
+        )
+        trak = atom(b"trak", tkhd, atom(b"mdia", mdhd, hdlr, minf))
+        return atom(b"moov", mvhd, trak, udta)
+
+    ftyp = atom(b"ftyp", b"M4A ", struct.pack(">I", 0), b"M4A ", b"mp42", b"isom")
+
+    # the chunk offset depends on the size of moov, which doesn't depend on the offset value
+    chunk_offset = len(ftyp) + len(moov(0)) + 8
+    return ftyp + moov(chunk_offset) + atom(b"mdat", *samples)
+
+
+def m4a():
+    # one AAC frame worth of samples, the sample data is never decoded by the tests
+    sample = bytes(8)
+
+    # AAC-LC, 44.1 kHz, stereo
+    audio_specific_config = bytes([0x12, 0x10])
+
+    ilst = atom(
+        b"ilst",
+        ilst_text(b"\xa9nam", TITLE),
+        ilst_text(b"\xa9ART", ARTISTS[0]),
+        ilst_text(b"\xa9alb", ALBUM),
+        ilst_text(b"aART", ALBUM_ARTIST),
+        ilst_pair(b"trkn", *TRACK),
+        ilst_pair(b"disk", *DISC),
+        ilst_text(b"\xa9day", DATE),
+        ilst_text(b"\xa9gen", GENRE),
+        ilst_text(b"\xa9wrt", COMPOSER),
+        ilst_freeform("ISRC", ISRC),
+        ilst_freeform("MusicBrainz Album Id", MB_ALBUM_ID),
+    )
+
+    write("tags.m4a", mp4([sample], CHANNELS, audio_specific_config, ilst))
+
+
+def gapless_m4a():
+    # silent AAC frames: a single channel element with global gain 100 and no
+    # scalefactor bands, followed by the end element
+    bits = "000" + "0000" + "01100100" + "0" + "00" + "0" + "000000" + "0" + "0000" + "111"
+    sample = int(bits, 2).to_bytes(len(bits) // 8, "big")
+    frame_count = 16
+
+    # AAC-LC, 44.1 kHz, mono
+    audio_specific_config = bytes([0x12, 0x08])
+
+    # iTunSMPB is a list of hex numbers: reserved, delay, padding, original length, ...
+    original_len = frame_count * 1024 - GAPLESS_AAC_DELAY - GAPLESS_PADDING
+    smpb = f" 00000000 {GAPLESS_AAC_DELAY:08X} {GAPLESS_PADDING:08X} {original_len:016X}"
+    ilst = atom(
+        b"ilst",
+        ilst_text(b"\xa9nam", TITLE),
+        ilst_freeform("iTunSMPB", smpb),
+    )
+
+    write("gapless.m4a", mp4([sample] * frame_count, 1, audio_specific_config, ilst))
+
+
+# --- Corrupt ------------------------------------------------------------------
+
+
+def corrupt():
+    # FLAC with a STREAMINFO block that's cut off
+    streaminfo = struct.pack(">HH", BLOCK_SIZE, BLOCK_SIZE)
+    write("corrupt.flac", b"fLaC" + bytes([0x80]) + (34).to_bytes(3, "big") + streaminfo)
+
+    # MP3 that's just noise, with no frame sync
+    noise = bytes((i * 7 + 3) % 251 & 0xDF for i in range(4096))
+    write("corrupt.mp3", noise)
+
+    # WAV with zero channels and a data chunk that's longer than the file
+    fmt = struct.pack("<HHIIHH", 1, 0, SAMPLE_RATE, 0, 0, 16)
+    data = b"WAVE" + riff_chunk(b"fmt ", fmt)
+    data += b"data" + struct.pack("<I", 1 << 20) + bytes(64)
+    write("corrupt.wav", b"RIFF" + struct.pack("<I", len(data)) + data)
+
+
+if __name__ == "__main__":
+    flac()
+    mp3()
+    lyrics_mp3()
+    art_mp3()
+    wav()
+    m4a()
+    gapless_mp3()
+    gapless_m4a()
+    corrupt()
@@ -9,6 +9,8 @@ pub struct Root {
     pub node_id: NodeId,
     pub name: String,
     pub path: String,
+    /// The kind of audio in the root, see `TranscodeContent::key`.
+    pub content_type: String,
 }
 
 pub struct File {
@@ -20,6 +22,14 @@ pub struct File {
     pub path: String,
     pub local_tree: String,
     pub local_path: String,
+    /// The cue sheet that splits the file at `local_path` into tracks, if the
+    /// file is one of its tracks.
+    pub cue_path: Option<String>,
+    /// The number of the file's track in the cue sheet.
+    pub cue_track: Option<u32>,
+    /// The revision of the transcode a downloaded file was copied from, see
+    /// `TranscodeStatusCache::revision`. Always 0 for local files.
+    pub revision: u64,
 }
 
 pub struct InsertFile<'a> {
@@ -29,6 +39,25 @@ pub struct InsertFile<'a> {
     pub path: &'a str,
     pub local_tree: &'a str,
     pub local_path: &'a str,
+    pub cue_path: Option<&'a str>,
+    pub cue_track: Option<u32>,
+    pub revision: u64,
+}
+
+pub struct TrackLoudness {
+    pub hash_kind: String,
+    pub hash: Vec<u8>,
+    pub loudness: Option<f64>,
+    pub gated_blocks: u64,
+}
+
+pub struct TranscodeFailure {
+    pub hash_kind: String,
+    pub hash: Vec<u8>,
+    pub local_path: String,
+    pub error: String,
+    pub attempts: u32,
+    pub failed_at: u64,
 }
 
 pub struct RecentServer {
@@ -59,6 +88,7 @@ impl Database {
         let db = Self { conn };
 
         db.create_tables()?;
+        db.migrate()?;
 
         Ok(db)
     }
@@ -70,6 +100,7 @@ impl Database {
                 node_id TEXT NOT NULL,
                 name TEXT NOT NULL,
                 path TEXT NOT NULL,
+                content_type TEXT NOT NULL DEFAULT 'music',
                 UNIQUE (node_id, name)
             )",
             [],
@@ -84,6 +115,9 @@ impl Database {
                 path TEXT NOT NULL,
                 local_tree TEXT NOT NULL,
                 local_path TEXT NOT NULL,
+                cue_path TEXT,
+                cue_track INTEGER,
+                revision INTEGER NOT NULL DEFAULT 0,
                 UNIQUE (node_id, root, path)
             )",
             [],
@@ -103,9 +137,81 @@ impl Database {
             )",
             [],
         )?;
+        self.conn.execute(
+            "CREATE TABLE IF NOT EXISTS track_loudness (
+                hash_kind TEXT NOT NULL,
+                hash BLOB NOT NULL,
+                album TEXT NOT NULL,
+                loudness REAL,
+                gated_blocks INTEGER NOT NULL,
+                PRIMARY KEY (hash_kind, hash)
+            )",
+            [],
+        )?;
+        self.conn.execute(
+            "CREATE TABLE IF NOT EXISTS transcode_failures (
+                hash_kind TEXT NOT NULL,
+                hash BLOB NOT NULL,
+                profile TEXT NOT NULL,
+                error TEXT NOT NULL,
+                attempts INTEGER NOT NULL,
+                failed_at INTEGER NOT NULL,
+                PRIMARY KEY (hash_kind, hash, profile)
+            )",
+            [],
+        )?;
+        self.conn.execute(
+            "CREATE TABLE IF NOT EXISTS settings (
+                key TEXT PRIMARY KEY NOT NULL,
+                value TEXT NOT NULL
+            )",
+            [],
+        )?;
         Ok(())
     }
 
+    /// Add columns that were added to existing tables after they were
+    /// created, and clean up rows left by older versions.
+    fn migrate(&self) -> anyhow::Result<()> {
+        if !self.has_column("roots", "content_type")? {
+            self.conn.execute(
+                "ALTER TABLE roots ADD COLUMN content_type TEXT NOT NULL DEFAULT 'music'",
+                [],
+            )?;
+        }
+        if !self.has_column("files", "cue_path")? {
+            self.conn
+                .execute("ALTER TABLE files ADD COLUMN cue_path TEXT", [])?;
+            self.conn
+                .execute("ALTER TABLE files ADD COLUMN cue_track INTEGER", [])?;
+        }
+        if !self.has_column("files", "revision")? {
+            self.conn.execute(
+                "ALTER TABLE files ADD COLUMN revision INTEGER NOT NULL DEFAULT 0",
+                [],
+            )?;
+        }
+
+        // cue sheet tracks used to fail with the FLAC profile, retry them
+        self.conn.execute(
+            "DELETE FROM transcode_failures WHERE error = 'cue sheet tracks can only be transcoded to Opus'",
+            [],
+        )?;
+        Ok(())
+    }
+
+    fn has_column(&self, table: &str, column: &str) -> anyhow::Result<bool> {
+        let count: u64 = self
+            .conn
+            .query_row(
+                "SELECT COUNT(*) FROM pragma_table_info(?) WHERE name = ?",
+                [table, column],
+                |row| row.get(0),
+            )
+            .context("failed to query table info")?;
+        Ok(count > 0)
+    }
+
     pub fn reset(&self) -> anyhow::Result<()> {
         self.conn.execute("DROP TABLE IF EXISTS roots", [])?;
         self.conn.execute("DROP TABLE IF EXISTS files", [])?;
@@ -131,10 +237,26 @@ impl Database {
         Ok(())
     }
 
+    /// Set the content type of a root, see `TranscodeContent::key`.
+    pub fn set_root_content_type(
+        &self,
+        node_id: NodeId,
+        name: &str,
+        content_type: &str,
+    ) -> anyhow::Result<()> {
+        let node_id = node_id_to_string(&node_id);
+        let updated = self.conn.execute(
+            "UPDATE roots SET content_type = ? WHERE node_id = ? AND name = ?",
+            [content_type, &node_id, name],
+        )?;
+        anyhow::ensure!(updated > 0, "root not found: {name}");
+        Ok(())
+    }
+
     pub fn get_roots_by_node_id(&self, node_id: NodeId) -> anyhow::Result<Vec<Root>> {
         let mut stmt = self
             .conn
-            .prepare("SELECT id, node_id, name, path FROM roots WHERE node_id = ?")
+            .prepare("SELECT id, node_id, name, path, content_type FROM roots WHERE node_id = ?")
             .expect("should prepare statement");
 
         let node_id = node_id_to_string(&node_id);
@@ -149,6 +271,7 @@ impl Database {
                 node_id,
                 name: row.get(2)?,
                 path: row.get(3)?,
+                content_type: row.get(4)?,
             })
         })
         .expect("should bind parameters")
@@ -186,7 +309,7 @@ impl Database {
         )?;
 
         {
-            let mut stmt = tx.prepare("INSERT INTO files (hash_kind, hash, node_id, root, path, local_tree, local_path) VALUES (?, ?, ?, ?, ?, ?, ?)")?;
+            let mut stmt = tx.prepare("INSERT INTO files (hash_kind, hash, node_id, root, path, local_tree, local_path, cue_path, cue_track, revision) VALUES (?, ?, ?, ?, ?, ?, ?, ?, ?, ?)")?;
             for file in iter {
                 stmt.execute((
                     file.hash_kind,
@@ -196,6 +319,9 @@ impl Database {
                     file.path,
                     file.local_tree,
                     file.local_path,
+                    file.cue_path,
+                    file.cue_track,
+                    file.revision,
                 ))?;
             }
         }
@@ -212,8 +338,8 @@ impl Database {
         file: InsertFile<'a>,
     ) -> anyhow::Result<()> {
         let mut stmt = self.conn.prepare(
-            "INSERT INTO files (hash_kind, hash, node_id, root, path, local_tree, local_path) VALUES (?, ?, ?, ?, ?, ?, ?)
-            ON CONFLICT(node_id, root, path) DO UPDATE SET hash_kind = excluded.hash_kind, hash = excluded.hash, local_tree = excluded.local_tree, local_path = excluded.local_path"
+            "INSERT INTO files (hash_kind, hash, node_id, root, path, local_tree, local_path, cue_path, cue_track, revision) VALUES (?, ?, ?, ?, ?, ?, ?, ?, ?, ?)
+            ON CONFLICT(node_id, root, path) DO UPDATE SET hash_kind = excluded.hash_kind, hash = excluded.hash, local_tree = excluded.local_tree, local_path = excluded.local_path, cue_path = excluded.cue_path, cue_track = excluded.cue_track, revision = excluded.revision"
         )?;
 
         stmt.execute((
@@ -224,6 +350,9 @@ impl Database {
             file.path,
             file.local_tree,
             file.local_path,
+            file.cue_path,
+            file.cue_track,
+            file.revision,
         ))?;
 
         Ok(())
@@ -232,7 +361,7 @@ impl Database {
     pub fn get_files(&self) -> anyhow::Result<Vec<File>> {
         let mut stmt = self
             .conn
-            .prepare("SELECT id, hash_kind, hash, node_id, root, path, local_tree, local_path FROM files")
+            .prepare("SELECT id, hash_kind, hash, node_id, root, path, local_tree, local_path, cue_path, cue_track, revision FROM files")
             .expect("should prepare statement");
 
         stmt.query_and_then([], |row| {
@@ -250,6 +379,9 @@ impl Database {
                 path: row.get(5)?,
                 local_tree: row.get(6)?,
                 local_path: row.get(7)?,
+                cue_path: row.get(8)?,
+                cue_track: row.get(9)?,
+                revision: row.get(10)?,
             })
         })
         .expect("should bind parameters")
@@ -260,7 +392,7 @@ impl Database {
     pub fn get_files_by_node_id(&self, node_id: NodeId) -> anyhow::Result<Vec<File>> {
         let mut stmt = self
             .conn
-            .prepare("SELECT id, hash_kind, hash, node_id, root, path, local_tree, local_path FROM files WHERE node_id = ?")
+            .prepare("SELECT id, hash_kind, hash, node_id, root, path, local_tree, local_path, cue_path, cue_track, revision FROM files WHERE node_id = ?")
             .expect("should prepare statement");
 
         let node_id = node_id_to_string(&node_id);
@@ -279,6 +411,9 @@ impl Database {
                 path: row.get(5)?,
                 local_tree: row.get(6)?,
                 local_path: row.get(7)?,
+                cue_path: row.get(8)?,
+                cue_track: row.get(9)?,
+                revision: row.get(10)?,
             })
         })
         .expect("should bind parameters")
@@ -289,7 +424,7 @@ impl Database {
     pub fn get_files_by_ne_node_id(&self, node_id: NodeId) -> anyhow::Result<Vec<File>> {
         let mut stmt = self
             .conn
-            .prepare("SELECT id, hash_kind, hash, node_id, root, path, local_tree, local_path FROM files WHERE node_id != ?")
+            .prepare("SELECT id, hash_kind, hash, node_id, root, path, local_tree, local_path, cue_path, cue_track, revision FROM files WHERE node_id != ?")
             .expect("should prepare statement");
 
         let node_id = node_id_to_string(&node_id);
@@ -308,6 +443,9 @@ impl Database {
                 path: row.get(5)?,
                 local_tree: row.get(6)?,
                 local_path: row.get(7)?,
+                cue_path: row.get(8)?,
+                cue_track: row.get(9)?,
+                revision: row.get(10)?,
             })
         })
         .expect("should bind parameters")
@@ -342,7 +480,7 @@ impl Database {
     ) -> anyhow::Result<Option<File>> {
         let mut stmt = self
         .conn
-        .prepare("SELECT id, hash_kind, hash, node_id, root, path, local_tree, local_path FROM files WHERE node_id = ? AND root = ? AND path = ?")
+        .prepare("SELECT id, hash_kind, hash, node_id, root, path, local_tree, local_path, cue_path, cue_track, revision FROM files WHERE node_id = ? AND root = ? AND path = ?")
         .expect("should prepare statement");
 
         let node_id = node_id_to_string(&node_id);
@@ -361,6 +499,9 @@ impl Database {
                 path: row.get(5)?,
                 local_tree: row.get(6)?,
                 local_path: row.get(7)?,
+                cue_path: row.get(8)?,
+                cue_track: row.get(9)?,
+                revision: row.get(10)?,
             })
         })
         .expect("should bind parameters")
@@ -378,7 +519,7 @@ impl Database {
 
         let placeholders = std::iter::repeat_n("(?, ?, ?)", keys.len()).join(", ");
         let sql = format!(
-            "SELECT id, hash_kind, hash, node_id, root, path, local_tree, local_path FROM files WHERE (node_id, root, path) IN ({placeholders})"
+            "SELECT id, hash_kind, hash, node_id, root, path, local_tree, local_path, cue_path, cue_track, revision FROM files WHERE (node_id, root, path) IN ({placeholders})"
         );
 
         let mut stmt = self.conn.prepare(&sql).expect("should prepare statement");
@@ -403,6 +544,9 @@ impl Database {
                 path: row.get(5)?,
                 local_tree: row.get(6)?,
                 local_path: row.get(7)?,
+                cue_path: row.get(8)?,
+                cue_track: row.get(9)?,
+                revision: row.get(10)?,
             })
         })
         .expect("should bind parameters")
@@ -508,8 +652,312 @@ impl Database {
         .expect("should bind parameters")
         .collect()
     }
+
+    /// Set the measured loudness and album of a file, replacing the existing
+    /// entry.
+    pub fn set_track_loudness(
+        &self,
+        hash_kind: &str,
+        hash: &[u8],
+        album: &str,
+        loudness: Option<f64>,
+        gated_blocks: u64,
+    ) -> anyhow::Result<()> {
+        self.conn.execute(
+            "INSERT INTO track_loudness (hash_kind, hash, album, loudness, gated_blocks) VALUES (?, ?, ?, ?, ?)
+            ON CONFLICT(hash_kind, hash) DO UPDATE SET album = excluded.album, loudness = excluded.loudness, gated_blocks = excluded.gated_blocks",
+            (hash_kind, hash, album, loudness, gated_blocks),
+        )?;
+        Ok(())
+    }
+
+    /// Get the measured loudness of the files in an album that belong to the
+    /// given node ID.
+    pub fn get_album_loudness_by_node_id(
+        &self,
+        node_id: NodeId,
+        album: &str,
+    ) -> anyhow::Result<Vec<TrackLoudness>> {
+        let mut stmt = self
+            .conn
+            .prepare(
+                "SELECT DISTINCT l.hash_kind, l.hash, l.loudness, l.gated_blocks FROM track_loudness l
+                JOIN files f ON f.hash_kind = l.hash_kind AND f.hash = l.hash
+                WHERE f.node_id = ? AND l.album = ?",
+            )
+            .expect("should prepare statement");
+
+        let node_id = node_id_to_string(&node_id);
+        stmt.query_and_then((&node_id, album), |row| {
+            Ok(TrackLoudness {
+                hash_kind: row.get(0)?,
+                hash: row.get(1)?,
+                loudness: row.get(2)?,
+                gated_blocks: row.get(3)?,
+            })
+        })
+        .expect("should bind parameters")
+        .collect()
+    }
+
+    /// Record that transcoding a file with a profile failed, incrementing the
+    /// attempt count if it failed before.
+    pub fn record_transcode_failure(
+        &self,
+        hash_kind: &str,
+        hash: &[u8],
+        profile: &str,
+        error: &str,
+        failed_at: u64,
+    ) -> anyhow::Result<()> {
+        self.conn.execute(
+            "INSERT INTO transcode_failures (hash_kind, hash, profile, error, attempts, failed_at) VALUES (?, ?, ?, ?, 1, ?)
+            ON CONFLICT(hash_kind, hash, profile) DO UPDATE SET error = excluded.error, attempts = attempts + 1, failed_at = excluded.failed_at",
+            (hash_kind, hash, profile, error, failed_at),
+        )?;
+        Ok(())
+    }
+
+    /// Delete the recorded failure of a file with a profile.
+    pub fn delete_transcode_failure(
+        &self,
+        hash_kind: &str,
+        hash: &[u8],
+        profile: &str,
+    ) -> anyhow::Result<()> {
+        self.conn.execute(
+            "DELETE FROM transcode_failures WHERE hash_kind = ? AND hash = ? AND profile = ?",
+            (hash_kind, hash, profile),
+        )?;
+        Ok(())
+    }
+
+    /// Delete recorded failures of files that don't belong to the given node
+    /// ID anymore.
+    pub fn delete_orphaned_transcode_failures(&self, node_id: NodeId) -> anyhow::Result<()> {
+        let node_id = node_id_to_string(&node_id);
+        self.conn.execute(
+            "DELETE FROM transcode_failures WHERE NOT EXISTS (
+                SELECT 1 FROM files f
+                WHERE f.node_id = ? AND f.hash_kind = transcode_failures.hash_kind AND f.hash = transcode_failures.hash
+            )",
+            [&node_id],
+        )?;
+        Ok(())
+    }
+
+    /// Get the recorded failures with a profile of files that belong to the
+    /// given node ID, with one entry per file.
+    pub fn get_transcode_failures_by_node_id(
+        &self,
+        node_id: NodeId,
+        profile: &str,
+    ) -> anyhow::Result<Vec<TranscodeFailure>> {
+        let mut stmt = self
+            .conn
+            .prepare(
+                "SELECT t.hash_kind, t.hash, f.local_path, t.error, t.attempts, t.failed_at FROM transcode_failures t
+                JOIN files f ON f.hash_kind = t.hash_kind AND f.hash = t.hash
+                WHERE f.node_id = ? AND t.profile = ?
+                ORDER BY t.failed_at DESC, f.local_path",
+            )
+            .expect("should prepare statement");
+
+        let node_id = node_id_to_string(&node_id);
+        stmt.query_and_then((&node_id, profile), |row| {
+            Ok(TranscodeFailure {
+                hash_kind: row.get(0)?,
+                hash: row.get(1)?,
+                local_path: row.get(2)?,
+                error: row.get(3)?,
+                attempts: row.get(4)?,
+                failed_at: row.get(5)?,
+            })
+        })
+        .expect("should bind parameters")
+        .collect()
+    }
+
+    /// Get the value of a persisted setting.
+    pub fn get_setting(&self, key: &str) -> anyhow::Result<Option<String>> {
+        let mut stmt = self
+            .conn
+            .prepare("SELECT value FROM settings WHERE key = ?")
+            .expect("should prepare statement");
+
+        stmt.query_row([key], |row| row.get(0))
+            .optional()
+            .context("failed to query setting")
+    }
+
+    /// Set the value of a persisted setting, replacing the existing value.
+    pub fn set_setting(&self, key: &str, value: &str) -> anyhow::Result<()> {
+        self.conn.execute(
+            "INSERT INTO settings (key, value) VALUES (?, ?)
+            ON CONFLICT(key) DO UPDATE SET value = excluded.value",
+            [key, value],
+        )?;
+        Ok(())
+    }
 }
 
 fn node_id_to_string(node_id: &NodeId) -> String {
     hex::encode(node_id)
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_migrate_root_content_type() {
+        // a roots table from before content types
+        let conn = rusqlite::Connection::open_in_memory().unwrap();
+        conn.execute(
+            "CREATE TABLE roots (
+                id INTEGER PRIMARY KEY AUTOINCREMENT,
+                node_id TEXT NOT NULL,
+                name TEXT NOT NULL,
+                path TEXT NOT NULL,
+                UNIQUE (node_id, name)
+            )",
+            [],
+        )
+        .unwrap();
+
+        let node_id = NodeId::from(iroh::SecretKey::from_bytes(&[1; 32]).public());
+        conn.execute(
+            "INSERT INTO roots (node_id, name, path) VALUES (?, 'books', '/books')",
+            [node_id_to_string(&node_id)],
+        )
+        .unwrap();
+
+        let db = Database::new_from_connection(conn).unwrap();
+
+        // existing roots are music
+        let roots = db.get_roots_by_node_id(node_id).unwrap();
+        assert_eq!(roots[0].content_type, "music");
+
+        db.set_root_content_type(node_id, "books", "speech")
+            .unwrap();
+        let roots = db.get_roots_by_node_id(node_id).unwrap();
+        assert_eq!(roots[0].content_type, "speech");
+
+        assert!(
+            db.set_root_content_type(node_id, "missing", "speech")
+                .is_err()
+        );
+    }
+
+    #[test]
+    fn test_migrate_files_cue() {
+        // a files table from before cue sheets
+        let conn = rusqlite::Connection::open_in_memory().unwrap();
+        conn.execute(
+            "CREATE TABLE files (
+                id INTEGER PRIMARY KEY AUTOINCREMENT,
+                hash_kind TEXT NOT NULL,
+                hash BLOB NOT NULL,
+                node_id TEXT NOT NULL,
+                root TEXT NOT NULL,
+                path TEXT NOT NULL,
+                local_tree TEXT NOT NULL,
+                local_path TEXT NOT NULL,
+                UNIQUE (node_id, root, path)
+            )",
+            [],
+        )
+        .unwrap();
+
+        let mut db = Database::new_from_connection(conn).unwrap();
+
+        let node_id = NodeId::from(iroh::SecretKey::from_bytes(&[1; 32]).public());
+        let file = |path, cue_path, cue_track| InsertFile {
+            hash_kind: "xxh3",
+            hash: &[1, 2, 3],
+            root: "music",
+            path,
+            local_tree: "",
+            local_path: "/music/album.flac",
+            cue_path,
+            cue_track,
+            revision: 0,
+        };
+        db.replace_local_files(
+            node_id,
+            [
+                file("album.flac", None, None),
+                file("01 - Intro.flac", Some("/music/album.cue"), Some(1)),
+            ]
+            .into_iter(),
+        )
+        .unwrap();
+
+        let files = db.get_files_by_node_id(node_id).unwrap();
+        assert_eq!(files[0].cue_path, None);
+        assert_eq!(files[1].cue_path.as_deref(), Some("/music/album.cue"));
+        assert_eq!(files[1].cue_track, Some(1));
+    }
+
+    #[test]
+    fn test_migrate_files_revision() {
+        // a files table from before revisions
+        let conn = rusqlite::Connection::open_in_memory().unwrap();
+        conn.execute(
+            "CREATE TABLE files (
+                id INTEGER PRIMARY KEY AUTOINCREMENT,
+                hash_kind TEXT NOT NULL,
+                hash BLOB NOT NULL,
+                node_id TEXT NOT NULL,
+                root TEXT NOT NULL,
+                path TEXT NOT NULL,
+                local_tree TEXT NOT NULL,
+                local_path TEXT NOT NULL,
+                cue_path TEXT,
+                cue_track INTEGER,
+                UNIQUE (node_id, root, path)
+            )",
+            [],
+        )
+        .unwrap();
+        conn.execute(
+            "INSERT INTO files (hash_kind, hash, node_id, root, path, local_tree, local_path) VALUES ('xxh3', x'010203', 'node', 'music', 'old.ogg', '', '/music/old.ogg')",
+            [],
+        )
+        .unwrap();
+
+        let mut db = Database::new_from_connection(conn).unwrap();
+
+        let node_id = NodeId::from(iroh::SecretKey::from_bytes(&[1; 32]).public());
+        db.insert_remote_file(
+            node_id,
+            InsertFile {
+                hash_kind: "xxh3",
+                hash: &[1, 2, 3],
+                root: "music",
+                path: "new.ogg",
+                local_tree: "",
+                local_path: "/music/new.ogg",
+                cue_path: None,
+                cue_track: None,
+                revision: 2,
+            },
+        )
+        .unwrap();
+
+        let old_revision: u64 = db
+            .conn
+            .query_row(
+                "SELECT revision FROM files WHERE path = 'old.ogg'",
+                [],
+                |row| row.get(0),
+            )
+            .unwrap();
+        let file = db
+            .get_file_by_node_root_path(node_id, "music", "new.ogg")
+            .unwrap()
+            .unwrap();
+        assert_eq!(old_revision, 0);
+        assert_eq!(file.revision, 2);
+    }
+}
@@ -11,7 +11,8 @@ use crate::{
     error::{CoreError, core_error},
     library::{
         Library, LibraryCommand, LibraryModel,
-        transcode::{TranscodePolicy, TranscodeStatusCache},
+        estimate::TranscodePlan,
+        transcode::{TranscodeContent, TranscodePolicy, TranscodeProfile, TranscodeStatusCache},
     },
     node::{DownloadPartialItemModel, Node, NodeCommand, NodeModel},
 };
@@ -45,6 +46,8 @@ pub struct CoreOptions {
     pub in_memory: bool,
     pub project_dirs: Option<ProjectDirsOptions>,
     pub transcode_policy: TranscodePolicy,
+    /// The number of transcode worker threads, or `None` to use one per core.
+    pub transcode_worker_count: Option<u32>,
 }
 
 /// Long-lived object created by Compose as the entry point to the Rust core.
@@ -199,6 +202,7 @@ impl Core {
                                 node_id,
                                 transcodes_dir.clone(),
                                 options.transcode_policy,
+                                options.transcode_worker_count,
                                 transcode_status_cache.clone(),
                             ),
                             Node::new(event_handler, secret_key, db, transcode_status_cache),
@@ -432,6 +436,23 @@ impl Core {
         Ok(())
     }
 
+    /// Sets the content type of a library root.
+    ///
+    /// Speech roots are transcoded to mono at a low bitrate with the Opus
+    /// speech mode. The root's files are transcoded again with the new
+    /// settings.
+    pub fn set_library_root_content_type(
+        &self,
+        name: String,
+        content_type: TranscodeContent,
+    ) -> Result<(), CoreError> {
+        self.library
+            .send(LibraryCommand::SetRootContentType { name, content_type })
+            .context("failed to send to library thread")?;
+
+        Ok(())
+    }
+
     // TODO: async wait for completion or return progress somehow
     pub fn rescan_library(&self) -> Result<(), CoreError> {
         self.library
@@ -440,6 +461,14 @@ impl Core {
         Ok(())
     }
 
+    /// Deletes transcodes of files that aren't in the library anymore.
+    pub fn collect_transcode_garbage(&self) -> Result<(), CoreError> {
+        self.library
+            .send(LibraryCommand::CollectGarbage)
+            .context("failed to send to library thread")?;
+        Ok(())
+    }
+
     pub fn set_transcode_policy(&self, transcode_policy: TranscodePolicy) -> Result<(), CoreError> {
         self.library
             .send(LibraryCommand::SetTranscodePolicy(transcode_policy))
@@ -447,6 +476,128 @@ impl Core {
         Ok(())
     }
 
+    pub fn set_transcode_profile(
+        &self,
+        transcode_profile: TranscodeProfile,
+    ) -> Result<(), CoreError> {
+        transcode_profile
+            .validate()
+            .context("invalid transcode profile")?;
+
+        self.library
+            .send(LibraryCommand::SetTranscodeProfile(transcode_profile))
+            .context("failed to send to library thread")?;
+        Ok(())
+    }
+
+    /// Sets the maximum size of the transcode cache, or `None` for no limit.
+    pub fn set_transcode_cache_size_limit(&self, limit: Option<u64>) -> Result<(), CoreError> {
+        self.library
+            .send(LibraryCommand::SetTranscodeCacheSizeLimit(limit))
+            .context("failed to send to library thread")?;
+        Ok(())
+    }
+
+    /// Retries failed transcodes of the files with the given source paths, or
+    /// all failed transcodes if `None`.
+    pub fn retry_failed_transcodes(
+        &self,
+        local_paths: Option<Vec<String>>,
+    ) -> Result<(), CoreError> {
+        self.library
+            .send(LibraryCommand::RetryFailedTranscodes(local_paths))
+            .context("failed to send to library thread")?;
+        Ok(())
+    }
+
+    /// Pauses transcoding.
+    ///
+    /// Running transcodes stop at the next packet until transcoding is
+    /// resumed.
+    pub fn pause_transcodes(&self) -> Result<(), CoreError> {
+        self.library
+            .send(LibraryCommand::PauseTranscodes)
+            .context("failed to send to library thread")?;
+        Ok(())
+    }
+
+    /// Resumes transcoding after `pause_transcodes`.
+    pub fn resume_transcodes(&self) -> Result<(), CoreError> {
+        self.library
+            .send(LibraryCommand::ResumeTranscodes)
+            .context("failed to send to library thread")?;
+        Ok(())
+    }
+
+    /// Cancels transcoding the files with the given source paths.
+    ///
+    /// Cancelled files are transcoded again when they're requested.
+    pub fn cancel_transcodes(&self, local_paths: Vec<String>) -> Result<(), CoreError> {
+        self.library
+            .send(LibraryCommand::CancelTranscodes(local_paths))
+            .context("failed to send to library thread")?;
+        Ok(())
+    }
+
+    /// Finds the highest bitrate that fits the files with the given source
+    /// paths into a budget in bytes.
+    ///
+    /// If `schedule` is set and a bitrate fits, the transcode profile is
+    /// switched to that bitrate and the files are transcoded first.
+    pub async fn plan_transcodes(
+        &self,
+        local_paths: Vec<String>,
+        budget: u64,
+        schedule: bool,
+    ) -> Result<TranscodePlan, CoreError> {
+        let (callback_tx, callback_rx) = tokio::sync::oneshot::channel();
+
+        self.library
+            .send(LibraryCommand::PlanTranscodes {
+                local_paths,
+                budget,
+                schedule,
+                callback: callback_tx,
+            })
+            .context("failed to send to library thread")?;
+
+        callback_rx
+            .await
+            .map_err(|_dropped| core_error!("plan failed, sender dropped"))?
+            .map_err(CoreError::from)
+    }
+
+    /// Sets the number of transcode worker threads, or `None` to use one per
+    /// core.
+    pub fn set_transcode_worker_count(&self, count: Option<u32>) -> Result<(), CoreError> {
+        self.library
+            .send(LibraryCommand::SetTranscodeWorkerCount(count))
+            .context("failed to send to library thread")?;
+        Ok(())
+    }
+
+    /// Sets whether transcoding runs in the background.
+    ///
+    /// In background mode, transcode workers run with a lower priority and
+    /// fewer workers run while a scan or transfer is active.
+    pub fn set_transcode_background(&self, enabled: bool) -> Result<(), CoreError> {
+        self.library
+            .send(LibraryCommand::SetTranscodeBackground(enabled))
+            .context("failed to send to library thread")?;
+        Ok(())
+    }
+
+    /// Sets whether Opus transcodes are verified before they're marked ready.
+    ///
+    /// Verified transcodes are decoded again and compared to the duration of
+    /// the source. Transcodes that fail verification are marked as failed.
+    pub fn set_transcode_verify(&self, enabled: bool) -> Result<(), CoreError> {
+        self.library
+            .send(LibraryCommand::SetTranscodeVerify(enabled))
+            .context("failed to send to library thread")?;
+        Ok(())
+    }
+
     pub fn reset_database(&self) -> Result<(), CoreError> {
         let db = self
             .db
@@ -0,0 +1,696 @@
+//! Chapter markers of audiobook sources.
+//!
+//! Chapters are read from the QuickTime chapter track or Nero `chpl` atom of
+//! MP4 files and from the `Chapters` element of Matroska files, and written
+//! to Opus transcodes as `CHAPTERxxx` and `CHAPTERxxxNAME` comments, following
+//! the Vorbis comment chapter extension.
+
+use crate::library::mp4::{atoms, find_atom, find_top_level_atom};
+use anyhow::Context;
+use std::{
+    fs::File,
+    io::{BufReader, Read, Seek, SeekFrom},
+    path::Path,
+};
+
+/// The maximum size of a chapter title sample or `Chapters` element that is
+/// read.
+const MAX_CHAPTER_DATA_BYTES: u64 = 16 * 1024 * 1024;
+
+/// The maximum number of chapters read from a chapter track.
+const MAX_CHAPTERS: usize = 10_000;
+
+const EBML_ID: u32 = 0x1A45DFA3;
+const SEGMENT_ID: u32 = 0x18538067;
+const CHAPTERS_ID: u32 = 0x1043A770;
+const EDITION_ENTRY_ID: u32 = 0x45B9;
+const CHAPTER_ATOM_ID: u32 = 0xB6;
+const CHAPTER_TIME_START_ID: u32 = 0x91;
+const CHAPTER_FLAG_HIDDEN_ID: u32 = 0x98;
+const CHAPTER_FLAG_ENABLED_ID: u32 = 0x4598;
+const CHAPTER_DISPLAY_ID: u32 = 0x80;
+const CHAP_STRING_ID: u32 = 0x85;
+
+/// A chapter marker.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Chapter {
+    /// The start of the chapter in seconds, from the start of the audio
+    /// after the encoder delay.
+    pub start_secs: f64,
+    pub title: String,
+}
+
+/// Reads the chapters of an MP4 or Matroska file.
+///
+/// Returns no chapters for other formats.
+pub fn read(path: &Path) -> anyhow::Result<Vec<Chapter>> {
+    let mut file = File::open(path).context("failed to open file")?;
+    let file_len = file
+        .metadata()
+        .context("failed to get file metadata")?
+        .len();
+
+    let mut magic = [0; 8];
+    if file.read_exact(&mut magic).is_err() {
+        return Ok(Vec::new());
+    }
+
+    let mut chapters = if &magic[4..] == b"ftyp" {
+        read_mp4(&mut file, file_len)?
+    } else if magic[..4] == EBML_ID.to_be_bytes() {
+        read_matroska(file, file_len)?
+    } else {
+        Vec::new()
+    };
+
+    // players expect chapters in order
+    chapters.sort_by(|a, b| a.start_secs.total_cmp(&b.start_secs));
+    chapters.dedup_by(|a, b| a.start_secs == b.start_secs);
+
+    Ok(chapters)
+}
+
+/// Converts chapters to Opus comments.
+///
+/// Chapter times are moved to the output's timeline. Frames before `range`
+/// are dropped, along with the encoder delay, and the rest is resampled from
+/// `sample_rate` to 48 kHz. Players skip the pre-skip before the timeline
+/// starts, so it doesn't move the chapters. Chapters after the range are
+/// dropped, and the chapter the range starts in starts at zero.
+pub fn opus_comments(
+    chapters: &[Chapter],
+    sample_rate: u32,
+    range: Option<(u64, Option<u64>)>,
+) -> Vec<String> {
+    let (start, end) = range.unwrap_or((0, None));
+
+    let frames = chapters
+        .iter()
+        .map(|chapter| {
+            (
+                (chapter.start_secs * sample_rate as f64).round() as u64,
+                chapter,
+            )
+        })
+        .filter(|(frame, _)| end.is_none_or(|end| *frame < end))
+        .collect::<Vec<_>>();
+
+    // the last chapter starting before the range covers the start
+    let first = frames
+        .iter()
+        .rposition(|(frame, _)| *frame <= start)
+        .unwrap_or(0);
+
+    let mut comments = Vec::new();
+    for (i, (frame, chapter)) in frames[first..].iter().enumerate() {
+        let output_frames = frame.saturating_sub(start) * 48000 / sample_rate as u64;
+        let millis = output_frames * 1000 / 48000;
+
+        comments.push(format!(
+            "CHAPTER{i:03}={:02}:{:02}:{:02}.{:03}",
+            millis / 3_600_000,
+            millis / 60_000 % 60,
+            millis / 1000 % 60,
+            millis % 1000
+        ));
+        comments.push(format!("CHAPTER{i:03}NAME={}", chapter.title));
+    }
+
+    comments
+}
+
+/// Reads the chapters of an MP4 file, preferring the QuickTime chapter track
+/// over the Nero `chpl` atom.
+fn read_mp4(file: &mut File, file_len: u64) -> anyhow::Result<Vec<Chapter>> {
+    let Some(moov) = find_top_level_atom(file, file_len, b"moov")? else {
+        return Ok(Vec::new());
+    };
+
+    let chapters = read_mp4_chapter_track(file, &moov)?;
+    if !chapters.is_empty() {
+        return Ok(chapters);
+    }
+
+    Ok(find_atom(&moov, b"udta")
+        .and_then(|udta| find_atom(udta, b"chpl"))
+        .map(parse_chpl)
+        .unwrap_or_default())
+}
+
+/// Parses a Nero `chpl` atom, whose chapter times are in 100 ns units.
+fn parse_chpl(data: &[u8]) -> Vec<Chapter> {
+    let mut chapters = Vec::new();
+
+    // full atom with 4 bytes of version and flags, and 4 more bytes in version 1
+    let Some(&version) = data.first() else {
+        return chapters;
+    };
+    let header_len = if version == 1 { 8 } else { 4 };
+    let Some((&count, mut data)) = data.get(header_len..).and_then(|data| data.split_first())
+    else {
+        return chapters;
+    };
+
+    for _ in 0..count {
+        let Some(start) = data.get(..8) else {
+            break;
+        };
+        let start = u64::from_be_bytes(start.try_into().unwrap());
+        let Some(&title_len) = data.get(8) else {
+            break;
+        };
+        let Some(title) = data.get(9..9 + title_len as usize) else {
+            break;
+        };
+
+        chapters.push(Chapter {
+            start_secs: start as f64 / 10_000_000.0,
+            title: String::from_utf8_lossy(title).into_owned(),
+        });
+        data = &data[9 + title_len as usize..];
+    }
+
+    chapters
+}
+
+/// Reads the text track that another track references as its chapters.
+fn read_mp4_chapter_track(file: &mut File, moov: &[u8]) -> anyhow::Result<Vec<Chapter>> {
+    let traks = atoms(moov)
+        .filter(|(kind, _)| *kind == b"trak")
+        .map(|(_, trak)| trak)
+        .collect::<Vec<_>>();
+
+    let chapter_track_ids = traks
+        .iter()
+        .filter_map(|trak| find_atom(trak, b"tref").and_then(|tref| find_atom(tref, b"chap")))
+        .flat_map(|chap| chap.chunks_exact(4))
+        .map(|id| u32::from_be_bytes(id.try_into().unwrap()))
+        .collect::<Vec<_>>();
+
+    let track = traks.iter().find(|trak| {
+        find_atom(trak, b"tkhd")
+            .and_then(|tkhd| full_atom_u32(tkhd, 12, 20))
+            .is_some_and(|id| chapter_track_ids.contains(&id))
+    });
+    let Some(track) = track else {
+        return Ok(Vec::new());
+    };
+
+    let mdia = find_atom(track, b"mdia").context("chapter track has no mdia atom")?;
+    let timescale = find_atom(mdia, b"mdhd")
+        .and_then(|mdhd| full_atom_u32(mdhd, 12, 20))
+        .filter(|timescale| *timescale > 0)
+        .context("chapter track has no timescale")?;
+    let stbl = find_atom(mdia, b"minf")
+        .and_then(|minf| find_atom(minf, b"stbl"))
+        .context("chapter track has no sample table")?;
+
+    // start times from the durations of the samples
+    let mut starts = Vec::new();
+    let mut time = 0u64;
+    let stts = find_atom(stbl, b"stts").context("chapter track has no stts atom")?;
+    for entry in table(stts, 8) {
+        let count = (be_u32(entry, 0) as usize).min(MAX_CHAPTERS - starts.len());
+        let delta = be_u32(entry, 4) as u64;
+        for _ in 0..count {
+            starts.push(time);
+            time += delta;
+        }
+    }
+
+    // sample sizes, which are either all the same or listed
+    let stsz = find_atom(stbl, b"stsz").context("chapter track has no stsz atom")?;
+    let sample_size = stsz.get(4..8).map(|size| be_u32(size, 0)).unwrap_or(0);
+    let sizes: Vec<u32> = match sample_size {
+        0 => stsz
+            .get(4..)
+            .map(|stsz| table(stsz, 4).map(|size| be_u32(size, 0)).collect())
+            .unwrap_or_default(),
+        size => vec![size; starts.len()],
+    };
+
+    // sample offsets from the chunk offsets and the samples in each chunk
+    let chunk_offsets: Vec<u64> = match find_atom(stbl, b"stco") {
+        Some(stco) => table(stco, 4)
+            .map(|offset| be_u32(offset, 0) as u64)
+            .collect(),
+        None => find_atom(stbl, b"co64")
+            .map(|co64| {
+                table(co64, 8)
+                    .map(|offset| u64::from_be_bytes(offset.try_into().unwrap()))
+                    .collect()
+            })
+            .unwrap_or_default(),
+    };
+    let stsc = find_atom(stbl, b"stsc")
+        .map(|stsc| {
+            table(stsc, 12)
+                .map(|entry| (be_u32(entry, 0), be_u32(entry, 4)))
+                .collect::<Vec<_>>()
+        })
+        .unwrap_or_default();
+
+    let mut offsets = Vec::new();
+    let mut sample = 0;
+    for (chunk, chunk_offset) in chunk_offsets.iter().enumerate() {
+        let samples_per_chunk = stsc
+            .iter()
+            .rev()
+            .find(|(first_chunk, _)| *first_chunk as usize <= chunk + 1)
+            .map_or(1, |(_, samples)| *samples);
+
+        let mut offset = *chunk_offset;
+        for _ in 0..samples_per_chunk {
+            let Some(&size) = sizes.get(sample) else {
+                break;
+            };
+            offsets.push((offset, size));
+            offset += size as u64;
+            sample += 1;
+        }
+    }
+
+    let mut chapters = Vec::new();
+    for (start, (offset, size)) in starts.into_iter().zip(offsets) {
+        if size as u64 > MAX_CHAPTER_DATA_BYTES {
+            anyhow::bail!("chapter title sample is too large");
+        }
+
+        file.seek(SeekFrom::Start(offset))
+            .context("failed to seek to chapter title")?;
+        let mut data = vec![0; size as usize];
+        file.read_exact(&mut data)
+            .context("failed to read chapter title")?;
+
+        chapters.push(Chapter {
+            start_secs: start as f64 / timescale as f64,
+            title: parse_text_sample(&data),
+        });
+    }
+
+    Ok(chapters)
+}
+
+/// Parses a QuickTime text sample, which is a 16-bit length followed by
+/// UTF-8 text, or UTF-16 text with a byte order mark.
+fn parse_text_sample(data: &[u8]) -> String {
+    let len = data
+        .get(..2)
+        .map_or(0, |len| u16::from_be_bytes([len[0], len[1]]));
+    let text = data.get(2..2 + len as usize).unwrap_or_default();
+
+    match text {
+        [0xFE, 0xFF, text @ ..] => String::from_utf16_lossy(
+            &text
+                .chunks_exact(2)
+                .map(|c| u16::from_be_bytes([c[0], c[1]]))
+                .collect::<Vec<_>>(),
+        ),
+        [0xFF, 0xFE, text @ ..] => String::from_utf16_lossy(
+            &text
+                .chunks_exact(2)
+                .map(|c| u16::from_le_bytes([c[0], c[1]]))
+                .collect::<Vec<_>>(),
+        ),
+        text => String::from_utf8_lossy(text).into_owned(),
+    }
+}
+
+/// Returns the entries of a sample table atom, which is a full atom with an
+/// entry count.
+fn table(data: &[u8], entry_len: usize) -> impl Iterator<Item = &[u8]> {
+    let count = data.get(4..8).map_or(0, |count| be_u32(count, 0) as usize);
+    data.get(8..)
+        .unwrap_or_default()
+        .chunks_exact(entry_len)
+        .take(count)
+}
+
+/// Reads a 32-bit field of a full atom, whose offset depends on whether the
+/// atom has 32-bit or 64-bit times.
+fn full_atom_u32(data: &[u8], v0_offset: usize, v1_offset: usize) -> Option<u32> {
+    let offset = match data.first()? {
+        1 => v1_offset,
+        _ => v0_offset,
+    };
+    data.get(offset..offset + 4).map(|value| be_u32(value, 0))
+}
+
+fn be_u32(data: &[u8], offset: usize) -> u32 {
+    u32::from_be_bytes(data[offset..offset + 4].try_into().unwrap())
+}
+
+/// Reads the chapters of a Matroska file from the first edition of its
+/// `Chapters` element.
+///
+/// The top level elements of the segment are skipped over until the
+/// `Chapters` element is found, which muxers usually write before the
+/// clusters.
+fn read_matroska(file: File, file_len: u64) -> anyhow::Result<Vec<Chapter>> {
+    let mut reader = BufReader::new(file);
+    reader
+        .seek(SeekFrom::Start(0))
+        .context("failed to seek to start")?;
+
+    // skip the EBML header
+    let (_, size) = read_element_header(&mut reader)?;
+    let size = size.context("EBML header has unknown size")?;
+    reader
+        .seek_relative(size as i64)
+        .context("failed to skip EBML header")?;
+
+    let (id, size) = read_element_header(&mut reader)?;
+    anyhow::ensure!(id == SEGMENT_ID, "expected segment, got element {id:#x}");
+    let segment_start = reader.stream_position()?;
+    let segment_end = size.map_or(file_len, |size| (segment_start + size).min(file_len));
+
+    while reader.stream_position()? < segment_end {
+        let (id, size) = read_element_header(&mut reader)?;
+
+        // elements with unknown sizes can't be skipped without parsing them
+        let Some(size) = size else {
+            break;
+        };
+
+        if id == CHAPTERS_ID {
+            anyhow::ensure!(
+                size <= MAX_CHAPTER_DATA_BYTES,
+                "chapters element is too large"
+            );
+            let mut data = vec![0; size as usize];
+            reader
+                .read_exact(&mut data)
+                .context("failed to read chapters")?;
+            return Ok(parse_matroska_chapters(&data));
+        }
+
+        reader
+            .seek_relative(size as i64)
+            .context("failed to skip element")?;
+    }
+
+    Ok(Vec::new())
+}
+
+/// Parses the contents of a Matroska `Chapters` element.
+fn parse_matroska_chapters(data: &[u8]) -> Vec<Chapter> {
+    let Some(edition) = ebml_elements(data)
+        .find(|(id, _)| *id == EDITION_ENTRY_ID)
+        .map(|(_, edition)| edition)
+    else {
+        return Vec::new();
+    };
+
+    ebml_elements(edition)
+        .filter(|(id, _)| *id == CHAPTER_ATOM_ID)
+        .filter_map(|(_, atom)| {
+            let mut start_ns = None;
+            let mut title = None;
+            let mut visible = true;
+
+            for (id, data) in ebml_elements(atom) {
+                match id {
+                    CHAPTER_TIME_START_ID => start_ns = Some(ebml_uint(data)),
+                    CHAPTER_FLAG_HIDDEN_ID => visible &= ebml_uint(data) == 0,
+                    CHAPTER_FLAG_ENABLED_ID => visible &= ebml_uint(data) != 0,
+                    CHAPTER_DISPLAY_ID if title.is_none() => {
+                        title = ebml_elements(data)
+                            .find(|(id, _)| *id == CHAP_STRING_ID)
+                            .map(|(_, string)| {
+                                String::from_utf8_lossy(string)
+                                    .trim_end_matches('\0')
+                                    .to_string()
+                            });
+                    }
+                    _ => {}
+                }
+            }
+
+            visible.then_some(Chapter {
+                start_secs: start_ns? as f64 / 1_000_000_000.0,
+                title: title.unwrap_or_default(),
+            })
+        })
+        .collect()
+}
+
+/// Reads the ID and size of an EBML element, where the size is `None` if
+/// it's unknown.
+fn read_element_header(reader: &mut impl Read) -> anyhow::Result<(u32, Option<u64>)> {
+    let (id, _) = read_vint(reader).context("failed to read element id")?;
+    let (size, len) = read_vint(reader).context("failed to read element size")?;
+
+    anyhow::ensure!(id <= u32::MAX as u64, "invalid element id");
+
+    // a size with all value bits set is unknown
+    let value_bits = 7 * len as u32;
+    let size = size & ((1 << value_bits) - 1);
+    let unknown = size == (1 << value_bits) - 1;
+
+    Ok((id as u32, (!unknown).then_some(size)))
+}
+
+/// Reads an EBML variable length integer, returning it with the length
+/// marker and its length in bytes.
+fn read_vint(reader: &mut impl Read) -> anyhow::Result<(u64, usize)> {
+    let mut first = [0];
+    reader.read_exact(&mut first)?;
+
+    let len = first[0].leading_zeros() as usize + 1;
+    anyhow::ensure!(len <= 8, "invalid variable length integer");
+
+    let mut rest = [0; 7];
+    reader.read_exact(&mut rest[..len - 1])?;
+
+    let value = rest[..len - 1]
+        .iter()
+        .fold(first[0] as u64, |value, byte| value << 8 | *byte as u64);
+    Ok((value, len))
+}
+
+/// Iterates over the EBML elements in a buffer, yielding their IDs and
+/// contents.
+fn ebml_elements(mut data: &[u8]) -> impl Iterator<Item = (u32, &[u8])> {
+    std::iter::from_fn(move || {
+        let (id, size) = read_element_header(&mut data).ok()?;
+        let size = usize::try_from(size?).ok()?;
+        let contents = data.get(..size)?;
+        data = &data[size..];
+        Some((id, contents))
+    })
+}
+
+/// Parses an EBML unsigned integer.
+fn ebml_uint(data: &[u8]) -> u64 {
+    data.iter().fold(0, |value, byte| value << 8 | *byte as u64)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::path::PathBuf;
+
+    fn atom(kind: &[u8; 4], contents: &[u8]) -> Vec<u8> {
+        let mut atom = ((contents.len() + 8) as u32).to_be_bytes().to_vec();
+        atom.extend(kind);
+        atom.extend(contents);
+        atom
+    }
+
+    fn element(id: u32, contents: &[u8]) -> Vec<u8> {
+        let mut element = id.to_be_bytes().to_vec();
+        while element[0] == 0 {
+            element.remove(0);
+        }
+        // 8-byte sizes for simplicity
+        element.push(0x01);
+        element.extend(&(contents.len() as u64).to_be_bytes()[1..]);
+        element.extend(contents);
+        element
+    }
+
+    fn write_temp(name: &str, data: &[u8]) -> PathBuf {
+        let path = std::env::temp_dir().join(format!(
+            "musicopy-test-{}-chapters-{name}",
+            std::process::id()
+        ));
+        std::fs::write(&path, data).unwrap();
+        path
+    }
+
+    fn chapter(start_secs: f64, title: &str) -> Chapter {
+        Chapter {
+            start_secs,
+            title: title.to_string(),
+        }
+    }
+
+    #[test]
+    fn test_read_mp4_chapter_track() {
+        let u32s = |values: &[u32]| {
+            values
+                .iter()
+                .flat_map(|value| value.to_be_bytes())
+                .collect::<Vec<_>>()
+        };
+
+        // two text samples in one chunk after the moov atom
+        let samples = [b"\x00\x05Intro".as_slice(), b"\x00\x09Chapter 2"].concat();
+        let ftyp = atom(b"ftyp", b"M4B \x00\x00\x00\x00");
+
+        let moov = |samples_offset: u32| {
+            // the audio track references track 2 as its chapters
+            let audio = atom(
+                b"trak",
+                &[
+                    atom(b"tkhd", &u32s(&[0, 0, 0, 1])),
+                    atom(b"tref", &atom(b"chap", &u32s(&[2]))),
+                ]
+                .concat(),
+            );
+
+            // 1000 units per second, the second chapter starts at 61.5 seconds
+            let stbl = [
+                atom(b"stts", &u32s(&[0, 2, 1, 61500, 1, 1000])),
+                atom(b"stsz", &u32s(&[0, 0, 2, 7, 11])),
+                atom(b"stsc", &u32s(&[0, 1, 1, 2, 1])),
+                atom(b"stco", &u32s(&[0, 1, samples_offset])),
+            ]
+            .concat();
+            let text = atom(
+                b"trak",
+                &[
+                    atom(b"tkhd", &u32s(&[0, 0, 0, 2])),
+                    atom(
+                        b"mdia",
+                        &[
+                            atom(b"mdhd", &u32s(&[0, 0, 0, 1000])),
+                            atom(b"minf", &atom(b"stbl", &stbl)),
+                        ]
+                        .concat(),
+                    ),
+                ]
+                .concat(),
+            );
+
+            atom(b"moov", &[audio, text].concat())
+        };
+
+        // the samples come after the moov atom, whose size doesn't depend on
+        // the offset
+        let samples_offset = (ftyp.len() + moov(0).len()) as u32;
+        let data = [ftyp, moov(samples_offset), samples].concat();
+
+        let path = write_temp("track.m4b", &data);
+        let chapters = read(&path).unwrap();
+        let _ = std::fs::remove_file(&path);
+
+        assert_eq!(
+            chapters,
+            vec![chapter(0.0, "Intro"), chapter(61.5, "Chapter 2")]
+        );
+    }
+
+    #[test]
+    fn test_parse_chpl() {
+        let mut data = vec![1, 0, 0, 0, 0, 0, 0, 0, 2];
+        data.extend(0u64.to_be_bytes());
+        data.extend([5]);
+        data.extend(b"Intro");
+        data.extend(615_000_000u64.to_be_bytes());
+        data.extend([9]);
+        data.extend(b"Chapter 2");
+
+        assert_eq!(
+            parse_chpl(&data),
+            vec![chapter(0.0, "Intro"), chapter(61.5, "Chapter 2")]
+        );
+
+        // truncated
+        assert_eq!(parse_chpl(&data[..25]), vec![chapter(0.0, "Intro")]);
+    }
+
+    #[test]
+    fn test_read_matroska() {
+        let chapter_atom = |start_ns: u64, title: &str, hidden: bool| {
+            element(
+                CHAPTER_ATOM_ID,
+                &[
+                    element(CHAPTER_TIME_START_ID, &start_ns.to_be_bytes()),
+                    element(CHAPTER_FLAG_HIDDEN_ID, &[hidden as u8]),
+                    element(
+                        CHAPTER_DISPLAY_ID,
+                        &element(CHAP_STRING_ID, title.as_bytes()),
+                    ),
+                ]
+                .concat(),
+            )
+        };
+        let chapters = element(
+            CHAPTERS_ID,
+            &element(
+                EDITION_ENTRY_ID,
+                &[
+                    chapter_atom(0, "Intro", false),
+                    chapter_atom(5_000_000_000, "Hidden", true),
+                    chapter_atom(61_500_000_000, "Chapter 2", false),
+                ]
+                .concat(),
+            ),
+        );
+
+        // chapters after an element that's skipped over
+        let data = [
+            element(EBML_ID, &[0; 4]),
+            element(
+                SEGMENT_ID,
+                &[element(0x1F43B675, &[0; 100]), chapters].concat(),
+            ),
+        ]
+        .concat();
+
+        let path = write_temp("chapters.mka", &data);
+        let chapters = read(&path).unwrap();
+        let _ = std::fs::remove_file(&path);
+
+        assert_eq!(
+            chapters,
+            vec![chapter(0.0, "Intro"), chapter(61.5, "Chapter 2")]
+        );
+    }
+
+    #[test]
+    fn test_read_other() {
+        let path = PathBuf::from(env!("CARGO_MANIFEST_DIR"))
+            .join("fixtures")
+            .join("tags.flac");
+        assert_eq!(read(&path).unwrap(), Vec::new());
+    }
+
+    #[test]
+    fn test_opus_comments() {
+        let chapters = [
+            chapter(0.0, "Intro"),
+            chapter(61.5, "Chapter 2"),
+            chapter(3723.25, "Chapter 3"),
+        ];
+
+        assert_eq!(
+            opus_comments(&chapters, 44100, None),
+            [
+                "CHAPTER000=00:00:00.000",
+                "CHAPTER000NAME=Intro",
+                "CHAPTER001=00:01:01.500",
+                "CHAPTER001NAME=Chapter 2",
+                "CHAPTER002=01:02:03.250",
+                "CHAPTER002NAME=Chapter 3",
+            ]
+        );
+
+        // a range starting in the second chapter and ending before the third
+        assert_eq!(
+            opus_comments(&chapters, 44100, Some((62 * 44100, Some(3000 * 44100)))),
+            ["CHAPTER000=00:00:00.000", "CHAPTER000NAME=Chapter 2"]
+        );
+    }
+}
@@ -0,0 +1,460 @@
+//! CUE sheets that split a single-file rip of an album into tracks.
+//!
+//! Each track of a split file is a separate library entry. Its path is
+//! virtual, next to the source file, and its hash is derived from the source
+//! hash and the track's sample range, so the same track of the same rip is
+//! recognized across scans. Only the track's range of the source is
+//! transcoded, and the cue sheet's titles and performers replace the source's
+//! tags.
+
+use anyhow::Context;
+use std::{
+    hash::Hasher,
+    path::{Path, PathBuf},
+};
+use twox_hash::XxHash3_64;
+
+/// The number of CD frames per second, which cue sheet times are counted in.
+const FRAMES_PER_SECOND: u64 = 75;
+
+/// The extensions of audio files found by the scan, used to find sources
+/// whose extension changed since the cue sheet was written.
+const AUDIO_EXTENSIONS: &[&str] = &[
+    "flac", "wav", "aif", "aiff", "m4a", "m4b", "mka", "mp3", "ogg",
+];
+
+/// An audio file referenced by a cue sheet.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct CueFile {
+    /// The path of the file, relative to the cue sheet.
+    pub path: String,
+    pub tracks: Vec<CueTrack>,
+}
+
+/// A track of a cue sheet.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct CueTrack {
+    pub number: u32,
+    pub title: Option<String>,
+    pub performer: Option<String>,
+    /// The title of the whole sheet.
+    pub album: Option<String>,
+    /// The performer of the whole sheet.
+    pub album_performer: Option<String>,
+    /// The start of the track in CD frames from the start of the file.
+    pub start: u64,
+    /// The end of the track in CD frames from the start of the file, or
+    /// `None` if the track ends with the file.
+    pub end: Option<u64>,
+}
+
+impl CueTrack {
+    /// Returns the range of the track in frames at the given sample rate.
+    pub fn sample_range(&self, sample_rate: u32) -> (u64, Option<u64>) {
+        let to_samples = |frames: u64| frames * sample_rate as u64 / FRAMES_PER_SECOND;
+        (to_samples(self.start), self.end.map(to_samples))
+    }
+
+    /// Returns the duration of the track in seconds, given the duration of
+    /// the whole file for the last track.
+    pub fn duration_secs(&self, file_duration_secs: f64) -> f64 {
+        let start = self.start as f64 / FRAMES_PER_SECOND as f64;
+        match self.end {
+            Some(end) => (end - self.start) as f64 / FRAMES_PER_SECOND as f64,
+            None => (file_duration_secs - start).max(0.0),
+        }
+    }
+
+    /// Returns the hash of the track, derived from the hash of the source and
+    /// the track's sample range.
+    pub fn hash(
+        &self,
+        source_hash_kind: &str,
+        source_hash: &[u8],
+        sample_rate: u32,
+    ) -> (&'static str, Vec<u8>) {
+        let (start, end) = self.sample_range(sample_rate);
+
+        let mut hasher = XxHash3_64::with_seed(0);
+        hasher.write(source_hash_kind.as_bytes());
+        hasher.write(source_hash);
+        hasher.write(&start.to_be_bytes());
+        hasher.write(&end.unwrap_or(u64::MAX).to_be_bytes());
+
+        ("xxh3", hasher.finish().to_be_bytes().to_vec())
+    }
+
+    /// Returns the metadata fingerprint of the track, derived from the
+    /// fingerprint of the source and the tags from the cue sheet, so editing
+    /// the sheet is noticed like editing the tags of a file.
+    pub fn metadata_fingerprint(&self, source_fingerprint: &[u8]) -> Vec<u8> {
+        let mut tags = Vec::new();
+        self.apply_tags(&mut tags);
+
+        let mut hasher = XxHash3_64::with_seed(0);
+        hasher.write(source_fingerprint);
+        for tag in tags {
+            hasher.write(tag.as_bytes());
+            hasher.write_u8(0);
+        }
+
+        hasher.finish().to_be_bytes().to_vec()
+    }
+
+    /// Returns the file name of the track's virtual path.
+    pub fn file_name(&self, extension: &str) -> String {
+        let title = self
+            .title
+            .as_deref()
+            .map(sanitize_file_name)
+            .filter(|title| !title.is_empty())
+            .unwrap_or_else(|| format!("Track {:02}", self.number));
+        format!("{:02} - {title}.{extension}", self.number)
+    }
+
+    /// Replaces the track's tags in Opus comments with the ones from the cue
+    /// sheet, keeping the source's other tags. Lyrics are removed since they're
+    /// for the whole file.
+    pub fn apply_tags(&self, comments: &mut Vec<String>) {
+        let mut tags = vec![("TRACKNUMBER", self.number.to_string())];
+        tags.extend(self.title.clone().map(|title| ("TITLE", title)));
+        tags.extend(
+            self.performer
+                .clone()
+                .or_else(|| self.album_performer.clone())
+                .map(|performer| ("ARTIST", performer)),
+        );
+        tags.extend(self.album.clone().map(|album| ("ALBUM", album)));
+        tags.extend(
+            self.album_performer
+                .clone()
+                .map(|performer| ("ALBUMARTIST", performer)),
+        );
+
+        comments.retain(|comment| {
+            let key = comment.split_once('=').map_or("", |(key, _)| key);
+            !key.eq_ignore_ascii_case("LYRICS")
+                && !tags.iter().any(|(tag, _)| key.eq_ignore_ascii_case(tag))
+        });
+        comments.extend(
+            tags.into_iter()
+                .map(|(tag, value)| format!("{tag}={value}")),
+        );
+    }
+}
+
+/// Reads a cue sheet, decoding it as Latin-1 if it isn't UTF-8.
+pub fn read(path: &Path) -> anyhow::Result<Vec<CueFile>> {
+    let bytes = std::fs::read(path).context("failed to read cue sheet")?;
+    let contents = match String::from_utf8(bytes) {
+        Ok(contents) => contents,
+        // older rippers write the system code page, which is most often latin-1
+        Err(e) => e.into_bytes().iter().map(|&b| b as char).collect(),
+    };
+    parse(&contents)
+}
+
+/// Finds the track of a cue sheet with the given number in the given file.
+pub fn find_track(cue_path: &Path, local_path: &Path, number: u32) -> anyhow::Result<CueTrack> {
+    read(cue_path)?
+        .into_iter()
+        .filter(|file| resolve_file(cue_path, &file.path).as_deref() == Some(local_path))
+        .flat_map(|file| file.tracks)
+        .find(|track| track.number == number)
+        .with_context(|| format!("cue sheet has no track {number} for this file"))
+}
+
+/// Returns the path of a file referenced by a cue sheet.
+///
+/// Rips are often converted after the cue sheet was written, so if the file
+/// doesn't exist, a file with the same name and another audio extension is
+/// used instead.
+pub fn resolve_file(cue_path: &Path, file_path: &str) -> Option<PathBuf> {
+    let dir = cue_path.parent()?;
+
+    // cue sheets written on windows use backslashes
+    let path = dir.join(file_path.replace('\\', "/"));
+    if path.is_file() {
+        return Some(path);
+    }
+
+    AUDIO_EXTENSIONS
+        .iter()
+        .map(|extension| path.with_extension(extension))
+        .find(|path| path.is_file())
+}
+
+/// Parses a cue sheet.
+fn parse(contents: &str) -> anyhow::Result<Vec<CueFile>> {
+    let contents = contents.strip_prefix('\u{feff}').unwrap_or(contents);
+
+    let mut album = None;
+    let mut album_performer = None;
+    let mut files: Vec<CueFile> = Vec::new();
+
+    // the start of the pregap of each track, which ends the previous track
+    let mut pregaps: Vec<Vec<Option<u64>>> = Vec::new();
+
+    for (index, line) in contents.lines().enumerate() {
+        let line = line.trim();
+        let (command, rest) = line.split_once(char::is_whitespace).unwrap_or((line, ""));
+        let rest = rest.trim();
+
+        let track = files.last_mut().and_then(|file| file.tracks.last_mut());
+
+        match command.to_ascii_uppercase().as_str() {
+            "FILE" => {
+                let (path, _kind) = parse_string(rest);
+                files.push(CueFile {
+                    path,
+                    tracks: Vec::new(),
+                });
+                pregaps.push(Vec::new());
+            }
+
+            "TRACK" => {
+                let file = files
+                    .last_mut()
+                    .with_context(|| format!("line {}: TRACK before FILE", index + 1))?;
+                let (number, _kind) = parse_string(rest);
+                let number = number
+                    .parse()
+                    .with_context(|| format!("line {}: invalid track number", index + 1))?;
+                file.tracks.push(CueTrack {
+                    number,
+                    title: None,
+                    performer: None,
+                    album: None,
+                    album_performer: None,
+                    start: 0,
+                    end: None,
+                });
+                pregaps.last_mut().expect("pushed with file").push(None);
+            }
+
+            "TITLE" => match track {
+                Some(track) => track.title = Some(parse_text(rest)),
+                None => album = Some(parse_text(rest)),
+            },
+
+            "PERFORMER" => match track {
+                Some(track) => track.performer = Some(parse_text(rest)),
+                None => album_performer = Some(parse_text(rest)),
+            },
+
+            "INDEX" => {
+                let track =
+                    track.with_context(|| format!("line {}: INDEX before TRACK", index + 1))?;
+                let (number, time) = parse_string(rest);
+                let time = parse_time(time.trim())
+                    .with_context(|| format!("line {}: invalid index time", index + 1))?;
+                match number.parse::<u32>() {
+                    Ok(0) => {
+                        *pregaps
+                            .last_mut()
+                            .and_then(|pregaps| pregaps.last_mut())
+                            .expect("pushed with track") = Some(time)
+                    }
+                    Ok(1) => track.start = time,
+                    // later indexes are positions within the track
+                    _ => {}
+                }
+            }
+
+            // comments, flags, and catalog numbers aren't used
+            _ => {}
+        }
+    }
+
+    // each track ends where the next one starts, including its pregap
+    for (file, pregaps) in files.iter_mut().zip(pregaps) {
+        for i in 1..file.tracks.len() {
+            let next_start = pregaps[i].unwrap_or(file.tracks[i].start);
+            file.tracks[i - 1].end = Some(next_start);
+        }
+
+        for track in &mut file.tracks {
+            track.album.clone_from(&album);
+            track.album_performer.clone_from(&album_performer);
+
+            if track.end.is_some_and(|end| end <= track.start) {
+                anyhow::bail!("track {} ends before it starts", track.number);
+            }
+        }
+    }
+
+    Ok(files)
+}
+
+/// Parses a quoted or unquoted string, returning it and the rest of the line.
+fn parse_string(s: &str) -> (String, &str) {
+    if let Some(s) = s.strip_prefix('"') {
+        match s.split_once('"') {
+            Some((string, rest)) => (string.to_string(), rest),
+            None => (s.to_string(), ""),
+        }
+    } else {
+        let (string, rest) = s.split_once(char::is_whitespace).unwrap_or((s, ""));
+        (string.to_string(), rest)
+    }
+}
+
+/// Parses a text value, which some rippers don't quote.
+fn parse_text(s: &str) -> String {
+    if s.starts_with('"') {
+        parse_string(s).0
+    } else {
+        s.to_string()
+    }
+}
+
+/// Parses a `mm:ss:ff` time into CD frames.
+fn parse_time(time: &str) -> anyhow::Result<u64> {
+    let mut parts = time.split(':').map(|part| part.parse::<u64>());
+    let (Some(Ok(minutes)), Some(Ok(seconds)), Some(Ok(frames)), None) =
+        (parts.next(), parts.next(), parts.next(), parts.next())
+    else {
+        anyhow::bail!("expected mm:ss:ff, got {time}");
+    };
+    anyhow::ensure!(
+        seconds < 60 && frames < FRAMES_PER_SECOND,
+        "time out of range: {time}"
+    );
+    Ok((minutes * 60 + seconds) * FRAMES_PER_SECOND + frames)
+}
+
+/// Replaces characters that aren't allowed in file names.
+fn sanitize_file_name(name: &str) -> String {
+    name.chars()
+        .map(|c| match c {
+            '/' | '\\' | ':' | '*' | '?' | '"' | '<' | '>' | '|' => '_',
+            c if c.is_control() => '_',
+            c => c,
+        })
+        .collect::<String>()
+        .trim()
+        .trim_end_matches('.')
+        .to_string()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse() {
+        let contents = "\u{feff}REM GENRE Rock\r\n\
+            PERFORMER \"The Band\"\r\n\
+            TITLE \"The Album\"\r\n\
+            FILE \"album.wav\" WAVE\r\n\
+            \x20 TRACK 01 AUDIO\r\n\
+            \x20   TITLE \"Intro\"\r\n\
+            \x20   INDEX 01 00:00:00\r\n\
+            \x20 TRACK 02 AUDIO\r\n\
+            \x20   TITLE \"Song: Part 2\"\r\n\
+            \x20   PERFORMER \"Guest\"\r\n\
+            \x20   INDEX 00 01:02:70\r\n\
+            \x20   INDEX 01 01:04:00\r\n\
+            \x20 TRACK 03 AUDIO\r\n\
+            \x20   INDEX 01 03:00:00\r\n";
+
+        let files = parse(contents).unwrap();
+        assert_eq!(files.len(), 1);
+        assert_eq!(files[0].path, "album.wav");
+
+        let tracks = &files[0].tracks;
+        assert_eq!(tracks.len(), 3);
+
+        // the first track ends at the pregap of the second
+        assert_eq!(tracks[0].title.as_deref(), Some("Intro"));
+        assert_eq!(tracks[0].performer, None);
+        assert_eq!(tracks[0].album.as_deref(), Some("The Album"));
+        assert_eq!(tracks[0].album_performer.as_deref(), Some("The Band"));
+        assert_eq!(tracks[0].start, 0);
+        assert_eq!(tracks[0].end, Some(62 * 75 + 70));
+
+        assert_eq!(tracks[1].performer.as_deref(), Some("Guest"));
+        assert_eq!(tracks[1].start, 64 * 75);
+        assert_eq!(tracks[1].end, Some(180 * 75));
+
+        // the last track ends with the file
+        assert_eq!(tracks[2].number, 3);
+        assert_eq!(tracks[2].end, None);
+
+        assert_eq!(
+            tracks[1].sample_range(44100),
+            (64 * 44100, Some(180 * 44100))
+        );
+        assert_eq!(tracks[2].duration_secs(200.0), 20.0);
+        assert_eq!(tracks[1].file_name("flac"), "02 - Song_ Part 2.flac");
+        assert_eq!(tracks[2].file_name("flac"), "03 - Track 03.flac");
+
+        // the range is part of the hash
+        assert_ne!(
+            tracks[0].hash("md5", &[1, 2, 3], 44100),
+            tracks[1].hash("md5", &[1, 2, 3], 44100)
+        );
+    }
+
+    #[test]
+    fn test_parse_invalid() {
+        assert!(parse("TRACK 01 AUDIO\n").is_err());
+        assert!(parse("FILE \"a.wav\" WAVE\nTRACK 01 AUDIO\nINDEX 01 00:61:00\n").is_err());
+    }
+
+    #[test]
+    fn test_apply_tags() {
+        let track = CueTrack {
+            number: 2,
+            title: Some("Song".to_string()),
+            performer: None,
+            album: Some("Album".to_string()),
+            album_performer: Some("Band".to_string()),
+            start: 0,
+            end: None,
+        };
+
+        let mut comments = vec![
+            "TITLE=Whole Album".to_string(),
+            "artist=Band".to_string(),
+            "DATE=2001".to_string(),
+            "LYRICS=Every song on the album".to_string(),
+        ];
+        track.apply_tags(&mut comments);
+
+        assert_eq!(
+            comments,
+            [
+                "DATE=2001",
+                "TRACKNUMBER=2",
+                "TITLE=Song",
+                "ARTIST=Band",
+                "ALBUM=Album",
+                "ALBUMARTIST=Band",
+            ]
+        );
+    }
+
+    #[test]
+    fn test_metadata_fingerprint() {
+        let track = CueTrack {
+            number: 2,
+            title: Some("Song".to_string()),
+            performer: None,
+            album: None,
+            album_performer: None,
+            start: 0,
+            end: None,
+        };
+        let fingerprint = track.metadata_fingerprint(&[1, 2, 3]);
+
+        // editing the sheet or the source's tags changes the fingerprint
+        let retitled = CueTrack {
+            title: Some("Other Song".to_string()),
+            ..track.clone()
+        };
+        assert_ne!(retitled.metadata_fingerprint(&[1, 2, 3]), fingerprint);
+        assert_ne!(track.metadata_fingerprint(&[4, 5, 6]), fingerprint);
+        assert_eq!(track.metadata_fingerprint(&[1, 2, 3]), fingerprint);
+    }
+}
@@ -0,0 +1,473 @@
+//! Estimating the size of files after transcoding.
+//!
+//! Estimates are used for queued files before they're transcoded, and for
+//! planning the bitrate that fits a set of files into a storage budget.
+
+use crate::library::transcode::{
+    TranscodeFormat, TranscodeItem, TranscodeProfile, passthrough_extension,
+};
+use anyhow::Context;
+use rayon::prelude::*;
+use std::path::{Path, PathBuf};
+use symphonia::core::{
+    formats::{TrackType, probe::Hint},
+    io::MediaSourceStream,
+};
+
+/// Estimates the size of a file after transcoding based on its duration.
+pub fn estimate_file_size(path: &PathBuf, profile: &TranscodeProfile) -> anyhow::Result<u64> {
+    Ok(SizeEstimate::probe(path, profile)?.size(profile))
+}
+
+/// Estimates the transcode size of a queued file, counting only its track if
+/// it's split by a cue sheet.
+pub fn estimate_item_size(item: &TranscodeItem, profile: &TranscodeProfile) -> anyhow::Result<u64> {
+    Ok(SizeEstimate::probe_item(item, profile)?.size(profile))
+}
+
+/// What a file's estimated transcode size depends on.
+///
+/// Probing the file is the slow part of estimating its size, so this lets the
+/// size be estimated at different bitrates after probing the file once.
+#[derive(Debug, Clone, Copy)]
+enum SizeEstimate {
+    /// The size doesn't depend on the bitrate.
+    Fixed(u64),
+    /// The size depends on the duration and the bitrate.
+    Duration {
+        duration_secs: f64,
+        channel_count: usize,
+        /// The size of the source if it's passed through at high enough
+        /// bitrates.
+        passthrough_size: Option<u64>,
+    },
+}
+
+impl SizeEstimate {
+    /// Probes a file for the inputs to its size estimate with the given
+    /// profile, ignoring the profile's bitrate.
+    fn probe(path: &Path, profile: &TranscodeProfile) -> anyhow::Result<Self> {
+        // lossless transcodes are estimated from the source size
+        if profile.format == TranscodeFormat::Flac {
+            return estimate_flac_file_size(path, profile).map(Self::Fixed);
+        }
+
+        // check if the file is passed through at the highest bitrate, then
+        // compare the bitrates when estimating
+        let max_profile = TranscodeProfile {
+            bitrate: TranscodeProfile::MAX_BITRATE,
+            ..*profile
+        };
+        let passthrough_size = match passthrough_extension(path, &max_profile) {
+            Ok(Some(_)) => Some(
+                std::fs::metadata(path)
+                    .context("failed to get file metadata")?
+                    .len(),
+            ),
+            _ => None,
+        };
+
+        let (duration_secs, channel_count) = probe_duration(path)?;
+
+        Ok(Self::Duration {
+            duration_secs,
+            channel_count,
+            passthrough_size,
+        })
+    }
+
+    /// Probes a queued file like `probe`, using the duration of its track if
+    /// it's split by a cue sheet.
+    fn probe_item(item: &TranscodeItem, profile: &TranscodeProfile) -> anyhow::Result<Self> {
+        let Some(cue) = &item.cue else {
+            return Self::probe(&item.local_path, profile);
+        };
+
+        // tracks are never passed through
+        let (duration_secs, channel_count) = probe_duration(&item.local_path)?;
+
+        // lossless tracks are estimated from their share of the source
+        if profile.format == TranscodeFormat::Flac {
+            let file_size = std::fs::metadata(&item.local_path)
+                .context("failed to get file metadata")?
+                .len();
+            let share = if duration_secs > 0.0 {
+                (cue.duration_secs(duration_secs) / duration_secs).min(1.0)
+            } else {
+                0.0
+            };
+            return Ok(Self::Fixed(
+                (file_size as f64 * flac_size_ratio(&item.local_path) * share) as u64,
+            ));
+        }
+
+        Ok(Self::Duration {
+            duration_secs: cue.duration_secs(duration_secs),
+            channel_count,
+            passthrough_size: None,
+        })
+    }
+
+    /// Returns the estimated size with the given profile.
+    fn size(&self, profile: &TranscodeProfile) -> u64 {
+        match *self {
+            Self::Fixed(size) => size,
+
+            Self::Duration {
+                duration_secs,
+                channel_count,
+                passthrough_size,
+            } => {
+                let total_bitrate = profile.total_bitrate(channel_count) as f64;
+
+                // passthrough files are copied as-is, same as passthrough_extension
+                if let Some(file_size) = passthrough_size
+                    && file_size as f64 * 8.0 / duration_secs <= total_bitrate
+                {
+                    return file_size;
+                }
+
+                // estimated size = duration * bitrate, converted to bytes
+                let estimated_size = duration_secs * total_bitrate / 8.0;
+
+                // add 150 KB for embedded cover art
+                let estimated_size = estimated_size + 150_000.0;
+
+                // add 1% for container overhead
+                let estimated_size = estimated_size * 1.01;
+
+                estimated_size as u64
+            }
+        }
+    }
+}
+
+/// The highest bitrate that fits a set of files into a storage budget, see
+/// `plan_bitrate`.
+#[derive(Debug, Clone, PartialEq, uniffi::Record)]
+pub struct TranscodePlan {
+    /// The highest bitrate that fits, or `None` if the files don't fit even at
+    /// the lowest bitrate.
+    pub bitrate: Option<u32>,
+    /// The predicted total size of the transcodes at the chosen bitrate, or at
+    /// the lowest bitrate if none fits.
+    pub estimated_size: u64,
+    /// The paths of files whose size couldn't be estimated, which aren't
+    /// counted in the total.
+    pub unestimated_paths: Vec<String>,
+}
+
+/// Finds the highest bitrate at which the files fit into the budget when
+/// transcoded with the profile.
+///
+/// Bitrates are whole kbps between the lowest and highest bitrates supported
+/// by Opus. Each file is probed once and then estimated at each bitrate, so
+/// lossy files that are passed through at higher bitrates are counted at
+/// their own size. Speech files are estimated with the speech settings of
+/// each bitrate.
+pub fn plan_bitrate(
+    files: &[TranscodeItem],
+    budget: u64,
+    profile: &TranscodeProfile,
+) -> anyhow::Result<TranscodePlan> {
+    if profile.format != TranscodeFormat::Opus {
+        anyhow::bail!("only Opus profiles have a bitrate to plan");
+    }
+
+    let estimates = files
+        .par_iter()
+        .map(|item| {
+            let estimate = SizeEstimate::probe_item(item, &profile.for_content(item.content));
+            (&item.local_path, item.content, estimate)
+        })
+        .collect::<Vec<_>>();
+
+    let mut unestimated_paths = Vec::new();
+    let estimates = estimates
+        .into_iter()
+        .filter_map(|(path, content, estimate)| match estimate {
+            Ok(estimate) => Some((content, estimate)),
+            Err(e) => {
+                log::warn!(
+                    "plan_bitrate: failed to estimate file size for {}: {e:#}",
+                    path.display()
+                );
+                unestimated_paths.push(path.to_string_lossy().into_owned());
+                None
+            }
+        })
+        .collect::<Vec<_>>();
+
+    let total_size = |kbps: u32| {
+        let profile = TranscodeProfile {
+            bitrate: kbps * 1000,
+            ..*profile
+        };
+        estimates
+            .iter()
+            .map(|(content, estimate)| estimate.size(&profile.for_content(*content)))
+            .sum::<u64>()
+    };
+
+    // the total doesn't always grow with the bitrate, since files that are
+    // passed through at higher bitrates shrink back to their own size, so
+    // every bitrate is checked
+    let min_kbps = TranscodeProfile::MIN_BITRATE / 1000;
+    let fit = (min_kbps..=TranscodeProfile::MAX_BITRATE / 1000)
+        .rev()
+        .map(|kbps| (kbps, total_size(kbps)))
+        .find(|(_, size)| *size <= budget);
+
+    let (bitrate, estimated_size) = match fit {
+        Some((kbps, size)) => (Some(kbps * 1000), size),
+        None => (None, total_size(min_kbps)),
+    };
+
+    Ok(TranscodePlan {
+        bitrate,
+        estimated_size,
+        unestimated_paths,
+    })
+}
+
+/// Estimates the size of a file transcoded to FLAC.
+fn estimate_flac_file_size(path: &Path, profile: &TranscodeProfile) -> anyhow::Result<u64> {
+    // passthrough files are copied as-is
+    let file_size = std::fs::metadata(path)
+        .context("failed to get file metadata")?
+        .len();
+    if let Ok(Some(_)) = passthrough_extension(path, profile) {
+        return Ok(file_size);
+    }
+
+    Ok((file_size as f64 * flac_size_ratio(path)) as u64)
+}
+
+/// Returns the size of a file transcoded to FLAC relative to its size.
+fn flac_size_ratio(path: &Path) -> f64 {
+    // flac compresses uncompressed pcm to about 60%, and alac sources end up
+    // about the same size
+    let is_pcm = path
+        .extension()
+        .and_then(|extension| extension.to_str())
+        .is_some_and(|extension| {
+            ["wav", "aif", "aiff"].contains(&extension.to_ascii_lowercase().as_str())
+        });
+    if is_pcm { 0.6 } else { 1.0 }
+}
+
+/// Returns the sample rate of a file's default audio track.
+pub fn source_sample_rate(path: &Path) -> anyhow::Result<u32> {
+    let src = std::fs::File::open(path).context("failed to open file")?;
+
+    let mss = MediaSourceStream::new(Box::new(src), Default::default());
+
+    let mut hint = Hint::new();
+    if let Some(extension) = path.extension() {
+        hint.with_extension(extension.to_str().context("invalid file extension")?);
+    }
+
+    let format = symphonia::default::get_probe()
+        .probe(&hint, mss, Default::default(), Default::default())
+        .context("failed to probe file")?;
+
+    format
+        .default_track(TrackType::Audio)
+        .context("failed to get default audio track")?
+        .codec_params
+        .as_ref()
+        .and_then(|codec_params| codec_params.audio())
+        .and_then(|audio_codec_params| audio_codec_params.sample_rate)
+        .context("failed to get sample rate from codec params")
+}
+
+/// Returns the duration in seconds and the channel count of a file.
+///
+/// The duration is read from the container if it's known, or found by
+/// decoding the whole file otherwise.
+fn probe_duration(path: &Path) -> anyhow::Result<(f64, usize)> {
+    let src = std::fs::File::open(path).context("failed to open file")?;
+
+    let mss = MediaSourceStream::new(Box::new(src), Default::default());
+
+    let mut hint = Hint::new();
+    if let Some(extension) = path.extension() {
+        hint.with_extension(extension.to_str().context("invalid file extension")?);
+    }
+
+    let mut format = symphonia::default::get_probe()
+        .probe(&hint, mss, Default::default(), Default::default())
+        .context("failed to probe file")?;
+
+    // get the default audio track
+    let audio_track = format
+        .default_track(TrackType::Audio)
+        .context("failed to get default audio track")?;
+    let audio_track_id = audio_track.id;
+
+    // get channel count to determine the total bitrate
+    let channel_count = audio_track
+        .codec_params
+        .as_ref()
+        .and_then(|codec_params| codec_params.audio())
+        .and_then(|audio_codec_params| audio_codec_params.channels.as_ref())
+        .map(|channels| channels.count())
+        .unwrap_or(2);
+
+    // get time base and number of frames from the audio track
+    let duration_secs = match (audio_track.time_base, audio_track.num_frames) {
+        (Some(time_base), Some(num_frames)) => {
+            let duration = time_base.calc_time(num_frames);
+            duration.seconds as f64 + duration.frac
+        }
+
+        _ => {
+            log::info!(
+                "file missing time_base or num_frames, decoding to find duration: {}",
+                path.display()
+            );
+
+            // get codec parameters for the audio track
+            let codec_params = audio_track
+                .codec_params
+                .as_ref()
+                .context("failed to get codec parameters")?;
+            let audio_codec_params = codec_params
+                .audio()
+                .context("codec parameters are not audio")?;
+
+            // get sample rate
+            let sample_rate = audio_codec_params
+                .sample_rate
+                .context("failed to get sample rate from codec params")?;
+
+            let mut decoder = symphonia::default::get_codecs()
+                .make_audio_decoder(audio_codec_params, &Default::default())
+                .context("failed to create decoder")?;
+
+            // decode the audio track and count frames
+            let mut num_frames = 0;
+            loop {
+                // read next packet
+                let packet = match format.next_packet() {
+                    Ok(Some(packet)) => packet,
+
+                    // end of track
+                    Ok(None) => break,
+
+                    Err(e) => {
+                        return Err(e).context("failed to read packet");
+                    }
+                };
+
+                // skip packets from other tracks
+                if packet.track_id() != audio_track_id {
+                    continue;
+                }
+
+                // decode packet
+                let audio_buf = decoder.decode(&packet).context("failed to decode packet")?;
+
+                // count frames
+                num_frames += audio_buf.frames();
+            }
+
+            // convert frames to seconds
+            num_frames as f64 / sample_rate as f64
+        }
+    };
+
+    Ok((duration_secs, channel_count))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::library::transcode::TranscodeContent;
+
+    fn fixture_path(name: &str) -> PathBuf {
+        PathBuf::from(env!("CARGO_MANIFEST_DIR"))
+            .join("fixtures")
+            .join(name)
+    }
+
+    fn test_item(hash: u8) -> TranscodeItem {
+        TranscodeItem {
+            hash_kind: "test".to_string(),
+            hash: vec![hash],
+            local_path: PathBuf::from("test.ogg"),
+            content: TranscodeContent::Music,
+            cue: None,
+        }
+    }
+
+    #[test]
+    fn test_plan_bitrate() {
+        let profile = TranscodeProfile::default();
+        let files = ["tags.flac", "tags.wav", "missing.flac"]
+            .into_iter()
+            .map(|name| TranscodeItem {
+                local_path: fixture_path(name),
+                ..test_item(0)
+            })
+            .collect::<Vec<_>>();
+
+        // the budget is the estimated size at 96 kbps
+        let profile_96 = TranscodeProfile {
+            bitrate: 96000,
+            ..profile
+        };
+        let budget = files[..2]
+            .iter()
+            .map(|item| estimate_file_size(&item.local_path, &profile_96).unwrap())
+            .sum::<u64>();
+
+        let plan = plan_bitrate(&files, budget, &profile).unwrap();
+        assert_eq!(
+            plan,
+            TranscodePlan {
+                bitrate: Some(96000),
+                estimated_size: budget,
+                unestimated_paths: vec![files[2].local_path.to_string_lossy().into_owned()],
+            }
+        );
+
+        // nothing fits in an empty budget
+        let plan = plan_bitrate(&files, 0, &profile).unwrap();
+        assert_eq!(plan.bitrate, None);
+        assert!(plan.estimated_size > 0);
+
+        // flac has no bitrate
+        let flac_profile = TranscodeProfile {
+            format: TranscodeFormat::Flac,
+            ..profile
+        };
+        assert!(plan_bitrate(&files, budget, &flac_profile).is_err());
+    }
+
+    #[test]
+    fn test_plan_bitrate_passthrough() {
+        // the mp3 is passed through above its own bitrate of about 130 kbps,
+        // and estimated with the cover art and overhead below it
+        let files = vec![TranscodeItem {
+            local_path: fixture_path("tags.mp3"),
+            ..test_item(0)
+        }];
+        let file_size = std::fs::metadata(fixture_path("tags.mp3")).unwrap().len();
+        let transcoded_size = estimate_file_size(
+            &fixture_path("tags.mp3"),
+            &TranscodeProfile {
+                bitrate: 64000,
+                ..TranscodeProfile::default()
+            },
+        )
+        .unwrap();
+        assert!(transcoded_size > file_size);
+
+        // a budget that only fits the passed through file, so the lower
+        // bitrates don't fit but the higher ones do
+        let plan = plan_bitrate(&files, file_size, &TranscodeProfile::default()).unwrap();
+        assert_eq!(plan.bitrate, Some(TranscodeProfile::MAX_BITRATE));
+        assert_eq!(plan.estimated_size, file_size);
+    }
+}
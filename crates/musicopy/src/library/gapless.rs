@@ -0,0 +1,340 @@
+//! Encoder delay and padding of lossy sources, for gapless playback.
+//!
+//! MP3 and AAC encoders add silence to the start and end of the stream. The
+//! amounts are stored in the LAME tag of MP3s and the iTunSMPB tag of M4As,
+//! and are trimmed from the decoded samples before encoding so that tracks
+//! play back to back without gaps.
+
+use crate::library::mp4::{atoms, find_atom, find_top_level_atom};
+use anyhow::Context;
+use std::{
+    fs::File,
+    io::{Read, Seek, SeekFrom},
+    path::Path,
+};
+
+/// The number of samples of delay added by MP3 decoders, on top of the
+/// encoder delay stored in the LAME tag.
+const MP3_DECODER_DELAY: u64 = 529;
+
+/// The maximum number of bytes read from the start of an MP3 to find the
+/// first frame.
+const MP3_MAX_SCAN_BYTES: u64 = 1024 * 1024;
+
+/// The number of frames of encoder delay and padding in a source.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct GaplessInfo {
+    /// Frames to trim from the start of the decoded audio.
+    pub delay: u64,
+    /// Frames to trim from the end of the decoded audio.
+    pub padding: u64,
+}
+
+/// Reads the encoder delay and padding from the LAME tag in the first frame
+/// of an MP3 file.
+///
+/// Returns `None` if the file has no LAME tag.
+pub fn read_mp3(path: &Path) -> anyhow::Result<Option<GaplessInfo>> {
+    let mut file = File::open(path).context("failed to open file")?;
+
+    // skip id3v2 tags
+    let mut header = [0; 10];
+    file.read_exact(&mut header)
+        .context("failed to read file header")?;
+    let mut offset = 0;
+    if &header[..3] == b"ID3" {
+        let size = header[6..10]
+            .iter()
+            .fold(0u64, |size, &byte| (size << 7) | (byte & 0x7f) as u64);
+        let footer = if header[5] & 0x10 != 0 { 10 } else { 0 };
+        offset = 10 + size + footer;
+    }
+
+    let mut data = Vec::new();
+    file.seek(SeekFrom::Start(offset))
+        .context("failed to seek to first frame")?;
+    file.take(MP3_MAX_SCAN_BYTES)
+        .read_to_end(&mut data)
+        .context("failed to read first frame")?;
+
+    Ok(parse_lame_tag(&data))
+}
+
+/// Finds the first MP3 frame in `data` and parses the LAME tag in its
+/// Xing/Info header.
+fn parse_lame_tag(data: &[u8]) -> Option<GaplessInfo> {
+    // find the frame sync
+    let start = data
+        .windows(2)
+        .position(|bytes| bytes[0] == 0xff && bytes[1] & 0xe0 == 0xe0)?;
+    let frame = &data[start..];
+    if frame.len() < 4 {
+        return None;
+    }
+
+    // only layer iii has a lame tag
+    let version = (frame[1] >> 3) & 0x03;
+    let layer = (frame[1] >> 1) & 0x03;
+    if layer != 0x01 {
+        return None;
+    }
+    let mono = frame[3] >> 6 == 0x03;
+    let has_crc = frame[1] & 0x01 == 0;
+
+    // the xing header comes after the side info, which is smaller for
+    // mpeg 2 and 2.5
+    let side_info_len = match (version == 0x03, mono) {
+        (true, false) => 32,
+        (true, true) | (false, false) => 17,
+        (false, true) => 9,
+    };
+    let mut pos = 4 + if has_crc { 2 } else { 0 } + side_info_len;
+
+    let tag = frame.get(pos..pos + 8)?;
+    if &tag[..4] != b"Xing" && &tag[..4] != b"Info" {
+        return None;
+    }
+    let flags = u32::from_be_bytes(tag[4..8].try_into().unwrap());
+    pos += 8;
+
+    // skip the optional xing fields: frame count, byte count, toc, quality
+    if flags & 0x01 != 0 {
+        pos += 4;
+    }
+    if flags & 0x02 != 0 {
+        pos += 4;
+    }
+    if flags & 0x04 != 0 {
+        pos += 100;
+    }
+    if flags & 0x08 != 0 {
+        pos += 4;
+    }
+
+    // the lame tag starts with the encoder version, e.g. `LAME3.100` or `Lavc`
+    let lame = frame.get(pos..pos + 24)?;
+    if !lame[..4].iter().all(|byte| byte.is_ascii_alphanumeric()) {
+        return None;
+    }
+
+    // 12 bits of delay and 12 bits of padding
+    let delay = ((lame[21] as u64) << 4) | (lame[22] as u64 >> 4);
+    let padding = (((lame[22] & 0x0f) as u64) << 8) | lame[23] as u64;
+
+    // the decoder delay is included in the padding, so it moves from the end
+    // to the start
+    Some(GaplessInfo {
+        delay: delay + MP3_DECODER_DELAY,
+        padding: padding.saturating_sub(MP3_DECODER_DELAY),
+    })
+}
+
+/// Reads the encoder delay and padding from the iTunSMPB tag of an MP4 file.
+///
+/// Returns `None` if the file has no iTunSMPB tag.
+pub fn read_mp4(path: &Path) -> anyhow::Result<Option<GaplessInfo>> {
+    let mut file = File::open(path).context("failed to open file")?;
+    let file_len = file
+        .metadata()
+        .context("failed to get file metadata")?
+        .len();
+
+    // find the moov atom at the top level, the rest is small enough to read
+    let Some(moov) = find_top_level_atom(&mut file, file_len, b"moov")? else {
+        return Ok(None);
+    };
+
+    let ilst = find_atom(&moov, b"udta")
+        .and_then(|udta| find_atom(udta, b"meta"))
+        // meta is a full atom with 4 bytes of version and flags
+        .and_then(|meta| meta.get(4..))
+        .and_then(|meta| find_atom(meta, b"ilst"));
+    let Some(ilst) = ilst else {
+        return Ok(None);
+    };
+
+    for (kind, freeform) in atoms(ilst) {
+        if kind != b"----" {
+            continue;
+        }
+
+        // mean and name are full atoms, data has another 4 bytes of locale
+        let name = find_atom(freeform, b"name").and_then(|name| name.get(4..));
+        if name != Some(b"iTunSMPB".as_slice()) {
+            continue;
+        }
+        let Some(value) = find_atom(freeform, b"data").and_then(|data| data.get(8..)) else {
+            continue;
+        };
+
+        return Ok(parse_itunsmpb(&String::from_utf8_lossy(value)));
+    }
+
+    Ok(None)
+}
+
+/// Parses an iTunSMPB value, which is a list of hex numbers where the second
+/// is the delay and the third is the padding.
+fn parse_itunsmpb(value: &str) -> Option<GaplessInfo> {
+    let mut fields = value
+        .split_whitespace()
+        .map(|field| u64::from_str_radix(field, 16));
+
+    let _ = fields.next()?;
+    let delay = fields.next()?.ok()?;
+    let padding = fields.next()?.ok()?;
+
+    Some(GaplessInfo { delay, padding })
+}
+
+/// Trims encoder delay and padding from a stream of decoded frames.
+///
+/// Frames are appended to a planar buffer that's consumed from the front.
+/// Delay frames are dropped as soon as they're decoded, and the last frames
+/// decoded so far are held back while the buffer is consumed, since they
+/// might turn out to be padding at the end of the stream.
+pub struct GaplessTrimmer {
+    delay_remaining: usize,
+    padding: usize,
+}
+
+impl GaplessTrimmer {
+    pub fn new(info: GaplessInfo) -> Self {
+        Self {
+            delay_remaining: info.delay as usize,
+            padding: info.padding as usize,
+        }
+    }
+
+    /// Drops delay frames from the start of the buffer.
+    ///
+    /// Nothing is consumed from the buffer until the delay is trimmed, so the
+    /// remaining delay is always at the start of the buffer.
+    pub fn trim_start(&mut self, samples: &mut [Vec<f32>]) {
+        let Some(len) = samples.first().map(|channel| channel.len()) else {
+            return;
+        };
+
+        let frames = len.min(self.delay_remaining);
+        for channel in samples.iter_mut() {
+            channel.drain(..frames);
+        }
+        self.delay_remaining -= frames;
+    }
+
+    /// Splits off the frames at the end of the buffer that might be padding,
+    /// so they aren't consumed. They should be put back with `restore`.
+    pub fn hold_back(&self, samples: &mut [Vec<f32>]) -> Vec<Vec<f32>> {
+        samples
+            .iter_mut()
+            .map(|channel| channel.split_off(channel.len().saturating_sub(self.padding)))
+            .collect()
+    }
+
+    /// Puts frames split off by `hold_back` back at the end of the buffer.
+    pub fn restore(&self, samples: &mut [Vec<f32>], held: Vec<Vec<f32>>) {
+        for (channel, held) in samples.iter_mut().zip(held) {
+            channel.extend(held);
+        }
+    }
+
+    /// Drops the padding at the end of the stream.
+    pub fn trim_end(&self, samples: &mut [Vec<f32>]) {
+        for channel in samples.iter_mut() {
+            channel.truncate(channel.len().saturating_sub(self.padding));
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::path::PathBuf;
+
+    fn fixture_path(name: &str) -> PathBuf {
+        PathBuf::from(env!("CARGO_MANIFEST_DIR"))
+            .join("fixtures")
+            .join(name)
+    }
+
+    #[test]
+    fn test_read_mp3() {
+        // LAME tag with 576 frames of delay and 1000 frames of padding
+        assert_eq!(
+            read_mp3(&fixture_path("gapless.mp3")).unwrap(),
+            Some(GaplessInfo {
+                delay: 576 + 529,
+                padding: 1000 - 529,
+            })
+        );
+
+        // no LAME tag
+        assert_eq!(read_mp3(&fixture_path("tags.mp3")).unwrap(), None);
+    }
+
+    #[test]
+    fn test_read_mp4() {
+        assert_eq!(
+            read_mp4(&fixture_path("gapless.m4a")).unwrap(),
+            Some(GaplessInfo {
+                delay: 2112,
+                padding: 1000,
+            })
+        );
+
+        // no iTunSMPB tag
+        assert_eq!(read_mp4(&fixture_path("tags.m4a")).unwrap(), None);
+    }
+
+    #[test]
+    fn test_parse_itunsmpb() {
+        assert_eq!(
+            parse_itunsmpb(
+                " 00000000 00000840 000001CA 00000000000E6B76 00000000 00000000 00000000 00000000"
+            ),
+            Some(GaplessInfo {
+                delay: 0x840,
+                padding: 0x1ca,
+            })
+        );
+        assert_eq!(parse_itunsmpb(""), None);
+        assert_eq!(parse_itunsmpb("00000000 xyz 000001CA"), None);
+    }
+
+    #[test]
+    fn test_trimmer() {
+        let mut trimmer = GaplessTrimmer::new(GaplessInfo {
+            delay: 3,
+            padding: 2,
+        });
+
+        let mut samples = vec![vec![0.0, 1.0]];
+        let mut output = Vec::new();
+
+        // the delay spans multiple blocks
+        trimmer.trim_start(&mut samples);
+        assert!(samples[0].is_empty());
+
+        samples[0].extend([2.0, 3.0, 4.0, 5.0]);
+        trimmer.trim_start(&mut samples);
+        assert_eq!(samples[0], vec![3.0, 4.0, 5.0]);
+
+        // consume everything except the held back frames
+        let held = trimmer.hold_back(&mut samples);
+        output.append(&mut samples[0]);
+        trimmer.restore(&mut samples, held);
+        assert_eq!(samples[0], vec![4.0, 5.0]);
+
+        samples[0].extend([6.0, 7.0, 8.0]);
+        trimmer.trim_start(&mut samples);
+        let held = trimmer.hold_back(&mut samples);
+        output.append(&mut samples[0]);
+        trimmer.restore(&mut samples, held);
+
+        // the last frames are padding
+        trimmer.trim_end(&mut samples);
+        output.append(&mut samples[0]);
+
+        assert_eq!(output, vec![3.0, 4.0, 5.0, 6.0]);
+    }
+}
@@ -0,0 +1,310 @@
+//! Loudness measurement following EBU R128 and ITU-R BS.1770.
+
+/// The sample rate the meter expects, since the K-weighting filter
+/// coefficients are for 48 kHz.
+const SAMPLE_RATE: usize = 48000;
+
+/// Number of frames in each gating step (100 ms). Gating blocks are four
+/// steps long (400 ms), so consecutive blocks overlap by 75%.
+const STEP_FRAMES: usize = SAMPLE_RATE / 10;
+const STEPS_PER_BLOCK: usize = 4;
+
+/// Blocks quieter than this are ignored.
+const ABSOLUTE_GATE_LUFS: f64 = -70.0;
+/// Blocks more than this much quieter than the ungated loudness are ignored.
+const RELATIVE_GATE_LU: f64 = -10.0;
+
+/// A loudness measurement.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Loudness {
+    /// The gated integrated loudness in LUFS.
+    pub lufs: f64,
+    /// The number of gating blocks that passed both gates.
+    pub blocks: u64,
+}
+
+/// Measures the integrated loudness of 48 kHz audio.
+#[derive(Debug)]
+pub struct LoudnessMeter {
+    filters: Vec<KWeightingFilter>,
+    /// Channel weights, in WAVE channel order.
+    weights: Vec<f64>,
+
+    /// Weighted sum of squares of the current step.
+    step_energy: f64,
+    /// Number of frames in the current step.
+    step_frames: usize,
+    /// Weighted sum of squares of each completed step.
+    steps: Vec<f64>,
+}
+
+impl LoudnessMeter {
+    /// Creates a meter for audio with the given channel count, in WAVE channel
+    /// order.
+    pub fn new(channel_count: usize) -> Self {
+        // surround channels are weighted by +1.5 dB, and LFE isn't measured
+        const S: f64 = 1.41;
+        let weights = match channel_count {
+            // FL, FR, BL, BR
+            4 => vec![1.0, 1.0, S, S],
+            // FL, FR, FC, BL, BR
+            5 => vec![1.0, 1.0, 1.0, S, S],
+            // FL, FR, FC, LFE, BL, BR
+            6 => vec![1.0, 1.0, 1.0, 0.0, S, S],
+            // FL, FR, FC, LFE, BC, SL, SR
+            7 => vec![1.0, 1.0, 1.0, 0.0, S, S, S],
+            // FL, FR, FC, LFE, BL, BR, SL, SR
+            8 => vec![1.0, 1.0, 1.0, 0.0, S, S, S, S],
+            _ => vec![1.0; channel_count],
+        };
+
+        Self {
+            filters: vec![KWeightingFilter::default(); channel_count],
+            weights,
+
+            step_energy: 0.0,
+            step_frames: 0,
+            steps: Vec::new(),
+        }
+    }
+
+    /// Measures planar samples.
+    pub fn process<S: AsRef<[f32]>>(&mut self, samples: &[S]) {
+        let frames = samples[0].as_ref().len();
+
+        for i in 0..frames {
+            for ((channel, filter), weight) in
+                samples.iter().zip(&mut self.filters).zip(&self.weights)
+            {
+                let filtered = filter.process(channel.as_ref()[i] as f64);
+                self.step_energy += weight * filtered * filtered;
+            }
+
+            self.step_frames += 1;
+            if self.step_frames == STEP_FRAMES {
+                self.steps.push(self.step_energy);
+                self.step_energy = 0.0;
+                self.step_frames = 0;
+            }
+        }
+    }
+
+    /// Returns the gated integrated loudness.
+    ///
+    /// Returns `None` if the audio is shorter than one gating block or
+    /// entirely below the absolute gate, e.g. if it's silent.
+    pub fn integrated_loudness(&self) -> Option<Loudness> {
+        // mean square of each gating block
+        let blocks = self
+            .steps
+            .windows(STEPS_PER_BLOCK)
+            .map(|steps| steps.iter().sum::<f64>() / (STEP_FRAMES * STEPS_PER_BLOCK) as f64)
+            .collect::<Vec<_>>();
+
+        let absolute_gated = blocks
+            .iter()
+            .copied()
+            .filter(|&block| energy_to_lufs(block) > ABSOLUTE_GATE_LUFS)
+            .collect::<Vec<_>>();
+        if absolute_gated.is_empty() {
+            return None;
+        }
+
+        let relative_gate = energy_to_lufs(mean(&absolute_gated)) + RELATIVE_GATE_LU;
+        let relative_gated = absolute_gated
+            .into_iter()
+            .filter(|&block| energy_to_lufs(block) > relative_gate)
+            .collect::<Vec<_>>();
+        if relative_gated.is_empty() {
+            return None;
+        }
+
+        Some(Loudness {
+            lufs: energy_to_lufs(mean(&relative_gated)),
+            blocks: relative_gated.len() as u64,
+        })
+    }
+}
+
+/// Combines track measurements into the loudness of an album in LUFS.
+///
+/// Each track's gated blocks are weighted equally, as if the album was
+/// measured as one long track. This is close to measuring the album directly,
+/// except the relative gate was applied per track. Returns `None` if no
+/// tracks could be measured.
+pub fn album_loudness(tracks: impl IntoIterator<Item = Loudness>) -> Option<f64> {
+    let (energy, blocks) = tracks
+        .into_iter()
+        .fold((0.0, 0), |(energy, blocks), track| {
+            (
+                energy + lufs_to_energy(track.lufs) * track.blocks as f64,
+                blocks + track.blocks,
+            )
+        });
+
+    if blocks == 0 {
+        return None;
+    }
+
+    Some(energy_to_lufs(energy / blocks as f64))
+}
+
+fn mean(values: &[f64]) -> f64 {
+    values.iter().sum::<f64>() / values.len() as f64
+}
+
+fn energy_to_lufs(energy: f64) -> f64 {
+    -0.691 + 10.0 * energy.log10()
+}
+
+fn lufs_to_energy(lufs: f64) -> f64 {
+    10f64.powf((lufs + 0.691) / 10.0)
+}
+
+/// The BS.1770 K-weighting filter, a high shelf followed by a high pass.
+#[derive(Debug, Clone, Default)]
+struct KWeightingFilter {
+    shelf: Biquad,
+    high_pass: Biquad,
+}
+
+impl KWeightingFilter {
+    fn process(&mut self, sample: f64) -> f64 {
+        // coefficients for 48 kHz from ITU-R BS.1770-4
+        let shelf = self.shelf.process(
+            sample,
+            [1.53512485958697, -2.69169618940638, 1.19839281085285],
+            [-1.69065929318241, 0.73248077421585],
+        );
+        self.high_pass.process(
+            shelf,
+            [1.0, -2.0, 1.0],
+            [-1.99004745483398, 0.99007225036621],
+        )
+    }
+}
+
+/// A biquad filter in direct form I.
+#[derive(Debug, Clone, Default)]
+struct Biquad {
+    x1: f64,
+    x2: f64,
+    y1: f64,
+    y2: f64,
+}
+
+impl Biquad {
+    fn process(&mut self, x: f64, b: [f64; 3], a: [f64; 2]) -> f64 {
+        let y = b[0] * x + b[1] * self.x1 + b[2] * self.x2 - a[0] * self.y1 - a[1] * self.y2;
+
+        self.x2 = self.x1;
+        self.x1 = x;
+        self.y2 = self.y1;
+        self.y1 = y;
+
+        y
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Generates `secs` seconds of a 997 Hz sine with the given amplitude.
+    fn sine(amplitude: f32, secs: usize) -> Vec<f32> {
+        (0..(SAMPLE_RATE * secs))
+            .map(|i| {
+                let t = i as f32 / SAMPLE_RATE as f32;
+                amplitude * (2.0 * std::f32::consts::PI * 997.0 * t).sin()
+            })
+            .collect()
+    }
+
+    #[test]
+    fn test_loudness_stereo_sine() {
+        // a -20 dBFS sine in both channels measures -20 LUFS
+        let channel = sine(0.1, 5);
+
+        let mut meter = LoudnessMeter::new(2);
+        meter.process(&[&channel, &channel]);
+
+        let loudness = meter.integrated_loudness().unwrap().lufs;
+        assert!((loudness - -20.0).abs() < 0.1, "loudness = {loudness}");
+    }
+
+    #[test]
+    fn test_loudness_mono_sine() {
+        // a 0 dBFS sine in one channel measures -3.01 LUFS
+        let channel = sine(1.0, 5);
+
+        let mut meter = LoudnessMeter::new(1);
+        meter.process(&[&channel]);
+
+        let loudness = meter.integrated_loudness().unwrap().lufs;
+        assert!((loudness - -3.01).abs() < 0.1, "loudness = {loudness}");
+    }
+
+    #[test]
+    fn test_loudness_gating() {
+        // silence is ignored by the absolute gate
+        let mut channel = sine(0.1, 5);
+        channel.extend(vec![0.0; SAMPLE_RATE * 5]);
+
+        let mut meter = LoudnessMeter::new(2);
+        meter.process(&[&channel, &channel]);
+
+        // the blocks overlapping the end of the sine are partially silent, so
+        // the result is slightly quieter
+        let loudness = meter.integrated_loudness().unwrap().lufs;
+        assert!((loudness - -20.0).abs() < 0.2, "loudness = {loudness}");
+    }
+
+    #[test]
+    fn test_loudness_silence() {
+        let channel = vec![0.0; SAMPLE_RATE * 5];
+
+        let mut meter = LoudnessMeter::new(2);
+        meter.process(&[&channel, &channel]);
+
+        assert_eq!(meter.integrated_loudness(), None);
+    }
+
+    #[test]
+    fn test_loudness_too_short() {
+        let channel = sine(0.1, 1);
+
+        let mut meter = LoudnessMeter::new(2);
+        meter.process(&[&channel[..(STEP_FRAMES * 3)], &channel[..(STEP_FRAMES * 3)]]);
+
+        assert_eq!(meter.integrated_loudness(), None);
+    }
+
+    #[test]
+    fn test_album_loudness() {
+        let quiet = Loudness {
+            lufs: -20.0,
+            blocks: 100,
+        };
+        let loud = Loudness {
+            lufs: -10.0,
+            blocks: 100,
+        };
+
+        // a single track is the album
+        assert!((album_loudness([quiet]).unwrap() - -20.0).abs() < 1e-9);
+
+        // energy is averaged, so the louder track dominates
+        let loudness = album_loudness([quiet, loud]).unwrap();
+        assert!((loudness - -12.6).abs() < 0.01, "loudness = {loudness}");
+
+        // longer tracks have more weight
+        let long_quiet = Loudness {
+            lufs: -20.0,
+            blocks: 900,
+        };
+        let loudness = album_loudness([long_quiet, loud]).unwrap();
+        assert!((loudness - -17.21).abs() < 0.01, "loudness = {loudness}");
+
+        assert_eq!(album_loudness([]), None);
+    }
+}
@@ -0,0 +1,395 @@
+//! Manifests that record how each transcode in the cache was made.
+//!
+//! Every transcode has a sidecar file next to it, named after the transcode
+//! with a `.manifest` extension. It records the profile, the encoder version,
+//! the source hash, and a checksum of the output, so that transcodes made by
+//! an older encoder or damaged on disk are redone instead of served. It also
+//! records the source's metadata fingerprint, so that transcodes are re-tagged
+//! when the source's tags change, and how many times the transcode was changed
+//! in place, so that clients can tell when their copies are out of date.
+
+use crate::library::transcode::TranscodeProfile;
+use anyhow::Context;
+use std::{
+    ffi::OsString,
+    fs::File,
+    hash::Hasher,
+    io::Read,
+    path::{Path, PathBuf},
+};
+use twox_hash::XxHash3_64;
+
+/// The version of the transcoder.
+///
+/// Bump this when a change to the transcoder changes its output, so that
+/// existing transcodes are made again with the new encoder.
+pub const ENCODER_VERSION: u32 = 1;
+
+/// The extension of manifest files.
+pub const MANIFEST_EXTENSION: &str = "manifest";
+
+/// The manifest of a transcode.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct TranscodeManifest {
+    /// The key of the profile the transcode was made with.
+    pub profile: String,
+    /// The version of the encoder the transcode was made with.
+    pub encoder_version: u32,
+    /// The version of musicopy the transcode was made with, for debugging.
+    pub musicopy_version: String,
+    pub hash_kind: String,
+    pub hash: Vec<u8>,
+    /// The size of the transcode.
+    pub file_size: u64,
+    /// The xxhash3 checksum of the transcode.
+    pub checksum: Vec<u8>,
+    /// The metadata fingerprint of the source the tags were copied from, see
+    /// `transcode::metadata_fingerprint`. Older manifests don't have one.
+    pub metadata: Option<Vec<u8>>,
+    /// How many times the transcode was changed in place, like when it's
+    /// re-tagged. Older manifests don't have one and are at revision 0.
+    pub revision: u64,
+}
+
+impl TranscodeManifest {
+    /// Creates the manifest of a finished transcode.
+    pub fn new(
+        transcode_path: &Path,
+        profile: &TranscodeProfile,
+        hash_kind: &str,
+        hash: &[u8],
+        metadata: Option<Vec<u8>>,
+    ) -> anyhow::Result<Self> {
+        let (file_size, checksum) = file_checksum(transcode_path)?;
+
+        Ok(Self {
+            profile: profile.key(),
+            encoder_version: ENCODER_VERSION,
+            musicopy_version: env!("CARGO_PKG_VERSION").to_string(),
+            hash_kind: hash_kind.to_string(),
+            hash: hash.to_vec(),
+            file_size,
+            checksum,
+            metadata,
+            revision: 0,
+        })
+    }
+
+    /// Reads the manifest of a transcode, or returns `None` if it has none.
+    pub fn read(transcode_path: &Path) -> anyhow::Result<Option<Self>> {
+        let contents = match std::fs::read_to_string(manifest_path(transcode_path)) {
+            Ok(contents) => contents,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(None),
+            Err(e) => return Err(e).context("failed to read manifest"),
+        };
+
+        Self::parse(&contents).map(Some)
+    }
+
+    /// Writes the manifest of a transcode.
+    pub fn write(&self, transcode_path: &Path) -> anyhow::Result<()> {
+        std::fs::write(manifest_path(transcode_path), self.to_string())
+            .context("failed to write manifest")
+    }
+
+    /// Parses a manifest from `key=value` lines.
+    fn parse(contents: &str) -> anyhow::Result<Self> {
+        let mut profile = None;
+        let mut encoder_version = None;
+        let mut musicopy_version = None;
+        let mut source = None;
+        let mut file_size = None;
+        let mut checksum = None;
+        let mut metadata = None;
+        let mut revision = 0;
+
+        for line in contents.lines() {
+            let Some((key, value)) = line.split_once('=') else {
+                continue;
+            };
+
+            match key {
+                "profile" => profile = Some(value.to_string()),
+                "encoder" => {
+                    encoder_version = Some(value.parse().context("invalid encoder version")?)
+                }
+                "musicopy" => musicopy_version = Some(value.to_string()),
+                "source" => {
+                    let (hash_kind, hash) = value.split_once('-').context("invalid source")?;
+                    let hash = hex::decode(hash).context("invalid source hash")?;
+                    source = Some((hash_kind.to_string(), hash));
+                }
+                "size" => file_size = Some(value.parse().context("invalid size")?),
+                "checksum" => checksum = Some(hex::decode(value).context("invalid checksum")?),
+                "metadata" => metadata = Some(hex::decode(value).context("invalid metadata")?),
+                "revision" => revision = value.parse().context("invalid revision")?,
+                // ignore keys from newer versions
+                _ => {}
+            }
+        }
+
+        let (hash_kind, hash) = source.context("missing source")?;
+        Ok(Self {
+            profile: profile.context("missing profile")?,
+            encoder_version: encoder_version.context("missing encoder version")?,
+            musicopy_version: musicopy_version.unwrap_or_default(),
+            hash_kind,
+            hash,
+            file_size: file_size.context("missing size")?,
+            checksum: checksum.context("missing checksum")?,
+            metadata,
+            revision,
+        })
+    }
+
+    /// Checks that a transcode matches its manifest and was made with the
+    /// current encoder, returning the reason if it doesn't.
+    ///
+    /// This only checks the size, so it doesn't read the transcode. The
+    /// checksum is checked separately with `verify_checksum`. The modified
+    /// time isn't checked, since it records when the transcode was last used.
+    pub fn validate(
+        &self,
+        transcode_path: &Path,
+        profile: &TranscodeProfile,
+        hash_kind: &str,
+        hash: &[u8],
+    ) -> Result<(), String> {
+        if self.profile != profile.key() {
+            return Err(format!("made with profile {}", self.profile));
+        }
+        if self.encoder_version != ENCODER_VERSION {
+            return Err(format!(
+                "made with encoder version {}",
+                self.encoder_version
+            ));
+        }
+        if self.hash_kind != hash_kind || self.hash != hash {
+            return Err("source hash doesn't match".to_string());
+        }
+
+        // check the size first to catch truncated files without reading them
+        let file_size = std::fs::metadata(transcode_path)
+            .map_err(|e| format!("failed to get file metadata: {e}"))?
+            .len();
+        if file_size != self.file_size {
+            return Err(format!("size is {file_size}, expected {}", self.file_size));
+        }
+
+        Ok(())
+    }
+
+    /// Checks that a transcode wasn't damaged on disk by reading it and
+    /// comparing its checksum, returning the reason if it was.
+    pub fn verify_checksum(&self, transcode_path: &Path) -> Result<(), String> {
+        let (_, checksum) = file_checksum(transcode_path).map_err(|e| format!("{e:#}"))?;
+        if checksum != self.checksum {
+            return Err("checksum doesn't match".to_string());
+        }
+
+        Ok(())
+    }
+
+    /// Updates the size and checksum after the transcode was modified in
+    /// place, and bumps the revision.
+    pub fn refresh(&mut self, transcode_path: &Path) -> anyhow::Result<()> {
+        (self.file_size, self.checksum) = file_checksum(transcode_path)?;
+        self.revision += 1;
+        Ok(())
+    }
+}
+
+impl std::fmt::Display for TranscodeManifest {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        writeln!(f, "profile={}", self.profile)?;
+        writeln!(f, "encoder={}", self.encoder_version)?;
+        writeln!(f, "musicopy={}", self.musicopy_version)?;
+        writeln!(f, "source={}-{}", self.hash_kind, hex::encode(&self.hash))?;
+        writeln!(f, "size={}", self.file_size)?;
+        writeln!(f, "checksum={}", hex::encode(&self.checksum))?;
+        if let Some(metadata) = &self.metadata {
+            writeln!(f, "metadata={}", hex::encode(metadata))?;
+        }
+        writeln!(f, "revision={}", self.revision)?;
+        Ok(())
+    }
+}
+
+/// Returns the path of a transcode's manifest.
+pub fn manifest_path(transcode_path: &Path) -> PathBuf {
+    let mut path = OsString::from(transcode_path);
+    path.push(".");
+    path.push(MANIFEST_EXTENSION);
+    PathBuf::from(path)
+}
+
+/// Returns the path of the transcode a manifest belongs to.
+pub fn transcode_path(manifest_path: &Path) -> PathBuf {
+    manifest_path.with_extension("")
+}
+
+/// Updates the manifest of a transcode after it was modified in place,
+/// returning the updated manifest.
+///
+/// Transcodes without a manifest are left alone.
+pub fn refresh_manifest(transcode_path: &Path) -> anyhow::Result<Option<TranscodeManifest>> {
+    let Some(mut manifest) = TranscodeManifest::read(transcode_path)? else {
+        return Ok(None);
+    };
+    manifest.refresh(transcode_path)?;
+    manifest.write(transcode_path)?;
+    Ok(Some(manifest))
+}
+
+/// Returns the size and xxhash3 checksum of a file.
+fn file_checksum(path: &Path) -> anyhow::Result<(u64, Vec<u8>)> {
+    let mut file = File::open(path).context("failed to open file")?;
+
+    let mut hasher = XxHash3_64::with_seed(0);
+    let mut file_size = 0;
+    let mut buf = vec![0; 64 * 1024];
+    loop {
+        let len = file.read(&mut buf).context("failed to read file")?;
+        if len == 0 {
+            break;
+        }
+        hasher.write(&buf[..len]);
+        file_size += len as u64;
+    }
+
+    Ok((file_size, hasher.finish().to_be_bytes().to_vec()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn test_transcode(name: &str) -> PathBuf {
+        let path = std::env::temp_dir().join(format!(
+            "musicopy-test-{}-manifest-{name}.ogg",
+            std::process::id()
+        ));
+        std::fs::write(&path, vec![1; 1000]).unwrap();
+        path
+    }
+
+    fn cleanup(path: &Path) {
+        let _ = std::fs::remove_file(path);
+        let _ = std::fs::remove_file(manifest_path(path));
+    }
+
+    #[test]
+    fn test_manifest_round_trip() {
+        let path = test_transcode("round-trip");
+        let profile = TranscodeProfile::default();
+
+        let manifest =
+            TranscodeManifest::new(&path, &profile, "xxh3", &[1, 2, 3], Some(vec![4, 5])).unwrap();
+        assert_eq!(manifest.file_size, 1000);
+        manifest.write(&path).unwrap();
+
+        let read = TranscodeManifest::read(&path).unwrap().unwrap();
+
+        // the metadata fingerprint and revision are optional
+        let old_manifest = TranscodeManifest {
+            metadata: None,
+            ..manifest.clone()
+        };
+        std::fs::write(
+            manifest_path(&path),
+            old_manifest.to_string().replace("revision=0\n", ""),
+        )
+        .unwrap();
+        let old_read = TranscodeManifest::read(&path).unwrap().unwrap();
+        cleanup(&path);
+
+        assert_eq!(read, manifest);
+        assert_eq!(old_read, old_manifest);
+    }
+
+    #[test]
+    fn test_manifest_missing() {
+        let path = test_transcode("missing");
+        let manifest = TranscodeManifest::read(&path).unwrap();
+        cleanup(&path);
+
+        assert_eq!(manifest, None);
+    }
+
+    #[test]
+    fn test_manifest_validate() {
+        let path = test_transcode("validate");
+        let profile = TranscodeProfile::default();
+
+        let manifest = TranscodeManifest::new(&path, &profile, "xxh3", &[1, 2, 3], None).unwrap();
+        assert_eq!(
+            manifest.validate(&path, &profile, "xxh3", &[1, 2, 3]),
+            Ok(())
+        );
+
+        // different profile or source
+        let other_profile = TranscodeProfile {
+            bitrate: profile.bitrate + 1,
+            ..profile
+        };
+        assert!(
+            manifest
+                .validate(&path, &other_profile, "xxh3", &[1, 2, 3])
+                .is_err()
+        );
+        assert!(manifest.validate(&path, &profile, "xxh3", &[4]).is_err());
+
+        // older encoder
+        let outdated = TranscodeManifest {
+            encoder_version: ENCODER_VERSION - 1,
+            ..manifest.clone()
+        };
+        assert!(
+            outdated
+                .validate(&path, &profile, "xxh3", &[1, 2, 3])
+                .is_err()
+        );
+
+        // corrupted, which only the checksum catches
+        assert_eq!(manifest.verify_checksum(&path), Ok(()));
+        std::fs::write(&path, vec![2; 1000]).unwrap();
+        assert_eq!(
+            manifest.validate(&path, &profile, "xxh3", &[1, 2, 3]),
+            Ok(())
+        );
+        assert!(manifest.verify_checksum(&path).is_err());
+
+        // truncated
+        std::fs::write(&path, vec![1; 500]).unwrap();
+        assert!(
+            manifest
+                .validate(&path, &profile, "xxh3", &[1, 2, 3])
+                .is_err()
+        );
+
+        // refreshed after modifying in place
+        manifest.write(&path).unwrap();
+        refresh_manifest(&path).unwrap();
+        let refreshed = TranscodeManifest::read(&path).unwrap().unwrap();
+        assert_eq!(
+            refreshed.validate(&path, &profile, "xxh3", &[1, 2, 3]),
+            Ok(())
+        );
+        assert_eq!(refreshed.verify_checksum(&path), Ok(()));
+        assert_eq!(refreshed.revision, manifest.revision + 1);
+
+        cleanup(&path);
+    }
+
+    #[test]
+    fn test_manifest_path() {
+        let path = Path::new("/cache/profile/xxh3-0102.passthrough.mp3");
+        let manifest = manifest_path(path);
+
+        assert_eq!(
+            manifest,
+            Path::new("/cache/profile/xxh3-0102.passthrough.mp3.manifest")
+        );
+        assert_eq!(transcode_path(&manifest), path);
+    }
+}
@@ -1,13 +1,33 @@
+mod chapters;
+mod cue;
+pub mod estimate;
+mod flac;
+mod gapless;
+mod loudness;
+mod manifest;
+mod mp4;
+mod opus_packet;
+mod queue;
+mod sidecar;
+mod tags;
 pub mod transcode;
 
 use crate::{
     EventHandler,
-    database::{Database, InsertFile},
-    library::transcode::{
-        TranscodeCommand, TranscodeItem, TranscodePolicy, TranscodePool, TranscodeStatusCache,
+    database::{Database, File, InsertFile, Root},
+    library::{
+        cue::CueTrack,
+        estimate::{TranscodePlan, plan_bitrate, source_sample_rate},
+        loudness::Loudness,
+        tags::R128_REFERENCE_LUFS,
+        transcode::{
+            JobPanicked, TranscodeCommand, TranscodeContent, TranscodeEvent, TranscodeItem,
+            TranscodePolicy, TranscodePool, TranscodePoolOptions, TranscodeProfile,
+            TranscodeStatusCache, catch_panic, default_worker_count, metadata_fingerprint,
+        },
     },
     model::CounterModel,
-    node::FileSizeModel,
+    node::{FileSizeModel, unix_epoch_now_secs},
 };
 use anyhow::Context;
 use iroh::NodeId;
@@ -15,6 +35,7 @@ use itertools::Itertools;
 use log::warn;
 use rayon::{iter::Either, prelude::*};
 use std::{
+    collections::{HashMap, HashSet},
     hash::Hasher,
     path::PathBuf,
     sync::{Arc, Mutex},
@@ -25,14 +46,47 @@ use symphonia::core::{
     formats::{TrackType, probe::Hint},
     io::MediaSourceStream,
 };
-use tokio::sync::mpsc;
+use tokio::sync::{mpsc, oneshot};
 use twox_hash::XxHash3_64;
 
+/// The settings key used to persist the transcode profile.
+const TRANSCODE_PROFILE_SETTING: &str = "transcode_profile";
+
+/// The settings key used to persist the transcode cache size limit.
+const TRANSCODE_CACHE_SIZE_LIMIT_SETTING: &str = "transcode_cache_size_limit";
+
+/// The settings key used to persist whether transcoding runs in the background.
+const TRANSCODE_BACKGROUND_SETTING: &str = "transcode_background";
+
+/// The settings key used to persist whether transcodes are verified.
+const TRANSCODE_VERIFY_SETTING: &str = "transcode_verify";
+
+/// How long no track of an album has to finish transcoding before the album
+/// gain is recomputed, so an album that's transcoded track by track is only
+/// rewritten once.
+const ALBUM_GAIN_DELAY: Duration = Duration::from_secs(10);
+
 #[derive(Debug, Clone, uniffi::Record)]
 pub struct LibraryRootModel {
     pub name: String,
     pub path: String,
     pub num_files: u64,
+    /// The kind of audio in the root, which picks the transcode settings.
+    pub content_type: TranscodeContent,
+}
+
+/// A file that failed to transcode with the current profile.
+#[derive(Debug, Clone, uniffi::Record)]
+pub struct TranscodeFailureModel {
+    pub hash_kind: String,
+    pub hash: Vec<u8>,
+    /// The path of the source file.
+    pub local_path: String,
+    pub error: String,
+    /// The number of times transcoding the file failed.
+    pub attempts: u32,
+    /// When transcoding the file last failed, in seconds since the Unix epoch.
+    pub failed_at: u64,
 }
 
 /// Library state sent to the UI.
@@ -45,23 +99,85 @@ pub struct LibraryModel {
     pub transcodes_dir: String,
     pub transcodes_dir_size: FileSizeModel,
 
+    /// The total size of transcodes in the cache.
+    pub transcode_cache_size: u64,
+    /// The maximum size of the transcode cache, or `None` if unlimited.
+    pub transcode_cache_size_limit: Option<u64>,
+
     pub transcode_count_waiting: Arc<CounterModel>,
     pub transcode_count_queued: Arc<CounterModel>,
     pub transcode_count_inprogress: Arc<CounterModel>,
     pub transcode_count_ready: Arc<CounterModel>,
     pub transcode_count_failed: Arc<CounterModel>,
 
+    /// The number of transcodes and file hashes that panicked, which are
+    /// recorded as failures instead of taking down their thread.
+    pub panic_count: Arc<CounterModel>,
+    /// The number of transcode workers that died and were restarted.
+    pub transcode_worker_restarts: Arc<CounterModel>,
+
+    /// Files that failed to transcode with the current profile.
+    pub transcode_failures: Vec<TranscodeFailureModel>,
+
     pub transcode_policy: TranscodePolicy,
+    pub transcode_profile: TranscodeProfile,
+
+    /// The number of transcode worker threads.
+    pub transcode_worker_count: u32,
+    /// Whether transcoding runs in the background, with lower priority and
+    /// fewer workers while a scan or transfer is active.
+    pub transcode_background: bool,
+    /// Whether Opus transcodes are decoded again to check them before they're
+    /// marked ready.
+    pub transcode_verify: bool,
+    /// Whether transcoding is paused.
+    pub transcodes_paused: bool,
 }
 
 #[derive(Debug)]
 pub enum LibraryCommand {
-    AddRoot { name: String, path: String },
-    RemoveRoot { name: String },
+    AddRoot {
+        name: String,
+        path: String,
+    },
+    RemoveRoot {
+        name: String,
+    },
+    /// Set the content type of a root and transcode its files again with the
+    /// settings for that content type.
+    SetRootContentType {
+        name: String,
+        content_type: TranscodeContent,
+    },
     Rescan,
 
+    CollectGarbage,
     PrioritizeTranscodes(Vec<(String, Vec<u8>)>),
     SetTranscodePolicy(TranscodePolicy),
+    SetTranscodeProfile(TranscodeProfile),
+    SetTranscodeCacheSizeLimit(Option<u64>),
+    SetTranscodeWorkerCount(Option<u32>),
+    SetTranscodeBackground(bool),
+    SetTranscodeVerify(bool),
+    /// Retry failed transcodes of the files with the given source paths, or
+    /// all failed transcodes if `None`.
+    RetryFailedTranscodes(Option<Vec<String>>),
+    PauseTranscodes,
+    ResumeTranscodes,
+    /// Cancel transcoding the files with the given source paths.
+    CancelTranscodes(Vec<String>),
+    /// Find the highest bitrate that fits the files with the given source
+    /// paths into a budget in bytes, and if `schedule` is set, switch the
+    /// profile to that bitrate and transcode the files first.
+    PlanTranscodes {
+        local_paths: Vec<String>,
+        budget: u64,
+        schedule: bool,
+        callback: oneshot::Sender<anyhow::Result<TranscodePlan>>,
+    },
+
+    TransferStarted,
+    TransferFinished,
 
     Stop,
 }
@@ -70,7 +186,14 @@ pub enum LibraryCommand {
 enum LibraryModelUpdate {
     UpdateLocalRoots,
     UpdateTranscodesDirSize,
+    UpdateTranscodeFailures,
     SetTranscodePolicy(TranscodePolicy),
+    SetTranscodeProfile(TranscodeProfile),
+    SetTranscodeCacheSizeLimit(Option<u64>),
+    SetTranscodeWorkerCount(u32),
+    SetTranscodeBackground(bool),
+    SetTranscodeVerify(bool),
+    SetTranscodesPaused(bool),
 }
 
 pub struct Library {
@@ -100,6 +223,7 @@ impl std::fmt::Debug for Library {
 #[derive(Debug)]
 pub struct LibraryRun {
     command_rx: mpsc::UnboundedReceiver<LibraryCommand>,
+    transcode_event_rx: mpsc::UnboundedReceiver<TranscodeEvent>,
 }
 
 impl Library {
@@ -109,13 +233,72 @@ impl Library {
         local_node_id: NodeId,
         transcodes_dir: PathBuf,
         transcode_policy: TranscodePolicy,
+        transcode_worker_count: Option<u32>,
         transcode_status_cache: TranscodeStatusCache,
     ) -> anyhow::Result<(Arc<Self>, LibraryRun)> {
+        // load persisted transcode profile
+        let transcode_profile = {
+            let db = db.lock().unwrap();
+            db.get_setting(TRANSCODE_PROFILE_SETTING)
+                .context("failed to get transcode profile setting")?
+                .map(|key| {
+                    TranscodeProfile::from_key(&key).unwrap_or_else(|e| {
+                        warn!("failed to parse transcode profile `{key}`, using default: {e:#}");
+                        TranscodeProfile::default()
+                    })
+                })
+                .unwrap_or_default()
+        };
+
+        // load persisted transcode cache size limit
+        let transcode_cache_size_limit = {
+            let db = db.lock().unwrap();
+            db.get_setting(TRANSCODE_CACHE_SIZE_LIMIT_SETTING)
+                .context("failed to get transcode cache size limit setting")?
+                .and_then(|limit| {
+                    limit
+                        .parse::<u64>()
+                        .inspect_err(|e| {
+                            warn!("failed to parse transcode cache size limit `{limit}`: {e:#}")
+                        })
+                        .ok()
+                })
+        };
+
+        // load persisted transcode background setting
+        let transcode_background = {
+            let db = db.lock().unwrap();
+            db.get_setting(TRANSCODE_BACKGROUND_SETTING)
+                .context("failed to get transcode background setting")?
+                .is_some_and(|value| value == "true")
+        };
+
+        // load persisted transcode verify setting
+        let transcode_verify = {
+            let db = db.lock().unwrap();
+            db.get_setting(TRANSCODE_VERIFY_SETTING)
+                .context("failed to get transcode verify setting")?
+                .is_some_and(|value| value == "true")
+        };
+
+        let transcode_worker_count = transcode_worker_count
+            .map(|count| count.max(1) as usize)
+            .unwrap_or_else(default_worker_count);
+
         // spawn transcode pool task
+        let (transcode_event_tx, transcode_event_rx) = mpsc::unbounded_channel();
         let transcode_pool = TranscodePool::spawn(
-            transcodes_dir.clone(),
-            transcode_policy,
+            TranscodePoolOptions {
+                transcodes_dir: transcodes_dir.clone(),
+                policy: transcode_policy,
+                profile: transcode_profile,
+                cache_size_limit: transcode_cache_size_limit,
+                worker_count: transcode_worker_count,
+                background: transcode_background,
+                verify: transcode_verify,
+            },
             transcode_status_cache,
+            transcode_event_tx,
         );
 
         let (command_tx, command_rx) = mpsc::unbounded_channel();
@@ -126,13 +309,27 @@ impl Library {
             transcodes_dir: transcode_pool.transcodes_dir(),
             transcodes_dir_size: transcode_pool.transcodes_dir_size(),
 
+            transcode_cache_size: transcode_pool.cache_size(),
+            transcode_cache_size_limit,
+
             transcode_count_waiting: Arc::new(transcode_pool.waiting_count_model()),
             transcode_count_queued: Arc::new(transcode_pool.queued_count_model()),
             transcode_count_inprogress: Arc::new(transcode_pool.inprogress_count_model()),
             transcode_count_ready: Arc::new(transcode_pool.ready_count_model()),
             transcode_count_failed: Arc::new(transcode_pool.failed_count_model()),
 
+            panic_count: Arc::new(transcode_pool.panic_count_model()),
+            transcode_worker_restarts: Arc::new(transcode_pool.restart_count_model()),
+
+            transcode_failures: Vec::new(),
+
             transcode_policy,
+            transcode_profile,
+
+            transcode_worker_count: transcode_worker_count as u32,
+            transcode_background,
+            transcode_verify,
+            transcodes_paused: false,
         };
 
         let library = Arc::new(Self {
@@ -150,6 +347,7 @@ impl Library {
         // initialize model
         // TODO: don't push updates during init
         library.update_model(LibraryModelUpdate::UpdateLocalRoots);
+        library.update_model(LibraryModelUpdate::UpdateTranscodeFailures);
 
         // send all local files to the transcode pool to be transcoded if needed
         library
@@ -167,13 +365,26 @@ impl Library {
             }
         });
 
-        let library_run = LibraryRun { command_rx };
+        let library_run = LibraryRun {
+            command_rx,
+            transcode_event_rx,
+        };
 
         Ok((library, library_run))
     }
 
     pub async fn run(self: &Arc<Self>, run_token: LibraryRun) -> anyhow::Result<()> {
-        let LibraryRun { mut command_rx } = run_token;
+        let LibraryRun {
+            mut command_rx,
+            mut transcode_event_rx,
+        } = run_token;
+
+        // album gains are updated in the background, see run_album_gain
+        let (album_gain_tx, album_gain_rx) = mpsc::unbounded_channel();
+        tokio::spawn({
+            let library = self.clone();
+            async move { library.run_album_gain(album_gain_rx).await }
+        });
 
         loop {
             tokio::select! {
@@ -209,10 +420,22 @@ impl Library {
                             self.spawn_scan();
                         }
 
+                        LibraryCommand::SetRootContentType { name, content_type } => {
+                            if let Err(e) = self.set_root_content_type(&name, content_type) {
+                                warn!("LibraryCommand::SetRootContentType: failed to set root content type: {e:#}");
+                            }
+                        }
+
                         LibraryCommand::Rescan => {
                             self.spawn_scan();
                         }
 
+                        LibraryCommand::CollectGarbage => {
+                            if let Err(e) = self.collect_garbage() {
+                                warn!("LibraryCommand::CollectGarbage: failed to collect garbage: {e:#}");
+                            }
+                        }
+
                         LibraryCommand::PrioritizeTranscodes(hashes) => {
                             if let Err(e) = self.transcode_pool.send(TranscodeCommand::Prioritize(hashes.clone())) {
                                 warn!("LibraryCommand::PrioritizeTranscodes: failed to send to transcode pool: {e:#}");
@@ -228,12 +451,193 @@ impl Library {
                             self.update_model(LibraryModelUpdate::SetTranscodePolicy(transcode_policy));
                         }
 
+                        LibraryCommand::SetTranscodeProfile(transcode_profile) => {
+                            {
+                                let db = self.db.lock().unwrap();
+                                db.set_setting(TRANSCODE_PROFILE_SETTING, &transcode_profile.key()).context("failed to persist transcode profile")?;
+                            }
+
+                            if let Err(e) = self.transcode_pool.send(TranscodeCommand::SetProfile(transcode_profile)) {
+                                warn!("LibraryCommand::SetTranscodeProfile: failed to send to transcode pool: {e:#}");
+                            }
+
+                            // update model
+                            // this comes first because failures are loaded for the profile in the model
+                            self.update_model(LibraryModelUpdate::SetTranscodeProfile(transcode_profile));
+                            self.update_model(LibraryModelUpdate::UpdateTranscodeFailures);
+
+                            // the pool forgets its statuses when the profile changes, so add all files again
+                            if let Err(e) = self.check_transcodes() {
+                                warn!("LibraryCommand::SetTranscodeProfile: failed to check transcodes: {e:#}");
+                            }
+                        }
+
+                        LibraryCommand::SetTranscodeCacheSizeLimit(limit) => {
+                            {
+                                let db = self.db.lock().unwrap();
+                                let value = limit.map(|limit| limit.to_string()).unwrap_or_default();
+                                db.set_setting(TRANSCODE_CACHE_SIZE_LIMIT_SETTING, &value).context("failed to persist transcode cache size limit")?;
+                            }
+
+                            if let Err(e) = self.transcode_pool.send(TranscodeCommand::SetCacheSizeLimit(limit)) {
+                                warn!("LibraryCommand::SetTranscodeCacheSizeLimit: failed to send to transcode pool: {e:#}");
+                            }
+
+                            // update model
+                            self.update_model(LibraryModelUpdate::SetTranscodeCacheSizeLimit(limit));
+                        }
+
+                        LibraryCommand::SetTranscodeWorkerCount(count) => {
+                            let count = count
+                                .map(|count| count.max(1) as usize)
+                                .unwrap_or_else(default_worker_count);
+
+                            if let Err(e) = self.transcode_pool.send(TranscodeCommand::SetWorkerCount(count)) {
+                                warn!("LibraryCommand::SetTranscodeWorkerCount: failed to send to transcode pool: {e:#}");
+                            }
+
+                            // update model
+                            self.update_model(LibraryModelUpdate::SetTranscodeWorkerCount(count as u32));
+                        }
+
+                        LibraryCommand::SetTranscodeBackground(enabled) => {
+                            {
+                                let db = self.db.lock().unwrap();
+                                db.set_setting(TRANSCODE_BACKGROUND_SETTING, if enabled { "true" } else { "false" }).context("failed to persist transcode background setting")?;
+                            }
+
+                            if let Err(e) = self.transcode_pool.send(TranscodeCommand::SetBackground(enabled)) {
+                                warn!("LibraryCommand::SetTranscodeBackground: failed to send to transcode pool: {e:#}");
+                            }
+
+                            // update model
+                            self.update_model(LibraryModelUpdate::SetTranscodeBackground(enabled));
+                        }
+
+                        LibraryCommand::SetTranscodeVerify(enabled) => {
+                            {
+                                let db = self.db.lock().unwrap();
+                                db.set_setting(TRANSCODE_VERIFY_SETTING, if enabled { "true" } else { "false" }).context("failed to persist transcode verify setting")?;
+                            }
+
+                            if let Err(e) = self.transcode_pool.send(TranscodeCommand::SetVerify(enabled)) {
+                                warn!("LibraryCommand::SetTranscodeVerify: failed to send to transcode pool: {e:#}");
+                            }
+
+                            // update model
+                            self.update_model(LibraryModelUpdate::SetTranscodeVerify(enabled));
+                        }
+
+                        LibraryCommand::RetryFailedTranscodes(local_paths) => {
+                            if let Err(e) = self.retry_failed_transcodes(local_paths) {
+                                warn!("LibraryCommand::RetryFailedTranscodes: failed to retry failed transcodes: {e:#}");
+                            }
+                        }
+
+                        LibraryCommand::PauseTranscodes => {
+                            if let Err(e) = self.transcode_pool.send(TranscodeCommand::Pause) {
+                                warn!("LibraryCommand::PauseTranscodes: failed to send to transcode pool: {e:#}");
+                            }
+
+                            // update model
+                            self.update_model(LibraryModelUpdate::SetTranscodesPaused(true));
+                        }
+
+                        LibraryCommand::ResumeTranscodes => {
+                            if let Err(e) = self.transcode_pool.send(TranscodeCommand::Resume) {
+                                warn!("LibraryCommand::ResumeTranscodes: failed to send to transcode pool: {e:#}");
+                            }
+
+                            // update model
+                            self.update_model(LibraryModelUpdate::SetTranscodesPaused(false));
+                        }
+
+                        LibraryCommand::CancelTranscodes(local_paths) => {
+                            if let Err(e) = self.cancel_transcodes(local_paths) {
+                                warn!("LibraryCommand::CancelTranscodes: failed to cancel transcodes: {e:#}");
+                            }
+                        }
+
+                        LibraryCommand::PlanTranscodes { local_paths, budget, schedule, callback } => {
+                            let library = self.clone();
+                            tokio::task::spawn(async move {
+                                let res = library.plan_transcodes(local_paths, budget, schedule).await;
+                                if let Err(e) = callback.send(res) {
+                                    warn!("LibraryCommand::PlanTranscodes: failed to send res: {e:?}");
+                                }
+                            });
+                        }
+
+                        LibraryCommand::TransferStarted => {
+                            if let Err(e) = self.transcode_pool.send(TranscodeCommand::ActivityStarted) {
+                                warn!("LibraryCommand::TransferStarted: failed to send to transcode pool: {e:#}");
+                            }
+                        }
+
+                        LibraryCommand::TransferFinished => {
+                            if let Err(e) = self.transcode_pool.send(TranscodeCommand::ActivityFinished) {
+                                warn!("LibraryCommand::TransferFinished: failed to send to transcode pool: {e:#}");
+                            }
+                        }
+
                         LibraryCommand::Stop => {
                             break;
                         }
                     }
                 }
 
+                Some(event) = transcode_event_rx.recv() => {
+                    match event {
+                        TranscodeEvent::Finished { hash_kind, hash, album, loudness } => {
+                            {
+                                let db = self.db.lock().unwrap();
+                                if let Err(e) = db.set_track_loudness(
+                                    &hash_kind,
+                                    &hash,
+                                    &album,
+                                    loudness.map(|loudness| loudness.lufs),
+                                    loudness.map_or(0, |loudness| loudness.blocks),
+                                ) {
+                                    warn!("TranscodeEvent::Finished: failed to set track loudness: {e:#}");
+                                }
+                            }
+
+                            let _ = album_gain_tx.send(album);
+                        }
+
+                        TranscodeEvent::Ready { hash_kind, hash, profile } => {
+                            // only files that failed before have a persisted failure to clear
+                            let failed = {
+                                let model = self.model.lock().unwrap();
+                                model.transcode_failures.iter().any(|failure| failure.hash_kind == hash_kind && failure.hash == hash)
+                            };
+                            if failed {
+                                {
+                                    let db = self.db.lock().unwrap();
+                                    if let Err(e) = db.delete_transcode_failure(&hash_kind, &hash, &profile.key()) {
+                                        warn!("TranscodeEvent::Ready: failed to delete transcode failure: {e:#}");
+                                    }
+                                }
+
+                                // update model
+                                self.update_model(LibraryModelUpdate::UpdateTranscodeFailures);
+                            }
+                        }
+
+                        TranscodeEvent::Failed { hash_kind, hash, profile, error } => {
+                            {
+                                let db = self.db.lock().unwrap();
+                                if let Err(e) = db.record_transcode_failure(&hash_kind, &hash, &profile.key(), &error, unix_epoch_now_secs()) {
+                                    warn!("TranscodeEvent::Failed: failed to record transcode failure: {e:#}");
+                                }
+                            }
+
+                            // update model
+                            self.update_model(LibraryModelUpdate::UpdateTranscodeFailures);
+                        }
+                    }
+                }
+
                 else => {
                     log::warn!("all senders dropped in Library::run, shutting down");
                     break;
@@ -248,9 +652,26 @@ impl Library {
         let library = self.clone();
         tokio::spawn(async move {
             log::debug!("spawning library scan");
+
+            // let the transcode pool know so it can yield to the scan in background mode
+            if let Err(e) = library
+                .transcode_pool
+                .send(TranscodeCommand::ActivityStarted)
+            {
+                warn!("Library::spawn_scan: failed to send to transcode pool: {e:#}");
+            }
+
             if let Err(e) = library.scan().await {
                 log::error!("error scanning library: {e:#}");
             }
+
+            if let Err(e) = library
+                .transcode_pool
+                .send(TranscodeCommand::ActivityFinished)
+            {
+                warn!("Library::spawn_scan: failed to send to transcode pool: {e:#}");
+            }
+
             log::debug!("finished library scan");
 
             // update root file counts in model
@@ -269,13 +690,19 @@ impl Library {
             let roots = db
                 .get_roots_by_node_id(self.local_node_id)
                 .context("failed to get local roots")?;
-            // let local_files = db.get_local_files().context("failed to get local files")?;
-            let local_files = (); // TODO
+            let local_files = db
+                .get_files_by_node_id(self.local_node_id)
+                .context("failed to get local files")?;
             (roots, local_files)
         };
 
         log::info!("scan: scanning {} roots", roots.len());
 
+        let root_contents = roots
+            .iter()
+            .map(|root| (root.name.clone(), root_content(root)))
+            .collect::<HashMap<_, _>>();
+
         // remove roots that don't exist
         let roots = roots
             .into_iter()
@@ -299,7 +726,7 @@ impl Library {
             .flat_map(|root| {
                 let walker = globwalk::GlobWalkerBuilder::new(
                     &root.path,
-                    "*.{mp3,flac,ogg,m4a,wav,aif,aiff}",
+                    "*.{mp3,flac,ogg,m4a,m4b,mka,wav,aif,aiff,cue}",
                 )
                 .file_type(globwalk::FileType::FILE)
                 .build()
@@ -318,10 +745,70 @@ impl Library {
                 .map(|e| anyhow::anyhow!("failed to scan file {:?}: {}", e.path(), e)),
         );
 
+        // find files that cue sheets split into tracks
+        let (cue_entries, entries): (Vec<_>, Vec<_>) =
+            entries.into_iter().partition(|(_, entry)| {
+                entry
+                    .path()
+                    .extension()
+                    .is_some_and(|extension| extension.eq_ignore_ascii_case("cue"))
+            });
+
+        struct ScanCue {
+            cue_path: String,
+            tracks: Vec<CueTrack>,
+        }
+
+        let mut cue_sources = HashMap::new();
+        for (_, entry) in cue_entries
+            .into_iter()
+            .sorted_by(|(_, a), (_, b)| a.path().cmp(b.path()))
+        {
+            let cue_path = entry.path();
+            let cue_files = match cue::read(cue_path) {
+                Ok(cue_files) => cue_files,
+                Err(e) => {
+                    errors.push(
+                        e.context(format!("failed to read cue sheet {}", cue_path.display())),
+                    );
+                    continue;
+                }
+            };
+
+            for cue_file in cue_files {
+                // files with one track are already separate tracks
+                if cue_file.tracks.len() < 2 {
+                    continue;
+                }
+
+                let Some(source_path) = cue::resolve_file(cue_path, &cue_file.path) else {
+                    warn!(
+                        "scan: cue sheet {} references missing file {}",
+                        cue_path.display(),
+                        cue_file.path
+                    );
+                    continue;
+                };
+
+                // rips often come with several copies of the same cue sheet
+                cue_sources.entry(source_path).or_insert(ScanCue {
+                    cue_path: cue_path.to_string_lossy().to_string(),
+                    tracks: cue_file.tracks,
+                });
+            }
+        }
+
+        log::info!(
+            "scan: found {} files split by cue sheets",
+            cue_sources.len()
+        );
+
         struct ScanItem {
             root: String,
             path: String,
             local_path: String,
+            /// The cue sheet that splits the file into tracks.
+            cue: Option<ScanCue>,
         }
 
         let (local_files, scan_errors): (Vec<_>, Vec<_>) = entries
@@ -341,6 +828,7 @@ impl Library {
                 anyhow::Result::Ok(ScanItem {
                     root: root.name.clone(),
                     path,
+                    cue: cue_sources.remove(&local_path),
                     local_path: local_path.to_string_lossy().to_string(),
                 })
             })
@@ -356,9 +844,13 @@ impl Library {
         struct HashItem {
             hash_kind: &'static str,
             hash: Vec<u8>,
+            /// The metadata fingerprint, see `metadata_fingerprint`.
+            metadata: Option<Vec<u8>>,
             root: String,
             path: String,
             local_path: String,
+            /// The cue sheet path and track, if the item is a track of a cue sheet.
+            cue: Option<(String, CueTrack)>,
         }
 
         // hash items in parallel using rayon
@@ -368,24 +860,107 @@ impl Library {
                 .map(|item| {
                     let local_path = PathBuf::from(&item.local_path);
 
-                    let (hash_kind, hash) = get_file_hash(&local_path)?;
+                    // a panic in a decoder only fails this file
+                    let (hash_kind, hash) = catch_panic(|| get_file_hash(&local_path))
+                        .with_context(|| format!("failed to hash file {}", local_path.display()))?;
+
+                    // the hash only covers the audio, so tag changes are tracked separately
+                    let metadata = catch_panic(|| metadata_fingerprint(&local_path))
+                        .inspect_err(|e| {
+                            log::warn!(
+                                "scan: failed to get metadata fingerprint of {}: {e:#}",
+                                local_path.display()
+                            )
+                        })
+                        .ok();
+
+                    // each track of a cue sheet is its own item with a virtual path
+                    if let Some(cue) = item.cue {
+                        let sample_rate = catch_panic(|| source_sample_rate(&local_path))
+                            .with_context(|| {
+                                format!("failed to get sample rate of {}", local_path.display())
+                            })?;
+
+                        let dir = match item.path.rsplit_once('/') {
+                            Some((dir, _)) => format!("{dir}/"),
+                            None => String::new(),
+                        };
+                        let extension = local_path
+                            .extension()
+                            .map(|extension| extension.to_string_lossy().to_string())
+                            .unwrap_or_default();
+
+                        let items = cue
+                            .tracks
+                            .into_iter()
+                            .map(|track| {
+                                let (hash_kind, hash) = track.hash(hash_kind, &hash, sample_rate);
+                                HashItem {
+                                    hash_kind,
+                                    hash,
+                                    // tracks are also tagged from the cue sheet
+                                    metadata: metadata
+                                        .as_ref()
+                                        .map(|metadata| track.metadata_fingerprint(metadata)),
+                                    root: item.root.clone(),
+                                    path: format!("{dir}{}", track.file_name(&extension)),
+                                    local_path: item.local_path.clone(),
+                                    cue: Some((cue.cue_path.clone(), track)),
+                                }
+                            })
+                            .collect::<Vec<_>>();
+                        return anyhow::Result::Ok(items);
+                    }
 
-                    anyhow::Result::Ok(HashItem {
+                    anyhow::Result::Ok(vec![HashItem {
                         hash_kind,
                         hash,
+                        metadata,
                         root: item.root,
                         path: item.path,
                         local_path: item.local_path,
-                    })
+                        cue: None,
+                    }])
                 })
                 .map(|res| match res {
-                    Ok(item) => Either::Left(item),
+                    Ok(items) => Either::Left(items),
                     Err(e) => Either::Right(e),
                 })
                 .collect::<(Vec<_>, Vec<_>)>()
         })
         .await?;
 
+        // skip tracks whose virtual path is taken by another file
+        let items = {
+            let mut paths = items
+                .iter()
+                .flatten()
+                .filter(|item| item.cue.is_none())
+                .map(|item| (item.root.clone(), item.path.clone()))
+                .collect::<HashSet<_>>();
+
+            items
+                .into_iter()
+                .flatten()
+                .filter(|item| {
+                    item.cue.is_none() || {
+                        let inserted = paths.insert((item.root.clone(), item.path.clone()));
+                        if !inserted {
+                            warn!(
+                                "scan: skipping cue sheet track {} in {}, the path is already taken",
+                                item.path, item.root
+                            );
+                        }
+                        inserted
+                    }
+                })
+                .collect::<Vec<_>>()
+        };
+
+        // count hashes that panicked in the supervisor health
+        let hash_panics = hash_errors.iter().filter(|e| e.is::<JobPanicked>()).count();
+        self.transcode_pool.record_panics(hash_panics as u64);
+
         // extend errors
         errors.extend(hash_errors);
 
@@ -404,6 +979,9 @@ impl Library {
                     path: &item.path,
                     local_tree: "", // local_tree is only used for remote files
                     local_path: &item.local_path,
+                    cue_path: item.cue.as_ref().map(|(cue_path, _)| cue_path.as_str()),
+                    cue_track: item.cue.as_ref().map(|(_, track)| track.number),
+                    revision: 0,
                 }),
             )
             .context("failed to insert files into database")?;
@@ -411,23 +989,71 @@ impl Library {
 
         log::info!("scan: inserted {} files into database", items.len());
 
+        // cancel transcodes for files that were removed
+        {
+            let hashes = items
+                .iter()
+                .map(|item| (item.hash_kind, item.hash.as_slice()))
+                .collect::<HashSet<_>>();
+
+            let transcode_remove_items = prev_local_files
+                .into_iter()
+                .filter(|file| !hashes.contains(&(file.hash_kind.as_str(), file.hash.as_slice())))
+                .map(|file| TranscodeItem {
+                    hash_kind: file.hash_kind,
+                    hash: file.hash,
+                    local_path: PathBuf::from(file.local_path),
+                    content: root_contents.get(&file.root).copied().unwrap_or_default(),
+                    // only the hash is used to remove items
+                    cue: None,
+                })
+                .collect::<Vec<_>>();
+
+            if !transcode_remove_items.is_empty() {
+                log::info!(
+                    "scan: removing {} files from transcode pool",
+                    transcode_remove_items.len()
+                );
+
+                self.transcode_pool
+                    .send(TranscodeCommand::Remove(transcode_remove_items))?;
+            }
+        }
+
         // send local files to transcode pool
         // will be skipped if already transcoded. might be able to make more efficient by only sending new files
-        // TODO: cancel transcodes for files that were removed
         {
-            let transcode_add_items = items
+            let (transcode_add_items, metadata): (Vec<_>, Vec<_>) = items
                 .into_iter()
-                .map(|item| TranscodeItem {
-                    hash_kind: item.hash_kind.to_string(),
-                    hash: item.hash,
-                    local_path: PathBuf::from(item.local_path),
+                .map(|item| {
+                    let transcode_item = TranscodeItem {
+                        hash_kind: item.hash_kind.to_string(),
+                        hash: item.hash,
+                        local_path: PathBuf::from(item.local_path),
+                        content: root_contents.get(&item.root).copied().unwrap_or_default(),
+                        cue: item.cue.map(|(_, track)| track),
+                    };
+                    (transcode_item, item.metadata)
                 })
+                .unzip();
+
+            // re-tag transcodes of files whose tags changed
+            let transcode_check_items = transcode_add_items
+                .iter()
+                .zip(metadata)
+                .filter_map(|(item, metadata)| Some((item.clone(), metadata?)))
                 .collect::<Vec<_>>();
 
             self.transcode_pool
                 .send(TranscodeCommand::Add(transcode_add_items))?;
+            self.transcode_pool
+                .send(TranscodeCommand::CheckMetadata(transcode_check_items))?;
         }
 
+        // delete transcodes of files that were removed
+        self.collect_garbage()
+            .context("failed to collect garbage")?;
+
         // TODO
         // self.notify_state();
 
@@ -436,23 +1062,407 @@ impl Library {
 
     /// Send all local files to the transcode pool to be transcoded if needed.
     fn check_transcodes(&self) -> anyhow::Result<()> {
+        let profile = self.model.lock().unwrap().transcode_profile;
+
+        let (local_files, failures) = {
+            let db = self.db.lock().expect("failed to lock database");
+            let local_files = db
+                .get_files_by_node_id(self.local_node_id)
+                .context("failed to get local files")?;
+            let failures = db
+                .get_transcode_failures_by_node_id(self.local_node_id, &profile.key())
+                .context("failed to get transcode failures")?;
+            (local_files, failures)
+        };
+
+        // files that failed before aren't transcoded again until they're retried
+        let failed_items = failures
+            .into_iter()
+            .map(|failure| (failure.hash_kind, failure.hash, failure.error))
+            .collect::<Vec<_>>();
+        self.transcode_pool
+            .send(TranscodeCommand::AddFailed(failed_items))?;
+
+        let transcode_add_items = self.transcode_items(local_files)?;
+
+        self.transcode_pool
+            .send(TranscodeCommand::Add(transcode_add_items))?;
+
+        Ok(())
+    }
+
+    /// Convert local files to transcode items, with the content type of each
+    /// file's root.
+    ///
+    /// Tracks of cue sheets are looked up in their sheets again, and skipped
+    /// if the sheet changed since the scan.
+    fn transcode_items(&self, files: Vec<File>) -> anyhow::Result<Vec<TranscodeItem>> {
+        let root_contents = {
+            let db = self.db.lock().expect("failed to lock database");
+            db.get_roots_by_node_id(self.local_node_id)
+                .context("failed to get local roots")?
+                .iter()
+                .map(|root| (root.name.clone(), root_content(root)))
+                .collect::<HashMap<_, _>>()
+        };
+
+        let items = files
+            .into_iter()
+            .filter_map(|file| {
+                let local_path = PathBuf::from(file.local_path);
+
+                let cue = match (file.cue_path, file.cue_track) {
+                    (Some(cue_path), Some(cue_track)) => {
+                        match cue::find_track(&PathBuf::from(&cue_path), &local_path, cue_track) {
+                            Ok(track) => Some(track),
+                            Err(e) => {
+                                warn!(
+                                    "Library::transcode_items: failed to find track {cue_track} of cue sheet {cue_path}: {e:#}"
+                                );
+                                return None;
+                            }
+                        }
+                    }
+                    _ => None,
+                };
+
+                Some(TranscodeItem {
+                    hash_kind: file.hash_kind,
+                    hash: file.hash,
+                    local_path,
+                    content: root_contents.get(&file.root).copied().unwrap_or_default(),
+                    cue,
+                })
+            })
+            .collect();
+
+        Ok(items)
+    }
+
+    /// Set the content type of a root, then transcode its files again with
+    /// the settings for that content type.
+    fn set_root_content_type(
+        &self,
+        name: &str,
+        content_type: TranscodeContent,
+    ) -> anyhow::Result<()> {
+        let root_files = {
+            let db = self.db.lock().expect("failed to lock database");
+            db.set_root_content_type(self.local_node_id, name, content_type.key())
+                .context("failed to set root content type")?;
+            db.get_files_by_node_id(self.local_node_id)
+                .context("failed to get local files")?
+                .into_iter()
+                .filter(|file| file.root == name)
+                .collect::<Vec<_>>()
+        };
+
+        // update model
+        self.update_model(LibraryModelUpdate::UpdateLocalRoots);
+
+        log::info!(
+            "setting content type of root {name} to {}, transcoding {} files again",
+            content_type.key(),
+            root_files.len()
+        );
+
+        // delete transcodes made with the old settings, then add the files
+        // with the new content type
+        let keys = root_files
+            .iter()
+            .map(|file| (file.hash_kind.clone(), file.hash.clone()))
+            .collect::<Vec<_>>();
+        self.transcode_pool.send(TranscodeCommand::Reset(keys))?;
+        self.transcode_pool
+            .send(TranscodeCommand::Add(self.transcode_items(root_files)?))?;
+
+        Ok(())
+    }
+
+    /// Delete transcodes of files that aren't local files anymore.
+    fn collect_garbage(&self) -> anyhow::Result<()> {
         let local_files = {
             let db = self.db.lock().expect("failed to lock database");
             db.get_files_by_node_id(self.local_node_id)
                 .context("failed to get local files")?
         };
 
-        let transcode_add_items = local_files
+        let keep = local_files
+            .into_iter()
+            .map(|file| (file.hash_kind, file.hash))
+            .collect::<HashSet<_>>();
+
+        self.transcode_pool
+            .send(TranscodeCommand::CollectGarbage(keep))?;
+
+        // forget failures of files that were removed
+        {
+            let db = self.db.lock().expect("failed to lock database");
+            db.delete_orphaned_transcode_failures(self.local_node_id)
+                .context("failed to delete orphaned transcode failures")?;
+        }
+
+        Ok(())
+    }
+
+    /// Clear the Failed status of files and add them to the transcode pool
+    /// again.
+    ///
+    /// Failures stay in the database until the file is transcoded, so the
+    /// attempt count keeps going up if it fails again.
+    fn retry_failed_transcodes(&self, local_paths: Option<Vec<String>>) -> anyhow::Result<()> {
+        let failures = self.model.lock().unwrap().transcode_failures.clone();
+
+        let local_paths =
+            local_paths.map(|local_paths| local_paths.into_iter().collect::<HashSet<_>>());
+        let failed_paths = failures
             .into_iter()
-            .map(|file| TranscodeItem {
-                hash_kind: file.hash_kind,
-                hash: file.hash,
-                local_path: PathBuf::from(file.local_path),
+            .filter(|failure| {
+                local_paths
+                    .as_ref()
+                    .is_none_or(|local_paths| local_paths.contains(&failure.local_path))
             })
+            .map(|failure| failure.local_path)
+            .collect::<HashSet<_>>();
+
+        // look up the files to get their roots' content types
+        let failed_files = {
+            let db = self.db.lock().expect("failed to lock database");
+            db.get_files_by_node_id(self.local_node_id)
+                .context("failed to get local files")?
+                .into_iter()
+                .filter(|file| failed_paths.contains(&file.local_path))
+                .collect::<Vec<_>>()
+        };
+        let items = self.transcode_items(failed_files)?;
+
+        if items.is_empty() {
+            return Ok(());
+        }
+
+        log::info!("retrying {} failed transcodes", items.len());
+
+        let keys = items
+            .iter()
+            .map(|item| (item.hash_kind.clone(), item.hash.clone()))
             .collect::<Vec<_>>();
 
         self.transcode_pool
-            .send(TranscodeCommand::Add(transcode_add_items))?;
+            .send(TranscodeCommand::ClearFailed(keys.clone()))?;
+        self.transcode_pool.send(TranscodeCommand::Add(items))?;
+
+        // retries are explicit, so transcode them even if the policy is IfRequested
+        self.transcode_pool
+            .send(TranscodeCommand::Prioritize(keys))?;
+
+        Ok(())
+    }
+
+    /// Cancel transcoding the files with the given source paths.
+    ///
+    /// Cancelled files are transcoded again when they're requested.
+    fn cancel_transcodes(&self, local_paths: Vec<String>) -> anyhow::Result<()> {
+        let local_files = {
+            let db = self.db.lock().expect("failed to lock database");
+            db.get_files_by_node_id(self.local_node_id)
+                .context("failed to get local files")?
+        };
+
+        let local_paths = local_paths.into_iter().collect::<HashSet<_>>();
+        let keys = local_files
+            .into_iter()
+            .filter(|file| local_paths.contains(&file.local_path))
+            .map(|file| (file.hash_kind, file.hash))
+            .collect::<Vec<_>>();
+
+        if keys.is_empty() {
+            return Ok(());
+        }
+
+        log::info!("cancelling {} transcodes", keys.len());
+
+        self.transcode_pool.send(TranscodeCommand::Cancel(keys))?;
+
+        Ok(())
+    }
+
+    /// Find the highest bitrate that fits the files with the given source
+    /// paths into a budget in bytes.
+    ///
+    /// If `schedule` is set and a bitrate fits, the profile is switched to
+    /// that bitrate and the files are transcoded before other files. Paths
+    /// that aren't local files are reported as unestimated.
+    async fn plan_transcodes(
+        self: &Arc<Self>,
+        local_paths: Vec<String>,
+        budget: u64,
+        schedule: bool,
+    ) -> anyhow::Result<TranscodePlan> {
+        let profile = self.model.lock().unwrap().transcode_profile;
+
+        let local_files = {
+            let db = self.db.lock().expect("failed to lock database");
+            db.get_files_by_node_id(self.local_node_id)
+                .context("failed to get local files")?
+        };
+
+        let local_paths = local_paths.into_iter().collect::<HashSet<_>>();
+        let files = local_files
+            .into_iter()
+            .filter(|file| local_paths.contains(&file.local_path))
+            .collect::<Vec<_>>();
+        let missing_paths = {
+            let found = files
+                .iter()
+                .map(|file| file.local_path.as_str())
+                .collect::<HashSet<_>>();
+            local_paths
+                .iter()
+                .filter(|local_path| !found.contains(local_path.as_str()))
+                .cloned()
+                .collect::<Vec<_>>()
+        };
+
+        let items = self.transcode_items(files)?;
+
+        let plan_files = items.clone();
+        let mut plan =
+            tokio::task::spawn_blocking(move || plan_bitrate(&plan_files, budget, &profile))
+                .await
+                .context("failed to join planning task")??;
+        plan.unestimated_paths.extend(missing_paths);
+
+        log::info!(
+            "planned bitrate {:?} for {} files, estimated size {} of {budget}",
+            plan.bitrate,
+            items.len(),
+            plan.estimated_size
+        );
+
+        if schedule && let Some(bitrate) = plan.bitrate {
+            let planned_profile = TranscodeProfile { bitrate, ..profile };
+            if planned_profile != profile {
+                self.send(LibraryCommand::SetTranscodeProfile(planned_profile))?;
+            }
+
+            // prioritizing comes after switching profiles, which adds all files again
+            let keys = items
+                .into_iter()
+                .map(|item| (item.hash_kind, item.hash))
+                .collect::<Vec<_>>();
+            self.send(LibraryCommand::PrioritizeTranscodes(keys))?;
+        }
+
+        Ok(plan)
+    }
+
+    /// Updates the gain of albums whose tracks finished transcoding.
+    ///
+    /// Albums are received from the run loop every time one of their tracks
+    /// is transcoded, so adding a track to an album updates the transcodes of
+    /// the rest of the album too. Each album is updated once none of its
+    /// tracks finished for `ALBUM_GAIN_DELAY`, and albums are updated one at
+    /// a time so an older gain can't be written last.
+    async fn run_album_gain(self: Arc<Self>, mut rx: mpsc::UnboundedReceiver<String>) {
+        // when each album with finished tracks is due to be updated
+        let mut pending: HashMap<String, tokio::time::Instant> = HashMap::new();
+
+        loop {
+            let next_deadline = pending.values().min().copied();
+
+            tokio::select! {
+                album = rx.recv() => {
+                    let Some(album) = album else {
+                        break;
+                    };
+                    pending.insert(album, tokio::time::Instant::now() + ALBUM_GAIN_DELAY);
+                }
+
+                _ = tokio::time::sleep_until(next_deadline.unwrap_or_else(tokio::time::Instant::now)), if next_deadline.is_some() => {
+                    let now = tokio::time::Instant::now();
+                    let due = pending
+                        .iter()
+                        .filter(|(_, deadline)| **deadline <= now)
+                        .map(|(album, _)| album.clone())
+                        .collect::<Vec<_>>();
+                    for album in &due {
+                        pending.remove(album);
+                    }
+
+                    for album in due {
+                        if let Err(e) = self.update_album_gain(&album).await {
+                            warn!("Library::run_album_gain: failed to update album gain: {e:#}");
+                        }
+                    }
+                }
+            }
+        }
+    }
+
+    /// Recomputes the gain of an album from the loudness of its transcoded
+    /// tracks and writes it to the album's transcodes.
+    async fn update_album_gain(&self, album: &str) -> anyhow::Result<()> {
+        let tracks = {
+            let db = self.db.lock().unwrap();
+            db.get_album_loudness_by_node_id(self.local_node_id, album)
+                .context("failed to get album loudness")?
+        };
+
+        let album_loudness = loudness::album_loudness(tracks.iter().filter_map(|track| {
+            Some(Loudness {
+                lufs: track.loudness?,
+                blocks: track.gated_blocks,
+            })
+        }));
+        let Some(album_loudness) = album_loudness else {
+            return Ok(());
+        };
+        let album_gain = R128_REFERENCE_LUFS - album_loudness;
+
+        // tracks that aren't transcoded yet will be updated when they are
+        let transcodes = tracks
+            .into_iter()
+            .filter_map(|track| {
+                let path = self
+                    .transcode_pool
+                    .ready_path(&track.hash_kind, &track.hash)?;
+                Some((track.hash_kind, track.hash, path))
+            })
+            .collect::<Vec<_>>();
+
+        let status_cache = self.transcode_pool.status_cache().clone();
+        tokio::task::spawn_blocking(move || {
+            for (hash_kind, hash, path) in transcodes {
+                // don't race with re-tagging or with transfers reading the file
+                let lock = status_cache.rewrite_lock(&hash_kind, &hash);
+                let _guard = lock.blocking_write();
+
+                match tags::write_album_gain(&path, album_gain) {
+                    Ok(true) => {
+                        log::debug!("updated album gain of {}", path.display());
+
+                        // the checksum changed, and clients should download it again
+                        match manifest::refresh_manifest(&path) {
+                            Ok(Some(manifest)) => status_cache.set_retagged(
+                                &hash_kind,
+                                &hash,
+                                manifest.file_size,
+                                manifest.revision,
+                            ),
+                            // transcodes without a manifest are made again on the next startup
+                            Ok(None) => {}
+                            Err(e) => {
+                                warn!("failed to update manifest of {}: {e:#}", path.display())
+                            }
+                        }
+                    }
+                    Ok(false) => {}
+                    Err(e) => warn!("failed to write album gain to {}: {e:#}", path.display()),
+                }
+            }
+        })
+        .await
+        .context("failed to join album gain task")?;
 
         Ok(())
     }
@@ -482,6 +1492,8 @@ impl Library {
                                 .count_files_by_root(self.local_node_id, &root.name)
                                 .expect("failed to count files"); // TODO
 
+                            let content_type = root_content(&root);
+
                             // de-UNC paths on windows (\\?\C:\foo -> C:\foo)
                             let path = PathBuf::from(root.path);
                             let path = dunce::simplified(&path).to_string_lossy().to_string();
@@ -490,6 +1502,7 @@ impl Library {
                                 name: root.name,
                                 path,
                                 num_files: count,
+                                content_type,
                             }
                         })
                         .collect()
@@ -501,9 +1514,45 @@ impl Library {
                 self.event_handler.on_library_model_snapshot(model.clone());
             }
 
+            LibraryModelUpdate::UpdateTranscodeFailures => {
+                let profile = self.model.lock().unwrap().transcode_profile;
+
+                let failures = {
+                    let db = self.db.lock().unwrap();
+                    db.get_transcode_failures_by_node_id(self.local_node_id, &profile.key())
+                };
+
+                // keep the previous list if the failures can't be read
+                let failures = match failures {
+                    Ok(failures) => failures,
+                    Err(e) => {
+                        warn!("UpdateTranscodeFailures: failed to get transcode failures: {e:#}");
+                        return;
+                    }
+                };
+
+                let transcode_failures = failures
+                    .into_iter()
+                    .map(|failure| TranscodeFailureModel {
+                        hash_kind: failure.hash_kind,
+                        hash: failure.hash,
+                        local_path: failure.local_path,
+                        error: failure.error,
+                        attempts: failure.attempts,
+                        failed_at: failure.failed_at,
+                    })
+                    .collect();
+
+                let mut model = self.model.lock().unwrap();
+                model.transcode_failures = transcode_failures;
+
+                self.event_handler.on_library_model_snapshot(model.clone());
+            }
+
             LibraryModelUpdate::UpdateTranscodesDirSize => {
                 let mut model = self.model.lock().unwrap();
                 model.transcodes_dir_size = self.transcode_pool.transcodes_dir_size();
+                model.transcode_cache_size = self.transcode_pool.cache_size();
 
                 self.event_handler.on_library_model_snapshot(model.clone());
             }
@@ -514,10 +1563,60 @@ impl Library {
 
                 self.event_handler.on_library_model_snapshot(model.clone());
             }
+
+            LibraryModelUpdate::SetTranscodeProfile(transcode_profile) => {
+                let mut model = self.model.lock().unwrap();
+                model.transcode_profile = transcode_profile;
+
+                self.event_handler.on_library_model_snapshot(model.clone());
+            }
+
+            LibraryModelUpdate::SetTranscodeCacheSizeLimit(limit) => {
+                let mut model = self.model.lock().unwrap();
+                model.transcode_cache_size_limit = limit;
+
+                self.event_handler.on_library_model_snapshot(model.clone());
+            }
+
+            LibraryModelUpdate::SetTranscodeWorkerCount(count) => {
+                let mut model = self.model.lock().unwrap();
+                model.transcode_worker_count = count;
+
+                self.event_handler.on_library_model_snapshot(model.clone());
+            }
+
+            LibraryModelUpdate::SetTranscodeBackground(enabled) => {
+                let mut model = self.model.lock().unwrap();
+                model.transcode_background = enabled;
+
+                self.event_handler.on_library_model_snapshot(model.clone());
+            }
+
+            LibraryModelUpdate::SetTranscodeVerify(enabled) => {
+                let mut model = self.model.lock().unwrap();
+                model.transcode_verify = enabled;
+
+                self.event_handler.on_library_model_snapshot(model.clone());
+            }
+
+            LibraryModelUpdate::SetTranscodesPaused(paused) => {
+                let mut model = self.model.lock().unwrap();
+                model.transcodes_paused = paused;
+
+                self.event_handler.on_library_model_snapshot(model.clone());
+            }
         }
     }
 }
 
+/// Get the content type of a root, defaulting to music if it's unknown.
+fn root_content(root: &Root) -> TranscodeContent {
+    TranscodeContent::from_key(&root.content_type).unwrap_or_else(|e| {
+        warn!("root {} has an invalid content type: {e:#}", root.name);
+        TranscodeContent::default()
+    })
+}
+
 /// Get the hash of a file.
 ///
 /// If the file contains an MD5 checksum (many flacs do), then it will be used.
@@ -585,3 +1684,20 @@ fn get_file_hash(path: &PathBuf) -> anyhow::Result<(&'static str, Vec<u8>)> {
         Ok(("xxh3", Vec::from(hash)))
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_get_file_hash_corrupt() {
+        for name in ["corrupt.flac", "corrupt.mp3", "corrupt.wav"] {
+            let path = PathBuf::from(env!("CARGO_MANIFEST_DIR"))
+                .join("fixtures")
+                .join(name);
+
+            let res = catch_panic(|| get_file_hash(&path));
+            assert!(res.is_err(), "{name} was hashed");
+        }
+    }
+}
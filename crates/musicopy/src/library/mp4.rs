@@ -0,0 +1,80 @@
+//! Reading atoms of MP4 files.
+//!
+//! Only the parts of the format needed for gapless info and chapters are
+//! read, without going through symphonia, which doesn't expose them.
+
+use anyhow::Context;
+use std::{
+    fs::File,
+    io::{Read, Seek, SeekFrom},
+};
+
+/// Reads the contents of a top level atom, skipping over other atoms without
+/// reading them.
+pub fn find_top_level_atom(
+    file: &mut File,
+    file_len: u64,
+    kind: &[u8; 4],
+) -> anyhow::Result<Option<Vec<u8>>> {
+    let mut pos = 0;
+    while pos + 8 <= file_len {
+        file.seek(SeekFrom::Start(pos))
+            .context("failed to seek to atom")?;
+
+        let mut header = [0; 8];
+        file.read_exact(&mut header)
+            .context("failed to read atom header")?;
+        let mut size = u32::from_be_bytes(header[..4].try_into().unwrap()) as u64;
+        let mut header_len = 8;
+
+        match size {
+            // atom extends to the end of the file
+            0 => size = file_len - pos,
+            // 64-bit size follows the header
+            1 => {
+                let mut large_size = [0; 8];
+                file.read_exact(&mut large_size)
+                    .context("failed to read atom size")?;
+                size = u64::from_be_bytes(large_size);
+                header_len = 16;
+            }
+            _ => {}
+        }
+
+        if size < header_len {
+            anyhow::bail!("invalid atom size");
+        }
+
+        if &header[4..] == kind {
+            let mut data = Vec::new();
+            file.take(size - header_len)
+                .read_to_end(&mut data)
+                .context("failed to read atom")?;
+            return Ok(Some(data));
+        }
+
+        pos += size;
+    }
+
+    Ok(None)
+}
+
+/// Iterates over the atoms in a buffer, yielding their kind and contents.
+pub fn atoms(mut data: &[u8]) -> impl Iterator<Item = (&[u8], &[u8])> {
+    std::iter::from_fn(move || {
+        let size = u32::from_be_bytes(data.get(..4)?.try_into().unwrap()) as usize;
+        if size < 8 || size > data.len() {
+            return None;
+        }
+
+        let (atom, rest) = data.split_at(size);
+        data = rest;
+
+        Some((&atom[4..8], &atom[8..]))
+    })
+}
+
+/// Returns the contents of the first atom of a kind in a buffer.
+pub fn find_atom<'a>(data: &'a [u8], kind: &[u8; 4]) -> Option<&'a [u8]> {
+    atoms(data).find(|(k, _)| *k == kind).map(|(_, data)| data)
+}
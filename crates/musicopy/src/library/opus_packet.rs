@@ -0,0 +1,317 @@
+//! Opus packet framing.
+//!
+//! Multistream Opus packets hold one packet for each stream, and all but the
+//! last use self-delimited framing so that they can be concatenated. The
+//! encoder writes them by reframing the packets of each stream's encoder, and
+//! the verifier splits them again to decode each stream.
+//!
+//! See RFC 6716 section 3.2 and appendix B.
+
+use anyhow::Context;
+
+/// Splits an Opus packet into its TOC byte and frames, ignoring padding.
+///
+/// See RFC 6716 section 3.2.
+fn parse_opus_packet(packet: &[u8]) -> anyhow::Result<(u8, Vec<&[u8]>)> {
+    let (&toc, data) = packet.split_first().context("empty opus packet")?;
+
+    let frames = match toc & 0x03 {
+        // one frame
+        0 => vec![data],
+
+        // two frames with equal size
+        1 => {
+            anyhow::ensure!(data.len() % 2 == 0, "invalid opus packet frame sizes");
+            let (first, second) = data.split_at(data.len() / 2);
+            vec![first, second]
+        }
+
+        // two frames with different sizes
+        2 => {
+            let (first_len, n) = read_opus_frame_length(data)?;
+            let data = &data[n..];
+            anyhow::ensure!(first_len <= data.len(), "invalid opus packet frame sizes");
+            let (first, second) = data.split_at(first_len);
+            vec![first, second]
+        }
+
+        // arbitrary number of frames
+        _ => {
+            let (&count_byte, mut data) = data.split_first().context("missing frame count")?;
+            let vbr = count_byte & 0x80 != 0;
+            let has_padding = count_byte & 0x40 != 0;
+            let count = (count_byte & 0x3f) as usize;
+            anyhow::ensure!(count > 0, "invalid opus packet frame count");
+
+            // padding length is stored in bytes of up to 254, with 255
+            // meaning 254 and another byte follows
+            let mut padding = 0;
+            if has_padding {
+                loop {
+                    let (&byte, rest) = data.split_first().context("missing padding length")?;
+                    data = rest;
+                    if byte == 255 {
+                        padding += 254;
+                    } else {
+                        padding += byte as usize;
+                        break;
+                    }
+                }
+            }
+            anyhow::ensure!(padding <= data.len(), "invalid opus packet padding");
+            let mut data = &data[..(data.len() - padding)];
+
+            let mut frames = Vec::with_capacity(count);
+            if vbr {
+                // the last frame's length is implied by the remaining data
+                let mut lengths = Vec::with_capacity(count - 1);
+                for _ in 0..(count - 1) {
+                    let (len, n) = read_opus_frame_length(data)?;
+                    data = &data[n..];
+                    lengths.push(len);
+                }
+
+                for len in lengths {
+                    anyhow::ensure!(len <= data.len(), "invalid opus packet frame sizes");
+                    let (frame, rest) = data.split_at(len);
+                    frames.push(frame);
+                    data = rest;
+                }
+                frames.push(data);
+            } else {
+                anyhow::ensure!(data.len() % count == 0, "invalid opus packet frame sizes");
+                let len = data.len() / count;
+                for i in 0..count {
+                    frames.push(&data[(i * len)..((i + 1) * len)]);
+                }
+            }
+
+            frames
+        }
+    };
+
+    Ok((toc, frames))
+}
+
+/// Reads an Opus frame length, returning the length and the number of bytes
+/// read.
+fn read_opus_frame_length(data: &[u8]) -> anyhow::Result<(usize, usize)> {
+    match data {
+        [first, ..] if *first < 252 => Ok((*first as usize, 1)),
+        [first, second, ..] => Ok((*second as usize * 4 + *first as usize, 2)),
+        _ => anyhow::bail!("missing opus frame length"),
+    }
+}
+
+/// Writes an Opus frame length.
+fn write_opus_frame_length(len: usize, out: &mut Vec<u8>) {
+    if len < 252 {
+        out.push(len as u8);
+    } else {
+        let first = 252 + (len & 0x03);
+        out.push(first as u8);
+        out.push(((len - first) / 4) as u8);
+    }
+}
+
+/// Writes an Opus packet using self-delimited framing, which adds the length
+/// of the last frame so that packets can be concatenated.
+///
+/// See RFC 6716 appendix B.
+pub fn write_self_delimited_packet(packet: &[u8], out: &mut Vec<u8>) -> anyhow::Result<()> {
+    let (toc, frames) = parse_opus_packet(packet)?;
+    let config = toc & !0x03;
+
+    match frames.as_slice() {
+        [_] => out.push(config),
+        [first, second] if first.len() == second.len() => out.push(config | 1),
+        [first, _] => {
+            out.push(config | 2);
+            write_opus_frame_length(first.len(), out);
+        }
+        _ => {
+            let vbr = frames.iter().any(|frame| frame.len() != frames[0].len());
+
+            out.push(config | 3);
+            out.push(frames.len() as u8 | if vbr { 0x80 } else { 0 });
+            if vbr {
+                for frame in &frames[..(frames.len() - 1)] {
+                    write_opus_frame_length(frame.len(), out);
+                }
+            }
+        }
+    }
+
+    // the self-delimiting length is the length of the last frame
+    let last_frame = frames.last().expect("packet should have frames");
+    write_opus_frame_length(last_frame.len(), out);
+
+    for frame in frames {
+        out.extend(frame);
+    }
+
+    Ok(())
+}
+
+/// Reads an Opus packet with self-delimited framing from the start of `data`,
+/// returning the packet with normal framing and the rest of the data.
+///
+/// This is the inverse of `write_self_delimited_packet`.
+pub fn read_self_delimited_packet(data: &[u8]) -> anyhow::Result<(Vec<u8>, &[u8])> {
+    let (&toc, mut data) = data.split_first().context("empty opus packet")?;
+
+    let mut packet = Vec::new();
+    let frame_lens = match toc & 0x03 {
+        0 => {
+            let (len, n) = read_opus_frame_length(data)?;
+            data = &data[n..];
+            packet.push(toc);
+            vec![len]
+        }
+        1 => {
+            let (len, n) = read_opus_frame_length(data)?;
+            data = &data[n..];
+            packet.push(toc);
+            vec![len, len]
+        }
+        2 => {
+            let (first_len, n) = read_opus_frame_length(data)?;
+            data = &data[n..];
+            let (second_len, n) = read_opus_frame_length(data)?;
+            data = &data[n..];
+            packet.push(toc);
+            write_opus_frame_length(first_len, &mut packet);
+            vec![first_len, second_len]
+        }
+        _ => {
+            let (&count_byte, rest) = data.split_first().context("missing frame count")?;
+            data = rest;
+            let vbr = count_byte & 0x80 != 0;
+            let has_padding = count_byte & 0x40 != 0;
+            let count = (count_byte & 0x3f) as usize;
+            anyhow::ensure!(count > 0, "invalid opus packet frame count");
+
+            let mut padding = 0;
+            if has_padding {
+                loop {
+                    let (&byte, rest) = data.split_first().context("missing padding length")?;
+                    data = rest;
+                    if byte == 255 {
+                        padding += 254;
+                    } else {
+                        padding += byte as usize;
+                        break;
+                    }
+                }
+            }
+
+            // the padding is dropped, so the packet is written without it
+            packet.push(toc);
+            packet.push(count_byte & !0x40);
+
+            let mut lens = Vec::with_capacity(count);
+            if vbr {
+                for _ in 0..(count - 1) {
+                    let (len, n) = read_opus_frame_length(data)?;
+                    data = &data[n..];
+                    write_opus_frame_length(len, &mut packet);
+                    lens.push(len);
+                }
+            }
+
+            // the self-delimiting length is the length of the last frame, or
+            // of every frame without vbr
+            let (len, n) = read_opus_frame_length(data)?;
+            data = &data[n..];
+            lens.resize(count, len);
+
+            let frames_len = lens.iter().sum::<usize>();
+            anyhow::ensure!(
+                frames_len + padding <= data.len(),
+                "invalid opus packet frame sizes"
+            );
+            packet.extend(&data[..frames_len]);
+            return Ok((packet, &data[(frames_len + padding)..]));
+        }
+    };
+
+    let frames_len = frame_lens.iter().sum::<usize>();
+    anyhow::ensure!(frames_len <= data.len(), "invalid opus packet frame sizes");
+    packet.extend(&data[..frames_len]);
+
+    Ok((packet, &data[frames_len..]))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_opus_frame_length() {
+        for len in [0, 1, 251, 252, 300, 1275] {
+            let mut buf = Vec::new();
+            write_opus_frame_length(len, &mut buf);
+            assert_eq!(read_opus_frame_length(&buf).unwrap(), (len, buf.len()));
+        }
+    }
+
+    #[test]
+    fn test_self_delimited_packet() {
+        let mut out = Vec::new();
+
+        // code 0
+        write_self_delimited_packet(&[0x78, 1, 2, 3], &mut out).unwrap();
+        assert_eq!(out, vec![0x78, 3, 1, 2, 3]);
+
+        // code 1
+        out.clear();
+        write_self_delimited_packet(&[0x79, 1, 2, 3, 4], &mut out).unwrap();
+        assert_eq!(out, vec![0x79, 2, 1, 2, 3, 4]);
+
+        // code 2
+        out.clear();
+        write_self_delimited_packet(&[0x7a, 1, 9, 7, 8], &mut out).unwrap();
+        assert_eq!(out, vec![0x7a, 1, 2, 9, 7, 8]);
+
+        // code 3, cbr with padding
+        out.clear();
+        write_self_delimited_packet(&[0x7b, 0x43, 2, 1, 2, 3, 0, 0], &mut out).unwrap();
+        assert_eq!(out, vec![0x7b, 0x03, 1, 1, 2, 3]);
+
+        // code 3, vbr
+        out.clear();
+        write_self_delimited_packet(&[0x7b, 0x83, 1, 2, 1, 2, 3, 4, 5, 6], &mut out).unwrap();
+        assert_eq!(out, vec![0x7b, 0x83, 1, 2, 3, 1, 2, 3, 4, 5, 6]);
+
+        // truncated
+        assert!(write_self_delimited_packet(&[0x7a, 5, 1], &mut out).is_err());
+    }
+
+    #[test]
+    fn test_read_self_delimited_packet() {
+        for packet in [
+            &[0x78, 1, 2, 3][..],
+            &[0x79, 1, 2, 3, 4],
+            &[0x7a, 1, 9, 7, 8],
+            &[0x7b, 0x03, 1, 2, 3],
+            &[0x7b, 0x83, 1, 2, 1, 2, 3, 4, 5, 6],
+        ] {
+            let mut data = Vec::new();
+            write_self_delimited_packet(packet, &mut data).unwrap();
+            data.extend([0xaa, 0xbb]);
+
+            let (read, rest) = read_self_delimited_packet(&data).unwrap();
+            assert_eq!(read, packet);
+            assert_eq!(rest, [0xaa, 0xbb]);
+        }
+
+        // padding is dropped
+        let (read, rest) =
+            read_self_delimited_packet(&[0x7b, 0x43, 2, 1, 1, 2, 3, 0, 0, 0xaa]).unwrap();
+        assert_eq!(read, [0x7b, 0x03, 1, 2, 3]);
+        assert_eq!(rest, [0xaa]);
+
+        // truncated
+        assert!(read_self_delimited_packet(&[0x78, 5, 1]).is_err());
+    }
+}
@@ -0,0 +1,805 @@
+//! The queue of files waiting to be transcoded.
+//!
+//! Workers take jobs from the queue in priority order. With the IfRequested
+//! policy, only files that were requested are taken. Jobs taken by workers
+//! can be paused or cancelled through their `TranscodeInterrupt`.
+
+use crate::library::transcode::{HashKey, TranscodeCancelled, TranscodeItem, TranscodePolicy};
+use priority_queue::PriorityQueue;
+use std::{
+    collections::{HashMap, HashSet},
+    sync::{
+        Arc, Condvar, Mutex,
+        atomic::{AtomicBool, AtomicU64, Ordering},
+    },
+};
+
+/// The queue of items to be transcoded.
+#[derive(Debug)]
+pub struct TranscodeQueue {
+    policy: Mutex<TranscodePolicy>,
+    /// Workers with an index at or above the limit wait instead of taking
+    /// jobs.
+    worker_limit: Mutex<usize>,
+    /// Workers don't take jobs and running transcodes wait while paused.
+    paused: AtomicBool,
+    /// Cancellation flags of the jobs taken by workers.
+    running: Mutex<HashMap<(String, Vec<u8>), Arc<AtomicBool>>>,
+    queue: Mutex<PriorityQueue<TranscodeItem, u64>>,
+    ready: Condvar,
+    ready_counter: Arc<AtomicU64>,
+}
+
+impl TranscodeQueue {
+    /// Creates a new TranscodeQueue.
+    pub fn new(policy: TranscodePolicy) -> Self {
+        TranscodeQueue {
+            policy: Mutex::new(policy),
+            worker_limit: Mutex::new(usize::MAX),
+            paused: AtomicBool::new(false),
+            running: Mutex::new(HashMap::new()),
+            queue: Mutex::new(PriorityQueue::new()),
+            ready: Condvar::new(),
+            ready_counter: Arc::new(AtomicU64::new(0)),
+        }
+    }
+
+    /// Sets the number of workers that can take jobs.
+    ///
+    /// Workers over the limit finish their current job and then wait until
+    /// the limit is raised.
+    pub fn set_worker_limit(&self, limit: usize) {
+        {
+            // hold the queue lock so waiting workers can't miss the update
+            let _queue = self.queue.lock().unwrap();
+
+            let mut worker_limit = self.worker_limit.lock().unwrap();
+            *worker_limit = limit;
+        }
+
+        // notify waiting consumers
+        self.ready.notify_all();
+    }
+
+    /// Pauses or resumes transcoding.
+    ///
+    /// While paused, workers don't take jobs, and running transcodes wait at
+    /// the next packet until resumed.
+    pub fn set_paused(&self, paused: bool) {
+        {
+            // hold the queue lock so waiting workers can't miss the update
+            let _queue = self.queue.lock().unwrap();
+
+            self.paused.store(paused, Ordering::Relaxed);
+        }
+
+        // notify waiting consumers
+        self.ready.notify_all();
+    }
+
+    /// Cancels the running transcodes of some files.
+    ///
+    /// The transcodes stop at the next packet. Files that aren't being
+    /// transcoded are ignored.
+    pub fn cancel<'a>(&self, hashes: impl Iterator<Item = (&'a str, &'a [u8])>) {
+        {
+            // hold the queue lock so paused transcodes can't miss the update
+            let _queue = self.queue.lock().unwrap();
+
+            let running = self.running.lock().unwrap();
+            for key in hashes {
+                if let Some(cancelled) = running.get(&key as &dyn HashKey) {
+                    cancelled.store(true, Ordering::Relaxed);
+                }
+            }
+        }
+
+        // notify paused transcodes
+        self.ready.notify_all();
+    }
+
+    /// Returns the interrupt of a job taken with `wait`.
+    pub fn interrupt(self: &Arc<Self>, item: &TranscodeItem) -> TranscodeInterrupt {
+        let cancelled = self
+            .running
+            .lock()
+            .unwrap()
+            .get(&(item.hash_kind.as_str(), item.hash.as_slice()) as &dyn HashKey)
+            .cloned()
+            .unwrap_or_default();
+
+        TranscodeInterrupt {
+            queue: Some(self.clone()),
+            cancelled,
+        }
+    }
+
+    /// Marks a job taken with `wait` as finished.
+    pub fn finish(&self, item: &TranscodeItem) {
+        self.running
+            .lock()
+            .unwrap()
+            .remove(&(item.hash_kind.as_str(), item.hash.as_slice()) as &dyn HashKey);
+    }
+
+    /// Waits while the queue is paused, unless the job is cancelled.
+    fn wait_resumed(&self, cancelled: &AtomicBool) {
+        if !self.paused.load(Ordering::Relaxed) {
+            return;
+        }
+
+        let mut queue = self.queue.lock().unwrap();
+        while self.paused.load(Ordering::Relaxed) && !cancelled.load(Ordering::Relaxed) {
+            queue = self.ready.wait(queue).unwrap();
+        }
+    }
+
+    /// Sets the transcoding policy.
+    pub fn set_policy(&self, policy: TranscodePolicy) {
+        // update policy
+        {
+            let mut policy_guard = self.policy.lock().unwrap();
+            *policy_guard = policy;
+        }
+
+        {
+            let queue = self.queue.lock().unwrap();
+
+            // update ready counter by re-counting queue
+            let ready_count = match policy {
+                TranscodePolicy::IfRequested => queue.iter().filter(|entry| *entry.1 > 0).count(),
+                TranscodePolicy::Always => queue.len(),
+            };
+            self.ready_counter
+                .store(ready_count as u64, Ordering::Relaxed);
+        }
+
+        // notify waiting consumers
+        self.ready.notify_all();
+    }
+
+    /// Adds items to the queue.
+    pub fn extend(&self, items: Vec<TranscodeItem>) {
+        // read policy before locking queue
+        let policy = {
+            let policy = self.policy.lock().unwrap();
+            *policy
+        };
+
+        {
+            // extend queue
+            let mut queue = self.queue.lock().unwrap();
+            queue.extend(items.into_iter().map(|item| (item, 0)));
+
+            // update ready counter by re-counting queue
+            let ready_count = match policy {
+                TranscodePolicy::IfRequested => queue.iter().filter(|entry| *entry.1 > 0).count(),
+                TranscodePolicy::Always => queue.len(),
+            };
+            self.ready_counter
+                .store(ready_count as u64, Ordering::Relaxed);
+        }
+
+        // notify waiting consumers
+        self.ready.notify_all();
+    }
+
+    /// Increases the priority of items in the queue.
+    pub fn prioritize<'a>(&self, hashes: impl Iterator<Item = (&'a str, &'a [u8])>) {
+        // read policy before locking queue
+        let policy = {
+            let policy = self.policy.lock().unwrap();
+            *policy
+        };
+
+        let hashes: HashSet<(&str, &[u8])> = HashSet::from_iter(hashes);
+
+        {
+            let mut queue = self.queue.lock().unwrap();
+
+            // increase priority
+            for (item, priority) in queue.iter_mut() {
+                if hashes.contains(&(item.hash_kind.as_str(), item.hash.as_slice())) {
+                    *priority += 1;
+                }
+            }
+
+            // update ready counter by re-counting queue
+            let ready_count = match policy {
+                TranscodePolicy::IfRequested => queue.iter().filter(|entry| *entry.1 > 0).count(),
+                TranscodePolicy::Always => queue.len(),
+            };
+            self.ready_counter
+                .store(ready_count as u64, Ordering::Relaxed);
+        }
+
+        // notify waiting consumers
+        self.ready.notify_all();
+    }
+
+    /// Removes items from the queue.
+    pub fn remove<'a>(&self, hashes: impl Iterator<Item = (&'a str, &'a [u8])>) {
+        // read policy before locking queue
+        let policy = {
+            let policy = self.policy.lock().unwrap();
+            *policy
+        };
+
+        let hashes: HashSet<(&str, &[u8])> = HashSet::from_iter(hashes);
+
+        {
+            // remove items from queue
+            let mut queue = self.queue.lock().unwrap();
+            queue.retain(|item, _priority| {
+                !hashes.contains(&(item.hash_kind.as_str(), item.hash.as_slice()))
+            });
+
+            // update ready counter by re-counting queue
+            let ready_count = match policy {
+                TranscodePolicy::IfRequested => queue.iter().filter(|entry| *entry.1 > 0).count(),
+                TranscodePolicy::Always => queue.len(),
+            };
+            self.ready_counter
+                .store(ready_count as u64, Ordering::Relaxed);
+        }
+    }
+
+    /// Returns the number of items that workers can take.
+    pub fn ready_counter(&self) -> &Arc<AtomicU64> {
+        &self.ready_counter
+    }
+
+    /// Returns the number of jobs taken by workers that aren't finished.
+    #[cfg(test)]
+    pub fn running_count(&self) -> usize {
+        self.running.lock().unwrap().len()
+    }
+
+    /// Removes all items from the queue.
+    pub fn clear(&self) {
+        let mut queue = self.queue.lock().unwrap();
+        queue.clear();
+
+        self.ready_counter.store(0, Ordering::Relaxed);
+    }
+
+    /// Waits for a job and takes it from the queue, see `wait_unless`.
+    #[cfg(test)]
+    pub fn wait(&self, worker: usize) -> TranscodeItem {
+        self.wait_unless(worker, || false)
+            .expect("wait without a stop condition should return a job")
+    }
+
+    /// Waits for a job and takes it from the queue, or returns `None` once
+    /// `stop` returns true.
+    ///
+    /// `worker` is the index of the calling worker, which only takes jobs
+    /// while it's under the worker limit and the queue isn't paused. The job
+    /// can be cancelled until it's marked as finished with `finish`.
+    ///
+    /// `stop` is checked whenever waiting workers are notified, so changes to
+    /// it should be followed by a notification like `set_worker_limit`.
+    pub fn wait_unless(&self, worker: usize, stop: impl Fn() -> bool) -> Option<TranscodeItem> {
+        let mut queue = self.queue.lock().unwrap();
+        loop {
+            if stop() {
+                return None;
+            }
+
+            // wait while paused or over the worker limit
+            if self.paused.load(Ordering::Relaxed) || worker >= *self.worker_limit.lock().unwrap() {
+                queue = self.ready.wait(queue).unwrap();
+                continue;
+            }
+
+            // check for a job
+            let next = queue.pop_if(|_item, priority| {
+                let policy = self.policy.lock().unwrap();
+                match *policy {
+                    TranscodePolicy::IfRequested => *priority > 0,
+                    TranscodePolicy::Always => true,
+                }
+            });
+
+            match next {
+                Some((item, _priority)) => {
+                    // decrease ready counter
+                    self.ready_counter.fetch_sub(1, Ordering::Relaxed);
+
+                    // register the job while holding the queue lock, so it
+                    // can't be cancelled before it's registered
+                    self.running.lock().unwrap().insert(
+                        (item.hash_kind.clone(), item.hash.clone()),
+                        Arc::new(AtomicBool::new(false)),
+                    );
+
+                    return Some(item);
+                }
+                None => {
+                    // no job, wait for notification
+                    queue = self.ready.wait(queue).unwrap();
+                }
+            }
+        }
+    }
+}
+
+/// Lets a running transcode be paused or cancelled.
+///
+/// Transcodes check it between packets, so they stop at a chunk boundary.
+#[derive(Debug, Default)]
+pub struct TranscodeInterrupt {
+    queue: Option<Arc<TranscodeQueue>>,
+    cancelled: Arc<AtomicBool>,
+}
+
+impl TranscodeInterrupt {
+    /// Waits while the queue is paused, and returns `TranscodeCancelled` if
+    /// the job was cancelled.
+    pub fn check(&self) -> anyhow::Result<()> {
+        if let Some(queue) = &self.queue {
+            queue.wait_resumed(&self.cancelled);
+        }
+
+        if self.is_cancelled() {
+            return Err(TranscodeCancelled.into());
+        }
+
+        Ok(())
+    }
+
+    /// Returns whether the job was cancelled.
+    pub fn is_cancelled(&self) -> bool {
+        self.cancelled.load(Ordering::Relaxed)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::library::transcode::TranscodeContent;
+    use std::{path::PathBuf, time::Duration};
+
+    fn test_item(hash: u8) -> TranscodeItem {
+        TranscodeItem {
+            hash_kind: "test".to_string(),
+            hash: vec![hash],
+            local_path: PathBuf::from("test.ogg"),
+            content: TranscodeContent::Music,
+            cue: None,
+        }
+    }
+
+    fn join_timeout<T>(timeout: std::time::Duration, thread: std::thread::JoinHandle<T>) -> T {
+        let now = std::time::Instant::now();
+
+        while now.elapsed() < timeout {
+            if thread.is_finished() {
+                return thread.join().unwrap();
+            }
+            std::thread::sleep(std::time::Duration::from_millis(10));
+        }
+
+        panic!("thread timed out");
+    }
+
+    /// Asserts that a condition is true for a given duration.
+    fn assert_duration(timeout: std::time::Duration, condition: impl Fn() -> bool) {
+        let now = std::time::Instant::now();
+
+        while now.elapsed() < timeout {
+            if !condition() {
+                panic!("condition failed before timeout");
+            }
+            std::thread::sleep(std::time::Duration::from_millis(10));
+        }
+    }
+
+    #[test]
+    fn test_assert_duration_success() {
+        let flag = Arc::new(AtomicBool::new(true));
+
+        // set flag to false after 200ms
+        std::thread::spawn({
+            let flag = flag.clone();
+            move || {
+                std::thread::sleep(std::time::Duration::from_millis(200));
+                flag.store(false, Ordering::SeqCst);
+            }
+        });
+
+        // should remain true for 100ms
+        assert_duration(std::time::Duration::from_millis(100), || {
+            flag.load(Ordering::SeqCst)
+        });
+    }
+
+    #[test]
+    #[should_panic]
+    fn test_assert_duration_panic() {
+        let flag = Arc::new(AtomicBool::new(true));
+
+        // set flag to false after 50ms
+        std::thread::spawn({
+            let flag = flag.clone();
+            move || {
+                std::thread::sleep(std::time::Duration::from_millis(50));
+                flag.store(false, Ordering::SeqCst);
+            }
+        });
+
+        // should become false before 100ms and panic
+        assert_duration(std::time::Duration::from_millis(100), || {
+            flag.load(Ordering::SeqCst)
+        });
+    }
+
+    #[test]
+    fn test_queue_wait_after() {
+        let queue = Arc::new(TranscodeQueue::new(TranscodePolicy::Always));
+
+        // add to queue
+        let item_1 = test_item(0x01);
+        let item_2 = test_item(0x02);
+        queue.extend(vec![item_1, item_2]);
+
+        std::thread::sleep(std::time::Duration::from_millis(100));
+
+        // wait after adding item
+        let thread = std::thread::spawn(move || {
+            let item = queue.wait(0);
+            assert_eq!(item.hash, [0x01]);
+            let item = queue.wait(0);
+            assert_eq!(item.hash, [0x02]);
+        });
+
+        join_timeout(std::time::Duration::from_secs(1), thread);
+    }
+
+    #[test]
+    fn test_queue_wait_before() {
+        let queue = Arc::new(TranscodeQueue::new(TranscodePolicy::Always));
+
+        // wait before before item
+        let thread = std::thread::spawn({
+            let queue = queue.clone();
+            move || {
+                let item = queue.wait(0);
+                assert_eq!(item.hash, vec![0x01]);
+                let item = queue.wait(0);
+                assert_eq!(item.hash, vec![0x02]);
+            }
+        });
+
+        std::thread::sleep(std::time::Duration::from_millis(100));
+
+        // add to queue
+        let item_1 = test_item(0x01);
+        let item_2 = test_item(0x02);
+        queue.extend(vec![item_1, item_2]);
+
+        join_timeout(std::time::Duration::from_secs(1), thread);
+    }
+
+    #[test]
+    fn test_queue_wait_unless() {
+        let queue = Arc::new(TranscodeQueue::new(TranscodePolicy::Always));
+        queue.set_worker_limit(1);
+        let stop = Arc::new(AtomicBool::new(false));
+
+        // a worker over the limit waits until it's told to stop
+        let thread = std::thread::spawn({
+            let queue = queue.clone();
+            let stop = stop.clone();
+            move || queue.wait_unless(1, || stop.load(Ordering::SeqCst))
+        });
+
+        std::thread::sleep(std::time::Duration::from_millis(100));
+        assert!(!thread.is_finished());
+
+        stop.store(true, Ordering::SeqCst);
+        queue.set_worker_limit(1);
+        assert!(join_timeout(std::time::Duration::from_secs(1), thread).is_none());
+
+        // stopping takes precedence over queued jobs
+        queue.extend(vec![test_item(0x01)]);
+        assert!(queue.wait_unless(0, || true).is_none());
+        assert_eq!(queue.wait(0).hash, [0x01]);
+    }
+
+    #[test]
+    fn test_queue_wait_parallel() {
+        let queue = Arc::new(TranscodeQueue::new(TranscodePolicy::Always));
+
+        // spawn consumer threads
+        let thread_1 = std::thread::spawn({
+            let queue = queue.clone();
+            move || {
+                queue.wait(0);
+            }
+        });
+        let thread_2 = std::thread::spawn({
+            let queue = queue.clone();
+            move || {
+                queue.wait(1);
+            }
+        });
+
+        std::thread::sleep(std::time::Duration::from_millis(100));
+
+        // add to queue
+        let item_1 = test_item(0x01);
+        let item_2 = test_item(0x02);
+        queue.extend(vec![item_1, item_2]);
+
+        join_timeout(std::time::Duration::from_secs(1), thread_1);
+        join_timeout(std::time::Duration::from_secs(1), thread_2);
+    }
+
+    #[test]
+    fn test_queue_worker_limit() {
+        let queue = Arc::new(TranscodeQueue::new(TranscodePolicy::Always));
+        queue.set_worker_limit(1);
+
+        // spawn consumer over the limit
+        let thread = std::thread::spawn({
+            let queue = queue.clone();
+            move || queue.wait(1)
+        });
+
+        // add to queue
+        let item_1 = test_item(0x01);
+        queue.extend(vec![item_1.clone()]);
+
+        // consumer over the limit should still be waiting
+        std::thread::sleep(std::time::Duration::from_millis(100));
+        assert!(!thread.is_finished());
+
+        // raise limit
+        queue.set_worker_limit(2);
+
+        let job = join_timeout(std::time::Duration::from_secs(1), thread);
+        assert_eq!(job, item_1);
+    }
+
+    #[test]
+    fn test_queue_remove() {
+        let queue = Arc::new(TranscodeQueue::new(TranscodePolicy::Always));
+
+        // add to queue
+        let item_1 = test_item(0x01);
+        let item_2 = test_item(0x02);
+        let item_3 = test_item(0x03);
+        queue.extend(vec![item_1.clone(), item_2.clone(), item_3.clone()]);
+
+        // wait for next
+        let item = queue.wait(0);
+        assert_eq!(item.hash, vec![0x01]);
+
+        // remove #2 from queue
+        queue.remove(
+            [item_2]
+                .iter()
+                .map(|i| (i.hash_kind.as_ref(), i.hash.as_slice())),
+        );
+
+        // wait for next
+        let item = queue.wait(0);
+        assert_eq!(item.hash, vec![0x03]);
+    }
+
+    #[test]
+    fn test_queue_pause() {
+        let queue = Arc::new(TranscodeQueue::new(TranscodePolicy::Always));
+        queue.set_paused(true);
+
+        // add to queue
+        queue.extend(vec![test_item(0x01)]);
+
+        // spawn consumer thread
+        let thread_1 = std::thread::spawn({
+            let queue = queue.clone();
+            move || queue.wait(0)
+        });
+
+        // should wait while paused
+        assert_duration(Duration::from_millis(100), || !thread_1.is_finished());
+
+        // should receive #1 after resuming
+        queue.set_paused(false);
+        let item = thread_1.join().unwrap();
+        assert_eq!(item.hash, vec![0x01]);
+    }
+
+    #[test]
+    fn test_queue_cancel() {
+        let queue = Arc::new(TranscodeQueue::new(TranscodePolicy::Always));
+
+        // take a job and pause it
+        queue.extend(vec![test_item(0x01)]);
+        let item = queue.wait(0);
+        let interrupt = queue.interrupt(&item);
+        assert!(interrupt.check().is_ok());
+        queue.set_paused(true);
+
+        // spawn a thread for the paused transcode
+        let thread_1 = std::thread::spawn(move || interrupt.check());
+
+        // should wait while paused
+        assert_duration(Duration::from_millis(100), || !thread_1.is_finished());
+
+        // should stop when cancelled, even while paused
+        queue.cancel(
+            [&item]
+                .iter()
+                .map(|i| (i.hash_kind.as_ref(), i.hash.as_slice())),
+        );
+        let res = thread_1.join().unwrap();
+        assert!(res.unwrap_err().is::<TranscodeCancelled>());
+    }
+
+    #[test]
+    fn test_queue_if_requested() {
+        let queue = Arc::new(TranscodeQueue::new(TranscodePolicy::IfRequested));
+
+        // add to queue
+        let item_1 = test_item(0x01);
+        let item_2 = test_item(0x02);
+        let item_3 = test_item(0x03);
+        queue.extend(vec![item_1.clone(), item_2.clone(), item_3.clone()]);
+
+        // spawn consumer thread
+        let thread_1 = std::thread::spawn({
+            let queue = queue.clone();
+            move || queue.wait(0)
+        });
+
+        // should wait and not receive item
+        assert_duration(Duration::from_millis(100), || !thread_1.is_finished());
+
+        // request #2
+        queue.prioritize(
+            [item_2]
+                .iter()
+                .map(|i| (i.hash_kind.as_ref(), i.hash.as_slice())),
+        );
+
+        // should receive #2
+        let item = thread_1.join().unwrap();
+        assert_eq!(item.hash, vec![0x02]);
+
+        // spawn another consumer thread
+        let thread_2 = std::thread::spawn({
+            let queue = queue.clone();
+            move || queue.wait(0)
+        });
+
+        // should wait and not receive item
+        assert_duration(Duration::from_millis(100), || !thread_2.is_finished());
+
+        // request #3
+        queue.prioritize(
+            [item_3]
+                .iter()
+                .map(|i| (i.hash_kind.as_ref(), i.hash.as_slice())),
+        );
+
+        // should receive #3
+        let item = thread_2.join().unwrap();
+        assert_eq!(item.hash, vec![0x03]);
+    }
+
+    #[test]
+    fn test_queue_change_policy_to_always() {
+        let queue = Arc::new(TranscodeQueue::new(TranscodePolicy::IfRequested));
+
+        // add to queue
+        let item_1 = test_item(0x01);
+        let item_2 = test_item(0x02);
+        let item_3 = test_item(0x03);
+        queue.extend(vec![item_1.clone(), item_2.clone(), item_3.clone()]);
+
+        // spawn consumer thread to wait for 3 items
+        let thread_1 = std::thread::spawn({
+            let queue = queue.clone();
+            move || {
+                queue.wait(0);
+                queue.wait(0);
+                queue.wait(0);
+            }
+        });
+
+        // should wait and not receive item
+        assert_duration(Duration::from_millis(100), || !thread_1.is_finished());
+
+        // change policy to Always
+        queue.set_policy(TranscodePolicy::Always);
+
+        // should receive items and exit
+        join_timeout(Duration::from_millis(100), thread_1);
+    }
+
+    #[test]
+    fn test_queue_change_policy_to_if_requested() {
+        let queue = Arc::new(TranscodeQueue::new(TranscodePolicy::Always));
+
+        // add to queue
+        let item_1 = test_item(0x01);
+        let item_2 = test_item(0x02);
+        let item_3 = test_item(0x03);
+        queue.extend(vec![item_1.clone(), item_2.clone(), item_3.clone()]);
+
+        // should receive some item
+        queue.wait(0);
+
+        // change policy to IfRequested
+        queue.set_policy(TranscodePolicy::IfRequested);
+
+        // spawn consumer thread to wait for 2 more items
+        let thread_1 = std::thread::spawn({
+            let queue = queue.clone();
+            move || {
+                queue.wait(0);
+                queue.wait(0);
+            }
+        });
+
+        // should wait and not receive item
+        assert_duration(Duration::from_millis(100), || !thread_1.is_finished());
+
+        // request all
+        queue.prioritize(
+            [item_1, item_2, item_3]
+                .iter()
+                .map(|i| (i.hash_kind.as_ref(), i.hash.as_slice())),
+        );
+
+        // should receive items and exit
+        join_timeout(Duration::from_millis(100), thread_1);
+    }
+
+    #[test]
+    fn test_queue_ready_count() {
+        let queue = Arc::new(TranscodeQueue::new(TranscodePolicy::Always));
+
+        // should have 0 ready
+        assert_eq!(queue.ready_counter.load(Ordering::SeqCst), 0);
+
+        // add to queue
+        let item_1 = test_item(0x01);
+        let item_2 = test_item(0x02);
+        let item_3 = test_item(0x03);
+        queue.extend(vec![item_1.clone(), item_2.clone(), item_3.clone()]);
+
+        // should have 3 ready
+        assert_eq!(queue.ready_counter.load(Ordering::SeqCst), 3);
+
+        // should receive some item
+        queue.wait(0);
+
+        // should have 2 ready
+        assert_eq!(queue.ready_counter.load(Ordering::SeqCst), 2);
+
+        // change policy to IfRequested
+        queue.set_policy(TranscodePolicy::IfRequested);
+
+        // should have 0 ready
+        assert_eq!(queue.ready_counter.load(Ordering::SeqCst), 0);
+
+        // request #2
+        queue.prioritize(
+            [item_2]
+                .iter()
+                .map(|i| (i.hash_kind.as_ref(), i.hash.as_slice())),
+        );
+
+        // should have 1 ready
+        assert_eq!(queue.ready_counter.load(Ordering::SeqCst), 1);
+
+        // should receive some item
+        queue.wait(0);
+
+        // should have 0 ready
+        assert_eq!(queue.ready_counter.load(Ordering::SeqCst), 0);
+    }
+}
@@ -0,0 +1,173 @@
+//! Sidecar files next to sources, like cover images and lyrics.
+//!
+//! Sidecar covers are embedded in transcodes of files without cover art, and
+//! sidecar lyrics replace the embedded lyrics.
+
+use anyhow::Context;
+use dashmap::DashMap;
+use std::{
+    path::{Path, PathBuf},
+    sync::LazyLock,
+    time::{Duration, SystemTime},
+};
+
+/// The file names of sidecar cover images, in order of preference.
+const SIDECAR_COVER_NAMES: &[&str] = &["cover", "folder", "front", "album"];
+
+/// The file extensions of sidecar cover images.
+const SIDECAR_COVER_EXTENSIONS: &[&str] = &["jpg", "jpeg", "png"];
+
+/// Cached sidecar cover lookups by directory, with the modification time of
+/// the directory when it was read. Adding, removing or renaming a file
+/// changes the modification time, so stale entries are read again.
+static SIDECAR_COVER_CACHE: LazyLock<DashMap<PathBuf, (SystemTime, Option<PathBuf>)>> =
+    LazyLock::new(DashMap::new);
+
+/// How old a directory's modification time must be before its sidecar cover
+/// lookup is cached. Timestamps are coarse, so a directory that was just
+/// modified could change again without its modification time changing.
+const SIDECAR_COVER_CACHE_MIN_AGE: Duration = Duration::from_secs(2);
+
+/// Finds a sidecar cover image like `cover.jpg` or `folder.png` in the same
+/// directory as a file. Names are matched case-insensitively.
+///
+/// Lookups are cached per directory, since every file in an album looks up
+/// the same cover.
+pub fn sidecar_cover_path(path: &Path) -> Option<PathBuf> {
+    let dir = path.parent()?;
+
+    let modified = std::fs::metadata(dir)
+        .and_then(|metadata| metadata.modified())
+        .ok();
+    if let Some(modified) = modified
+        && let Some(entry) = SIDECAR_COVER_CACHE.get(dir)
+        && entry.0 == modified
+    {
+        return entry.1.clone();
+    }
+
+    let cover_path = find_sidecar_cover(dir);
+
+    if let Some(modified) = modified
+        && SystemTime::now()
+            .duration_since(modified)
+            .is_ok_and(|age| age >= SIDECAR_COVER_CACHE_MIN_AGE)
+    {
+        SIDECAR_COVER_CACHE.insert(dir.to_path_buf(), (modified, cover_path.clone()));
+    }
+
+    cover_path
+}
+
+/// Finds a sidecar cover image in a directory, see `sidecar_cover_path`.
+fn find_sidecar_cover(dir: &Path) -> Option<PathBuf> {
+    let entries = std::fs::read_dir(dir).ok()?;
+    let mut candidates = entries
+        .filter_map(|entry| {
+            let path = entry.ok()?.path();
+            let stem = path.file_stem()?.to_str()?.to_ascii_lowercase();
+            let extension = path.extension()?.to_str()?.to_ascii_lowercase();
+
+            let rank = SIDECAR_COVER_NAMES.iter().position(|name| *name == stem)?;
+            if !SIDECAR_COVER_EXTENSIONS.contains(&extension.as_str()) {
+                return None;
+            }
+
+            Some((rank, path))
+        })
+        .collect::<Vec<_>>();
+
+    // sort by path too so the choice doesn't depend on directory order
+    candidates.sort();
+    candidates.into_iter().next().map(|(_, path)| path)
+}
+
+/// Finds a sidecar lyrics file with the same name as a file and the `.lrc`
+/// extension, like `01 Track.lrc` next to `01 Track.flac`. The extension can
+/// be lowercase or uppercase.
+pub fn sidecar_lyrics_path(path: &Path) -> Option<PathBuf> {
+    ["lrc", "LRC"]
+        .into_iter()
+        .map(|extension| path.with_extension(extension))
+        .find(|candidate| candidate.is_file())
+}
+
+/// Reads the sidecar lyrics of a file, see `sidecar_lyrics_path`.
+///
+/// The lyrics are kept as-is, including LRC timestamps, since players that
+/// support synced lyrics read them from the LYRICS comment.
+pub fn sidecar_lyrics(path: &Path) -> anyhow::Result<Option<String>> {
+    let Some(sidecar_path) = sidecar_lyrics_path(path) else {
+        return Ok(None);
+    };
+
+    let bytes = std::fs::read(&sidecar_path).context("failed to read sidecar lyrics")?;
+    let lyrics = match String::from_utf8(bytes) {
+        Ok(lyrics) => lyrics,
+        // lyrics from older tools are often latin-1
+        Err(e) => e.into_bytes().iter().map(|&b| b as char).collect(),
+    };
+    let lyrics = lyrics.trim_start_matches('\u{feff}').replace("\r\n", "\n");
+    let lyrics = lyrics.trim();
+
+    Ok((!lyrics.is_empty()).then(|| lyrics.to_string()))
+}
+
+/// Replaces the LYRICS comments with the sidecar lyrics of a file, if it has
+/// any. Sidecar lyrics take precedence since they're usually newer than the
+/// embedded lyrics.
+pub fn apply_sidecar_lyrics(comments: &mut Vec<String>, input_path: &Path) -> anyhow::Result<()> {
+    let Some(lyrics) = sidecar_lyrics(input_path)? else {
+        return Ok(());
+    };
+
+    log::debug!("using sidecar lyrics for {}", input_path.display());
+
+    comments.retain(|comment| {
+        let key = comment.split_once('=').map_or("", |(key, _)| key);
+        !key.eq_ignore_ascii_case("LYRICS")
+    });
+    comments.push(format!("LYRICS={lyrics}"));
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs::File;
+
+    #[test]
+    fn test_sidecar_cover_cache() {
+        let dir = std::env::temp_dir().join(format!(
+            "musicopy-test-{}-sidecar-cache",
+            std::process::id()
+        ));
+        std::fs::create_dir_all(&dir).unwrap();
+        let track_path = dir.join("01 track.flac");
+        let set_dir_modified = |modified: SystemTime| {
+            File::open(&dir).unwrap().set_modified(modified).unwrap();
+        };
+
+        // recently modified directories aren't cached
+        std::fs::write(dir.join("cover.jpg"), "cover").unwrap();
+        assert_eq!(sidecar_cover_path(&track_path), Some(dir.join("cover.jpg")));
+        assert!(!SIDECAR_COVER_CACHE.contains_key(&dir));
+
+        // older directories are cached until their modification time changes
+        let modified = SystemTime::now() - Duration::from_secs(60);
+        set_dir_modified(modified);
+        assert_eq!(sidecar_cover_path(&track_path), Some(dir.join("cover.jpg")));
+        std::fs::rename(dir.join("cover.jpg"), dir.join("folder.jpg")).unwrap();
+        set_dir_modified(modified);
+        assert_eq!(sidecar_cover_path(&track_path), Some(dir.join("cover.jpg")));
+
+        set_dir_modified(modified + Duration::from_secs(1));
+        assert_eq!(
+            sidecar_cover_path(&track_path),
+            Some(dir.join("folder.jpg"))
+        );
+
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+}
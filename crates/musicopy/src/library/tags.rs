@@ -0,0 +1,899 @@
+//! Conversion of source metadata to Vorbis comments for Opus and FLAC output.
+
+use anyhow::Context;
+use std::{
+    fs::File,
+    io::{Read, Seek, SeekFrom, Write},
+    path::Path,
+};
+use symphonia::core::meta::{StandardTag, Tag};
+
+/// The vendor string written to Vorbis comment headers.
+const VENDOR: &str = "musicopy";
+
+/// Converts source tags to Vorbis comments in `FIELD=value` form.
+///
+/// Multi-value tags become one comment per value, since Vorbis comments allow
+/// a field to be repeated. Duplicate comments are removed.
+pub fn vorbis_comments(tags: &[Tag]) -> Vec<String> {
+    let mut comments: Vec<String> = Vec::new();
+
+    for tag in tags.iter().flat_map(|t| &t.std) {
+        for (field, value) in vorbis_fields(tag) {
+            // ID3v2.4 separates multiple values in a text frame with null
+            for value in value.split('\0') {
+                let value = value.trim();
+                if value.is_empty() {
+                    continue;
+                }
+
+                // field names can't contain `=`, but values can since only the
+                // first `=` separates the field from the value
+                let comment = format!("{field}={value}");
+                if !comments.contains(&comment) {
+                    comments.push(comment);
+                }
+            }
+        }
+    }
+
+    comments
+}
+
+/// Returns the Vorbis comment fields for a standard tag.
+///
+/// Field names follow the Xiph recommendations and the conventions used by
+/// MusicBrainz Picard. Tags that describe the source encoding aren't copied
+/// since they don't apply to the transcode. ReplayGain tags are converted to
+/// R128 gains separately, see `ReplayGain`.
+fn vorbis_fields(tag: &StandardTag) -> Vec<(&'static str, String)> {
+    let field = match tag {
+        StandardTag::Album(v) => ("ALBUM", v.to_string()),
+        StandardTag::AlbumArtist(v) => ("ALBUMARTIST", v.to_string()),
+        StandardTag::Arranger(v) => ("ARRANGER", v.to_string()),
+        StandardTag::Artist(v) => ("ARTIST", v.to_string()),
+        StandardTag::Bpm(v) => ("BPM", v.to_string()),
+        StandardTag::Comment(v) => ("COMMENT", v.to_string()),
+        StandardTag::Compilation(v) => ("COMPILATION", if *v { "1" } else { "0" }.to_string()),
+        StandardTag::Composer(v) => ("COMPOSER", v.to_string()),
+        StandardTag::Conductor(v) => ("CONDUCTOR", v.to_string()),
+        StandardTag::ContentGroup(v) => ("GROUPING", v.to_string()),
+        StandardTag::Copyright(v) => ("COPYRIGHT", v.to_string()),
+        StandardTag::Date(v) => ("DATE", v.to_string()),
+        StandardTag::Description(v) => ("DESCRIPTION", v.to_string()),
+        StandardTag::DiscNumber(v) => return number_fields("DISCNUMBER", "DISCTOTAL", v),
+        StandardTag::DiscSubtitle(v) => ("DISCSUBTITLE", v.to_string()),
+        StandardTag::DiscTotal(v) => ("DISCTOTAL", v.to_string()),
+        StandardTag::EncodedBy(v) => ("ENCODEDBY", v.to_string()),
+        StandardTag::Engineer(v) => ("ENGINEER", v.to_string()),
+        StandardTag::Ensemble(v) => ("ENSEMBLE", v.to_string()),
+        StandardTag::Genre(v) => ("GENRE", v.to_string()),
+        StandardTag::IdentAsin(v) => ("ASIN", v.to_string()),
+        StandardTag::IdentBarcode(v) => ("BARCODE", v.to_string()),
+        StandardTag::IdentCatalogNumber(v) => ("CATALOGNUMBER", v.to_string()),
+        StandardTag::IdentEanUpn(v) => ("EAN/UPN", v.to_string()),
+        StandardTag::IdentIsrc(v) => ("ISRC", v.to_string()),
+        StandardTag::IdentUpc(v) => ("UPC", v.to_string()),
+        StandardTag::Label(v) => ("LABEL", v.to_string()),
+        StandardTag::Language(v) => ("LANGUAGE", v.to_string()),
+        StandardTag::License(v) => ("LICENSE", v.to_string()),
+        StandardTag::Lyricist(v) => ("LYRICIST", v.to_string()),
+        StandardTag::Lyrics(v) => ("LYRICS", v.to_string()),
+        StandardTag::MediaFormat(v) => ("MEDIA", v.to_string()),
+        StandardTag::MixDj(v) => ("DJMIXER", v.to_string()),
+        StandardTag::MixEngineer(v) => ("MIXER", v.to_string()),
+        StandardTag::Mood(v) => ("MOOD", v.to_string()),
+        StandardTag::MovementName(v) => ("MOVEMENTNAME", v.to_string()),
+        StandardTag::MovementNumber(v) => ("MOVEMENT", v.to_string()),
+        StandardTag::MusicBrainzAlbumArtistId(v) => ("MUSICBRAINZ_ALBUMARTISTID", v.to_string()),
+        StandardTag::MusicBrainzAlbumId(v) => ("MUSICBRAINZ_ALBUMID", v.to_string()),
+        StandardTag::MusicBrainzArtistId(v) => ("MUSICBRAINZ_ARTISTID", v.to_string()),
+        StandardTag::MusicBrainzDiscId(v) => ("MUSICBRAINZ_DISCID", v.to_string()),
+        StandardTag::MusicBrainzGenreId(v) => ("MUSICBRAINZ_GENREID", v.to_string()),
+        StandardTag::MusicBrainzLabelId(v) => ("MUSICBRAINZ_LABELID", v.to_string()),
+        StandardTag::MusicBrainzOriginalAlbumId(v) => {
+            ("MUSICBRAINZ_ORIGINALALBUMID", v.to_string())
+        }
+        StandardTag::MusicBrainzOriginalArtistId(v) => {
+            ("MUSICBRAINZ_ORIGINALARTISTID", v.to_string())
+        }
+        // Picard stores the recording ID as MUSICBRAINZ_TRACKID
+        StandardTag::MusicBrainzRecordingId(v) => ("MUSICBRAINZ_TRACKID", v.to_string()),
+        StandardTag::MusicBrainzReleaseGroupId(v) => ("MUSICBRAINZ_RELEASEGROUPID", v.to_string()),
+        StandardTag::MusicBrainzReleaseStatus(v) => ("RELEASESTATUS", v.to_string()),
+        StandardTag::MusicBrainzReleaseTrackId(v) => ("MUSICBRAINZ_RELEASETRACKID", v.to_string()),
+        StandardTag::MusicBrainzReleaseType(v) => ("RELEASETYPE", v.to_string()),
+        StandardTag::MusicBrainzTrackId(v) => ("MUSICBRAINZ_TRACKID", v.to_string()),
+        StandardTag::MusicBrainzWorkId(v) => ("MUSICBRAINZ_WORKID", v.to_string()),
+        StandardTag::Opus(v) => ("OPUS", v.to_string()),
+        StandardTag::OriginalAlbum(v) => ("ORIGINALALBUM", v.to_string()),
+        StandardTag::OriginalArtist(v) => ("ORIGINALARTIST", v.to_string()),
+        StandardTag::OriginalDate(v) => ("ORIGINALDATE", v.to_string()),
+        StandardTag::OriginalFile(v) => ("ORIGINALFILENAME", v.to_string()),
+        StandardTag::OriginalWriter(v) => ("ORIGINALLYRICIST", v.to_string()),
+        StandardTag::Part(v) => ("PART", v.to_string()),
+        StandardTag::PartTotal(v) => ("PARTTOTAL", v.to_string()),
+        StandardTag::Performer(v) => ("PERFORMER", v.to_string()),
+        StandardTag::Producer(v) => ("PRODUCER", v.to_string()),
+        StandardTag::ReleaseCountry(v) => ("RELEASECOUNTRY", v.to_string()),
+        StandardTag::ReleaseDate(v) => ("RELEASEDATE", v.to_string()),
+        StandardTag::Remixer(v) => ("REMIXER", v.to_string()),
+        StandardTag::Script(v) => ("SCRIPT", v.to_string()),
+        StandardTag::SortAlbum(v) => ("ALBUMSORT", v.to_string()),
+        StandardTag::SortAlbumArtist(v) => ("ALBUMARTISTSORT", v.to_string()),
+        StandardTag::SortArtist(v) => ("ARTISTSORT", v.to_string()),
+        StandardTag::SortComposer(v) => ("COMPOSERSORT", v.to_string()),
+        StandardTag::SortTrackTitle(v) => ("TITLESORT", v.to_string()),
+        StandardTag::TrackNumber(v) => return number_fields("TRACKNUMBER", "TRACKTOTAL", v),
+        StandardTag::TrackSubtitle(v) => ("SUBTITLE", v.to_string()),
+        StandardTag::TrackTitle(v) => ("TITLE", v.to_string()),
+        StandardTag::TrackTotal(v) => ("TRACKTOTAL", v.to_string()),
+        StandardTag::Version(v) => ("VERSION", v.to_string()),
+        StandardTag::Writer(v) => ("WRITER", v.to_string()),
+        _ => return Vec::new(),
+    };
+
+    vec![field]
+}
+
+/// Returns the fields for a number that might include a total, like ID3v2's
+/// `3/12` track numbers.
+fn number_fields(
+    number_field: &'static str,
+    total_field: &'static str,
+    value: impl ToString,
+) -> Vec<(&'static str, String)> {
+    let value = value.to_string();
+
+    match value.split_once('/') {
+        Some((number, total)) => vec![
+            (number_field, number.trim().to_string()),
+            (total_field, total.trim().to_string()),
+        ],
+        None => vec![(number_field, value)],
+    }
+}
+
+/// ReplayGain values from source tags, in dB.
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct ReplayGain {
+    pub track_gain: Option<f64>,
+    pub album_gain: Option<f64>,
+}
+
+impl ReplayGain {
+    /// Reads ReplayGain values from source tags.
+    pub fn from_tags(tags: &[Tag]) -> Self {
+        let mut replay_gain = Self::default();
+
+        for tag in tags.iter().flat_map(|t| &t.std) {
+            match tag {
+                StandardTag::ReplayGainTrackGain(v) => {
+                    replay_gain.track_gain = parse_gain(&v.to_string())
+                }
+                StandardTag::ReplayGainAlbumGain(v) => {
+                    replay_gain.album_gain = parse_gain(&v.to_string())
+                }
+                _ => {}
+            }
+        }
+
+        replay_gain
+    }
+}
+
+/// Returns the key that groups the tracks of an album, for album gain.
+///
+/// Tracks tagged with an album artist and album are grouped by those tags.
+/// Otherwise, the album is scoped to the track's directory, so untagged albums
+/// are grouped by folder and albums with the same title by different artists
+/// aren't merged.
+pub fn album_key(tags: &[Tag], path: &Path) -> String {
+    let mut album = None;
+    let mut album_artist = None;
+
+    for tag in tags.iter().flat_map(|t| &t.std) {
+        match tag {
+            StandardTag::Album(v) if album.is_none() => album = Some(v.to_string()),
+            StandardTag::AlbumArtist(v) if album_artist.is_none() => {
+                album_artist = Some(v.to_string())
+            }
+            _ => {}
+        }
+    }
+
+    let album = album.as_deref().map(str::trim).unwrap_or_default();
+    let album_artist = album_artist.as_deref().map(str::trim).unwrap_or_default();
+
+    if !album.is_empty() && !album_artist.is_empty() {
+        format!("tags\0{album_artist}\0{album}")
+    } else {
+        let dir = path.parent().unwrap_or(path);
+        format!("dir\0{}\0{album}", dir.display())
+    }
+}
+
+/// Parses a ReplayGain value like `-6.54 dB`.
+fn parse_gain(value: &str) -> Option<f64> {
+    let value = value.trim();
+    let value = value
+        .strip_suffix("dB")
+        .or_else(|| value.strip_suffix("db"))
+        .unwrap_or(value);
+
+    value
+        .trim()
+        .parse()
+        .ok()
+        .filter(|gain: &f64| gain.is_finite())
+}
+
+/// The loudness that R128 gains are relative to.
+pub const R128_REFERENCE_LUFS: f64 = -23.0;
+
+/// The loudness that ReplayGain 2.0 gains are relative to.
+const REPLAYGAIN_REFERENCE_LUFS: f64 = -18.0;
+
+/// Converts a ReplayGain gain to an R128 gain in dB.
+pub fn replay_gain_to_r128(gain: f64) -> f64 {
+    gain + R128_REFERENCE_LUFS - REPLAYGAIN_REFERENCE_LUFS
+}
+
+/// Converts a gain in dB to the Q7.8 fixed point format used by the OpusHead
+/// output gain and the R128 tags, clamping to the representable range.
+pub fn gain_to_q78(gain: f64) -> i16 {
+    (gain * 256.0)
+        .round()
+        .clamp(i16::MIN as f64, i16::MAX as f64) as i16
+}
+
+/// Number of padding bytes reserved at the end of OpusTags packets, so that
+/// tags like R128 gains can be added later without changing the page layout.
+pub const OPUS_TAGS_PADDING: usize = 256;
+
+/// Builds an OpusTags packet containing the comments.
+///
+/// See RFC 7845 section 5.2.
+pub fn opus_tags_packet(comments: &[String]) -> Vec<u8> {
+    let mut buf = opus_tags_packet_unpadded(comments);
+    buf.resize(buf.len() + OPUS_TAGS_PADDING, 0);
+    buf
+}
+
+/// Builds an OpusTags packet with the same length as an existing packet by
+/// using up or adding padding.
+pub fn opus_tags_packet_with_len(comments: &[String], len: usize) -> anyhow::Result<Vec<u8>> {
+    let mut buf = opus_tags_packet_unpadded(comments);
+    anyhow::ensure!(
+        buf.len() <= len,
+        "not enough padding in OpusTags packet, need {} bytes but have {len}",
+        buf.len()
+    );
+    buf.resize(len, 0);
+    Ok(buf)
+}
+
+fn opus_tags_packet_unpadded(comments: &[String]) -> Vec<u8> {
+    let mut buf = Vec::new();
+
+    buf.extend(b"OpusTags"); // magic signature
+    buf.extend(vorbis_comment_header(comments));
+
+    // zero padding follows, which readers ignore
+    buf
+}
+
+/// Builds a Vorbis comment header without framing.
+///
+/// This is the body of OpusTags packets and of FLAC VORBIS_COMMENT metadata
+/// blocks.
+pub fn vorbis_comment_header(comments: &[String]) -> Vec<u8> {
+    let mut buf = Vec::new();
+
+    buf.extend((VENDOR.len() as u32).to_le_bytes()); // vendor string length
+    buf.extend(VENDOR.as_bytes()); // vendor string
+
+    buf.extend((comments.len() as u32).to_le_bytes()); // user comment list length
+    for comment in comments {
+        buf.extend((comment.len() as u32).to_le_bytes());
+        buf.extend(comment.as_bytes());
+    }
+
+    buf
+}
+
+/// Parses the user comments of an OpusTags packet.
+pub fn parse_opus_tags(packet: &[u8]) -> anyhow::Result<Vec<String>> {
+    anyhow::ensure!(
+        packet.starts_with(b"OpusTags"),
+        "invalid OpusTags signature"
+    );
+
+    let read_u32 = |pos: usize| -> anyhow::Result<u32> {
+        let bytes = packet
+            .get(pos..(pos + 4))
+            .context("OpusTags packet too short")?;
+        Ok(u32::from_le_bytes(
+            bytes.try_into().expect("slice has 4 bytes"),
+        ))
+    };
+
+    let vendor_len = read_u32(8)? as usize;
+    let mut pos = 12 + vendor_len;
+
+    let count = read_u32(pos)?;
+    pos += 4;
+
+    let mut comments = Vec::new();
+    for _ in 0..count {
+        let len = read_u32(pos)? as usize;
+        pos += 4;
+
+        let comment = packet
+            .get(pos..(pos + len))
+            .context("OpusTags packet too short")?;
+        comments.push(String::from_utf8_lossy(comment).to_string());
+        pos += len;
+    }
+
+    Ok(comments)
+}
+
+/// Sets the R128 album gain of an Ogg Opus file in place.
+///
+/// Like the track gain, the gain is written relative to the file's output
+/// gain. Returns whether the file was changed.
+pub fn write_album_gain(path: &Path, album_gain: f64) -> anyhow::Result<bool> {
+    let mut file = File::options()
+        .read(true)
+        .write(true)
+        .open(path)
+        .context("failed to open file")?;
+
+    let (opus_head, opus_tags) = {
+        let mut reader = ogg::PacketReader::new(&mut file);
+        let opus_head = reader
+            .read_packet()
+            .context("failed to read OpusHead packet")?
+            .context("missing OpusHead packet")?
+            .data;
+        let opus_tags = reader
+            .read_packet()
+            .context("failed to read OpusTags packet")?
+            .context("missing OpusTags packet")?
+            .data;
+        (opus_head, opus_tags)
+    };
+
+    anyhow::ensure!(
+        opus_head.starts_with(b"OpusHead") && opus_head.len() >= 19,
+        "invalid OpusHead packet"
+    );
+    let output_gain = i16::from_le_bytes([opus_head[16], opus_head[17]]) as f64 / 256.0;
+
+    let comment = format!("R128_ALBUM_GAIN={}", gain_to_q78(album_gain - output_gain));
+
+    let mut comments = parse_opus_tags(&opus_tags)?;
+    if comments
+        .iter()
+        .filter(|c| c.starts_with("R128_ALBUM_GAIN="))
+        .eq([&comment])
+    {
+        return Ok(false);
+    }
+    comments.retain(|c| !c.starts_with("R128_ALBUM_GAIN="));
+    comments.push(comment);
+
+    let opus_tags = opus_tags_packet_with_len(&comments, opus_tags.len())?;
+    rewrite_opus_headers(&mut file, &opus_head, &opus_tags)?;
+
+    Ok(true)
+}
+
+/// Replaces the comments of an Ogg Opus file without touching the audio,
+/// returning the new file size.
+///
+/// The R128 gains are measured from the audio, so they're kept from the old
+/// comments. If the new comments fit in the OpusTags packet, it's rewritten in
+/// place. Otherwise the stream is re-paginated with a bigger packet.
+pub fn retag_opus_file(path: &Path, comments: &[String]) -> anyhow::Result<u64> {
+    let mut file = File::options()
+        .read(true)
+        .write(true)
+        .open(path)
+        .context("failed to open file")?;
+
+    let (opus_head, opus_tags) = {
+        let mut reader = ogg::PacketReader::new(&mut file);
+        let opus_head = reader
+            .read_packet()
+            .context("failed to read OpusHead packet")?
+            .context("missing OpusHead packet")?
+            .data;
+        let opus_tags = reader
+            .read_packet()
+            .context("failed to read OpusTags packet")?
+            .context("missing OpusTags packet")?
+            .data;
+        (opus_head, opus_tags)
+    };
+
+    anyhow::ensure!(
+        opus_head.starts_with(b"OpusHead"),
+        "invalid OpusHead packet"
+    );
+
+    let mut comments = comments.to_vec();
+    comments.extend(
+        parse_opus_tags(&opus_tags)?
+            .into_iter()
+            .filter(|c| c.starts_with("R128_TRACK_GAIN=") || c.starts_with("R128_ALBUM_GAIN=")),
+    );
+
+    if let Ok(new_tags) = opus_tags_packet_with_len(&comments, opus_tags.len()) {
+        rewrite_opus_headers(&mut file, &opus_head, &new_tags)?;
+
+        return file
+            .seek(SeekFrom::End(0))
+            .context("failed to seek to end of file");
+    }
+
+    // write the re-paginated stream next to the file, then replace it
+    let temp_path = path.with_extension("tmp");
+    let res = repaginate_opus_file(&mut file, &temp_path, &opus_tags_packet(&comments)).and_then(
+        |file_size| {
+            std::fs::rename(&temp_path, path).context("failed to rename temp file")?;
+            Ok(file_size)
+        },
+    );
+    if res.is_err() {
+        let _ = std::fs::remove_file(&temp_path);
+    }
+    res
+}
+
+/// Copies an Ogg Opus stream to a new file with a different OpusTags packet,
+/// returning the size of the new file.
+///
+/// Packets end pages where they did before and keep their granule positions,
+/// so the audio is copied as it is.
+fn repaginate_opus_file(
+    input: &mut File,
+    output_path: &Path,
+    opus_tags: &[u8],
+) -> anyhow::Result<u64> {
+    input
+        .seek(SeekFrom::Start(0))
+        .context("failed to seek to start of file")?;
+    let mut reader = ogg::PacketReader::new(input);
+
+    let mut output = File::create(output_path).context("failed to create output file")?;
+    {
+        let mut writer = ogg::PacketWriter::new(&mut output);
+
+        let mut index = 0;
+        while let Some(packet) = reader.read_packet().context("failed to read packet")? {
+            let end_info = if packet.last_in_stream() {
+                ogg::PacketWriteEndInfo::EndStream
+            } else if packet.last_in_page() || index <= 1 {
+                // the header packets always end their pages
+                ogg::PacketWriteEndInfo::EndPage
+            } else {
+                ogg::PacketWriteEndInfo::NormalPacket
+            };
+
+            let serial = packet.stream_serial();
+            let absgp = packet.absgp_page();
+            let data = if index == 1 {
+                opus_tags.to_vec()
+            } else {
+                packet.data
+            };
+
+            writer
+                .write_packet(data, serial, end_info, absgp)
+                .context("failed to write packet")?;
+            index += 1;
+        }
+    }
+
+    output
+        .seek(SeekFrom::End(0))
+        .context("failed to seek to end of file")
+}
+
+/// Replaces the OpusHead and OpusTags packets at the start of an Ogg Opus
+/// stream with packets of the same length.
+///
+/// The header packets are on their own pages, so keeping their lengths the
+/// same means only the page payloads and checksums change and the rest of the
+/// stream can be left as-is.
+pub fn rewrite_opus_headers<F: Read + Write + Seek>(
+    file: &mut F,
+    opus_head: &[u8],
+    opus_tags: &[u8],
+) -> anyhow::Result<()> {
+    let mut pos = 0;
+
+    for packet in [opus_head, opus_tags] {
+        let mut offset = 0;
+        loop {
+            // read page header and segment table
+            file.seek(SeekFrom::Start(pos))
+                .context("failed to seek to page")?;
+            let mut header = [0u8; 27];
+            file.read_exact(&mut header)
+                .context("failed to read page header")?;
+            anyhow::ensure!(&header[0..4] == b"OggS", "invalid page capture pattern");
+
+            let mut segment_table = vec![0u8; header[26] as usize];
+            file.read_exact(&mut segment_table)
+                .context("failed to read page segment table")?;
+            let payload_len = segment_table.iter().map(|&len| len as usize).sum::<usize>();
+
+            // replace the payload with the matching part of the new packet
+            anyhow::ensure!(
+                offset + payload_len <= packet.len(),
+                "header packet length changed"
+            );
+            let payload = &packet[offset..(offset + payload_len)];
+
+            // recompute the checksum with the checksum field zeroed
+            header[22..26].fill(0);
+            let mut crc = ogg_crc32(0, &header);
+            crc = ogg_crc32(crc, &segment_table);
+            crc = ogg_crc32(crc, payload);
+            header[22..26].copy_from_slice(&crc.to_le_bytes());
+
+            file.seek(SeekFrom::Start(pos))
+                .context("failed to seek to page")?;
+            file.write_all(&header)
+                .context("failed to write page header")?;
+            file.write_all(&segment_table)
+                .context("failed to write page segment table")?;
+            file.write_all(payload)
+                .context("failed to write page payload")?;
+
+            pos += (header.len() + segment_table.len() + payload_len) as u64;
+            offset += payload_len;
+
+            // the packet ends on a page with a final segment shorter than 255
+            if segment_table.last().is_some_and(|&len| len < 255) {
+                break;
+            }
+        }
+
+        anyhow::ensure!(offset == packet.len(), "header packet length changed");
+    }
+
+    Ok(())
+}
+
+/// Computes the CRC-32 used by Ogg pages.
+fn ogg_crc32(mut crc: u32, data: &[u8]) -> u32 {
+    for &byte in data {
+        crc ^= (byte as u32) << 24;
+        for _ in 0..8 {
+            crc = if crc & 0x8000_0000 != 0 {
+                (crc << 1) ^ 0x04c1_1db7
+            } else {
+                crc << 1
+            };
+        }
+    }
+    crc
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::path::PathBuf;
+    use symphonia::core::{formats::probe::Hint, io::MediaSourceStream};
+
+    fn fixture_path(name: &str) -> PathBuf {
+        PathBuf::from(env!("CARGO_MANIFEST_DIR"))
+            .join("fixtures")
+            .join(name)
+    }
+
+    /// Reads the tags of a fixture.
+    fn fixture_tags(name: &str) -> Vec<Tag> {
+        let path = fixture_path(name);
+        let file = File::open(&path).unwrap();
+        let mss = MediaSourceStream::new(Box::new(file), Default::default());
+
+        let mut hint = Hint::new();
+        hint.with_extension(path.extension().unwrap().to_str().unwrap());
+
+        let mut format = symphonia::default::get_probe()
+            .probe(&hint, mss, Default::default(), Default::default())
+            .unwrap();
+
+        let mut metadata = format.metadata();
+        let revision = metadata.skip_to_latest().expect("fixture should have tags");
+        revision.tags().to_vec()
+    }
+
+    /// Reads the comments that would be written for a fixture.
+    fn fixture_comments(name: &str) -> Vec<String> {
+        vorbis_comments(&fixture_tags(name))
+    }
+
+    /// Asserts that the comments include the tags written by
+    /// `fixtures/generate.py`.
+    fn assert_fixture_comments(comments: &[String]) {
+        for expected in [
+            "TITLE=Tag Test = Title",
+            "ARTIST=Artist A",
+            "ALBUM=Fixture Album",
+            "ALBUMARTIST=Various Artists",
+            "TRACKNUMBER=3",
+            "TRACKTOTAL=12",
+            "DISCNUMBER=2",
+            "DISCTOTAL=2",
+            "DATE=2024",
+            "GENRE=Electronic",
+            "COMPOSER=Composer C",
+            "ISRC=USABC2400001",
+            "MUSICBRAINZ_ALBUMID=0f6b2a3e-6f6e-4a53-9b5c-6c0f3b0d2d1e",
+        ] {
+            assert!(
+                comments.iter().any(|c| c == expected),
+                "missing `{expected}` in {comments:?}"
+            );
+        }
+    }
+
+    #[test]
+    fn test_vorbis_comments_flac() {
+        let comments = fixture_comments("tags.flac");
+        assert_fixture_comments(&comments);
+        assert!(comments.iter().any(|c| c == "ARTIST=Artist B"));
+    }
+
+    #[test]
+    fn test_vorbis_comments_mp3() {
+        let comments = fixture_comments("tags.mp3");
+        assert_fixture_comments(&comments);
+        assert!(comments.iter().any(|c| c == "ARTIST=Artist B"));
+    }
+
+    #[test]
+    fn test_vorbis_comments_m4a() {
+        let comments = fixture_comments("tags.m4a");
+        assert_fixture_comments(&comments);
+    }
+
+    #[test]
+    fn test_number_fields() {
+        assert_eq!(
+            number_fields("TRACKNUMBER", "TRACKTOTAL", "3/12"),
+            vec![
+                ("TRACKNUMBER", "3".to_string()),
+                ("TRACKTOTAL", "12".to_string())
+            ]
+        );
+        assert_eq!(
+            number_fields("TRACKNUMBER", "TRACKTOTAL", 3),
+            vec![("TRACKNUMBER", "3".to_string())]
+        );
+    }
+
+    #[test]
+    fn test_opus_tags_packet() {
+        let packet = opus_tags_packet(&["TITLE=a=b".to_string()]);
+
+        let mut expected = b"OpusTags".to_vec();
+        expected.extend(8u32.to_le_bytes());
+        expected.extend(b"musicopy");
+        expected.extend(1u32.to_le_bytes());
+        expected.extend(9u32.to_le_bytes());
+        expected.extend(b"TITLE=a=b");
+        expected.extend(vec![0; OPUS_TAGS_PADDING]);
+        assert_eq!(packet, expected);
+
+        // adding a comment uses up padding
+        let comments = ["TITLE=a=b".to_string(), "R128_TRACK_GAIN=-512".to_string()];
+        let repacked = opus_tags_packet_with_len(&comments, packet.len()).unwrap();
+        assert_eq!(repacked.len(), packet.len());
+        assert_eq!(
+            &repacked[..(packet.len() - OPUS_TAGS_PADDING + 24)],
+            &opus_tags_packet_unpadded(&comments)[..]
+        );
+
+        // but not more than is available
+        let comments = vec!["X".repeat(OPUS_TAGS_PADDING)];
+        assert!(opus_tags_packet_with_len(&comments, packet.len()).is_err());
+    }
+
+    #[test]
+    fn test_parse_gain() {
+        assert_eq!(parse_gain("-6.54 dB"), Some(-6.54));
+        assert_eq!(parse_gain("+1.20 dB"), Some(1.2));
+        assert_eq!(parse_gain("3"), Some(3.0));
+        assert_eq!(parse_gain("loud"), None);
+    }
+
+    #[test]
+    fn test_gain_conversion() {
+        assert_eq!(replay_gain_to_r128(-6.0), -11.0);
+        assert_eq!(gain_to_q78(-11.0), -2816);
+        assert_eq!(gain_to_q78(1000.0), i16::MAX);
+    }
+
+    #[test]
+    fn test_rewrite_opus_headers() {
+        use std::io::Cursor;
+
+        let opus_head = b"OpusHead\x01\x02\x38\x01\x80\xbb\x00\x00\x00\x00\x00".to_vec();
+        // long enough to span multiple pages
+        let opus_tags = opus_tags_packet(&["X=".to_string() + &"x".repeat(70000)]);
+        let audio = vec![1u8, 2, 3];
+
+        let mut file = Cursor::new(Vec::new());
+        {
+            let mut writer = ogg::PacketWriter::new(&mut file);
+            writer
+                .write_packet(&opus_head[..], 0, ogg::PacketWriteEndInfo::EndPage, 0)
+                .unwrap();
+            writer
+                .write_packet(&opus_tags[..], 0, ogg::PacketWriteEndInfo::EndPage, 0)
+                .unwrap();
+            writer
+                .write_packet(&audio[..], 0, ogg::PacketWriteEndInfo::EndStream, 960)
+                .unwrap();
+        }
+
+        let mut new_head = opus_head.clone();
+        new_head[16] = 0x12;
+        new_head[17] = 0x34;
+        let new_tags = opus_tags_packet_with_len(
+            &[
+                "X=".to_string() + &"y".repeat(70000),
+                "R128_TRACK_GAIN=100".to_string(),
+            ],
+            opus_tags.len(),
+        )
+        .unwrap();
+
+        rewrite_opus_headers(&mut file, &new_head, &new_tags).unwrap();
+
+        // the reader checks page checksums
+        file.set_position(0);
+        let mut reader = ogg::PacketReader::new(file);
+        assert_eq!(reader.read_packet().unwrap().unwrap().data, new_head);
+        assert_eq!(reader.read_packet().unwrap().unwrap().data, new_tags);
+        assert_eq!(reader.read_packet().unwrap().unwrap().data, audio);
+    }
+
+    #[test]
+    fn test_album_key() {
+        // tagged tracks are grouped by tags regardless of format or folder
+        let flac = album_key(&fixture_tags("tags.flac"), Path::new("/music/a/01.flac"));
+        let mp3 = album_key(&fixture_tags("tags.mp3"), Path::new("/music/b/02.mp3"));
+        assert_eq!(flac, "tags\0Various Artists\0Fixture Album");
+        assert_eq!(flac, mp3);
+
+        // untagged tracks are grouped by folder
+        assert_eq!(
+            album_key(&[], Path::new("/music/a/01.flac")),
+            album_key(&[], Path::new("/music/a/02.flac"))
+        );
+        assert_ne!(
+            album_key(&[], Path::new("/music/a/01.flac")),
+            album_key(&[], Path::new("/music/b/01.flac"))
+        );
+    }
+
+    #[test]
+    fn test_write_album_gain() {
+        // output gain of +1 dB
+        let opus_head = b"OpusHead\x01\x02\x38\x01\x80\xbb\x00\x00\x00\x01\x00".to_vec();
+        let opus_tags = opus_tags_packet(&["TITLE=a".to_string(), "R128_ALBUM_GAIN=0".to_string()]);
+
+        let path = std::env::temp_dir().join(format!(
+            "musicopy-test-{}-album-gain.ogg",
+            std::process::id()
+        ));
+        {
+            let mut file = File::create(&path).unwrap();
+            let mut writer = ogg::PacketWriter::new(&mut file);
+            writer
+                .write_packet(&opus_head[..], 0, ogg::PacketWriteEndInfo::EndPage, 0)
+                .unwrap();
+            writer
+                .write_packet(&opus_tags[..], 0, ogg::PacketWriteEndInfo::EndPage, 0)
+                .unwrap();
+            writer
+                .write_packet(&[1u8, 2, 3][..], 0, ogg::PacketWriteEndInfo::EndStream, 960)
+                .unwrap();
+        }
+
+        // the gain is relative to the output gain
+        assert!(write_album_gain(&path, -5.0).unwrap());
+
+        let mut reader = ogg::PacketReader::new(File::open(&path).unwrap());
+        assert_eq!(reader.read_packet().unwrap().unwrap().data, opus_head);
+        let comments = parse_opus_tags(&reader.read_packet().unwrap().unwrap().data).unwrap();
+        assert_eq!(comments, ["TITLE=a", "R128_ALBUM_GAIN=-1536"]);
+        assert_eq!(reader.read_packet().unwrap().unwrap().data, [1, 2, 3]);
+
+        // writing the same gain again doesn't change the file
+        assert!(!write_album_gain(&path, -5.0).unwrap());
+
+        let _ = std::fs::remove_file(&path);
+    }
+
+    #[test]
+    fn test_retag_opus_file() {
+        let opus_head = b"OpusHead\x01\x02\x38\x01\x80\xbb\x00\x00\x00\x00\x00".to_vec();
+        let opus_tags =
+            opus_tags_packet(&["TITLE=a".to_string(), "R128_TRACK_GAIN=-256".to_string()]);
+        let audio = [vec![1u8, 2, 3], vec![4, 5], vec![6]];
+
+        let path =
+            std::env::temp_dir().join(format!("musicopy-test-{}-retag.ogg", std::process::id()));
+        {
+            let mut file = File::create(&path).unwrap();
+            let mut writer = ogg::PacketWriter::new(&mut file);
+            writer
+                .write_packet(&opus_head[..], 0, ogg::PacketWriteEndInfo::EndPage, 0)
+                .unwrap();
+            writer
+                .write_packet(&opus_tags[..], 0, ogg::PacketWriteEndInfo::EndPage, 0)
+                .unwrap();
+            writer
+                .write_packet(&audio[0][..], 0, ogg::PacketWriteEndInfo::NormalPacket, 960)
+                .unwrap();
+            writer
+                .write_packet(&audio[1][..], 0, ogg::PacketWriteEndInfo::EndPage, 960)
+                .unwrap();
+            writer
+                .write_packet(&audio[2][..], 0, ogg::PacketWriteEndInfo::EndStream, 1920)
+                .unwrap();
+        }
+        let original_size = std::fs::metadata(&path).unwrap().len();
+
+        let read_file = || {
+            let mut reader = ogg::PacketReader::new(File::open(&path).unwrap());
+            let mut packets = Vec::new();
+            while let Some(packet) = reader.read_packet().unwrap() {
+                packets.push((packet.data, packet.absgp_page()));
+            }
+            packets
+        };
+
+        // small changes fit in the padding
+        let file_size = retag_opus_file(&path, &["TITLE=b".to_string()]).unwrap();
+        assert_eq!(file_size, original_size);
+
+        let packets = read_file();
+        assert_eq!(
+            parse_opus_tags(&packets[1].0).unwrap(),
+            ["TITLE=b", "R128_TRACK_GAIN=-256"]
+        );
+
+        // big changes re-paginate the stream
+        let title = "TITLE=".to_string() + &"c".repeat(70000);
+        let file_size = retag_opus_file(&path, std::slice::from_ref(&title)).unwrap();
+        assert_eq!(file_size, std::fs::metadata(&path).unwrap().len());
+        assert!(file_size > original_size);
+
+        let packets = read_file();
+        let _ = std::fs::remove_file(&path);
+
+        assert_eq!(packets[0].0, opus_head);
+        assert_eq!(
+            parse_opus_tags(&packets[1].0).unwrap(),
+            [title, "R128_TRACK_GAIN=-256".to_string()]
+        );
+        assert_eq!(
+            packets[2..],
+            [
+                (audio[0].clone(), 960),
+                (audio[1].clone(), 960),
+                (audio[2].clone(), 1920)
+            ]
+        );
+    }
+}
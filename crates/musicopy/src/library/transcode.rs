@@ -1,30 +1,55 @@
-use crate::{model::CounterModel, node::FileSizeModel};
+use crate::{
+    library::{
+        chapters,
+        cue::CueTrack,
+        estimate::{estimate_file_size, estimate_item_size},
+        flac::{self, FlacEncoder},
+        gapless::{self, GaplessInfo, GaplessTrimmer},
+        loudness::{Loudness, LoudnessMeter},
+        manifest::{self, MANIFEST_EXTENSION, TranscodeManifest},
+        opus_packet::{read_self_delimited_packet, write_self_delimited_packet},
+        queue::{TranscodeInterrupt, TranscodeQueue},
+        sidecar::{apply_sidecar_lyrics, sidecar_cover_path, sidecar_lyrics, sidecar_lyrics_path},
+        tags::{self, R128_REFERENCE_LUFS, ReplayGain},
+    },
+    model::{CounterModel, ProgressModel},
+    node::FileSizeModel,
+};
 use anyhow::Context;
 use base64::{Engine, prelude::BASE64_STANDARD};
 use dashmap::DashMap;
-use image::{ImageReader, codecs::jpeg::JpegEncoder, imageops::FilterType};
-use priority_queue::PriorityQueue;
+use image::{
+    DynamicImage, ImageFormat, ImageReader, codecs::jpeg::JpegEncoder, imageops::FilterType,
+};
 use rayon::prelude::*;
 use rubato::{FftFixedIn, Resampler};
 use std::{
-    borrow::Borrow,
-    collections::HashSet,
+    borrow::{Borrow, Cow},
+    collections::{HashMap, HashSet},
     fs::File,
     hash::{Hash, Hasher},
-    io::{Cursor, Seek, SeekFrom},
+    io::{Cursor, Read, Seek, SeekFrom, Write},
     ops::Deref,
+    panic::AssertUnwindSafe,
     path::{Path, PathBuf},
     sync::{
-        Arc, Condvar, Mutex,
-        atomic::{AtomicU64, Ordering},
+        Arc, Mutex,
+        atomic::{AtomicBool, AtomicU32, AtomicU64, Ordering},
     },
+    time::{Duration, SystemTime},
 };
 use symphonia::core::{
-    formats::{TrackType, probe::Hint},
-    io::MediaSourceStream,
-    meta::{StandardTag, StandardVisualKey},
+    codecs::audio::{
+        AudioCodecId,
+        well_known::{CODEC_ID_AAC, CODEC_ID_FLAC, CODEC_ID_MP3, CODEC_ID_OPUS, CODEC_ID_VORBIS},
+    },
+    formats::{FormatReader, SeekMode, SeekTo, TrackType, probe::Hint},
+    io::{MediaSource, MediaSourceStream},
+    meta::{StandardVisualKey, Visual},
+    units::TimeBase,
 };
-use tokio::sync::mpsc;
+use tokio::sync::{RwLock, mpsc};
+use twox_hash::XxHash3_64;
 
 /// The transcode status of a file.
 #[derive(Debug)]
@@ -36,7 +61,14 @@ pub enum TranscodeStatus {
     Waiting { estimated_size: Option<u64> },
 
     /// The file is transcoded and available at `local_path`.
-    Ready { local_path: PathBuf, file_size: u64 },
+    ///
+    /// If `passthrough` is true, the file is a copy of the original file
+    /// instead of an Opus transcode, and keeps its original container.
+    Ready {
+        local_path: PathBuf,
+        file_size: u64,
+        passthrough: bool,
+    },
 
     /// Transcoding the file failed.
     Failed { error: anyhow::Error },
@@ -49,7 +81,7 @@ pub enum TranscodeStatus {
 /// needing to clone.
 ///
 /// See https://stackoverflow.com/a/45795699
-trait HashKey {
+pub trait HashKey {
     fn hash_kind(&self) -> &str;
     fn hash(&self) -> &[u8];
 }
@@ -125,6 +157,30 @@ impl Deref for TranscodeStatusCacheEntry<'_> {
 pub struct TranscodeStatusCache {
     cache: Arc<DashMap<(String, Vec<u8>), TranscodeStatus>>,
 
+    /// When each Ready transcode was last served, or when it was created if
+    /// it hasn't been served yet. Used to evict the least recently used
+    /// transcodes when the cache is over its size limit.
+    last_used: Arc<DashMap<(String, Vec<u8>), SystemTime>>,
+
+    /// The progress of files being transcoded, shared with the models of
+    /// transfer jobs waiting for them.
+    progress: Arc<DashMap<(String, Vec<u8>), TranscodeProgress>>,
+
+    /// How many times each transcode was changed in place, like when it's
+    /// re-tagged. Servers compare this to tell clients to download it again.
+    ///
+    /// The revisions are stored in the transcodes' manifests, so they're
+    /// remembered across restarts.
+    revisions: Arc<DashMap<(String, Vec<u8>), u64>>,
+
+    /// Locks that serialize changing a Ready transcode in place with other
+    /// changes to it and with transfers reading it.
+    rewrite_locks: Arc<DashMap<(String, Vec<u8>), Arc<RwLock<()>>>>,
+
+    /// How many pins each Ready transcode has, see `pin`. Pinned transcodes
+    /// aren't evicted.
+    pins: Arc<DashMap<(String, Vec<u8>), usize>>,
+
     waiting_counter: Arc<AtomicU64>,
     ready_counter: Arc<AtomicU64>,
     failed_counter: Arc<AtomicU64>,
@@ -136,6 +192,16 @@ impl TranscodeStatusCache {
         TranscodeStatusCache {
             cache: Arc::new(DashMap::new()),
 
+            last_used: Arc::new(DashMap::new()),
+
+            progress: Arc::new(DashMap::new()),
+
+            revisions: Arc::new(DashMap::new()),
+
+            rewrite_locks: Arc::new(DashMap::new()),
+
+            pins: Arc::new(DashMap::new()),
+
             waiting_counter: Arc::new(AtomicU64::new(0)),
             ready_counter: Arc::new(AtomicU64::new(0)),
             failed_counter: Arc::new(AtomicU64::new(0)),
@@ -150,16 +216,28 @@ impl TranscodeStatusCache {
     }
 
     /// Inserts a key and a value into the cache, replacing the old value.
+    ///
+    /// Ready transcodes are marked as used now.
     pub fn insert(&self, hash_kind: String, hash: Vec<u8>, status: TranscodeStatus) {
         match status {
             TranscodeStatus::Waiting { .. } => {
                 self.waiting_counter.fetch_add(1, Ordering::Relaxed);
+                self.last_used
+                    .remove(&(hash_kind.as_str(), hash.as_slice()) as &dyn HashKey);
             }
             TranscodeStatus::Ready { .. } => {
                 self.ready_counter.fetch_add(1, Ordering::Relaxed);
+                self.last_used
+                    .insert((hash_kind.clone(), hash.clone()), SystemTime::now());
+                self.progress
+                    .remove(&(hash_kind.as_str(), hash.as_slice()) as &dyn HashKey);
             }
             TranscodeStatus::Failed { .. } => {
                 self.failed_counter.fetch_add(1, Ordering::Relaxed);
+                self.last_used
+                    .remove(&(hash_kind.as_str(), hash.as_slice()) as &dyn HashKey);
+                self.progress
+                    .remove(&(hash_kind.as_str(), hash.as_slice()) as &dyn HashKey);
             }
         }
 
@@ -192,6 +270,169 @@ impl TranscodeStatusCache {
         }
     }
 
+    /// Removes an entry from the cache if the status is Failed.
+    pub fn remove_failed(&self, hash_kind: &str, hash: &[u8]) {
+        let prev = self
+            .cache
+            .remove_if(&(hash_kind, hash) as &dyn HashKey, |_, status| {
+                matches!(status, TranscodeStatus::Failed { .. })
+            });
+
+        if prev.is_some() {
+            self.failed_counter.fetch_sub(1, Ordering::Relaxed);
+        }
+    }
+
+    /// Removes an entry from the cache.
+    pub fn remove(&self, hash_kind: &str, hash: &[u8]) {
+        let prev = self.cache.remove(&(hash_kind, hash) as &dyn HashKey);
+        self.last_used.remove(&(hash_kind, hash) as &dyn HashKey);
+        self.progress.remove(&(hash_kind, hash) as &dyn HashKey);
+
+        match prev {
+            Some((_, TranscodeStatus::Waiting { .. })) => {
+                self.waiting_counter.fetch_sub(1, Ordering::Relaxed);
+            }
+            Some((_, TranscodeStatus::Ready { .. })) => {
+                self.ready_counter.fetch_sub(1, Ordering::Relaxed);
+            }
+            Some((_, TranscodeStatus::Failed { .. })) => {
+                self.failed_counter.fetch_sub(1, Ordering::Relaxed);
+            }
+            None => {}
+        }
+    }
+
+    /// Marks a Ready transcode as used now, e.g. when it's served.
+    ///
+    /// The file's modification time is updated too, so that the time is
+    /// remembered across restarts.
+    pub fn touch(&self, hash_kind: &str, hash: &[u8]) {
+        let Some(entry) = self.get(hash_kind, hash) else {
+            return;
+        };
+        let TranscodeStatus::Ready { local_path, .. } = &*entry else {
+            return;
+        };
+
+        let now = SystemTime::now();
+        if let Err(e) = File::options()
+            .write(true)
+            .open(local_path)
+            .and_then(|file| file.set_modified(now))
+        {
+            log::warn!(
+                "TranscodeStatusCache::touch: failed to set modified time of {}: {e:#}",
+                local_path.display()
+            );
+        }
+
+        self.last_used
+            .insert((hash_kind.to_string(), hash.to_vec()), now);
+    }
+
+    /// Returns the progress of transcoding a file.
+    ///
+    /// The progress is shared by everyone waiting for the file, and is
+    /// removed once the file is Ready or Failed.
+    pub fn progress(&self, hash_kind: &str, hash: &[u8]) -> TranscodeProgress {
+        if let Some(progress) = self.progress.get(&(hash_kind, hash) as &dyn HashKey) {
+            return progress.clone();
+        }
+
+        self.progress
+            .entry((hash_kind.to_string(), hash.to_vec()))
+            .or_default()
+            .clone()
+    }
+
+    /// Updates the size and revision of a Ready transcode that was changed in
+    /// place.
+    ///
+    /// The revision is the one in the transcode's refreshed manifest.
+    pub fn set_retagged(&self, hash_kind: &str, hash: &[u8], new_file_size: u64, revision: u64) {
+        if let Some(mut entry) = self.cache.get_mut(&(hash_kind, hash) as &dyn HashKey)
+            && let TranscodeStatus::Ready { file_size, .. } = &mut *entry
+        {
+            *file_size = new_file_size;
+        }
+
+        self.set_revision(hash_kind.to_string(), hash.to_vec(), revision);
+    }
+
+    /// Sets how many times a transcode was changed in place.
+    fn set_revision(&self, hash_kind: String, hash: Vec<u8>, revision: u64) {
+        self.revisions.insert((hash_kind, hash), revision);
+    }
+
+    /// Returns how many times a transcode was changed in place.
+    pub fn revision(&self, hash_kind: &str, hash: &[u8]) -> u64 {
+        self.revisions
+            .get(&(hash_kind, hash) as &dyn HashKey)
+            .map_or(0, |revision| *revision)
+    }
+
+    /// Returns the lock that guards a Ready transcode against being changed in
+    /// place, like when it's re-tagged.
+    ///
+    /// Hold it for writing while changing the file in place, and for reading
+    /// while reading the file, so that readers never see a partial change.
+    pub fn rewrite_lock(&self, hash_kind: &str, hash: &[u8]) -> Arc<RwLock<()>> {
+        if let Some(lock) = self.rewrite_locks.get(&(hash_kind, hash) as &dyn HashKey) {
+            return lock.clone();
+        }
+
+        self.rewrite_locks
+            .entry((hash_kind.to_string(), hash.to_vec()))
+            .or_default()
+            .clone()
+    }
+
+    /// Pins a Ready transcode so it isn't evicted until the returned pin is
+    /// dropped, like while a server job is ready to send it.
+    pub fn pin(&self, hash_kind: &str, hash: &[u8]) -> TranscodePin {
+        let key = (hash_kind.to_string(), hash.to_vec());
+        *self.pins.entry(key.clone()).or_default() += 1;
+
+        TranscodePin {
+            pins: self.pins.clone(),
+            key,
+        }
+    }
+
+    /// Returns whether a transcode is pinned, see `pin`.
+    fn is_pinned(&self, hash_kind: &str, hash: &[u8]) -> bool {
+        self.pins.contains_key(&(hash_kind, hash) as &dyn HashKey)
+    }
+
+    /// Sets when a Ready transcode was last used.
+    fn set_last_used(&self, hash_kind: String, hash: Vec<u8>, last_used: SystemTime) {
+        self.last_used.insert((hash_kind, hash), last_used);
+    }
+
+    /// Returns the total size of Ready transcodes.
+    pub fn ready_size(&self) -> u64 {
+        self.cache
+            .iter()
+            .map(|entry| match &*entry {
+                TranscodeStatus::Ready { file_size, .. } => *file_size,
+                _ => 0,
+            })
+            .sum()
+    }
+
+    /// Removes all entries from the cache.
+    pub fn clear(&self) {
+        self.cache.clear();
+        self.last_used.clear();
+        self.progress.clear();
+        self.revisions.clear();
+
+        self.waiting_counter.store(0, Ordering::Relaxed);
+        self.ready_counter.store(0, Ordering::Relaxed);
+        self.failed_counter.store(0, Ordering::Relaxed);
+    }
+
     pub fn waiting_counter(&self) -> &Arc<AtomicU64> {
         &self.waiting_counter
     }
@@ -211,12 +452,80 @@ impl Default for TranscodeStatusCache {
     }
 }
 
+/// Keeps a transcode from being evicted until it's dropped, see
+/// `TranscodeStatusCache::pin`.
+#[derive(Debug)]
+pub struct TranscodePin {
+    pins: Arc<DashMap<(String, Vec<u8>), usize>>,
+    key: (String, Vec<u8>),
+}
+
+impl Drop for TranscodePin {
+    fn drop(&mut self) {
+        if let dashmap::mapref::entry::Entry::Occupied(mut entry) =
+            self.pins.entry(self.key.clone())
+        {
+            *entry.get_mut() -= 1;
+            if *entry.get() == 0 {
+                entry.remove();
+            }
+        }
+    }
+}
+
+/// The share of a transcode's progress for decoding the input. The rest is
+/// for encoding the frames that are still buffered when decoding finishes,
+/// like the resampler's leftover input and the final chunk.
+const DECODE_PROGRESS: f32 = 0.95;
+
+/// The progress of transcoding a file, from 0 to 1.
+///
+/// This is a shared atomic so it can be updated by the worker while it's read
+/// by models and connections.
+#[derive(Debug, Clone, Default)]
+pub struct TranscodeProgress(Arc<AtomicU32>);
+
+impl TranscodeProgress {
+    pub fn get(&self) -> f32 {
+        f32::from_bits(self.0.load(Ordering::Relaxed))
+    }
+
+    pub fn set(&self, progress: f32) {
+        self.0.store(progress.to_bits(), Ordering::Relaxed);
+    }
+
+    /// Sets the progress from the fraction of the input decoded so far, see
+    /// `DECODE_PROGRESS`.
+    fn set_decoded(&self, fraction: f32) {
+        self.set(fraction.clamp(0.0, 1.0) * DECODE_PROGRESS);
+    }
+
+    /// Sets the progress from the number of chunks encoded after the input
+    /// was decoded, out of the chunks that were still buffered.
+    fn set_encoded(&self, encoded_chunks: u64, total_chunks: u64) {
+        let fraction = match total_chunks {
+            0 => 1.0,
+            _ => (encoded_chunks as f32 / total_chunks as f32).min(1.0),
+        };
+        self.set(DECODE_PROGRESS + fraction * (1.0 - DECODE_PROGRESS));
+    }
+
+    pub fn model(&self) -> ProgressModel {
+        ProgressModel::from(&self.0)
+    }
+}
+
 /// An item in the transcoding queue.
 #[derive(Debug, Clone, PartialEq, Eq, Hash)]
 pub struct TranscodeItem {
     pub hash_kind: String,
     pub hash: Vec<u8>,
     pub local_path: PathBuf,
+    /// The content type of the file's root.
+    pub content: TranscodeContent,
+    /// The track of a cue sheet, if only that part of the file is
+    /// transcoded.
+    pub cue: Option<CueTrack>,
 }
 
 /// When to transcode files.
@@ -228,166 +537,512 @@ pub enum TranscodePolicy {
     Always,
 }
 
-/// The queue of items to be transcoded.
-#[derive(Debug)]
-struct TranscodeQueue {
-    policy: Mutex<TranscodePolicy>,
-    queue: Mutex<PriorityQueue<TranscodeItem, u64>>,
-    ready: Condvar,
-    ready_counter: Arc<AtomicU64>,
+/// The codec and container of transcodes.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, uniffi::Enum)]
+pub enum TranscodeFormat {
+    /// Lossy Opus in Ogg, using the profile's encoder settings.
+    Opus,
+    /// Lossless FLAC, for receivers that want bit-perfect copies. The Opus
+    /// encoder settings are ignored.
+    Flac,
 }
 
-impl TranscodeQueue {
-    /// Creates a new TranscodeQueue.
-    pub fn new(policy: TranscodePolicy) -> Self {
-        TranscodeQueue {
-            policy: Mutex::new(policy),
-            queue: Mutex::new(PriorityQueue::new()),
-            ready: Condvar::new(),
-            ready_counter: Arc::new(AtomicU64::new(0)),
+impl TranscodeFormat {
+    /// Returns the file extension of transcodes in this format.
+    pub fn extension(&self) -> &'static str {
+        match self {
+            TranscodeFormat::Opus => "ogg",
+            TranscodeFormat::Flac => "flac",
         }
     }
+}
 
-    /// Sets the transcoding policy.
-    pub fn set_policy(&self, policy: TranscodePolicy) {
-        // update policy
-        {
-            let mut policy_guard = self.policy.lock().unwrap();
-            *policy_guard = policy;
+/// How the encoder controls the bitrate.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, uniffi::Enum)]
+pub enum TranscodeBitrateMode {
+    /// Unconstrained variable bitrate.
+    Vbr,
+    /// Variable bitrate constrained to stay close to the target.
+    ConstrainedVbr,
+    /// Constant bitrate.
+    Cbr,
+}
+
+/// The duration of each Opus frame.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, uniffi::Enum)]
+pub enum TranscodeFrameDuration {
+    Ms10,
+    Ms20,
+    Ms40,
+    Ms60,
+}
+
+impl TranscodeFrameDuration {
+    /// Returns the duration in milliseconds.
+    pub fn millis(&self) -> usize {
+        match self {
+            TranscodeFrameDuration::Ms10 => 10,
+            TranscodeFrameDuration::Ms20 => 20,
+            TranscodeFrameDuration::Ms40 => 40,
+            TranscodeFrameDuration::Ms60 => 60,
         }
+    }
 
-        {
-            let queue = self.queue.lock().unwrap();
+    /// Returns the number of sample frames per Opus frame at 48 kHz.
+    pub fn frames(&self) -> usize {
+        48000 / 1000 * self.millis()
+    }
+}
 
-            // update ready counter by re-counting queue
-            let ready_count = match policy {
-                TranscodePolicy::IfRequested => queue.iter().filter(|entry| *entry.1 > 0).count(),
-                TranscodePolicy::Always => queue.len(),
-            };
-            self.ready_counter
-                .store(ready_count as u64, Ordering::Relaxed);
+/// The Opus application mode, which tunes the encoder for a kind of signal.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, uniffi::Enum)]
+pub enum TranscodeApplication {
+    Audio,
+    Voip,
+    LowDelay,
+}
+
+impl From<TranscodeApplication> for opus::Application {
+    fn from(application: TranscodeApplication) -> Self {
+        match application {
+            TranscodeApplication::Audio => opus::Application::Audio,
+            TranscodeApplication::Voip => opus::Application::Voip,
+            TranscodeApplication::LowDelay => opus::Application::LowDelay,
+        }
+    }
+}
+
+/// The kind of audio in a library root, which picks the settings its files
+/// are transcoded with.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Hash, uniffi::Enum)]
+pub enum TranscodeContent {
+    /// Music, transcoded with the profile's settings.
+    #[default]
+    Music,
+    /// Spoken word like audiobooks and podcasts, transcoded to mono at a low
+    /// bitrate with the Opus speech mode, see `TranscodeProfile::for_content`.
+    Speech,
+}
+
+impl TranscodeContent {
+    /// Returns the string used to persist the content type.
+    pub fn key(&self) -> &'static str {
+        match self {
+            TranscodeContent::Music => "music",
+            TranscodeContent::Speech => "speech",
         }
+    }
 
-        // notify waiting consumers
-        self.ready.notify_all();
+    /// Parses a content type from a string created by `key`.
+    pub fn from_key(key: &str) -> anyhow::Result<Self> {
+        match key {
+            "music" => Ok(TranscodeContent::Music),
+            "speech" => Ok(TranscodeContent::Speech),
+            _ => anyhow::bail!("unknown content type: {key}"),
+        }
     }
+}
 
-    /// Adds items to the queue.
-    pub fn extend(&self, items: Vec<TranscodeItem>) {
-        // read policy before locking queue
-        let policy = {
-            let policy = self.policy.lock().unwrap();
-            *policy
-        };
+/// The image format of cover art embedded in transcodes.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, uniffi::Enum)]
+pub enum TranscodeArtFormat {
+    Jpeg,
+    Png,
+}
 
-        {
-            // extend queue
-            let mut queue = self.queue.lock().unwrap();
-            queue.extend(items.into_iter().map(|item| (item, 0)));
-
-            // update ready counter by re-counting queue
-            let ready_count = match policy {
-                TranscodePolicy::IfRequested => queue.iter().filter(|entry| *entry.1 > 0).count(),
-                TranscodePolicy::Always => queue.len(),
-            };
-            self.ready_counter
-                .store(ready_count as u64, Ordering::Relaxed);
+impl TranscodeArtFormat {
+    /// Returns the MIME type of images in this format.
+    fn media_type(&self) -> &'static str {
+        match self {
+            TranscodeArtFormat::Jpeg => "image/jpeg",
+            TranscodeArtFormat::Png => "image/png",
         }
+    }
 
-        // notify waiting consumers
-        self.ready.notify_all();
+    /// Returns the equivalent format of the image crate.
+    fn image_format(&self) -> ImageFormat {
+        match self {
+            TranscodeArtFormat::Jpeg => ImageFormat::Jpeg,
+            TranscodeArtFormat::Png => ImageFormat::Png,
+        }
     }
+}
 
-    /// Increases the priority of items in the queue.
-    pub fn prioritize<'a>(&self, hashes: impl Iterator<Item = (&'a str, &'a [u8])>) {
-        // read policy before locking queue
-        let policy = {
-            let policy = self.policy.lock().unwrap();
-            *policy
-        };
+/// Settings for cover art embedded in transcodes.
+///
+/// Art is taken from the front cover visual of the source file, or from a
+/// sidecar image like `cover.jpg` in the same directory if the file has no
+/// visuals.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, uniffi::Record)]
+pub struct TranscodeArtOptions {
+    /// Whether to embed cover art. Dropping art saves space.
+    pub embed: bool,
+    /// The maximum width and height of embedded art in pixels.
+    pub max_size: u32,
+    pub format: TranscodeArtFormat,
+    /// JPEG quality, from 1 to 100. Ignored for PNG.
+    pub quality: u8,
+    /// Whether to keep images that already fit within `max_size` at their
+    /// original size instead of scaling them up. Images that are already in
+    /// the output format are embedded without re-encoding.
+    pub keep_small: bool,
+}
 
-        let hashes: HashSet<(&str, &[u8])> = HashSet::from_iter(hashes);
+impl TranscodeArtOptions {
+    /// The smallest supported art size.
+    pub const MIN_SIZE: u32 = 16;
+    /// The largest supported art size.
+    pub const MAX_SIZE: u32 = 4096;
+
+    /// Checks that the art settings are supported.
+    fn validate(&self) -> anyhow::Result<()> {
+        if !(Self::MIN_SIZE..=Self::MAX_SIZE).contains(&self.max_size) {
+            anyhow::bail!(
+                "art size must be between {} and {}, got {}",
+                Self::MIN_SIZE,
+                Self::MAX_SIZE,
+                self.max_size
+            );
+        }
 
-        {
-            let mut queue = self.queue.lock().unwrap();
+        if !(1..=100).contains(&self.quality) {
+            anyhow::bail!(
+                "art quality must be between 1 and 100, got {}",
+                self.quality
+            );
+        }
+
+        Ok(())
+    }
+
+    /// Appends the profile key tokens for settings that differ from the
+    /// defaults, so that existing keys stay the same.
+    fn push_key_tokens(&self, key: &mut String) {
+        let default = Self::default();
+
+        if !self.embed {
+            key.push_str("-noart");
+            return;
+        }
+
+        if self.max_size != default.max_size {
+            key.push_str(&format!("-art{}", self.max_size));
+        }
+        if self.format == TranscodeArtFormat::Png {
+            key.push_str("-png");
+        }
+        if self.quality != default.quality {
+            key.push_str(&format!("-q{}", self.quality));
+        }
+        if self.keep_small {
+            key.push_str("-keepsmall");
+        }
+    }
+
+    /// Parses a profile key token created by `push_key_tokens`, returning
+    /// false if the token isn't an art token.
+    fn parse_key_token(&mut self, token: &str) -> anyhow::Result<bool> {
+        match token {
+            "noart" => self.embed = false,
+            "png" => self.format = TranscodeArtFormat::Png,
+            "keepsmall" => self.keep_small = true,
 
-            // increase priority
-            for (item, priority) in queue.iter_mut() {
-                if hashes.contains(&(item.hash_kind.as_str(), item.hash.as_slice())) {
-                    *priority += 1;
+            _ => {
+                if let Some(size) = token.strip_prefix("art") {
+                    self.max_size = size
+                        .parse()
+                        .context("failed to parse art size in profile key")?;
+                } else if let Some(quality) = token.strip_prefix('q') {
+                    self.quality = quality
+                        .parse()
+                        .context("failed to parse art quality in profile key")?;
+                } else {
+                    return Ok(false);
                 }
             }
+        }
 
-            // update ready counter by re-counting queue
-            let ready_count = match policy {
-                TranscodePolicy::IfRequested => queue.iter().filter(|entry| *entry.1 > 0).count(),
-                TranscodePolicy::Always => queue.len(),
-            };
-            self.ready_counter
-                .store(ready_count as u64, Ordering::Relaxed);
+        Ok(true)
+    }
+}
+
+impl Default for TranscodeArtOptions {
+    fn default() -> Self {
+        Self {
+            embed: true,
+            max_size: 500,
+            format: TranscodeArtFormat::Jpeg,
+            quality: 90,
+            keep_small: false,
+        }
+    }
+}
+
+/// Settings used to encode transcodes.
+///
+/// Transcodes are stored in a subdirectory of the transcodes directory named
+/// after the profile's key, so transcodes made with one profile are never
+/// served after switching to another.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, uniffi::Record)]
+pub struct TranscodeProfile {
+    pub format: TranscodeFormat,
+    /// Target bitrate in bits per second.
+    pub bitrate: u32,
+    pub bitrate_mode: TranscodeBitrateMode,
+    /// Encoder complexity, from 0 (fastest) to 10 (best quality).
+    pub complexity: u8,
+    pub frame_duration: TranscodeFrameDuration,
+    pub application: TranscodeApplication,
+    /// Whether to downmix surround input to stereo instead of encoding every
+    /// channel.
+    pub downmix_stereo: bool,
+    /// Whether to downmix all input to mono. This takes precedence over
+    /// `downmix_stereo`.
+    pub downmix_mono: bool,
+    /// Whether to normalize loudness using the OpusHead output gain, instead
+    /// of only writing R128 gain tags.
+    pub normalize_loudness: bool,
+    /// Settings for embedded cover art, used by every format.
+    pub art: TranscodeArtOptions,
+}
+
+impl TranscodeProfile {
+    /// The lowest bitrate supported by Opus.
+    pub const MIN_BITRATE: u32 = 6000;
+    /// The highest bitrate supported by Opus.
+    pub const MAX_BITRATE: u32 = 510000;
+    /// The bitrate of speech transcodes, which is plenty for mono speech.
+    pub const SPEECH_BITRATE: u32 = 32000;
+
+    /// Checks that the profile's settings are supported by the encoder.
+    pub fn validate(&self) -> anyhow::Result<()> {
+        if !(Self::MIN_BITRATE..=Self::MAX_BITRATE).contains(&self.bitrate) {
+            anyhow::bail!(
+                "bitrate must be between {} and {}, got {}",
+                Self::MIN_BITRATE,
+                Self::MAX_BITRATE,
+                self.bitrate
+            );
+        }
+
+        if self.complexity > 10 {
+            anyhow::bail!(
+                "complexity must be between 0 and 10, got {}",
+                self.complexity
+            );
         }
 
-        // notify waiting consumers
-        self.ready.notify_all();
+        self.art.validate()?;
+
+        Ok(())
     }
 
-    /// Removes items from the queue.
-    pub fn remove(&self, items: Vec<TranscodeItem>) {
-        // read policy before locking queue
-        let policy = {
-            let policy = self.policy.lock().unwrap();
-            *policy
+    /// Returns a string that uniquely identifies the profile's settings.
+    ///
+    /// This is used as the name of the profile's transcodes directory and to
+    /// persist the profile, and can be parsed with `from_key`.
+    pub fn key(&self) -> String {
+        // flac has no encoder settings
+        if self.format == TranscodeFormat::Flac {
+            let mut key = "flac".to_string();
+            self.art.push_key_tokens(&mut key);
+            return key;
+        }
+
+        let bitrate_mode = match self.bitrate_mode {
+            TranscodeBitrateMode::Vbr => "vbr",
+            TranscodeBitrateMode::ConstrainedVbr => "cvbr",
+            TranscodeBitrateMode::Cbr => "cbr",
+        };
+        let application = match self.application {
+            TranscodeApplication::Audio => "audio",
+            TranscodeApplication::Voip => "voip",
+            TranscodeApplication::LowDelay => "lowdelay",
         };
 
-        let hashes: HashSet<(&str, &[u8])> = HashSet::from_iter(
-            items
-                .iter()
-                .map(|item| (item.hash_kind.as_str(), item.hash.as_slice())),
+        let mut key = format!(
+            "opus-b{}-{}-c{}-f{}-{}",
+            self.bitrate,
+            bitrate_mode,
+            self.complexity,
+            self.frame_duration.millis(),
+            application
         );
 
-        {
-            // remove items from queue
-            let mut queue = self.queue.lock().unwrap();
-            queue.retain(|item, _priority| {
-                !hashes.contains(&(item.hash_kind.as_str(), item.hash.as_slice()))
-            });
+        // optional settings are only added when enabled so that existing keys
+        // stay the same
+        if self.downmix_stereo {
+            key.push_str("-stereo");
+        }
+        if self.downmix_mono {
+            key.push_str("-mono");
+        }
+        if self.normalize_loudness {
+            key.push_str("-norm");
+        }
+        self.art.push_key_tokens(&mut key);
 
-            // update ready counter by re-counting queue
-            let ready_count = match policy {
-                TranscodePolicy::IfRequested => queue.iter().filter(|entry| *entry.1 > 0).count(),
-                TranscodePolicy::Always => queue.len(),
-            };
-            self.ready_counter
-                .store(ready_count as u64, Ordering::Relaxed);
+        key
+    }
+
+    /// Returns the total bitrate for a file with the given channel count.
+    ///
+    /// The profile bitrate is used for mono and stereo. For surround, each
+    /// coupled stream uses the profile bitrate and each uncoupled stream uses
+    /// half of it.
+    pub fn total_bitrate(&self, channel_count: usize) -> u32 {
+        self.output_channel_count(channel_count)
+            .and_then(ChannelMapping::new)
+            .map(|mapping| {
+                (0..mapping.stream_count)
+                    .map(|stream| mapping.stream_bitrate(stream, self))
+                    .sum()
+            })
+            .unwrap_or(self.bitrate)
+    }
+
+    /// Returns the number of channels to encode for input with the given
+    /// channel count.
+    fn output_channel_count(&self, channel_count: usize) -> anyhow::Result<usize> {
+        match channel_count {
+            0 => anyhow::bail!("unsupported channel count: 0"),
+            1..=8 if self.downmix_mono => Ok(1),
+            1 | 2 => Ok(channel_count),
+            3..=8 if self.downmix_stereo => Ok(2),
+            3..=8 => Ok(channel_count),
+            _ => anyhow::bail!("unsupported channel count: {}", channel_count),
         }
     }
 
-    /// Waits for a job and takes it from the queue.
-    pub fn wait(&self) -> TranscodeItem {
-        let mut queue = self.queue.lock().unwrap();
-        loop {
-            // check for a job
-            let next = queue.pop_if(|_item, priority| {
-                let policy = self.policy.lock().unwrap();
-                match *policy {
-                    TranscodePolicy::IfRequested => *priority > 0,
-                    TranscodePolicy::Always => true,
+    /// Returns the settings used for files with the given content type.
+    ///
+    /// Speech is downmixed to mono and encoded with the Opus speech mode at
+    /// no more than `SPEECH_BITRATE`. Lossless profiles are used as they are.
+    pub fn for_content(&self, content: TranscodeContent) -> Self {
+        match (content, self.format) {
+            (TranscodeContent::Music, _) | (_, TranscodeFormat::Flac) => *self,
+            (TranscodeContent::Speech, TranscodeFormat::Opus) => Self {
+                bitrate: self.bitrate.min(Self::SPEECH_BITRATE),
+                application: TranscodeApplication::Voip,
+                downmix_mono: true,
+                ..*self
+            },
+        }
+    }
+
+    /// Parses a profile from a key created by `key`.
+    pub fn from_key(key: &str) -> anyhow::Result<Self> {
+        let mut tokens = key.split('-');
+
+        match tokens.next() {
+            Some("opus") => {}
+            Some("flac") => {
+                // flac only has art settings
+                let mut profile = Self {
+                    format: TranscodeFormat::Flac,
+                    ..Self::default()
+                };
+                for token in tokens {
+                    if !profile.art.parse_key_token(token)? {
+                        anyhow::bail!("unknown token `{token}` in profile key: {key}");
+                    }
                 }
-            });
 
-            match next {
-                Some((item, _priority)) => {
-                    // decrease ready counter
-                    self.ready_counter.fetch_sub(1, Ordering::Relaxed);
+                profile.validate()?;
 
-                    return item;
-                }
-                None => {
-                    // no job, wait for notification
-                    queue = self.ready.wait(queue).unwrap();
+                return Ok(profile);
+            }
+            _ => anyhow::bail!("unknown codec in profile key: {key}"),
+        }
+
+        let mut profile = Self::default();
+        for token in tokens {
+            if profile.art.parse_key_token(token)? {
+                continue;
+            }
+
+            match token {
+                "vbr" => profile.bitrate_mode = TranscodeBitrateMode::Vbr,
+                "cvbr" => profile.bitrate_mode = TranscodeBitrateMode::ConstrainedVbr,
+                "cbr" => profile.bitrate_mode = TranscodeBitrateMode::Cbr,
+
+                "audio" => profile.application = TranscodeApplication::Audio,
+                "voip" => profile.application = TranscodeApplication::Voip,
+                "lowdelay" => profile.application = TranscodeApplication::LowDelay,
+
+                "f10" => profile.frame_duration = TranscodeFrameDuration::Ms10,
+                "f20" => profile.frame_duration = TranscodeFrameDuration::Ms20,
+                "f40" => profile.frame_duration = TranscodeFrameDuration::Ms40,
+                "f60" => profile.frame_duration = TranscodeFrameDuration::Ms60,
+
+                "stereo" => profile.downmix_stereo = true,
+                "mono" => profile.downmix_mono = true,
+                "norm" => profile.normalize_loudness = true,
+
+                _ => {
+                    if let Some(bitrate) = token.strip_prefix('b') {
+                        profile.bitrate = bitrate
+                            .parse()
+                            .context("failed to parse bitrate in profile key")?;
+                    } else if let Some(complexity) = token.strip_prefix('c') {
+                        profile.complexity = complexity
+                            .parse()
+                            .context("failed to parse complexity in profile key")?;
+                    } else {
+                        anyhow::bail!("unknown token `{token}` in profile key: {key}");
+                    }
                 }
             }
         }
+
+        profile.validate()?;
+
+        Ok(profile)
+    }
+}
+
+impl Default for TranscodeProfile {
+    fn default() -> Self {
+        Self {
+            format: TranscodeFormat::Opus,
+            bitrate: 128000,
+            bitrate_mode: TranscodeBitrateMode::ConstrainedVbr,
+            complexity: 10,
+            frame_duration: TranscodeFrameDuration::Ms20,
+            application: TranscodeApplication::Audio,
+            downmix_stereo: false,
+            downmix_mono: false,
+            normalize_loudness: false,
+            art: TranscodeArtOptions::default(),
+        }
+    }
+}
+
+/// The error returned by a transcode that was cancelled.
+#[derive(Debug, thiserror::Error)]
+#[error("transcode was cancelled")]
+pub struct TranscodeCancelled;
+
+/// The error returned by a job that panicked.
+#[derive(Debug, thiserror::Error)]
+#[error("panicked: {0}")]
+pub struct JobPanicked(String);
+
+/// Runs a job, turning a panic into a `JobPanicked` error.
+///
+/// Decoders and encoders can panic on malformed files, which would otherwise
+/// take down the thread running the job.
+pub fn catch_panic<T>(job: impl FnOnce() -> anyhow::Result<T>) -> anyhow::Result<T> {
+    match std::panic::catch_unwind(AssertUnwindSafe(job)) {
+        Ok(res) => res,
+        Err(payload) => {
+            let message = payload
+                .downcast_ref::<&str>()
+                .map(|message| message.to_string())
+                .or_else(|| payload.downcast_ref::<String>().cloned())
+                .unwrap_or_else(|| "unknown panic".to_string());
+            Err(JobPanicked(message).into())
+        }
     }
 }
 
@@ -406,56 +1061,212 @@ pub enum TranscodeCommand {
     Prioritize(Vec<(String, Vec<u8>)>),
 
     /// Sent when files are removed from the library. Files are dequeued if
-    /// they are currently queued for transcoding.
+    /// they are currently queued for transcoding, and their transcodes are
+    /// cancelled if they're in progress.
     Remove(Vec<TranscodeItem>),
 
+    /// Cancel transcoding some files.
+    ///
+    /// Files are dequeued and running transcodes are stopped. Like evicted
+    /// files, they're kept out of the queue and only transcoded again when
+    /// requested.
+    Cancel(Vec<(String, Vec<u8>)>),
+
+    /// Delete the transcodes and statuses of some files, so they're
+    /// transcoded again when they're added. Sent when the settings the files
+    /// are transcoded with change, like their root's content type.
+    Reset(Vec<(String, Vec<u8>)>),
+
+    /// Sent after a scan with the metadata fingerprints of files, see
+    /// `metadata_fingerprint`.
+    ///
+    /// Transcodes made from older metadata are re-tagged without re-encoding
+    /// if they're Opus, or transcoded again otherwise.
+    CheckMetadata(Vec<(TranscodeItem, Vec<u8>)>),
+
+    /// Pause transcoding. Workers stop taking jobs, and running transcodes
+    /// wait until resumed.
+    Pause,
+
+    /// Resume transcoding after `Pause`.
+    Resume,
+
+    /// Mark files as Failed with a persisted error, so they aren't
+    /// transcoded again until they're retried.
+    ///
+    /// This should be sent before adding the files. Files that are already
+    /// transcoded are left as they are.
+    AddFailed(Vec<(String, Vec<u8>, String)>),
+
+    /// Clear the Failed status of files so they can be added again.
+    ClearFailed(Vec<(String, Vec<u8>)>),
+
     /// Delete transcodes of files that aren't in the library anymore.
-    CollectGarbage(Vec<u64>),
+    ///
+    /// Contains the hashes of all local files. Transcodes made with any
+    /// profile are deleted if their hash isn't in the set.
+    CollectGarbage(HashSet<(String, Vec<u8>)>),
 
     /// Set the transcode policy.
     SetPolicy(TranscodePolicy),
-}
-
-/// A handle to a pool of worker threads for transcoding files.
-pub struct TranscodePool {
-    transcodes_dir: PathBuf,
-    status_cache: TranscodeStatusCache,
 
-    queue: Arc<TranscodeQueue>,
-    inprogress_counter: RegionCounter,
+    /// Set the maximum total size of transcodes, or `None` for no limit.
+    ///
+    /// When the transcodes are over the limit, the least recently used ones
+    /// are evicted.
+    SetCacheSizeLimit(Option<u64>),
 
-    command_tx: mpsc::UnboundedSender<TranscodeCommand>,
-}
+    /// Set the number of worker threads.
+    SetWorkerCount(usize),
 
-impl TranscodePool {
-    /// Spawns the transcode worker pool and returns its handle.
+    /// Set whether transcoding runs in the background.
     ///
-    /// The transcode status cache is guaranteed to be populated after this
+    /// In background mode, workers run with a lower thread priority, and only
+    /// `BACKGROUND_WORKER_COUNT` workers run while a scan or transfer is
+    /// active.
+    SetBackground(bool),
+
+    /// Set whether Opus transcodes are verified before they're marked Ready,
+    /// see `verify_transcode`.
+    SetVerify(bool),
+
+    /// Sent when a scan or transfer starts.
+    ActivityStarted,
+
+    /// Sent when a scan or transfer finishes.
+    ActivityFinished,
+
+    /// Set the transcode profile.
+    ///
+    /// This clears the queue and the status cache, and then reloads the
+    /// status cache from the new profile's transcodes directory. Files need
+    /// to be added again after this.
+    SetProfile(TranscodeProfile),
+}
+
+/// An event sent from the transcoding pool to the library.
+#[derive(Debug)]
+pub enum TranscodeEvent {
+    /// Sent when a file is transcoded.
+    Finished {
+        hash_kind: String,
+        hash: Vec<u8>,
+        /// The album the file belongs to, used to compute album gain.
+        album: String,
+        /// The loudness measured while transcoding.
+        loudness: Option<Loudness>,
+    },
+
+    /// Sent when a file is transcoded or passed through with any format.
+    Ready {
+        hash_kind: String,
+        hash: Vec<u8>,
+        profile: TranscodeProfile,
+    },
+
+    /// Sent when transcoding a file fails.
+    Failed {
+        hash_kind: String,
+        hash: Vec<u8>,
+        profile: TranscodeProfile,
+        error: String,
+    },
+}
+
+/// A transcode found in a transcodes directory.
+struct TranscodesDirEntry {
+    local_path: PathBuf,
+    hash_kind: String,
+    hash: Vec<u8>,
+    file_size: u64,
+    modified: SystemTime,
+    passthrough: bool,
+}
+
+/// The settings a transcode pool starts with, see `TranscodePool::spawn`.
+#[derive(Debug)]
+pub struct TranscodePoolOptions {
+    pub transcodes_dir: PathBuf,
+    pub policy: TranscodePolicy,
+    pub profile: TranscodeProfile,
+    pub cache_size_limit: Option<u64>,
+    pub worker_count: usize,
+    pub background: bool,
+    pub verify: bool,
+}
+
+/// A handle to a pool of worker threads for transcoding files.
+pub struct TranscodePool {
+    transcodes_dir: PathBuf,
+    status_cache: TranscodeStatusCache,
+    profile: Arc<Mutex<TranscodeProfile>>,
+
+    queue: Arc<TranscodeQueue>,
+    inprogress_counter: RegionCounter,
+
+    /// The number of jobs that panicked.
+    panic_counter: Arc<AtomicU64>,
+    /// The number of workers that died and were restarted.
+    restart_counter: Arc<AtomicU64>,
+
+    command_tx: mpsc::UnboundedSender<TranscodeCommand>,
+}
+
+impl TranscodePool {
+    /// Spawns the transcode worker pool and returns its handle.
+    ///
+    /// The transcode status cache is guaranteed to be populated after this
     /// returns.
     pub fn spawn(
-        transcodes_dir: PathBuf,
-        initial_policy: TranscodePolicy,
+        options: TranscodePoolOptions,
         status_cache: TranscodeStatusCache,
+        event_tx: mpsc::UnboundedSender<TranscodeEvent>,
     ) -> Self {
+        let TranscodePoolOptions {
+            transcodes_dir,
+            policy,
+            profile,
+            cache_size_limit,
+            worker_count,
+            background,
+            verify,
+        } = options;
+
+        Self::remove_legacy_transcodes(&transcodes_dir);
+
         // initialize status cache
-        Self::read_transcodes_dir(&transcodes_dir, &status_cache);
+        let evicted_markers = Self::read_profile_dirs(&transcodes_dir, &profile, &status_cache);
 
-        let queue = Arc::new(TranscodeQueue::new(initial_policy));
+        let profile = Arc::new(Mutex::new(profile));
+        let queue = Arc::new(TranscodeQueue::new(policy));
         let inprogress_counter = RegionCounter::new();
+        let panic_counter = Arc::new(AtomicU64::new(0));
+        let restart_counter = Arc::new(AtomicU64::new(0));
 
         let (command_tx, command_rx) = tokio::sync::mpsc::unbounded_channel();
 
+        let context = WorkerContext {
+            transcodes_dir: transcodes_dir.clone(),
+            status_cache: status_cache.clone(),
+            profile: profile.clone(),
+            queue: queue.clone(),
+            inprogress_counter: inprogress_counter.clone(),
+            panic_counter: panic_counter.clone(),
+            background: Arc::new(AtomicBool::new(background)),
+            verify: Arc::new(AtomicBool::new(verify)),
+            event_tx,
+            make_output: TranscodeWorker::make_output,
+        };
+
         tokio::spawn({
-            let transcodes_dir = transcodes_dir.clone();
-            let status_cache = status_cache.clone();
-            let queue = queue.clone();
-            let inprogress_counter = inprogress_counter.clone();
+            let restart_counter = restart_counter.clone();
             async move {
                 if let Err(e) = Self::run(
-                    transcodes_dir,
-                    status_cache,
-                    queue,
-                    inprogress_counter,
+                    context,
+                    restart_counter,
+                    cache_size_limit,
+                    worker_count,
+                    evicted_markers,
                     command_rx,
                 )
                 .await
@@ -468,16 +1279,103 @@ impl TranscodePool {
         TranscodePool {
             transcodes_dir,
             status_cache,
+            profile,
             queue,
 
             inprogress_counter,
 
+            panic_counter,
+            restart_counter,
+
             command_tx,
         }
     }
 
-    // initialize the transcode status cache by reading the transcode cache directory
-    fn read_transcodes_dir(transcodes_dir: &Path, status_cache: &TranscodeStatusCache) {
+    /// Initializes the transcode status cache by reading the directories of a
+    /// profile and of its speech settings, and returns the evicted markers.
+    ///
+    /// Speech transcodes are made with different settings, so they're stored
+    /// in the speech profile's own directory. Evicted markers are kept in the
+    /// profile's directory for both.
+    fn read_profile_dirs(
+        transcodes_dir: &Path,
+        profile: &TranscodeProfile,
+        status_cache: &TranscodeStatusCache,
+    ) -> HashSet<(String, Vec<u8>)> {
+        let profile_dir = profile_transcodes_dir(transcodes_dir, profile);
+        Self::read_transcodes_dir(&profile_dir, profile, status_cache);
+
+        let speech_profile = profile.for_content(TranscodeContent::Speech);
+        if speech_profile != *profile {
+            let speech_dir = profile_transcodes_dir(transcodes_dir, &speech_profile);
+            Self::read_transcodes_dir(&speech_dir, &speech_profile, status_cache);
+        }
+
+        Self::read_evicted_markers(&profile_dir, status_cache)
+    }
+
+    /// Checks the checksums of the Ready transcodes against their manifests,
+    /// and returns the ones that were damaged on disk along with their paths
+    /// and revisions.
+    ///
+    /// This reads every transcode, so it runs in the background after the
+    /// transcodes are loaded instead of holding up startup.
+    fn verify_checksums(
+        status_cache: &TranscodeStatusCache,
+    ) -> Vec<((String, Vec<u8>), PathBuf, u64)> {
+        let ready = status_cache
+            .cache
+            .iter()
+            .filter_map(|entry| match entry.value() {
+                TranscodeStatus::Ready { local_path, .. } => {
+                    Some((entry.key().clone(), local_path.clone()))
+                }
+                _ => None,
+            })
+            .collect::<Vec<_>>();
+
+        ready
+            .into_par_iter()
+            .filter_map(|(key, local_path)| {
+                // don't read transcodes halfway through being re-tagged
+                let lock = status_cache.rewrite_lock(&key.0, &key.1);
+                let _guard = lock.blocking_read();
+                let revision = status_cache.revision(&key.0, &key.1);
+
+                let res = match TranscodeManifest::read(&local_path) {
+                    Ok(Some(manifest)) => manifest.verify_checksum(&local_path),
+                    Ok(None) => Err("missing manifest".to_string()),
+                    Err(e) => Err(format!("{e:#}")),
+                };
+
+                match res {
+                    Ok(()) => None,
+                    // evicted or reset since the statuses were read
+                    Err(_) if !local_path.exists() => None,
+                    Err(reason) => {
+                        log::info!(
+                            "found damaged transcode at {}: {reason}",
+                            local_path.display()
+                        );
+                        Some((key, local_path, revision))
+                    }
+                }
+            })
+            .collect()
+    }
+
+    // initialize the transcode status cache by reading a profile's transcode cache directory
+    //
+    // transcodes that don't match their manifest or were made by an older
+    // encoder are deleted, so they're queued again when the library adds them
+    //
+    // only the sizes are checked, since reading every file is slow with a
+    // large cache, see `verify_checksums`
+    fn read_transcodes_dir(
+        transcodes_dir: &Path,
+        profile: &TranscodeProfile,
+        status_cache: &TranscodeStatusCache,
+    ) {
         // create transcode cache directory if it doesn't exist
         if let Err(e) = std::fs::create_dir_all(transcodes_dir) {
             log::error!(
@@ -522,22 +1420,134 @@ impl TranscodePool {
             })
             .collect::<Vec<_>>();
 
+        // check transcodes against their manifests
+        let items = items
+            .into_par_iter()
+            .filter_map(|item| {
+                let res = match TranscodeManifest::read(&item.local_path) {
+                    Ok(Some(manifest)) => manifest
+                        .validate(&item.local_path, profile, &item.hash_kind, &item.hash)
+                        .map(|()| manifest.revision),
+                    Ok(None) => Err("missing manifest".to_string()),
+                    Err(e) => Err(format!("{e:#}")),
+                };
+
+                match res {
+                    Ok(revision) => Some((item, revision)),
+                    Err(reason) => {
+                        log::info!(
+                            "removing invalid transcode at {}: {reason}",
+                            item.local_path.display()
+                        );
+                        remove_transcode(&item.local_path);
+                        None
+                    }
+                }
+            })
+            .collect::<Vec<_>>();
+
         // update status cache
-        for (local_path, hash_kind, hash, file_size) in items {
+        for (item, revision) in items {
+            // files are only transcoded once, so a transcode that's also in
+            // another directory that was read is left over
+            if status_cache.get(&item.hash_kind, &item.hash).is_some() {
+                log::info!(
+                    "removing duplicate transcode at {}",
+                    item.local_path.display()
+                );
+                remove_transcode(&item.local_path);
+                continue;
+            }
+
+            status_cache.set_revision(item.hash_kind.clone(), item.hash.clone(), revision);
+
             status_cache.insert(
-                hash_kind,
-                hash,
+                item.hash_kind.clone(),
+                item.hash.clone(),
                 TranscodeStatus::Ready {
-                    local_path,
-                    file_size,
+                    local_path: item.local_path,
+                    file_size: item.file_size,
+                    passthrough: item.passthrough,
                 },
             );
+
+            // the modified time is the last time the file was served or created
+            status_cache.set_last_used(item.hash_kind, item.hash, item.modified);
+        }
+    }
+
+    /// Reads the markers of evicted files in a profile's transcode cache
+    /// directory, see `mark_evicted`.
+    ///
+    /// Markers of files that are transcoded are stale and deleted.
+    fn read_evicted_markers(
+        profile_dir: &Path,
+        status_cache: &TranscodeStatusCache,
+    ) -> HashSet<(String, Vec<u8>)> {
+        let Ok(entries) = std::fs::read_dir(profile_dir) else {
+            return HashSet::new();
+        };
+
+        entries
+            .filter_map(Result::ok)
+            .filter_map(|entry| {
+                let path = entry.path();
+                let (hash_kind, hash) = parse_evicted_marker(&path)?;
+
+                if status_cache.get(&hash_kind, &hash).is_some() {
+                    let _ = std::fs::remove_file(&path);
+                    return None;
+                }
+
+                Some((hash_kind, hash))
+            })
+            .collect()
+    }
+
+    /// Deletes transcodes stored directly in the transcodes directory.
+    ///
+    /// Older versions didn't have a directory for each profile. Their
+    /// transcodes have no manifest, so the profile they were made with is
+    /// unknown and they can't be moved to a profile's directory.
+    fn remove_legacy_transcodes(transcodes_dir: &Path) {
+        let Ok(entries) = std::fs::read_dir(transcodes_dir) else {
+            return;
+        };
+
+        let mut deleted_count = 0;
+        for entry in entries.filter_map(Result::ok) {
+            // profile directories are read separately
+            if entry.file_type().is_ok_and(|t| t.is_dir()) {
+                continue;
+            }
+
+            let local_path = match Self::parse_transcodes_dir_entry(&entry) {
+                Ok(Some(entry)) => entry.local_path,
+                Ok(None) | Err(_) => continue,
+            };
+
+            match std::fs::remove_file(&local_path) {
+                Ok(()) => {
+                    let _ = std::fs::remove_file(manifest::manifest_path(&local_path));
+                    deleted_count += 1;
+                }
+                Err(e) => {
+                    log::error!(
+                        "failed to delete legacy transcode at {}: {e:#}",
+                        local_path.display()
+                    );
+                }
+            }
+        }
+
+        if deleted_count > 0 {
+            log::info!("TranscodePool: deleted {deleted_count} legacy transcodes");
         }
     }
 
     fn parse_transcodes_dir_entry(
         entry: &std::fs::DirEntry,
-    ) -> anyhow::Result<Option<(PathBuf, String, Vec<u8>, u64)>> {
+    ) -> anyhow::Result<Option<TranscodesDirEntry>> {
         // get entry file type
         let file_type = entry.file_type().context("failed to get file type")?;
 
@@ -548,17 +1558,43 @@ impl TranscodePool {
 
         let path = entry.path();
 
+        // parse file name as <hash kind>-<hash hex>.<ogg|flac> for transcodes,
+        // or <hash kind>-<hash hex>.passthrough.<ext> for copies of the original
+        let file_stem = path
+            .file_stem()
+            .context("file missing name")?
+            .to_string_lossy();
+        let (file_stem, passthrough) = match file_stem.strip_suffix(".passthrough") {
+            Some(file_stem) => (file_stem, true),
+            None => (&*file_stem, false),
+        };
+
         // check if the file has a valid extension
         match path.extension() {
-            Some(ext) if ext == "ogg" => {}
+            Some(ext) if ext == "ogg" || ext == "flac" || passthrough => {}
             Some(ext) if ext == "tmp" => {
                 // remove temp files from previous runs
                 log::info!("removing old temp file: {}", path.display());
 
-                let _ = std::fs::remove_file(path);
+                let _ = std::fs::remove_file(&path);
+
+                return Ok(None);
+            }
+            Some(ext) if ext == MANIFEST_EXTENSION => {
+                // manifests are read with their transcodes, but remove
+                // manifests whose transcodes are gone
+                if !manifest::transcode_path(&path).exists() {
+                    log::info!("removing orphaned manifest: {}", path.display());
+
+                    let _ = std::fs::remove_file(&path);
+                }
 
                 return Ok(None);
             }
+            Some(ext) if ext == EVICTED_EXTENSION => {
+                // markers are read separately, see `read_evicted_markers`
+                return Ok(None);
+            }
             _ => {
                 log::warn!("unexpected file in transcodes dir: {}", path.display());
 
@@ -566,1237 +1602,5089 @@ impl TranscodePool {
             }
         }
 
-        // parse file name as <hash kind>-<hash hex>.ext
-        let file_stem = path
-            .file_stem()
-            .context("file missing name")?
-            .to_string_lossy();
         let (hash_kind, hash) = file_stem
             .split_once("-")
             .context("failed to parse file name")?;
         let hash_kind = hash_kind.to_string();
         let hash = hex::decode(hash).context("failed to decode hash bytes")?;
 
-        // get file size
-        let file_size = path
-            .metadata()
-            .context("failed to get file metadata")?
-            .len();
-
-        Ok(Some((path, hash_kind, hash, file_size)))
+        // get file size and modified time
+        let metadata = path.metadata().context("failed to get file metadata")?;
+        let file_size = metadata.len();
+        let modified = metadata.modified().unwrap_or(SystemTime::UNIX_EPOCH);
+
+        Ok(Some(TranscodesDirEntry {
+            local_path: path,
+            hash_kind,
+            hash,
+            file_size,
+            modified,
+            passthrough,
+        }))
     }
 
-    async fn run(
-        transcodes_dir: PathBuf,
-        status_cache: TranscodeStatusCache,
-        queue: Arc<TranscodeQueue>,
-        inprogress_counter: RegionCounter,
-        mut rx: mpsc::UnboundedReceiver<TranscodeCommand>,
-    ) -> anyhow::Result<()> {
-        // spawn transcode workers
-        // TODO
-        for _ in 0..8 {
-            TranscodeWorker::new(
-                transcodes_dir.clone(),
-                status_cache.clone(),
-                queue.clone(),
-                inprogress_counter.clone(),
-            );
-        }
-
-        loop {
-            tokio::select! {
-                Some(command) = rx.recv() => {
-                    match command {
-                        TranscodeCommand::Add(mut items) => {
-                            let mut seen: HashSet<(String, Vec<u8>)> = HashSet::new();
-                            items.retain(|item| {
-                                // remove duplicates from the same batch
-                                if !seen.insert((item.hash_kind.clone(), item.hash.clone())) {
-                                    log::trace!("TranscodePool: skipping duplicate file {}", item.local_path.display());
-                                    return false;
-                                }
-
-                                // remove items that are already waiting/transcoded/failed
-                                let status = status_cache.get(&item.hash_kind, &item.hash);
-                                match status {
-                                    Some(status) => {
-                                        log::trace!("TranscodePool: skipping file {} (status: {:?})", item.local_path.display(), *status);
-                                        false
-                                    },
-                                    None => {
-                                        true
-                                    },
-                                }
-                            });
-
-                            if !items.is_empty() {
-                                // estimate file sizes in parallel using rayon
-                                let (items, estimated_sizes) = tokio::task::spawn_blocking(move || {
-                                    let estimated_sizes = items.par_iter().map(|item| {
-                                        match estimate_file_size(&item.local_path) {
-                                            Ok(size) => Some(size),
-                                            Err(e) => {
-                                                log::warn!("TranscodePool: failed to estimate file size for {}: {e:#}", item.local_path.display());
-                                                None
-                                            }
-                                        }
-                                    }).collect::<Vec<_>>();
+    /// Deletes transcodes whose hashes aren't in `keep` from every profile's
+    /// transcodes directory, and removes their statuses from the cache.
+    fn collect_garbage(
+        transcodes_dir: &Path,
+        status_cache: &TranscodeStatusCache,
+        keep: &HashSet<(String, Vec<u8>)>,
+    ) {
+        let profile_dirs = match std::fs::read_dir(transcodes_dir) {
+            Ok(entries) => entries,
+            Err(e) => {
+                log::error!(
+                    "failed to read transcode cache directory at {}: {}",
+                    transcodes_dir.display(),
+                    e
+                );
+                return;
+            }
+        };
 
-                                    (items, estimated_sizes)
-                                }).await.context("failed to join file size task")?;
+        let mut deleted_count = 0;
+        let mut deleted_size = 0;
 
-                                // set statuses to Waiting
-                                for (i, item) in items.iter().enumerate() {
-                                    let estimated_size = estimated_sizes[i];
+        for profile_dir in profile_dirs.filter_map(Result::ok) {
+            if !profile_dir.file_type().is_ok_and(|t| t.is_dir()) {
+                continue;
+            }
 
-                                    status_cache.insert(
-                                        item.hash_kind.clone(),
-                                        item.hash.clone(),
-                                        TranscodeStatus::Waiting { estimated_size },
-                                    );
-                                }
+            let entries = match std::fs::read_dir(profile_dir.path()) {
+                Ok(entries) => entries,
+                Err(e) => {
+                    log::error!(
+                        "failed to read transcode cache directory at {}: {}",
+                        profile_dir.path().display(),
+                        e
+                    );
+                    continue;
+                }
+            };
 
-                                // add items to queue
-                                queue.extend(items);
-                            }
-                        },
+            for entry in entries.filter_map(Result::ok) {
+                // skip temp files, which belong to in-progress jobs
+                if entry.path().extension().is_some_and(|ext| ext == "tmp") {
+                    continue;
+                }
 
-                        TranscodeCommand::Prioritize(items) => {
-                            queue.prioritize(items.iter().map(|(kind, hash)| (kind.as_str(), hash.as_slice())));
-                        },
+                // delete markers of evicted files that aren't in the library
+                if let Some(key) = parse_evicted_marker(&entry.path()) {
+                    if !keep.contains(&key) {
+                        let _ = std::fs::remove_file(entry.path());
+                    }
+                    continue;
+                }
 
-                        TranscodeCommand::Remove(items) => {
-                            // clear statuses for items that are Waiting
-                            // if Ready or Failed they are left in the cache
-                            for item in &items {
-                                status_cache.remove_waiting(&item.hash_kind, &item.hash);
-                            }
+                let TranscodesDirEntry {
+                    local_path,
+                    hash_kind,
+                    hash,
+                    file_size,
+                    ..
+                } = match Self::parse_transcodes_dir_entry(&entry) {
+                    Ok(Some(res)) => res,
+                    Ok(None) | Err(_) => continue,
+                };
 
-                            // remove items from queue
-                            queue.remove(items);
-                        },
+                if keep.contains(&(hash_kind, hash)) {
+                    continue;
+                }
 
-                        TranscodeCommand::CollectGarbage(items) => todo!(),
+                match std::fs::remove_file(&local_path) {
+                    Ok(()) => {
+                        let _ = std::fs::remove_file(manifest::manifest_path(&local_path));
 
-                        TranscodeCommand::SetPolicy(policy) => {
-                            queue.set_policy(policy);
-                        }
+                        log::debug!("deleted unused transcode: {}", local_path.display());
+                        deleted_count += 1;
+                        deleted_size += file_size;
+                    }
+                    Err(e) => {
+                        log::error!(
+                            "failed to delete unused transcode at {}: {}",
+                            local_path.display(),
+                            e
+                        );
                     }
                 }
             }
         }
-    }
 
-    pub fn send(&self, command: TranscodeCommand) -> anyhow::Result<()> {
-        self.command_tx
-            .send(command)
-            .map_err(|e| anyhow::anyhow!("failed to send TranscodeCommand: {e:#}"))
-    }
+        // the cache only holds the current profile's statuses
+        let unused = status_cache
+            .cache
+            .iter()
+            .filter(|entry| {
+                !matches!(entry.value(), TranscodeStatus::Waiting { .. })
+                    && !keep.contains(entry.key())
+            })
+            .map(|entry| entry.key().clone())
+            .collect::<Vec<_>>();
+        for (hash_kind, hash) in unused {
+            status_cache.remove(&hash_kind, &hash);
+        }
 
-    pub fn transcodes_dir(&self) -> String {
-        self.transcodes_dir.to_string_lossy().to_string()
+        log::info!(
+            "TranscodePool: collected garbage, deleted {deleted_count} transcodes ({deleted_size} bytes)"
+        );
     }
 
-    pub fn transcodes_dir_size(&self) -> FileSizeModel {
-        let (size, estimated) = self.status_cache.cache.iter().fold(
-            (0, false),
-            |(acc_size, acc_estimated), e| match &*e {
-                TranscodeStatus::Waiting { estimated_size } => {
-                    (acc_size + estimated_size.unwrap_or(0), true)
-                }
-                TranscodeStatus::Ready { file_size, .. } => (acc_size + file_size, acc_estimated),
-                TranscodeStatus::Failed { .. } => (acc_size, acc_estimated),
-            },
-        );
+    /// Re-tags transcodes whose source metadata changed since they were made.
+    ///
+    /// Opus transcodes are re-tagged in place. Other transcodes are deleted
+    /// and returned with their estimated sizes to be transcoded again.
+    fn check_metadata(
+        status_cache: &TranscodeStatusCache,
+        profile: &TranscodeProfile,
+        items: Vec<(TranscodeItem, Vec<u8>)>,
+    ) -> Vec<(TranscodeItem, Option<u64>)> {
+        let mut redo = Vec::new();
+        let mut retagged_count = 0;
+
+        for (item, metadata) in items {
+            let (local_path, passthrough) =
+                match status_cache.get(&item.hash_kind, &item.hash).as_deref() {
+                    Some(TranscodeStatus::Ready {
+                        local_path,
+                        passthrough,
+                        ..
+                    }) => (local_path.clone(), *passthrough),
+                    _ => continue,
+                };
 
-        if estimated {
-            FileSizeModel::Estimated(size)
+            // transcodes without a manifest are made again on the next startup
+            let mut manifest = match TranscodeManifest::read(&local_path) {
+                Ok(Some(manifest)) => manifest,
+                Ok(None) => continue,
+                Err(e) => {
+                    log::warn!(
+                        "TranscodePool::check_metadata: failed to read manifest of {}: {e:#}",
+                        local_path.display()
+                    );
+                    continue;
+                }
+            };
+
+            match &manifest.metadata {
+                Some(prev) if *prev == metadata => continue,
+
+                // older manifests don't have a fingerprint, assume the tags are current
+                None => {
+                    manifest.metadata = Some(metadata);
+                    if let Err(e) = manifest.write(&local_path) {
+                        log::warn!(
+                            "TranscodePool::check_metadata: failed to write manifest of {}: {e:#}",
+                            local_path.display()
+                        );
+                    }
+                    continue;
+                }
+
+                Some(_) => {}
+            }
+
+            log::info!(
+                "TranscodePool: metadata changed, re-tagging {}",
+                item.local_path.display()
+            );
+
+            let item_profile = profile.for_content(item.content);
+
+            if !passthrough && item_profile.format == TranscodeFormat::Opus {
+                let lock = status_cache.rewrite_lock(&item.hash_kind, &item.hash);
+                let _guard = lock.blocking_write();
+
+                let res = retag_transcode(
+                    &local_path,
+                    &item.local_path,
+                    item.cue.as_ref(),
+                    &item_profile,
+                )
+                .and_then(|file_size| {
+                    manifest.metadata = Some(metadata);
+                    manifest.refresh(&local_path)?;
+                    manifest.write(&local_path)?;
+                    Ok(file_size)
+                });
+
+                match res {
+                    Ok(file_size) => {
+                        status_cache.set_retagged(
+                            &item.hash_kind,
+                            &item.hash,
+                            file_size,
+                            manifest.revision,
+                        );
+                        retagged_count += 1;
+                        continue;
+                    }
+                    Err(e) => {
+                        log::warn!(
+                            "TranscodePool::check_metadata: failed to re-tag {}, transcoding again: {e:#}",
+                            local_path.display()
+                        );
+                    }
+                }
+            }
+
+            remove_transcode(&local_path);
+            status_cache.remove(&item.hash_kind, &item.hash);
+
+            let estimated_size = estimate_item_size(&item, &item_profile).ok();
+            redo.push((item, estimated_size));
+        }
+
+        if retagged_count > 0 || !redo.is_empty() {
+            log::info!(
+                "TranscodePool: re-tagged {retagged_count} transcodes, transcoding {} again",
+                redo.len()
+            );
+        }
+
+        redo
+    }
+
+    async fn run(
+        context: WorkerContext,
+        restart_counter: Arc<AtomicU64>,
+        mut cache_size_limit: Option<u64>,
+        mut worker_count: usize,
+        mut evicted_markers: HashSet<(String, Vec<u8>)>,
+        mut rx: mpsc::UnboundedReceiver<TranscodeCommand>,
+    ) -> anyhow::Result<()> {
+        let WorkerContext {
+            transcodes_dir,
+            status_cache,
+            profile,
+            queue,
+            background,
+            verify,
+            event_tx,
+            ..
+        } = context.clone();
+
+        // number of scans and transfers in progress
+        let mut active_count = 0;
+
+        // workers send their index when they die so they can be restarted
+        let (worker_exit_tx, mut worker_exit_rx) = mpsc::unbounded_channel();
+
+        // metadata checks run in the background, since re-tagging waits for
+        // transfers of the transcodes, and send back the transcodes to make
+        // again along with the profile they were checked with
+        let (metadata_tx, mut metadata_rx) = mpsc::unbounded_channel();
+
+        // checksums are verified in the background after transcodes are
+        // loaded, which sends back the damaged transcodes along with the
+        // profile they were loaded with
+        let (damaged_tx, mut damaged_rx) = mpsc::unbounded_channel();
+        let spawn_verify_checksums = |checked_profile: TranscodeProfile| {
+            let status_cache = status_cache.clone();
+            let damaged_tx = damaged_tx.clone();
+            tokio::spawn(async move {
+                let task =
+                    tokio::task::spawn_blocking(move || Self::verify_checksums(&status_cache));
+                match task.await {
+                    Ok(damaged) => {
+                        let _ = damaged_tx.send((checked_profile, damaged));
+                    }
+                    Err(e) => {
+                        log::error!("TranscodePool: failed to join verify checksums task: {e:#}");
+                    }
+                }
+            });
+        };
+        spawn_verify_checksums(*profile.lock().unwrap());
+
+        let spawn_worker = |index: usize| {
+            TranscodeWorker::new(index, context.clone(), worker_exit_tx.clone());
+        };
+
+        // workers are never stopped, extra workers just wait when the count is lowered
+        let mut spawned_count = 0;
+        let mut update_workers = |worker_count: usize, active_count: usize| {
+            while spawned_count < worker_count {
+                spawn_worker(spawned_count);
+                spawned_count += 1;
+            }
+
+            let limit = if background.load(Ordering::Relaxed) && active_count > 0 {
+                worker_count.min(BACKGROUND_WORKER_COUNT)
+            } else {
+                worker_count
+            };
+            queue.set_worker_limit(limit);
+        };
+
+        update_workers(worker_count, active_count);
+
+        // files in the library, used to transcode evicted files again
+        let mut sources: HashMap<(String, Vec<u8>), TranscodeItem> = HashMap::new();
+
+        // files that were evicted from the cache, which are only transcoded
+        // again when they're requested
+        //
+        // they're marked in the profile's directory, and files evicted before
+        // a restart are moved here from evicted_markers when they're added
+        let mut evicted: HashMap<(String, Vec<u8>), TranscodeItem> = HashMap::new();
+
+        let profile_dir = || profile_transcodes_dir(&transcodes_dir, &profile.lock().unwrap());
+
+        let mut evict_interval = tokio::time::interval(Duration::from_secs(5));
+
+        loop {
+            tokio::select! {
+                Some(exit) = worker_exit_rx.recv() => {
+                    match exit {
+                        WorkerExit::Failed(index) => {
+                            log::warn!("TranscodePool: restarting transcode worker {index}");
+                            restart_counter.fetch_add(1, Ordering::Relaxed);
+                            spawn_worker(index);
+                        }
+
+                        // the new thread is spawned from the pool, so it
+                        // doesn't inherit the old thread's priority
+                        WorkerExit::Retired(index) => {
+                            log::info!("TranscodePool: replacing transcode worker {index} to restore its priority");
+                            spawn_worker(index);
+                        }
+                    }
+                }
+
+                Some((checked_profile, damaged)) = damaged_rx.recv() => {
+                    // after a profile change, the statuses belong to the new profile
+                    if checked_profile != *profile.lock().unwrap() {
+                        continue;
+                    }
+
+                    let mut paths = Vec::new();
+                    let mut redo = Vec::new();
+                    for (key, local_path, revision) in damaged {
+                        // skip transcodes that were changed since they were checked
+                        let file_size = match status_cache.get(&key.0, &key.1).as_deref() {
+                            Some(TranscodeStatus::Ready { local_path: path, file_size, .. })
+                                if *path == local_path
+                                    && status_cache.revision(&key.0, &key.1) == revision =>
+                            {
+                                *file_size
+                            }
+                            _ => continue,
+                        };
+
+                        status_cache.remove(&key.0, &key.1);
+                        paths.push(local_path);
+
+                        // make files in the library again, like after a reset
+                        if let Some(item) = sources.get(&key) {
+                            status_cache.insert(
+                                key.0.clone(),
+                                key.1.clone(),
+                                TranscodeStatus::Waiting { estimated_size: Some(file_size) },
+                            );
+                            redo.push(item.clone());
+                        }
+                    }
+
+                    if paths.is_empty() {
+                        continue;
+                    }
+
+                    log::info!("TranscodePool: deleting {} damaged transcodes", paths.len());
+
+                    tokio::task::spawn_blocking(move || {
+                        for path in paths {
+                            remove_transcode(&path);
+                        }
+                    }).await.context("failed to join remove damaged transcodes task")?;
+
+                    queue.extend(redo);
+                }
+
+                Some((checked_profile, redo)) = metadata_rx.recv() => {
+                    // after a profile change, the files are transcoded again anyway
+                    if checked_profile != *profile.lock().unwrap() {
+                        continue;
+                    }
+
+                    // transcodes that couldn't be re-tagged are made again,
+                    // unless they were reset or re-added since
+                    let redo = redo
+                        .into_iter()
+                        .filter(|(item, _)| status_cache.get(&item.hash_kind, &item.hash).is_none())
+                        .collect::<Vec<_>>();
+                    if !redo.is_empty() {
+                        for (item, estimated_size) in &redo {
+                            status_cache.insert(
+                                item.hash_kind.clone(),
+                                item.hash.clone(),
+                                TranscodeStatus::Waiting { estimated_size: *estimated_size },
+                            );
+                        }
+
+                        queue.extend(redo.into_iter().map(|(item, _)| item).collect());
+                    }
+                }
+
+                Some(command) = rx.recv() => {
+                    match command {
+                        TranscodeCommand::Add(mut items) => {
+                            for item in &items {
+                                sources.insert((item.hash_kind.clone(), item.hash.clone()), item.clone());
+                            }
+
+                            let mut seen: HashSet<(String, Vec<u8>)> = HashSet::new();
+                            items.retain(|item| {
+                                // remove duplicates from the same batch
+                                if !seen.insert((item.hash_kind.clone(), item.hash.clone())) {
+                                    log::trace!("TranscodePool: skipping duplicate file {}", item.local_path.display());
+                                    return false;
+                                }
+
+                                // remove items that are already waiting/transcoded/failed
+                                let status = status_cache.get(&item.hash_kind, &item.hash);
+                                match status {
+                                    Some(status) => {
+                                        log::trace!("TranscodePool: skipping file {} (status: {:?})", item.local_path.display(), *status);
+                                        false
+                                    },
+                                    None => {
+                                        true
+                                    },
+                                }
+                            });
+
+                            if !items.is_empty() {
+                                let profile = *profile.lock().unwrap();
+
+                                // estimate file sizes in parallel using rayon
+                                let (items, estimated_sizes) = tokio::task::spawn_blocking(move || {
+                                    let estimated_sizes = items.par_iter().map(|item| {
+                                        match estimate_item_size(item, &profile.for_content(item.content)) {
+                                            Ok(size) => Some(size),
+                                            Err(e) => {
+                                                log::warn!("TranscodePool: failed to estimate file size for {}: {e:#}", item.local_path.display());
+                                                None
+                                            }
+                                        }
+                                    }).collect::<Vec<_>>();
+
+                                    (items, estimated_sizes)
+                                }).await.context("failed to join file size task")?;
+
+                                // set statuses to Waiting
+                                for (i, item) in items.iter().enumerate() {
+                                    let estimated_size = estimated_sizes[i];
+
+                                    status_cache.insert(
+                                        item.hash_kind.clone(),
+                                        item.hash.clone(),
+                                        TranscodeStatus::Waiting { estimated_size },
+                                    );
+                                }
+
+                                // files evicted before a restart stay out of the queue
+                                let (items, evicted_items): (Vec<_>, Vec<_>) = items
+                                    .into_iter()
+                                    .partition(|item| !evicted_markers.remove(&(item.hash_kind.clone(), item.hash.clone())));
+                                for item in evicted_items {
+                                    evicted.insert((item.hash_kind.clone(), item.hash.clone()), item);
+                                }
+
+                                // add items to queue
+                                queue.extend(items);
+                            }
+                        },
+
+                        TranscodeCommand::Prioritize(items) => {
+                            // requested files that were evicted are transcoded again
+                            let requeue = items.iter().filter_map(|key| evicted.remove(key)).collect::<Vec<_>>();
+                            let unmark = requeue
+                                .iter()
+                                .map(|item| (item.hash_kind.clone(), item.hash.clone()))
+                                .chain(items.iter().filter(|key| evicted_markers.remove(*key)).cloned())
+                                .collect::<Vec<_>>();
+                            if !unmark.is_empty() {
+                                let profile_dir = profile_dir();
+                                for (hash_kind, hash) in &unmark {
+                                    unmark_evicted(&profile_dir, hash_kind, hash);
+                                }
+                            }
+                            if !requeue.is_empty() {
+                                queue.extend(requeue);
+                            }
+
+                            queue.prioritize(items.iter().map(|(kind, hash)| (kind.as_str(), hash.as_slice())));
+                        },
+
+                        TranscodeCommand::Remove(items) => {
+                            // clear statuses for items that are Waiting
+                            // if Ready or Failed they are left in the cache
+                            for item in &items {
+                                status_cache.remove_waiting(&item.hash_kind, &item.hash);
+
+                                let key = (item.hash_kind.clone(), item.hash.clone());
+                                sources.remove(&key);
+                                if evicted.remove(&key).is_some() || evicted_markers.remove(&key) {
+                                    unmark_evicted(&profile_dir(), &item.hash_kind, &item.hash);
+                                }
+                            }
+
+                            // remove items from queue and stop running transcodes
+                            let hashes = || items.iter().map(|item| (item.hash_kind.as_str(), item.hash.as_slice()));
+                            queue.remove(hashes());
+                            queue.cancel(hashes());
+                        },
+
+                        TranscodeCommand::Cancel(keys) => {
+                            // only files that aren't transcoded yet can be cancelled
+                            let items = keys
+                                .into_iter()
+                                .filter(|(hash_kind, hash)| {
+                                    status_cache.get(hash_kind, hash).is_some_and(|status| {
+                                        matches!(*status, TranscodeStatus::Waiting { .. })
+                                    })
+                                })
+                                .filter_map(|key| sources.get(&key).cloned())
+                                .collect::<Vec<_>>();
+
+                            let hashes = || items.iter().map(|item| (item.hash_kind.as_str(), item.hash.as_slice()));
+                            queue.remove(hashes());
+                            queue.cancel(hashes());
+
+                            // keep them out of the queue until they're requested
+                            let profile_dir = profile_dir();
+                            for item in items {
+                                mark_evicted(&profile_dir, &item.hash_kind, &item.hash);
+                                evicted.insert((item.hash_kind.clone(), item.hash.clone()), item);
+                            }
+                        },
+
+                        TranscodeCommand::Reset(keys) => {
+                            // stop transcoding them with the old settings
+                            let hashes = || keys.iter().map(|(hash_kind, hash)| (hash_kind.as_str(), hash.as_slice()));
+                            queue.remove(hashes());
+                            queue.cancel(hashes());
+
+                            let mut paths = Vec::new();
+                            for key in &keys {
+                                if let Some(TranscodeStatus::Ready { local_path, .. }) = status_cache.get(&key.0, &key.1).as_deref() {
+                                    paths.push(local_path.clone());
+                                }
+                                status_cache.remove(&key.0, &key.1);
+
+                                sources.remove(key);
+                                if evicted.remove(key).is_some() || evicted_markers.remove(key) {
+                                    unmark_evicted(&profile_dir(), &key.0, &key.1);
+                                }
+                            }
+
+                            log::info!("TranscodePool: resetting {} files, deleting {} transcodes", keys.len(), paths.len());
+
+                            tokio::task::spawn_blocking(move || {
+                                for path in paths {
+                                    remove_transcode(&path);
+                                }
+                            }).await.context("failed to join reset task")?;
+                        }
+
+                        TranscodeCommand::CheckMetadata(items) => {
+                            let profile = *profile.lock().unwrap();
+
+                            let task = tokio::task::spawn_blocking({
+                                let status_cache = status_cache.clone();
+                                move || Self::check_metadata(&status_cache, &profile, items)
+                            });
+
+                            let metadata_tx = metadata_tx.clone();
+                            tokio::spawn(async move {
+                                match task.await {
+                                    Ok(redo) => {
+                                        let _ = metadata_tx.send((profile, redo));
+                                    }
+                                    Err(e) => {
+                                        log::error!("TranscodePool: failed to join metadata task: {e:#}");
+                                    }
+                                }
+                            });
+                        }
+
+                        TranscodeCommand::Pause => {
+                            log::info!("TranscodePool: pausing");
+                            queue.set_paused(true);
+                        }
+
+                        TranscodeCommand::Resume => {
+                            log::info!("TranscodePool: resuming");
+                            queue.set_paused(false);
+                        }
+
+                        TranscodeCommand::AddFailed(items) => {
+                            for (hash_kind, hash, error) in items {
+                                if status_cache.get(&hash_kind, &hash).is_some() {
+                                    continue;
+                                }
+
+                                status_cache.insert(hash_kind, hash, TranscodeStatus::Failed { error: anyhow::anyhow!(error) });
+                            }
+                        },
+
+                        TranscodeCommand::ClearFailed(items) => {
+                            for (hash_kind, hash) in items {
+                                status_cache.remove_failed(&hash_kind, &hash);
+                            }
+                        },
+
+                        TranscodeCommand::CollectGarbage(keep) => {
+                            sources.retain(|key, _| keep.contains(key));
+                            // their markers are deleted with the unused transcodes
+                            evicted_markers.retain(|key| keep.contains(key));
+                            evicted.retain(|key, _| {
+                                let retain = keep.contains(key);
+                                if !retain {
+                                    status_cache.remove_waiting(&key.0, &key.1);
+                                }
+                                retain
+                            });
+
+                            let transcodes_dir = transcodes_dir.clone();
+                            let status_cache = status_cache.clone();
+                            tokio::task::spawn_blocking(move || {
+                                Self::collect_garbage(&transcodes_dir, &status_cache, &keep);
+                            }).await.context("failed to join collect garbage task")?;
+                        },
+
+                        TranscodeCommand::SetPolicy(policy) => {
+                            queue.set_policy(policy);
+                        }
+
+                        TranscodeCommand::SetCacheSizeLimit(limit) => {
+                            cache_size_limit = limit;
+
+                            // evict right away if the limit was lowered
+                            evict_interval.reset_immediately();
+                        }
+
+                        TranscodeCommand::SetWorkerCount(count) => {
+                            worker_count = count.max(1);
+                            update_workers(worker_count, active_count);
+                        }
+
+                        TranscodeCommand::SetBackground(enabled) => {
+                            background.store(enabled, Ordering::Relaxed);
+                            update_workers(worker_count, active_count);
+                        }
+
+                        TranscodeCommand::SetVerify(enabled) => {
+                            verify.store(enabled, Ordering::Relaxed);
+                        }
+
+                        TranscodeCommand::ActivityStarted => {
+                            active_count += 1;
+                            update_workers(worker_count, active_count);
+                        }
+
+                        TranscodeCommand::ActivityFinished => {
+                            active_count = active_count.saturating_sub(1);
+                            update_workers(worker_count, active_count);
+                        }
+
+                        TranscodeCommand::SetProfile(new_profile) => {
+                            {
+                                let mut profile = profile.lock().unwrap();
+                                if *profile == new_profile {
+                                    continue;
+                                }
+                                *profile = new_profile;
+                            }
+
+                            log::info!("TranscodePool: switching to profile {}", new_profile.key());
+
+                            // statuses and queued items belong to the old profile
+                            queue.clear();
+                            status_cache.clear();
+                            evicted.clear();
+
+                            // load transcodes made with the new profile
+                            let transcodes_dir = transcodes_dir.clone();
+                            let status_cache = status_cache.clone();
+                            evicted_markers = tokio::task::spawn_blocking(move || {
+                                Self::read_profile_dirs(&transcodes_dir, &new_profile, &status_cache)
+                            }).await.context("failed to join read transcodes dir task")?;
+                            spawn_verify_checksums(new_profile);
+                        }
+                    }
+                }
+
+                _ = evict_interval.tick() => {
+                    let Some(size_limit) = cache_size_limit else {
+                        continue;
+                    };
+                    if status_cache.ready_size() <= size_limit {
+                        continue;
+                    }
+
+                    let status_cache = status_cache.clone();
+                    let profile_dir = profile_dir();
+                    (sources, evicted) = tokio::task::spawn_blocking(move || {
+                        Self::evict(&status_cache, &profile_dir, &sources, &mut evicted, size_limit);
+                        (sources, evicted)
+                    }).await.context("failed to join evict task")?;
+                }
+            }
+        }
+    }
+
+    /// Evicts the least recently used transcodes until the Ready transcodes
+    /// fit in the size limit.
+    ///
+    /// Evicted files are set back to Waiting. They're kept out of the queue
+    /// and only transcoded again when requested, like with the IfRequested
+    /// policy, so they don't fill up the cache again right away. A marker is
+    /// left in the profile's directory so that this is remembered across
+    /// restarts.
+    ///
+    /// Pinned transcodes, like ones that server jobs are ready to send, and
+    /// transcodes whose rewrite lock is held aren't evicted.
+    fn evict(
+        status_cache: &TranscodeStatusCache,
+        profile_dir: &Path,
+        sources: &HashMap<(String, Vec<u8>), TranscodeItem>,
+        evicted: &mut HashMap<(String, Vec<u8>), TranscodeItem>,
+        size_limit: u64,
+    ) {
+        let mut size = status_cache.ready_size();
+
+        // least recently used first
+        let mut candidates = status_cache
+            .cache
+            .iter()
+            .filter_map(|entry| match entry.value() {
+                TranscodeStatus::Ready {
+                    local_path,
+                    file_size,
+                    ..
+                } if !status_cache.is_pinned(&entry.key().0, &entry.key().1) => {
+                    let last_used = status_cache
+                        .last_used
+                        .get(entry.key())
+                        .map(|last_used| *last_used)
+                        .unwrap_or(SystemTime::UNIX_EPOCH);
+                    Some((
+                        last_used,
+                        entry.key().clone(),
+                        local_path.clone(),
+                        *file_size,
+                    ))
+                }
+                _ => None,
+            })
+            .collect::<Vec<_>>();
+        candidates.sort_by_key(|(last_used, ..)| *last_used);
+
+        for (_, key, local_path, file_size) in candidates {
+            if size <= size_limit {
+                break;
+            }
+
+            // skip transcodes that are being sent or re-tagged, or were pinned
+            // since the candidates were collected
+            // the lock is held until the status is updated, so transfers that
+            // start after this don't read a deleted file
+            let lock = status_cache.rewrite_lock(&key.0, &key.1);
+            let Ok(_guard) = lock.try_write() else {
+                continue;
+            };
+            if status_cache.is_pinned(&key.0, &key.1) {
+                continue;
+            }
+
+            if let Err(e) = std::fs::remove_file(&local_path) {
+                log::error!(
+                    "failed to evict transcode at {}: {}",
+                    local_path.display(),
+                    e
+                );
+                continue;
+            }
+
+            let _ = std::fs::remove_file(manifest::manifest_path(&local_path));
+
+            log::debug!("evicted transcode: {}", local_path.display());
+            size = size.saturating_sub(file_size);
+
+            let (hash_kind, hash) = key.clone();
+            match sources.get(&key) {
+                Some(source) => {
+                    status_cache.insert(
+                        hash_kind,
+                        hash,
+                        TranscodeStatus::Waiting {
+                            estimated_size: Some(file_size),
+                        },
+                    );
+                    mark_evicted(profile_dir, &key.0, &key.1);
+                    evicted.insert(key, source.clone());
+                }
+
+                // the file isn't in the library anymore
+                None => status_cache.remove(&hash_kind, &hash),
+            }
+        }
+    }
+
+    pub fn send(&self, command: TranscodeCommand) -> anyhow::Result<()> {
+        self.command_tx
+            .send(command)
+            .map_err(|e| anyhow::anyhow!("failed to send TranscodeCommand: {e:#}"))
+    }
+
+    pub fn transcodes_dir(&self) -> String {
+        self.transcodes_dir.to_string_lossy().to_string()
+    }
+
+    pub fn profile(&self) -> TranscodeProfile {
+        *self.profile.lock().unwrap()
+    }
+
+    pub fn status_cache(&self) -> &TranscodeStatusCache {
+        &self.status_cache
+    }
+
+    /// Returns the path of a file's Opus transcode if it's ready.
+    ///
+    /// Passthrough files and FLAC transcodes aren't included since they aren't
+    /// Opus files.
+    pub fn ready_path(&self, hash_kind: &str, hash: &[u8]) -> Option<PathBuf> {
+        match &*self.status_cache.get(hash_kind, hash)? {
+            TranscodeStatus::Ready {
+                local_path,
+                passthrough: false,
+                ..
+            } if local_path.extension().is_some_and(|ext| ext == "ogg") => Some(local_path.clone()),
+            _ => None,
+        }
+    }
+
+    pub fn transcodes_dir_size(&self) -> FileSizeModel {
+        let (size, estimated) = self.status_cache.cache.iter().fold(
+            (0, false),
+            |(acc_size, acc_estimated), e| match &*e {
+                TranscodeStatus::Waiting { estimated_size } => {
+                    (acc_size + estimated_size.unwrap_or(0), true)
+                }
+                TranscodeStatus::Ready { file_size, .. } => (acc_size + file_size, acc_estimated),
+                TranscodeStatus::Failed { .. } => (acc_size, acc_estimated),
+            },
+        );
+
+        if estimated {
+            FileSizeModel::Estimated(size)
+        } else {
+            FileSizeModel::Actual(size)
+        }
+    }
+
+    /// Returns the total size of the transcodes in the cache.
+    pub fn cache_size(&self) -> u64 {
+        self.status_cache.ready_size()
+    }
+
+    pub fn waiting_count_model(&self) -> CounterModel {
+        CounterModel::from(&self.status_cache.waiting_counter)
+    }
+
+    pub fn queued_count_model(&self) -> CounterModel {
+        CounterModel::from(self.queue.ready_counter())
+    }
+
+    pub fn inprogress_count_model(&self) -> CounterModel {
+        CounterModel::from(&self.inprogress_counter.0)
+    }
+
+    pub fn ready_count_model(&self) -> CounterModel {
+        CounterModel::from(&self.status_cache.ready_counter)
+    }
+
+    pub fn failed_count_model(&self) -> CounterModel {
+        CounterModel::from(&self.status_cache.failed_counter)
+    }
+
+    pub fn panic_count_model(&self) -> CounterModel {
+        CounterModel::from(&self.panic_counter)
+    }
+
+    pub fn restart_count_model(&self) -> CounterModel {
+        CounterModel::from(&self.restart_counter)
+    }
+
+    /// Counts jobs outside the pool that panicked, like hashing during scans.
+    pub fn record_panics(&self, count: u64) {
+        self.panic_counter.fetch_add(count, Ordering::Relaxed);
+    }
+}
+
+/// Why a transcode worker thread exited, sent to the pool so it can start a
+/// new thread in its place.
+#[derive(Debug)]
+enum WorkerExit {
+    /// The worker failed outside of a job.
+    Failed(usize),
+    /// The worker's priority was lowered in background mode, and it exited
+    /// after background mode was turned off.
+    Retired(usize),
+}
+
+/// Makes a job's file at its temp path, returning the file's size and the
+/// transcode's output if it was encoded.
+///
+/// Workers normally use `TranscodeWorker::make_output`. Tests pass their own
+/// to make jobs fail in ways that fixtures can't, like panicking.
+type MakeOutput = fn(&OutputJob<'_>) -> anyhow::Result<(u64, Option<TranscodeOutput>)>;
+
+/// The state shared by the pool and its workers.
+#[derive(Clone)]
+struct WorkerContext {
+    transcodes_dir: PathBuf,
+    status_cache: TranscodeStatusCache,
+    profile: Arc<Mutex<TranscodeProfile>>,
+    queue: Arc<TranscodeQueue>,
+    inprogress_counter: RegionCounter,
+    panic_counter: Arc<AtomicU64>,
+    /// Workers lower their priority when this is set.
+    background: Arc<AtomicBool>,
+    /// Workers decode their transcodes again when this is set.
+    verify: Arc<AtomicBool>,
+    event_tx: mpsc::UnboundedSender<TranscodeEvent>,
+    make_output: MakeOutput,
+}
+
+/// What a worker needs to make a job's file, see `MakeOutput`.
+struct OutputJob<'a> {
+    item: &'a TranscodeItem,
+    profile: &'a TranscodeProfile,
+    /// The extension of the source if it's copied instead of transcoded.
+    passthrough_extension: Option<&'a str>,
+    temp_path: &'a Path,
+    progress: &'a TranscodeProgress,
+    interrupt: &'a TranscodeInterrupt,
+    verify: bool,
+}
+
+struct TranscodeWorker {}
+
+impl TranscodeWorker {
+    /// Start a new transcode worker thread and return a handle to it.
+    ///
+    /// `index` is the worker's position in the pool, used to check it against
+    /// the worker limit. When the worker exits, it sends its index to
+    /// `exit_tx` so the pool can start a new thread in its place.
+    pub fn new(
+        index: usize,
+        context: WorkerContext,
+        exit_tx: mpsc::UnboundedSender<WorkerExit>,
+    ) -> Self {
+        std::thread::spawn(move || {
+            // jobs catch their own panics, this catches anything else
+            let res = catch_panic(|| Self::run(index, &context));
+
+            match res {
+                Ok(()) => {
+                    let _ = exit_tx.send(WorkerExit::Retired(index));
+                }
+                Err(e) => {
+                    log::error!("transcode worker {index} failed: {e:#}");
+
+                    let _ = exit_tx.send(WorkerExit::Failed(index));
+                }
+            }
+        });
+
+        Self {}
+    }
+
+    /// Implementation of the transcode worker thread.
+    ///
+    /// Raising the priority again after background mode usually needs
+    /// privileges, so a worker whose priority was lowered returns instead,
+    /// and the pool starts a new thread at normal priority.
+    fn run(index: usize, context: &WorkerContext) -> anyhow::Result<()> {
+        let WorkerContext {
+            status_cache,
+            profile,
+            queue,
+            inprogress_counter,
+            panic_counter,
+            background,
+            event_tx,
+            ..
+        } = context;
+
+        // whether this thread's priority is lowered
+        let mut niced = false;
+
+        loop {
+            // wait for a job, or exit to be replaced if background mode was
+            // turned off after lowering the priority
+            let Some(job) =
+                queue.wait_unless(index, || niced && !background.load(Ordering::Relaxed))
+            else {
+                break;
+            };
+
+            // lower the thread priority if background mode was turned on
+            if !niced && background.load(Ordering::Relaxed) {
+                match set_thread_nice(BACKGROUND_NICE) {
+                    Ok(()) => niced = true,
+                    Err(e) => {
+                        log::warn!(
+                            "failed to set transcode worker priority to {BACKGROUND_NICE}: {e:#}"
+                        );
+                    }
+                }
+            }
+
+            // mark thread as in-progress
+            let _counter_guard = inprogress_counter.entered();
+
+            // the whole job is run in catch_panic, so a panic outside of
+            // making the file still fails only this job
+            let res = catch_panic(|| Self::run_job(&job, context));
+
+            // the job may have panicked before it was finished
+            queue.finish(&job);
+
+            if let Err(e) = res {
+                if e.is::<JobPanicked>() {
+                    panic_counter.fetch_add(1, Ordering::Relaxed);
+                }
+
+                log::error!(
+                    "failed to run transcode job for file: {}: {e:#}",
+                    job.local_path.display()
+                );
+
+                // notify the library so it can persist the failure
+                let _ = event_tx.send(TranscodeEvent::Failed {
+                    hash_kind: job.hash_kind.clone(),
+                    hash: job.hash.clone(),
+                    profile: *profile.lock().unwrap(),
+                    error: format!("{e:#}"),
+                });
+
+                // set status to Failed
+                status_cache.insert(
+                    job.hash_kind,
+                    job.hash,
+                    TranscodeStatus::Failed { error: e },
+                );
+            }
+        }
+
+        // worker retired so it can be replaced
+        Ok(())
+    }
+
+    /// Runs a job taken from the queue, and sets its status and notifies the
+    /// library when it's done.
+    fn run_job(job: &TranscodeItem, context: &WorkerContext) -> anyhow::Result<()> {
+        let WorkerContext {
+            transcodes_dir,
+            status_cache,
+            profile,
+            queue,
+            panic_counter,
+            event_tx,
+            make_output,
+            ..
+        } = context;
+        let verify = context.verify.load(Ordering::Relaxed);
+
+        // use the current profile for the whole job, with the settings
+        // for the file's content type, and store the transcode with others
+        // made with the same settings
+        let pool_profile = *profile.lock().unwrap();
+        let job_profile = pool_profile.for_content(job.content);
+        let profile_dir = profile_transcodes_dir(transcodes_dir, &job_profile);
+
+        // write to temp filename
+        let temp_path =
+            profile_dir.join(format!("{}-{}.tmp", job.hash_kind, hex::encode(&job.hash)));
+
+        // copy compact lossy sources instead of re-encoding them
+        // tracks of a cue sheet are only part of the file, so they're never copied
+        let passthrough_extension = match &job.cue {
+            Some(_) => None,
+            None => passthrough_extension(&job.local_path, &job_profile).unwrap_or_else(|e| {
+                log::warn!(
+                    "failed to check passthrough for file: {}: {e:#}",
+                    job.local_path.display()
+                );
+                None
+            }),
+        };
+
+        // remember the metadata the tags are copied from, so they can be
+        // updated if it changes
+        // tracks of a cue sheet also include the tags from the sheet
+        let metadata = metadata_fingerprint(&job.local_path)
+            .map(|fingerprint| match &job.cue {
+                Some(cue) => cue.metadata_fingerprint(&fingerprint),
+                None => fingerprint,
+            })
+            .inspect_err(|e| {
+                log::warn!(
+                    "failed to get metadata fingerprint of {}: {e:#}",
+                    job.local_path.display()
+                )
+            })
+            .ok();
+
+        // reset the progress left over from a previous attempt
+        let progress = status_cache.progress(&job.hash_kind, &job.hash);
+        progress.set(0.0);
+
+        // lets the job be paused or cancelled between packets
+        let interrupt = queue.interrupt(job);
+
+        let res = catch_panic(|| {
+            make_output(&OutputJob {
+                item: job,
+                profile: &job_profile,
+                passthrough_extension: passthrough_extension.as_deref(),
+                temp_path: &temp_path,
+                progress: &progress,
+                interrupt: &interrupt,
+                verify,
+            })
+        });
+        queue.finish(job);
+
+        // a job cancelled after its last check is discarded too, since it
+        // may have been reset to use other settings
+        let res = match res {
+            Ok(_) if interrupt.is_cancelled() => Err(TranscodeCancelled.into()),
+            res => res,
+        };
+
+        // if the profile changed while transcoding, the status cache now
+        // belongs to the new profile and this job's result is discarded
+        if *profile.lock().unwrap() != pool_profile {
+            log::info!(
+                "profile changed while transcoding file, discarding result: {}",
+                job.local_path.display()
+            );
+
+            let _ = std::fs::remove_file(&temp_path);
+
+            return Ok(());
+        }
+
+        let (file_size, output) = match res {
+            Ok(res) => res,
+
+            // cancelled files aren't failed, they're transcoded again when requested
+            Err(e) if e.is::<TranscodeCancelled>() => {
+                log::info!("cancelled transcoding file: {}", job.local_path.display());
+
+                let _ = std::fs::remove_file(&temp_path);
+                progress.set(0.0);
+
+                return Ok(());
+            }
+
+            Err(e) => {
+                if e.is::<JobPanicked>() {
+                    panic_counter.fetch_add(1, Ordering::Relaxed);
+                }
+
+                log::error!(
+                    "failed to transcode file: {} -> {}: {e:#}",
+                    job.local_path.display(),
+                    temp_path.display()
+                );
+
+                // try to remove the temp file
+                let _ = std::fs::remove_file(&temp_path);
+
+                // notify the library so it can persist the failure
+                let _ = event_tx.send(TranscodeEvent::Failed {
+                    hash_kind: job.hash_kind.clone(),
+                    hash: job.hash.clone(),
+                    profile: pool_profile,
+                    error: format!("{e:#}"),
+                });
+
+                // set status to Failed
+                status_cache.insert(
+                    job.hash_kind.clone(),
+                    job.hash.clone(),
+                    TranscodeStatus::Failed { error: e },
+                );
+
+                // next job
+                return Ok(());
+            }
+        };
+
+        // rename the temp file
+        // passthrough files keep the original extension since they keep the original container
+        let final_path = match &passthrough_extension {
+            Some(extension) => temp_path.with_extension(format!("passthrough.{extension}")),
+            None => temp_path.with_extension(job_profile.format.extension()),
+        };
+        if let Err(e) = std::fs::rename(&temp_path, &final_path) {
+            log::error!(
+                "failed to rename temp file: {} -> {}: {e:#}",
+                temp_path.display(),
+                final_path.display()
+            );
+
+            let error = format!("failed to rename temp file: {e:#}");
+
+            // notify the library so it can persist the failure
+            let _ = event_tx.send(TranscodeEvent::Failed {
+                hash_kind: job.hash_kind.clone(),
+                hash: job.hash.clone(),
+                profile: pool_profile,
+                error: error.clone(),
+            });
+
+            // set status to Failed
+            status_cache.insert(
+                job.hash_kind.clone(),
+                job.hash.clone(),
+                TranscodeStatus::Failed {
+                    error: anyhow::anyhow!(error),
+                },
+            );
+
+            // next job
+            return Ok(());
+        };
+
+        log::info!(
+            "finished transcoding file: {} -> {}",
+            job.local_path.display(),
+            final_path.display()
+        );
+
+        // without a manifest the transcode is made again on the next startup
+        if let Err(e) = TranscodeManifest::new(
+            &final_path,
+            &job_profile,
+            &job.hash_kind,
+            &job.hash,
+            metadata,
+        )
+        .and_then(|mut manifest| {
+            // keep the revision of the transcode this replaces so it doesn't go back
+            manifest.revision = status_cache.revision(&job.hash_kind, &job.hash);
+            manifest.write(&final_path)
+        }) {
+            log::warn!(
+                "failed to write manifest for transcode at {}: {e:#}",
+                final_path.display()
+            );
+        }
+
+        // set status to Ready
+        status_cache.insert(
+            job.hash_kind.clone(),
+            job.hash.clone(),
+            TranscodeStatus::Ready {
+                local_path: final_path,
+                file_size,
+                passthrough: passthrough_extension.is_some(),
+            },
+        );
+
+        // notify the library so it can clear a persisted failure
+        let _ = event_tx.send(TranscodeEvent::Ready {
+            hash_kind: job.hash_kind.clone(),
+            hash: job.hash.clone(),
+            profile: pool_profile,
+        });
+
+        // notify the library so it can update the album gain
+        if let Some(output) = output {
+            let _ = event_tx.send(TranscodeEvent::Finished {
+                hash_kind: job.hash_kind.clone(),
+                hash: job.hash.clone(),
+                album: output.album,
+                loudness: output.loudness,
+            });
+        }
+
+        Ok(())
+    }
+
+    /// Makes a job's file by copying the source for passthrough, or by
+    /// transcoding it.
+    fn make_output(job: &OutputJob<'_>) -> anyhow::Result<(u64, Option<TranscodeOutput>)> {
+        let item = job.item;
+
+        if job.passthrough_extension.is_some() {
+            log::info!(
+                "copying file for passthrough: {}",
+                item.local_path.display()
+            );
+            return std::fs::copy(&item.local_path, job.temp_path)
+                .context("failed to copy file")
+                .map(|file_size| (file_size, None));
+        }
+
+        log::info!("transcoding file: {}", item.local_path.display());
+        match job.profile.format {
+            TranscodeFormat::Opus => transcode(
+                &item.local_path,
+                item.cue.as_ref(),
+                job.temp_path,
+                job.profile,
+                job.progress,
+                job.interrupt,
+            )
+            .and_then(|output| {
+                // decode the transcode again before it's marked ready
+                if job.verify {
+                    verify_transcode(job.temp_path, output.duration_secs)
+                        .context("transcode failed verification")?;
+                }
+                Ok((output.file_size, Some(output)))
+            }),
+            TranscodeFormat::Flac => transcode_flac(
+                &item.local_path,
+                item.cue.as_ref(),
+                job.temp_path,
+                job.profile,
+                job.progress,
+                job.interrupt,
+            )
+            .map(|file_size| (file_size, None)),
+        }
+    }
+}
+
+/// Counts the number of threads of execution that are in a region.
+///
+/// This is used to track how many worker threads are currently working.
+#[derive(Debug, Clone)]
+struct RegionCounter(Arc<AtomicU64>);
+
+impl RegionCounter {
+    /// Creates a new RegionCounter.
+    pub fn new() -> Self {
+        Self(Arc::new(AtomicU64::new(0)))
+    }
+
+    /// Gets the current count.
+    pub fn count(&self) -> u64 {
+        self.0.load(Ordering::Relaxed)
+    }
+
+    /// Enters the region and increments the count, returning a guard that
+    /// decrements the count when dropped at the end of the region.
+    pub fn entered(&self) -> RegionCounterGuard {
+        RegionCounterGuard::new(self)
+    }
+}
+
+struct RegionCounterGuard<'a>(&'a RegionCounter);
+
+impl<'a> RegionCounterGuard<'a> {
+    fn new(counter: &'a RegionCounter) -> Self {
+        counter.0.fetch_add(1, Ordering::Relaxed);
+        Self(counter)
+    }
+}
+
+impl Drop for RegionCounterGuard<'_> {
+    fn drop(&mut self) {
+        self.0.0.fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
+/// The loudness that tracks are normalized to when loudness normalization is
+/// enabled. This is the ReplayGain 2.0 reference level, which is louder than
+/// the R128 reference and better suited for listening on phones.
+const NORMALIZATION_TARGET_LUFS: f64 = -18.0;
+
+/// The number of workers that run in background mode while a scan or transfer
+/// is active.
+const BACKGROUND_WORKER_COUNT: usize = 1;
+
+/// The niceness of worker threads in background mode.
+const BACKGROUND_NICE: i32 = 10;
+
+/// Returns the default number of worker threads, one per available core.
+pub fn default_worker_count() -> usize {
+    std::thread::available_parallelism()
+        .map(|count| count.get())
+        .unwrap_or(4)
+}
+
+/// Sets the niceness of the calling thread.
+///
+/// On Linux, `setpriority` with a pid of 0 only affects the calling thread.
+/// Lowering the niceness again usually needs privileges, so workers are
+/// replaced instead of restored after leaving background mode.
+#[cfg(any(target_os = "linux", target_os = "android"))]
+fn set_thread_nice(nice: i32) -> anyhow::Result<()> {
+    // SAFETY: setpriority only reads its arguments
+    let res = unsafe { libc::setpriority(libc::PRIO_PROCESS, 0, nice) };
+    if res != 0 {
+        return Err(std::io::Error::last_os_error()).context("failed to set priority");
+    }
+    Ok(())
+}
+
+/// Sets the niceness of the calling thread.
+///
+/// Other platforms would change the priority of the whole process, so this
+/// does nothing.
+#[cfg(not(any(target_os = "linux", target_os = "android")))]
+fn set_thread_nice(_nice: i32) -> anyhow::Result<()> {
+    Ok(())
+}
+
+/// Removes a transcode and its manifest.
+fn remove_transcode(path: &Path) {
+    if let Err(e) = std::fs::remove_file(path) {
+        log::error!("failed to remove transcode at {}: {e:#}", path.display());
+    }
+    let _ = std::fs::remove_file(manifest::manifest_path(path));
+}
+
+/// Returns the directory that holds transcodes made with a profile.
+fn profile_transcodes_dir(transcodes_dir: &Path, profile: &TranscodeProfile) -> PathBuf {
+    transcodes_dir.join(profile.key())
+}
+
+/// The extension of the markers left in place of evicted transcodes.
+///
+/// Evicted files are only transcoded again when they're requested, and the
+/// markers keep it that way across restarts.
+const EVICTED_EXTENSION: &str = "evicted";
+
+/// Returns the path of the marker of a file whose transcode was evicted.
+fn evicted_marker_path(profile_dir: &Path, hash_kind: &str, hash: &[u8]) -> PathBuf {
+    profile_dir.join(format!(
+        "{hash_kind}-{}.{EVICTED_EXTENSION}",
+        hex::encode(hash)
+    ))
+}
+
+/// Parses the hash of a file from the path of its evicted marker, or returns
+/// `None` if the path isn't a marker.
+fn parse_evicted_marker(path: &Path) -> Option<(String, Vec<u8>)> {
+    if path.extension().is_none_or(|ext| ext != EVICTED_EXTENSION) {
+        return None;
+    }
+
+    let file_stem = path.file_stem()?.to_str()?;
+    let (hash_kind, hash) = file_stem.split_once('-')?;
+    Some((hash_kind.to_string(), hex::decode(hash).ok()?))
+}
+
+/// Leaves a marker that a file's transcode was evicted.
+fn mark_evicted(profile_dir: &Path, hash_kind: &str, hash: &[u8]) {
+    let path = evicted_marker_path(profile_dir, hash_kind, hash);
+    if let Err(e) = std::fs::write(&path, []) {
+        log::warn!(
+            "failed to write evicted marker at {}: {e:#}",
+            path.display()
+        );
+    }
+}
+
+/// Removes the marker that a file's transcode was evicted, if it has one.
+fn unmark_evicted(profile_dir: &Path, hash_kind: &str, hash: &[u8]) {
+    let _ = std::fs::remove_file(evicted_marker_path(profile_dir, hash_kind, hash));
+}
+
+/// The result of transcoding a file.
+#[derive(Debug)]
+struct TranscodeOutput {
+    /// The size of the output file.
+    file_size: u64,
+    /// The measured loudness, if the track was long and loud enough.
+    loudness: Option<Loudness>,
+    /// The album the track belongs to, see `tags::album_key`.
+    album: String,
+    /// The duration of the source audio that was encoded, in seconds.
+    duration_secs: f64,
+}
+
+/// Transcode a file, or only a track of it if it's split by a cue sheet.
+///
+/// `progress` is updated as the input is decoded and then as the frames that
+/// are still buffered are encoded, see `DECODE_PROGRESS`.
+fn transcode(
+    input_path: &Path,
+    cue: Option<&CueTrack>,
+    output_path: &Path,
+    profile: &TranscodeProfile,
+    progress: &TranscodeProgress,
+    interrupt: &TranscodeInterrupt,
+) -> anyhow::Result<TranscodeOutput> {
+    let input_file = PositionSource::open(input_path)?;
+    let input_position = input_file.position();
+
+    let mss = MediaSourceStream::new(Box::new(input_file), Default::default());
+
+    let mut hint = Hint::new();
+    if let Some(extension) = input_path.extension() {
+        hint.with_extension(extension.to_str().context("invalid file extension")?);
+    }
+
+    let mut format = symphonia::default::get_probe()
+        .probe(&hint, mss, Default::default(), Default::default())
+        .context("failed to probe file format")?;
+
+    // get the default audio track
+    let audio_track = format
+        .default_track(TrackType::Audio)
+        .context("failed to get default audio track")?;
+    let audio_track_id = audio_track.id;
+    let time_base = audio_track.time_base;
+    let total_frames = audio_track.num_frames;
+
+    // get codec parameters for the audio track
+    let codec_params = audio_track
+        .codec_params
+        .as_ref()
+        .context("failed to get codec parameters")?;
+    let audio_codec_params = codec_params
+        .audio()
+        .context("codec parameters are not audio")?;
+
+    // get channel count and sample rate from codec parameters
+    let channel_count = audio_codec_params
+        .channels
+        .as_ref()
+        .context("failed to get channel count from codec params")?
+        .count();
+    let sample_rate = audio_codec_params
+        .sample_rate
+        .context("failed to get sample rate from codec params")? as usize;
+
+    let mut decoder = symphonia::default::get_codecs()
+        .make_audio_decoder(audio_codec_params, &Default::default())
+        .context("failed to create decoder")?;
+
+    // trim the encoder delay and padding of lossy sources
+    let gapless_info = match audio_codec_params.codec {
+        CODEC_ID_MP3 => gapless::read_mp3(input_path),
+        CODEC_ID_AAC => gapless::read_mp4(input_path),
+        _ => Ok(None),
+    };
+    let gapless_info = gapless_info
+        .unwrap_or_else(|e| {
+            log::warn!(
+                "failed to read gapless info from {}: {e:#}",
+                input_path.display()
+            );
+            None
+        })
+        .unwrap_or_default();
+
+    // tracks of a cue sheet only transcode their range of the decoded frames.
+    // the range is offset by the encoder delay, which is dropped along with
+    // the frames before the range, and only the last track has the padding
+    let range = cue.map(|cue| {
+        let (start, end) = cue.sample_range(sample_rate as u32);
+        let delay = gapless_info.delay;
+        (start + delay, end.map(|end| end + delay))
+    });
+    let mut trimmer = GaplessTrimmer::new(match range {
+        Some((_, end)) => GaplessInfo {
+            delay: 0,
+            padding: if end.is_none() {
+                gapless_info.padding
+            } else {
+                0
+            },
+        },
+        None => gapless_info,
+    });
+
+    // progress only counts the frames in the range
+    let mut decode_progress = DecodeProgress::new(range, total_frames, input_position);
+
+    // downmix to stereo or mono if enabled
+    let output_channel_count = profile.output_channel_count(channel_count)?;
+    let downmix = if output_channel_count != channel_count {
+        Some(Downmix::new(channel_count)?)
+    } else {
+        None
+    };
+
+    // construct the encoder first to determine the lookahead
+    let mapping = ChannelMapping::new(output_channel_count)?;
+    let encoder = MultistreamEncoder::new(&mapping, profile)?;
+
+    let lookahead_frames = encoder.lookahead()?;
+
+    let mut output_file = File::create(output_path).context("failed to create output file")?;
+
+    let mut packet_writer = ogg::PacketWriter::new(&mut output_file);
+
+    // we write the number of lookahead frames as pre-skip in the opus header
+    // we added this many zeros to the start of the resampled samples to account for encoder lookahead
+    // players should skip these frames when decoding
+    let preskip_bytes = lookahead_frames.to_le_bytes();
+
+    // input sample rate is always 48000 since we resample to it
+    let rate_bytes = 48000u32.to_le_bytes();
+
+    #[rustfmt::skip]
+    let mut opus_head = vec![
+        b'O', b'p', b'u', b's', b'H', b'e', b'a', b'd', // magic signature
+        1, // version, always 1
+        output_channel_count as u8, // channel count
+        preskip_bytes[0], preskip_bytes[1], // pre-skip
+        rate_bytes[0], rate_bytes[1], rate_bytes[2], rate_bytes[3], // input sample rate
+        0, 0, // output gain
+        mapping.family, // channel mapping family
+    ];
+
+    // families other than 0 have a channel mapping table
+    if mapping.family != 0 {
+        opus_head.push(mapping.stream_count as u8); // stream count
+        opus_head.push(mapping.coupled_count as u8); // coupled stream count
+        opus_head.extend(&mapping.mapping); // channel mapping
+    }
+
+    let (mut user_comments, mut replay_gain, album) =
+        opus_comments(&mut *format, input_path, profile)?;
+
+    // tracks of a cue sheet are tagged from the sheet, and the source's track
+    // gain is for the whole file
+    if let Some(cue) = cue {
+        cue.apply_tags(&mut user_comments);
+        replay_gain.track_gain = None;
+    }
+
+    // chapters are moved to the output's timeline, which starts at the range
+    // of a cue sheet track
+    user_comments.extend(chapter_comments(
+        input_path,
+        sample_rate as u32,
+        cue.map(|cue| cue.sample_range(sample_rate as u32)),
+    ));
+
+    // the tags packet is padded so the loudness can be added after encoding
+    let opus_tags = tags::opus_tags_packet(&user_comments);
+
+    // stream unique serial identifier
+    let serial = 0;
+
+    // write opus head and opus tags packets
+    packet_writer
+        .write_packet(&opus_head, serial, ogg::PacketWriteEndInfo::EndPage, 0)
+        .context("failed to write packet")?;
+    packet_writer
+        .write_packet(&opus_tags, serial, ogg::PacketWriteEndInfo::EndPage, 0)
+        .context("failed to write packet")?;
+
+    // number of frames per chunk (e.g. 48khz / 1000 * 20ms = 960 frames)
+    // NB: we are calling opus frames 'chunks' to differentiate from sample frames (one sample per channel)
+    let chunk_frames = profile.frame_duration.frames();
+
+    let mut sink = OpusPacketSink::new(encoder, packet_writer, serial, chunk_frames);
+
+    // pad the start with zeros to account for encoder lookahead
+    // players skip these frames when decoding since we write the lookahead as pre-skip
+    sink.push_silence(lookahead_frames)?;
+
+    // resample to 48k if needed
+    let mut resampler = if sample_rate != 48000 {
+        Some(StreamResampler::new(sample_rate, output_channel_count)?)
+    } else {
+        None
+    };
+
+    // decoded frames that haven't been passed to the resampler or encoder yet
+    // this only ever holds one decoded packet plus less than one resampler chunk,
+    // so memory use doesn't depend on the length of the track
+    let mut decoded_samples: Vec<Vec<f32>> = vec![Vec::new(); output_channel_count];
+
+    // input frames from the current packet when downmixing
+    let mut packet_samples: Vec<Vec<f32>> = vec![Vec::new(); channel_count];
+
+    // tracks of a cue sheet start decoding close to their range
+    let mut decoded_frames = match range {
+        Some((start, _)) => {
+            let position = seek_to_frame(
+                &mut *format,
+                audio_track_id,
+                time_base,
+                sample_rate as u32,
+                start,
+            )?;
+            decoder.reset();
+            position
+        }
+        None => 0,
+    };
+
+    // decode, resample, and encode the audio track in chunks
+    loop {
+        interrupt.check()?;
+
+        // stop at the end of the range
+        if let Some((_, Some(end))) = range
+            && decoded_frames >= end
+        {
+            break;
+        }
+
+        // read next packet
+        let packet = match format.next_packet() {
+            Ok(Some(packet)) => packet,
+
+            // end of track
+            Ok(None) => break,
+
+            Err(e) => {
+                return Err(e).context("failed to read packet");
+            }
+        };
+
+        // skip packets from other tracks
+        if packet.track_id() != audio_track_id {
+            continue;
+        }
+
+        // decode packet
+        let audio_buf = decoder.decode(&packet).context("failed to decode packet")?;
+
+        let packet_start = decoded_frames;
+        decoded_frames += audio_buf.frames() as u64;
+        decode_progress.update(decoded_frames, progress);
+
+        // the packet's frames are appended after the frames that are held back
+        let packet_offset = decoded_samples[0].len();
+
+        if let Some(downmix) = &downmix {
+            // copy to packet buffer and mix into the decoded buffer
+            for channel in &mut packet_samples {
+                channel.resize(audio_buf.frames(), 0.0);
+            }
+            audio_buf.copy_to_slice_planar(&mut packet_samples);
+
+            downmix.process(&packet_samples, &mut decoded_samples);
+        } else {
+            // copy to decoded buffer
+            // symphonia only lets us copy to vecs/slices, which replaces instead of extending
+            // we need to manually resize each channel and then copy to mut slices of the new extended areas
+            let mut output_slices = Vec::with_capacity(channel_count);
+            for channel in &mut decoded_samples {
+                let curr_len = channel.len();
+                let new_len = curr_len + audio_buf.frames();
+                channel.resize(new_len, 0.0);
+                output_slices.push(&mut channel[curr_len..new_len]);
+            }
+            audio_buf.copy_to_slice_planar(&mut output_slices);
+        }
+
+        if let Some(range) = range {
+            trim_to_range(&mut decoded_samples, packet_offset, packet_start, range);
+        }
+
+        // the last frames might be padding, so they're kept until the next packet
+        trimmer.trim_start(&mut decoded_samples);
+        let held = trimmer.hold_back(&mut decoded_samples);
+
+        match resampler.as_mut() {
+            Some(resampler) => resampler.process(&mut decoded_samples, &mut sink)?,
+
+            None => {
+                sink.push(&decoded_samples)?;
+
+                for channel in &mut decoded_samples {
+                    channel.clear();
+                }
+            }
+        }
+
+        trimmer.restore(&mut decoded_samples, held);
+    }
+
+    // drop the padding from the end of the track
+    trimmer.trim_end(&mut decoded_samples);
+
+    // the encoded frames are the decoded frames without the trimmed ones
+    let (start, end) = range.unwrap_or((gapless_info.delay, None));
+    let end = end
+        .unwrap_or(decoded_frames.saturating_sub(gapless_info.padding))
+        .min(decoded_frames);
+    let duration_secs = end.saturating_sub(start) as f64 / sample_rate as f64;
+
+    // the rest of the progress is encoding the frames that are still buffered
+    let remaining_frames = match &resampler {
+        Some(resampler) => resampler.remaining_output_frames(&decoded_samples),
+        None => decoded_samples[0].len(),
+    };
+    sink.track_progress(progress, remaining_frames);
+
+    // resample the remaining frames and flush the resampler
+    match resampler {
+        Some(resampler) => resampler.finish(&decoded_samples, &mut sink)?,
+        None => sink.push(&decoded_samples)?,
+    }
+
+    let loudness = sink.integrated_loudness();
+
+    // encode the final chunk and end the stream
+    let file = sink.finish()?;
+
+    // now that the whole track was measured, write the loudness to the headers
+    // the output gain is applied by players unconditionally, so only use it if enabled
+    let output_gain_q78 = match loudness {
+        Some(loudness) if profile.normalize_loudness => {
+            tags::gain_to_q78(NORMALIZATION_TARGET_LUFS - loudness.lufs)
+        }
+        _ => 0,
+    };
+    let output_gain = output_gain_q78 as f64 / 256.0;
+
+    // prefer the measured loudness, but fall back to converting the source's
+    // replaygain tags if the track is too short or quiet to measure
+    // the album gain is replaced with a measured one once the album's tracks
+    // are transcoded, see `tags::write_album_gain`
+    let track_gain = loudness
+        .map(|loudness| R128_REFERENCE_LUFS - loudness.lufs)
+        .or(replay_gain.track_gain.map(tags::replay_gain_to_r128));
+    let album_gain = replay_gain.album_gain.map(tags::replay_gain_to_r128);
+
+    // r128 gains are relative to the output gain
+    let mut comments = user_comments;
+    if let Some(track_gain) = track_gain {
+        comments.push(format!(
+            "R128_TRACK_GAIN={}",
+            tags::gain_to_q78(track_gain - output_gain)
+        ));
+    }
+    if let Some(album_gain) = album_gain {
+        comments.push(format!(
+            "R128_ALBUM_GAIN={}",
+            tags::gain_to_q78(album_gain - output_gain)
+        ));
+    }
+
+    if output_gain_q78 != 0 || track_gain.is_some() || album_gain.is_some() {
+        opus_head[16..18].copy_from_slice(&output_gain_q78.to_le_bytes());
+        let opus_tags = tags::opus_tags_packet_with_len(&comments, opus_tags.len())?;
+
+        tags::rewrite_opus_headers(file, &opus_head, &opus_tags)
+            .context("failed to write loudness to headers")?;
+    }
+
+    let file_size = file
+        .seek(SeekFrom::End(0))
+        .context("failed to seek to end of file")?;
+
+    // we did it
+    Ok(TranscodeOutput {
+        file_size,
+        loudness,
+        album,
+        duration_secs,
+    })
+}
+
+/// How far before a cue sheet track to seek, in seconds. Lossy decoders need
+/// the packets before a frame to decode it, like the bit reservoir of MP3 and
+/// the overlapping transform of AAC, so these frames are decoded and dropped.
+const CUE_SEEK_PREROLL_SECS: u64 = 1;
+
+/// Seeks a format reader to before a frame of a track, so a cue sheet track
+/// doesn't have to decode the frames of the tracks before it.
+///
+/// Returns the frame that the next packet starts at.
+fn seek_to_frame(
+    format: &mut dyn FormatReader,
+    track_id: u32,
+    time_base: Option<TimeBase>,
+    sample_rate: u32,
+    frame: u64,
+) -> anyhow::Result<u64> {
+    let target = frame.saturating_sub(CUE_SEEK_PREROLL_SECS * sample_rate as u64);
+    if target == 0 {
+        return Ok(0);
+    }
+
+    // timestamps are in the track's time base, which is usually the sample rate
+    let (numer, denom) = match time_base {
+        Some(time_base) => (time_base.numer as u64, time_base.denom as u64),
+        None => (1, sample_rate as u64),
+    };
+    let ts = target * denom / (sample_rate as u64 * numer);
+
+    let seeked_to = format
+        .seek(SeekMode::Accurate, SeekTo::TimeStamp { ts, track_id })
+        .context("failed to seek to track")?;
+
+    Ok(seeked_to.actual_ts * numer * sample_rate as u64 / denom)
+}
+
+/// Drops the frames of a decoded packet that are outside of a range of
+/// decoded frames.
+///
+/// The packet starts at `offset` in `samples` and at `position` in the
+/// decoded frames.
+fn trim_to_range<T>(
+    samples: &mut [Vec<T>],
+    offset: usize,
+    position: u64,
+    (start, end): (u64, Option<u64>),
+) {
+    for channel in samples {
+        let frames = (channel.len() - offset) as u64;
+        let keep_end = end.map_or(frames, |end| end.saturating_sub(position).min(frames));
+        let keep_start = start.saturating_sub(position).min(keep_end);
+
+        channel.truncate(offset + keep_end as usize);
+        channel.drain(offset..offset + keep_start as usize);
+    }
+}
+
+/// Measures how much of a transcode's input was decoded.
+///
+/// This counts decoded frames if the length of the track is known. Some
+/// containers don't store the length, like MP3s without a Xing header, so
+/// otherwise it falls back to how much of the input file was read.
+struct DecodeProgress {
+    /// The first decoded frame that's counted, like the start of a cue sheet
+    /// track.
+    start: u64,
+    /// The number of frames that are counted, if known.
+    frames: Option<u64>,
+
+    /// The read position of the input file, see `PositionSource`.
+    position: Arc<AtomicU64>,
+    /// The length of the input file.
+    file_len: u64,
+    /// The read position when decoding reached `start`.
+    start_position: Option<u64>,
+}
+
+impl DecodeProgress {
+    fn new(
+        range: Option<(u64, Option<u64>)>,
+        total_frames: Option<u64>,
+        position: (Arc<AtomicU64>, u64),
+    ) -> Self {
+        let (start, frames) = match range {
+            Some((start, end)) => (
+                start,
+                end.or(total_frames).map(|end| end.saturating_sub(start)),
+            ),
+            None => (0, total_frames),
+        };
+        let (position, file_len) = position;
+
+        Self {
+            start,
+            frames: frames.filter(|frames| *frames > 0),
+
+            position,
+            file_len,
+            start_position: None,
+        }
+    }
+
+    /// Updates the progress after a packet is decoded.
+    fn update(&mut self, decoded_frames: u64, progress: &TranscodeProgress) {
+        if decoded_frames < self.start {
+            return;
+        }
+
+        if let Some(frames) = self.frames {
+            progress.set_decoded((decoded_frames - self.start) as f32 / frames as f32);
+            return;
+        }
+
+        let position = self.position.load(Ordering::Relaxed);
+        let start_position = *self.start_position.get_or_insert(position);
+        let len = self.file_len.saturating_sub(start_position);
+        if len > 0 {
+            progress.set_decoded(position.saturating_sub(start_position) as f32 / len as f32);
+        }
+    }
+}
+
+/// A media source that shares its read position, so the progress of decoding
+/// it can be measured when the length of the track isn't known.
+struct PositionSource {
+    file: File,
+    len: u64,
+    position: Arc<AtomicU64>,
+}
+
+impl PositionSource {
+    fn open(path: &Path) -> anyhow::Result<Self> {
+        let file = File::open(path).context("failed to open input file")?;
+        let len = file
+            .metadata()
+            .context("failed to get input file metadata")?
+            .len();
+
+        Ok(Self {
+            file,
+            len,
+            position: Arc::new(AtomicU64::new(0)),
+        })
+    }
+
+    /// Returns the shared read position and the length of the file.
+    fn position(&self) -> (Arc<AtomicU64>, u64) {
+        (self.position.clone(), self.len)
+    }
+}
+
+impl Read for PositionSource {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        let read = self.file.read(buf)?;
+        self.position.fetch_add(read as u64, Ordering::Relaxed);
+        Ok(read)
+    }
+}
+
+impl Seek for PositionSource {
+    fn seek(&mut self, pos: SeekFrom) -> std::io::Result<u64> {
+        let position = self.file.seek(pos)?;
+        self.position.store(position, Ordering::Relaxed);
+        Ok(position)
+    }
+}
+
+impl MediaSource for PositionSource {
+    fn is_seekable(&self) -> bool {
+        true
+    }
+
+    fn byte_len(&self) -> Option<u64> {
+        Some(self.len)
+    }
+}
+
+/// Reads the tags of a source file and converts them to Opus comments.
+///
+/// Returns the comments along with the source's ReplayGain values and album,
+/// which are used for loudness.
+fn opus_comments(
+    format: &mut dyn FormatReader,
+    input_path: &Path,
+    profile: &TranscodeProfile,
+) -> anyhow::Result<(Vec<String>, ReplayGain, String)> {
+    let mut comments = Vec::new();
+    let mut replay_gain = ReplayGain::default();
+    let mut album = tags::album_key(&[], input_path);
+    let picture;
+
+    if let Some(metadata) = format.metadata().skip_to_latest() {
+        comments.extend(tags::vorbis_comments(metadata.tags()));
+        replay_gain = ReplayGain::from_tags(metadata.tags());
+        album = tags::album_key(metadata.tags(), input_path);
+
+        picture = cover_picture(metadata.visuals(), input_path, &profile.art)?;
+    } else {
+        picture = cover_picture(&[], input_path, &profile.art)?;
+    }
+
+    apply_sidecar_lyrics(&mut comments, input_path)?;
+
+    if let Some(picture) = picture {
+        // encode picture with base64 for comment
+        let comment = format!(
+            "METADATA_BLOCK_PICTURE={}",
+            BASE64_STANDARD.encode(&picture)
+        );
+
+        log::debug!(
+            "adding visual to opus tags, picture size = {}, comment size = {}",
+            picture.len(),
+            comment.len(),
+        );
+
+        comments.push(comment);
+    }
+
+    Ok((comments, replay_gain, album))
+}
+
+/// Reads the chapters of a source file and converts them to Opus comments,
+/// see `chapters::opus_comments`.
+///
+/// Chapters that can't be read are left out instead of failing the transcode.
+fn chapter_comments(
+    input_path: &Path,
+    sample_rate: u32,
+    range: Option<(u64, Option<u64>)>,
+) -> Vec<String> {
+    match chapters::read(input_path) {
+        Ok(chapters) => chapters::opus_comments(&chapters, sample_rate, range),
+        Err(e) => {
+            log::warn!(
+                "failed to read chapters from {}: {e:#}",
+                input_path.display()
+            );
+            Vec::new()
+        }
+    }
+}
+
+/// Copies the current tags of a source file to its Opus transcode without
+/// re-encoding, returning the new size of the transcode.
+///
+/// Tracks of a cue sheet are tagged from the sheet, like with `transcode`.
+fn retag_transcode(
+    transcode_path: &Path,
+    input_path: &Path,
+    cue: Option<&CueTrack>,
+    profile: &TranscodeProfile,
+) -> anyhow::Result<u64> {
+    let input_file = File::open(input_path).context("failed to open input file")?;
+
+    let mss = MediaSourceStream::new(Box::new(input_file), Default::default());
+
+    let mut hint = Hint::new();
+    if let Some(extension) = input_path.extension() {
+        hint.with_extension(extension.to_str().context("invalid file extension")?);
+    }
+
+    let mut format = symphonia::default::get_probe()
+        .probe(&hint, mss, Default::default(), Default::default())
+        .context("failed to probe file format")?;
+
+    let sample_rate = format
+        .default_track(TrackType::Audio)
+        .and_then(|track| track.codec_params.as_ref())
+        .and_then(|codec_params| codec_params.audio())
+        .and_then(|audio_codec_params| audio_codec_params.sample_rate)
+        .context("failed to get sample rate from codec params")?;
+
+    let (mut comments, _replay_gain, _album) = opus_comments(&mut *format, input_path, profile)?;
+    if let Some(cue) = cue {
+        cue.apply_tags(&mut comments);
+    }
+    comments.extend(chapter_comments(
+        input_path,
+        sample_rate,
+        cue.map(|cue| cue.sample_range(sample_rate)),
+    ));
+
+    tags::retag_opus_file(transcode_path, &comments)
+}
+
+/// Returns a fingerprint of the metadata of a file.
+///
+/// The file hash only covers the audio, so this is used to notice when the
+/// tags or cover art change. It covers the tags and visuals, the sidecar
+/// cover if there are no visuals, and the sidecar lyrics.
+pub fn metadata_fingerprint(path: &Path) -> anyhow::Result<Vec<u8>> {
+    let file = File::open(path).context("failed to open file")?;
+
+    let mss = MediaSourceStream::new(Box::new(file), Default::default());
+
+    let mut hint = Hint::new();
+    if let Some(extension) = path.extension() {
+        hint.with_extension(extension.to_str().context("invalid file extension")?);
+    }
+
+    let mut format = symphonia::default::get_probe()
+        .probe(&hint, mss, Default::default(), Default::default())
+        .context("failed to probe file format")?;
+
+    let mut hasher = XxHash3_64::with_seed(0);
+    let mut has_visuals = false;
+
+    if let Some(metadata) = format.metadata().skip_to_latest() {
+        for comment in tags::vorbis_comments(metadata.tags()) {
+            hasher.write(comment.as_bytes());
+            hasher.write_u8(0);
+        }
+
+        let replay_gain = ReplayGain::from_tags(metadata.tags());
+        hasher.write(format!("{replay_gain:?}").as_bytes());
+
+        for visual in metadata.visuals() {
+            hasher.write(&visual.data);
+            has_visuals = true;
+        }
+    }
+
+    if !has_visuals && let Some(sidecar_path) = sidecar_cover_path(path) {
+        let data = std::fs::read(&sidecar_path).context("failed to read sidecar cover image")?;
+        hasher.write(&data);
+    }
+
+    if let Some(lyrics) = sidecar_lyrics(path)? {
+        hasher.write(lyrics.as_bytes());
+    }
+
+    Ok(hasher.finish().to_be_bytes().to_vec())
+}
+
+/// The maximum difference between the duration of a transcode and its source
+/// for it to pass verification. The last Opus frame is padded to a full frame,
+/// so this is longer than the longest frame duration.
+const VERIFY_DURATION_TOLERANCE_SECS: f64 = 0.1;
+
+/// Checks that an Opus transcode can be read back before it's marked Ready.
+///
+/// The headers and tags are parsed, every packet is decoded, and the decoded
+/// duration is compared to the duration of the source audio that was encoded.
+/// Symphonia reads the stream but doesn't decode Opus, so the packets are
+/// decoded with libopus.
+fn verify_transcode(path: &Path, expected_duration_secs: f64) -> anyhow::Result<()> {
+    // symphonia doesn't expose the channel mapping, so the headers are read here
+    let (opus_head, opus_tags) = {
+        let file = File::open(path).context("failed to open transcode")?;
+        let mut reader = ogg::PacketReader::new(file);
+        let opus_head = reader
+            .read_packet()
+            .context("failed to read OpusHead packet")?
+            .context("missing OpusHead packet")?
+            .data;
+        let opus_tags = reader
+            .read_packet()
+            .context("failed to read OpusTags packet")?
+            .context("missing OpusTags packet")?
+            .data;
+        (opus_head, opus_tags)
+    };
+
+    anyhow::ensure!(
+        opus_head.len() >= 19 && opus_head.starts_with(b"OpusHead"),
+        "invalid OpusHead packet"
+    );
+    let channel_count = opus_head[9] as usize;
+    let preskip = u16::from_le_bytes([opus_head[10], opus_head[11]]) as u64;
+    let (stream_count, coupled_count) = match opus_head[18] {
+        0 => {
+            anyhow::ensure!(
+                (1..=2).contains(&channel_count),
+                "invalid OpusHead channel count"
+            );
+            (1, channel_count - 1)
+        }
+        _ => {
+            anyhow::ensure!(opus_head.len() >= 21, "missing OpusHead channel mapping");
+            (opus_head[19] as usize, opus_head[20] as usize)
+        }
+    };
+    anyhow::ensure!(
+        stream_count > 0 && coupled_count <= stream_count,
+        "invalid OpusHead channel mapping"
+    );
+
+    tags::parse_opus_tags(&opus_tags).context("failed to parse OpusTags packet")?;
+
+    let file = File::open(path).context("failed to open transcode")?;
+
+    let mss = MediaSourceStream::new(Box::new(file), Default::default());
+
+    let mut hint = Hint::new();
+    hint.with_extension("ogg");
+
+    let mut format = symphonia::default::get_probe()
+        .probe(&hint, mss, Default::default(), Default::default())
+        .context("failed to probe transcode")?;
+
+    // get the default audio track
+    let audio_track = format
+        .default_track(TrackType::Audio)
+        .context("failed to get default audio track")?;
+    let audio_track_id = audio_track.id;
+
+    let codec = audio_track
+        .codec_params
+        .as_ref()
+        .and_then(|codec_params| codec_params.audio())
+        .map(|audio_codec_params| audio_codec_params.codec);
+    anyhow::ensure!(codec == Some(CODEC_ID_OPUS), "transcode is not Opus");
+
+    let mut decoders = (0..stream_count)
+        .map(|stream| {
+            let channels = if stream < coupled_count {
+                opus::Channels::Stereo
+            } else {
+                opus::Channels::Mono
+            };
+            opus::Decoder::new(48000, channels)
+        })
+        .collect::<Result<Vec<_>, _>>()
+        .context("failed to create opus decoder")?;
+
+    // fits the longest packet, 120 ms of stereo
+    let mut output_buf = vec![0.0; 5760 * 2];
+
+    let mut decoded_frames = 0;
+
+    loop {
+        // read next packet
+        let packet = match format.next_packet() {
+            Ok(Some(packet)) => packet,
+
+            // end of track
+            Ok(None) => break,
+
+            Err(e) => {
+                return Err(e).context("failed to read packet");
+            }
+        };
+
+        // skip packets from other tracks
+        if packet.track_id() != audio_track_id {
+            continue;
+        }
+
+        // every stream except the last uses self-delimited framing
+        let mut data = packet.buf();
+        let mut packet_frames = None;
+        for (stream, decoder) in decoders.iter_mut().enumerate() {
+            let stream_packet = if stream == stream_count - 1 {
+                Cow::Borrowed(data)
+            } else {
+                let (stream_packet, rest) = read_self_delimited_packet(data)?;
+                data = rest;
+                Cow::Owned(stream_packet)
+            };
+
+            let frames = decoder
+                .decode_float(&stream_packet, &mut output_buf, false)
+                .context("failed to decode packet")?;
+            anyhow::ensure!(
+                packet_frames.is_none_or(|packet_frames| packet_frames == frames),
+                "opus streams have different lengths"
+            );
+            packet_frames = Some(frames);
+        }
+
+        decoded_frames += packet_frames.unwrap_or(0) as u64;
+    }
+
+    let duration_secs = decoded_frames.saturating_sub(preskip) as f64 / 48000.0;
+    anyhow::ensure!(
+        (duration_secs - expected_duration_secs).abs() <= VERIFY_DURATION_TOLERANCE_SECS,
+        "duration is {duration_secs:.3} s, expected {expected_duration_secs:.3} s"
+    );
+
+    Ok(())
+}
+
+/// Transcode a file to lossless FLAC, returning the size of the output file.
+///
+/// Samples are decoded as integers at the source's bit depth so that the
+/// output is bit-perfect. Float and 32-bit sources are stored as 24-bit, the
+/// highest depth supported by `FlacEncoder`.
+///
+/// Tracks of a cue sheet only keep their range of the source, like with
+/// `transcode`.
+fn transcode_flac(
+    input_path: &Path,
+    cue: Option<&CueTrack>,
+    output_path: &Path,
+    profile: &TranscodeProfile,
+    progress: &TranscodeProgress,
+    interrupt: &TranscodeInterrupt,
+) -> anyhow::Result<u64> {
+    let input_file = PositionSource::open(input_path)?;
+    let input_position = input_file.position();
+
+    let mss = MediaSourceStream::new(Box::new(input_file), Default::default());
+
+    let mut hint = Hint::new();
+    if let Some(extension) = input_path.extension() {
+        hint.with_extension(extension.to_str().context("invalid file extension")?);
+    }
+
+    let mut format = symphonia::default::get_probe()
+        .probe(&hint, mss, Default::default(), Default::default())
+        .context("failed to probe file format")?;
+
+    // get the default audio track
+    let audio_track = format
+        .default_track(TrackType::Audio)
+        .context("failed to get default audio track")?;
+    let audio_track_id = audio_track.id;
+    let time_base = audio_track.time_base;
+    let total_frames = audio_track.num_frames;
+
+    // get codec parameters for the audio track
+    let audio_codec_params = audio_track
+        .codec_params
+        .as_ref()
+        .context("failed to get codec parameters")?
+        .audio()
+        .context("codec parameters are not audio")?;
+
+    let channel_count = audio_codec_params
+        .channels
+        .as_ref()
+        .context("failed to get channel count from codec params")?
+        .count();
+    let sample_rate = audio_codec_params
+        .sample_rate
+        .context("failed to get sample rate from codec params")?;
+    let bits_per_sample = audio_codec_params
+        .bits_per_sample
+        .unwrap_or(flac::MAX_BITS_PER_SAMPLE)
+        .clamp(8, flac::MAX_BITS_PER_SAMPLE);
+
+    let mut decoder = symphonia::default::get_codecs()
+        .make_audio_decoder(audio_codec_params, &Default::default())
+        .context("failed to create decoder")?;
+
+    // tracks of a cue sheet only keep their range of the decoded frames,
+    // which is offset by the encoder delay of lossy sources
+    let range = match cue {
+        Some(cue) => {
+            let delay = match audio_codec_params.codec {
+                CODEC_ID_MP3 => gapless::read_mp3(input_path),
+                CODEC_ID_AAC => gapless::read_mp4(input_path),
+                _ => Ok(None),
+            }
+            .unwrap_or_else(|e| {
+                log::warn!(
+                    "failed to read gapless info from {}: {e:#}",
+                    input_path.display()
+                );
+                None
+            })
+            .map_or(0, |gapless_info| gapless_info.delay);
+
+            let (start, end) = cue.sample_range(sample_rate);
+            Some((start + delay, end.map(|end| end + delay)))
+        }
+        None => None,
+    };
+
+    // progress only counts the frames in the range
+    let mut decode_progress = DecodeProgress::new(range, total_frames, input_position);
+
+    let (comments, picture) = {
+        let mut comments = Vec::new();
+        let picture;
+
+        if let Some(metadata) = format.metadata().skip_to_latest() {
+            comments.extend(tags::vorbis_comments(metadata.tags()));
+
+            // flac players read replaygain tags directly, but the source's
+            // track gain is for the whole file if it's split by a cue sheet
+            let replay_gain = ReplayGain::from_tags(metadata.tags());
+            if let Some(track_gain) = replay_gain.track_gain
+                && cue.is_none()
+            {
+                comments.push(format!("REPLAYGAIN_TRACK_GAIN={track_gain:.2} dB"));
+            }
+            if let Some(album_gain) = replay_gain.album_gain {
+                comments.push(format!("REPLAYGAIN_ALBUM_GAIN={album_gain:.2} dB"));
+            }
+
+            picture = cover_picture(metadata.visuals(), input_path, &profile.art)?;
         } else {
-            FileSizeModel::Actual(size)
+            picture = cover_picture(&[], input_path, &profile.art)?;
+        }
+
+        apply_sidecar_lyrics(&mut comments, input_path)?;
+
+        // tracks of a cue sheet are tagged from the sheet
+        if let Some(cue) = cue {
+            cue.apply_tags(&mut comments);
+        }
+
+        (comments, picture)
+    };
+
+    let output_file = File::create(output_path).context("failed to create output file")?;
+
+    let mut encoder = FlacEncoder::new(
+        output_file,
+        sample_rate,
+        channel_count,
+        bits_per_sample,
+        &tags::vorbis_comment_header(&comments),
+        picture.as_deref(),
+    )?;
+
+    // symphonia scales integer samples to the full range of i32
+    let shift = 32 - bits_per_sample;
+
+    let mut packet_samples: Vec<Vec<i32>> = vec![Vec::new(); channel_count];
+
+    // tracks of a cue sheet start decoding close to their range
+    let mut decoded_frames = match range {
+        Some((start, _)) => {
+            let position =
+                seek_to_frame(&mut *format, audio_track_id, time_base, sample_rate, start)?;
+            decoder.reset();
+            position
+        }
+        None => 0,
+    };
+
+    loop {
+        interrupt.check()?;
+
+        // stop at the end of the range
+        if let Some((_, Some(end))) = range
+            && decoded_frames >= end
+        {
+            break;
+        }
+
+        // read next packet
+        let packet = match format.next_packet() {
+            Ok(Some(packet)) => packet,
+
+            // end of track
+            Ok(None) => break,
+
+            Err(e) => {
+                return Err(e).context("failed to read packet");
+            }
+        };
+
+        // skip packets from other tracks
+        if packet.track_id() != audio_track_id {
+            continue;
+        }
+
+        // decode packet
+        let audio_buf = decoder.decode(&packet).context("failed to decode packet")?;
+
+        let packet_start = decoded_frames;
+        decoded_frames += audio_buf.frames() as u64;
+        decode_progress.update(decoded_frames, progress);
+
+        for channel in &mut packet_samples {
+            channel.resize(audio_buf.frames(), 0);
+        }
+        audio_buf.copy_to_slice_planar(&mut packet_samples);
+
+        if let Some(range) = range {
+            trim_to_range(&mut packet_samples, 0, packet_start, range);
+        }
+
+        for channel in &mut packet_samples {
+            for sample in channel.iter_mut() {
+                *sample >>= shift;
+            }
+        }
+
+        encoder.push(&packet_samples)?;
+    }
+
+    // encode the final block
+    let mut file = encoder.finish()?;
+    progress.set_encoded(1, 1);
+
+    let file_size = file
+        .seek(SeekFrom::End(0))
+        .context("failed to seek to end of file")?;
+
+    Ok(file_size)
+}
+
+/// Builds a FLAC picture structure from the front cover, or the first visual
+/// if there's no front cover, or a sidecar cover image next to the input file
+/// if there are no visuals.
+///
+/// The image is scaled to fit the profile's art size and encoded in the art
+/// format. The structure is stored in a PICTURE block in FLAC files, and
+/// base64 encoded in a METADATA_BLOCK_PICTURE comment in Opus files.
+fn cover_picture(
+    visuals: &[Visual],
+    input_path: &Path,
+    options: &TranscodeArtOptions,
+) -> anyhow::Result<Option<Vec<u8>>> {
+    if !options.embed {
+        return Ok(None);
+    }
+
+    // find front cover visual or first available
+    let mut best_visual = visuals.first();
+    for visual in visuals {
+        if visual.usage == Some(StandardVisualKey::FrontCover) {
+            best_visual = Some(visual);
         }
     }
 
-    pub fn waiting_count_model(&self) -> CounterModel {
-        CounterModel::from(&self.status_cache.waiting_counter)
+    let data = match best_visual {
+        Some(visual) => Cow::Borrowed(&visual.data[..]),
+        None => {
+            let Some(sidecar_path) = sidecar_cover_path(input_path) else {
+                return Ok(None);
+            };
+
+            log::debug!("using sidecar cover image: {}", sidecar_path.display());
+
+            Cow::Owned(std::fs::read(&sidecar_path).context("failed to read sidecar cover image")?)
+        }
+    };
+
+    let rdr = ImageReader::new(Cursor::new(&data[..]))
+        .with_guessed_format()
+        .expect("cursor io never fails");
+    let source_format = rdr.format();
+
+    let original_image = rdr.decode().context("failed to decode image")?;
+    let fits =
+        original_image.width() <= options.max_size && original_image.height() <= options.max_size;
+
+    let (width, height, image_buf) =
+        if options.keep_small && fits && source_format == Some(options.format.image_format()) {
+            // small images in the right format are embedded as they are
+            (
+                original_image.width(),
+                original_image.height(),
+                data.into_owned(),
+            )
+        } else {
+            let image = if options.keep_small && fits {
+                original_image
+            } else {
+                original_image.resize(options.max_size, options.max_size, FilterType::Lanczos3)
+            };
+
+            let mut image_buf = vec![];
+            match options.format {
+                TranscodeArtFormat::Jpeg => {
+                    // jpeg has no alpha channel
+                    let image = DynamicImage::ImageRgb8(image.to_rgb8());
+
+                    let mut encoder =
+                        JpegEncoder::new_with_quality(&mut image_buf, options.quality);
+                    encoder
+                        .encode_image(&image)
+                        .context("failed to encode image")?;
+                }
+                TranscodeArtFormat::Png => {
+                    image
+                        .write_to(&mut Cursor::new(&mut image_buf), ImageFormat::Png)
+                        .context("failed to encode image")?;
+                }
+            }
+
+            (image.width(), image.height(), image_buf)
+        };
+
+    // construct flac picture structure
+    // note that flac uses big endian while vorbis comments use little endian
+    let mut picture = Vec::<u8>::new();
+    picture.extend(&3u32.to_be_bytes()); // picture type (3, front cover)
+
+    let media_type = options.format.media_type();
+    picture.extend(&(media_type.len() as u32).to_be_bytes());
+    picture.extend(media_type.as_bytes());
+
+    picture.extend(&[0, 0, 0, 0]); // description length
+    picture.extend(&width.to_be_bytes()); // width
+    picture.extend(&height.to_be_bytes()); // height
+    picture.extend(&[0, 0, 0, 0]); // color depth (0, unknown)
+    picture.extend(&[0, 0, 0, 0]); // indexed color count (0, non-indexed)
+
+    picture.extend(&(image_buf.len() as u32).to_be_bytes()); // picture data length
+    picture.extend(&image_buf); // picture data
+
+    Ok(Some(picture))
+}
+
+/// Resamples planar audio to 48 kHz in fixed-size chunks.
+///
+/// Input frames are buffered by the caller until a full chunk is available.
+/// The resampler's output delay is trimmed so that the output lines up with
+/// the input, and the output is truncated to the resampled input length.
+struct StreamResampler {
+    resampler: FftFixedIn<f32>,
+    sample_rate: usize,
+
+    /// Reused chunk output buffer.
+    output_buf: Vec<Vec<f32>>,
+
+    /// Number of output frames that still need to be discarded to account for
+    /// the resampler delay.
+    delay_remaining: usize,
+
+    /// Total number of input frames consumed.
+    input_frames: usize,
+    /// Total number of output frames passed to the sink.
+    output_frames: usize,
+}
+
+impl StreamResampler {
+    fn new(sample_rate: usize, channel_count: usize) -> anyhow::Result<Self> {
+        let resampler = FftFixedIn::<f32>::new(
+            sample_rate,
+            48000,
+            1024, // arbitrary
+            4,    // arbitrary
+            channel_count,
+        )
+        .context("failed to create resampler")?;
+
+        let output_buf = resampler.output_buffer_allocate(true);
+        let delay_remaining = resampler.output_delay();
+
+        Ok(Self {
+            resampler,
+            sample_rate,
+
+            output_buf,
+
+            delay_remaining,
+
+            input_frames: 0,
+            output_frames: 0,
+        })
     }
 
-    pub fn queued_count_model(&self) -> CounterModel {
-        CounterModel::from(&self.queue.ready_counter)
+    /// Resamples as many full chunks as are available and passes the output
+    /// to the sink.
+    ///
+    /// Consumed frames are removed from `input`. Leftover frames are kept for
+    /// the next call.
+    fn process<W: Write>(
+        &mut self,
+        input: &mut [Vec<f32>],
+        sink: &mut OpusPacketSink<'_, W>,
+    ) -> anyhow::Result<()> {
+        let available_frames = input[0].len();
+
+        let mut pos = 0;
+        loop {
+            // get number of frames needed for next chunk
+            let frames_needed = self.resampler.input_frames_next();
+
+            // check if we have enough frames for a full chunk
+            if pos + frames_needed > available_frames {
+                break;
+            }
+
+            let input_slices: Vec<&[f32]> = input
+                .iter()
+                .map(|channel| &channel[pos..(pos + frames_needed)])
+                .collect();
+
+            let (input_frames, output_frames) = self
+                .resampler
+                .process_into_buffer(&input_slices, &mut self.output_buf, None)
+                .expect("bad inputs to resampler");
+
+            self.emit(output_frames, None, sink)?;
+
+            // increment position by number of input frames consumed
+            self.input_frames += input_frames;
+            pos += input_frames;
+        }
+
+        // remove consumed frames, this is less than one chunk so it's cheap
+        for channel in input.iter_mut() {
+            channel.drain(0..pos);
+        }
+
+        Ok(())
     }
 
-    pub fn inprogress_count_model(&self) -> CounterModel {
-        CounterModel::from(&self.inprogress_counter.0)
+    /// Returns the number of frames after resampling the whole input,
+    /// including the remaining input frames.
+    fn total_output_frames(&self, input: &[Vec<f32>]) -> usize {
+        (self.input_frames + input[0].len()) * 48000 / self.sample_rate
     }
 
-    pub fn ready_count_model(&self) -> CounterModel {
-        CounterModel::from(&self.status_cache.ready_counter)
+    /// Returns the number of frames that `finish` passes to the sink.
+    fn remaining_output_frames(&self, input: &[Vec<f32>]) -> usize {
+        self.total_output_frames(input)
+            .saturating_sub(self.output_frames)
     }
 
-    pub fn failed_count_model(&self) -> CounterModel {
-        CounterModel::from(&self.status_cache.failed_counter)
+    /// Resamples the remaining input frames and flushes the resampler's
+    /// internal buffer.
+    fn finish<W: Write>(
+        mut self,
+        input: &[Vec<f32>],
+        sink: &mut OpusPacketSink<'_, W>,
+    ) -> anyhow::Result<()> {
+        let remaining_frames = input[0].len();
+
+        // number of frames after resampling the whole input
+        let total_frames = self.total_output_frames(input);
+
+        // resample final chunk with remaining frames
+        if remaining_frames > 0 {
+            let input_slices: Vec<&[f32]> = input.iter().map(|channel| &channel[..]).collect();
+
+            let (_input_frames, output_frames) = self
+                .resampler
+                .process_partial_into_buffer(Some(&input_slices), &mut self.output_buf, None)
+                .expect("bad inputs to resampler");
+
+            self.emit(output_frames, Some(total_frames), sink)?;
+        }
+
+        // continue feeding zeros to the resampler until we have enough frames
+        // this ensures we account for resample delay and push everything through its internal buffer
+        while self.output_frames < total_frames {
+            let (_input_frames, output_frames) = self
+                .resampler
+                .process_partial_into_buffer(None::<&[&[f32]]>, &mut self.output_buf, None)
+                .expect("bad inputs to resampler");
+
+            self.emit(output_frames, Some(total_frames), sink)?;
+        }
+
+        Ok(())
+    }
+
+    /// Passes frames from the chunk output buffer to the sink, skipping the
+    /// resampler delay and stopping once `limit` total frames were passed.
+    fn emit<W: Write>(
+        &mut self,
+        frames: usize,
+        limit: Option<usize>,
+        sink: &mut OpusPacketSink<'_, W>,
+    ) -> anyhow::Result<()> {
+        // remove resample delay frames from the start
+        let start = frames.min(self.delay_remaining);
+        self.delay_remaining -= start;
+
+        // truncate to the resampled input length
+        let end = match limit {
+            Some(limit) => frames.min(start + limit.saturating_sub(self.output_frames)),
+            None => frames,
+        };
+
+        if end > start {
+            let output_slices: Vec<&[f32]> = self
+                .output_buf
+                .iter()
+                .map(|channel| &channel[start..end])
+                .collect();
+            sink.push(&output_slices)?;
+
+            self.output_frames += end - start;
+        }
+
+        Ok(())
     }
 }
 
-struct TranscodeWorker {}
+/// Encodes planar 48 kHz audio into Opus packets and writes them to an Ogg
+/// stream.
+///
+/// Input is measured for loudness and buffered by the encoder until a full
+/// chunk is available. The most recent packet is held back so that it can be
+/// written as the end of the stream with an end-trimming granule position.
+struct OpusPacketSink<'a, W: Write> {
+    encoder: MultistreamEncoder,
+    packet_writer: ogg::PacketWriter<'a, W>,
+    serial: u32,
+
+    meter: LoudnessMeter,
+
+    chunk_frames: usize,
+    /// Number of frames buffered in the encoder for the next chunk.
+    buffered_frames: usize,
+
+    /// The most recently encoded packet and its granule position.
+    pending_packet: Option<(Vec<u8>, u64)>,
+
+    /// Total number of frames in encoded chunks, including padding.
+    encoded_frames: u64,
+    /// Total number of frames pushed, including the lookahead padding.
+    total_frames: u64,
+
+    /// Encoded chunks are counted toward this progress after the input is
+    /// decoded, with the number of chunks encoded before that and the total
+    /// number of chunks. See `track_progress`.
+    progress: Option<(TranscodeProgress, u64, u64)>,
+}
 
-impl TranscodeWorker {
-    /// Start a new transcode worker thread and return a handle to it.
-    pub fn new(
-        transcodes_dir: PathBuf,
-        status_cache: TranscodeStatusCache,
-        queue: Arc<TranscodeQueue>,
-        inprogress_counter: RegionCounter,
+impl<'a, W: Write> OpusPacketSink<'a, W> {
+    fn new(
+        encoder: MultistreamEncoder,
+        packet_writer: ogg::PacketWriter<'a, W>,
+        serial: u32,
+        chunk_frames: usize,
     ) -> Self {
-        std::thread::spawn(move || {
-            if let Err(e) = Self::run(transcodes_dir, status_cache, queue, inprogress_counter) {
-                log::error!("transcode worker failed: {e:#}");
-            }
-        });
+        let meter = LoudnessMeter::new(encoder.channel_count());
 
-        Self {}
+        Self {
+            encoder,
+            packet_writer,
+            serial,
+
+            meter,
+
+            chunk_frames,
+            buffered_frames: 0,
+
+            pending_packet: None,
+
+            encoded_frames: 0,
+            total_frames: 0,
+
+            progress: None,
+        }
     }
 
-    /// Implementation of the transcode worker thread.
-    fn run(
-        transcodes_dir: PathBuf,
-        status_cache: TranscodeStatusCache,
-        queue: Arc<TranscodeQueue>,
-        inprogress_counter: RegionCounter,
-    ) -> anyhow::Result<()> {
-        loop {
-            // wait for a job
-            let job = queue.wait();
+    /// Counts the chunks encoded from now on toward a transcode's progress,
+    /// given the number of frames that are still going to be pushed.
+    fn track_progress(&mut self, progress: &TranscodeProgress, remaining_frames: usize) {
+        let chunk_frames = self.chunk_frames as u64;
+        let encoded_chunks = self.encoded_frames / chunk_frames;
+        let total_chunks = encoded_chunks
+            + (self.buffered_frames as u64 + remaining_frames as u64).div_ceil(chunk_frames);
 
-            // mark thread as in-progress
-            let _counter_guard = inprogress_counter.entered();
+        progress.set_encoded(0, total_chunks - encoded_chunks);
+        self.progress = Some((progress.clone(), encoded_chunks, total_chunks));
+    }
 
-            // write to temp filename
-            let temp_path =
-                transcodes_dir.join(format!("{}-{}.tmp", job.hash_kind, hex::encode(&job.hash)));
+    /// Pushes planar samples to be measured and encoded.
+    fn push<S: AsRef<[f32]>>(&mut self, samples: &[S]) -> anyhow::Result<()> {
+        self.meter.process(samples);
 
-            log::info!("transcoding file: {}", job.local_path.display());
-            let file_size = match transcode(&job.local_path, &temp_path) {
-                Ok(file_size) => file_size,
+        self.encode_frames(samples)
+    }
 
-                Err(e) => {
-                    log::error!(
-                        "failed to transcode file: {} -> {}: {e:#}",
-                        job.local_path.display(),
-                        temp_path.display()
-                    );
+    /// Pushes silent frames.
+    ///
+    /// Silence isn't measured, since it's only used for padding.
+    fn push_silence(&mut self, frames: usize) -> anyhow::Result<()> {
+        self.encode_frames(&vec![vec![0.0; frames]; self.encoder.channel_count()])
+    }
 
-                    // try to remove the temp file
-                    let _ = std::fs::remove_file(&temp_path);
+    /// Returns the integrated loudness of the pushed samples.
+    fn integrated_loudness(&self) -> Option<Loudness> {
+        self.meter.integrated_loudness()
+    }
 
-                    // set status to Failed
-                    status_cache.insert(
-                        job.hash_kind.clone(),
-                        job.hash.clone(),
-                        TranscodeStatus::Failed { error: e },
-                    );
+    /// Buffers planar samples, encoding each full chunk.
+    fn encode_frames<S: AsRef<[f32]>>(&mut self, samples: &[S]) -> anyhow::Result<()> {
+        let frames = samples[0].as_ref().len();
 
-                    // next job
-                    continue;
-                }
-            };
+        for i in 0..frames {
+            self.encoder.push_frame(samples, i);
+            self.buffered_frames += 1;
 
-            // rename the temp file
-            let final_path = temp_path.with_extension("ogg");
-            if let Err(e) = std::fs::rename(&temp_path, &final_path) {
-                log::error!(
-                    "failed to rename temp file: {} -> {}: {e:#}",
-                    temp_path.display(),
-                    final_path.display()
-                );
+            if self.buffered_frames == self.chunk_frames {
+                self.encode_chunk()?;
+            }
+        }
 
-                // set status to Failed
-                status_cache.insert(
-                    job.hash_kind.clone(),
-                    job.hash.clone(),
-                    TranscodeStatus::Failed {
-                        error: anyhow::anyhow!("failed to rename temp file: {e:#}"),
-                    },
-                );
+        self.total_frames += frames as u64;
 
-                // next job
-                continue;
-            };
+        Ok(())
+    }
 
-            log::info!(
-                "finished transcoding file: {} -> {}",
-                job.local_path.display(),
-                final_path.display()
-            );
+    /// Encodes the full chunk buffered in the encoder.
+    fn encode_chunk(&mut self) -> anyhow::Result<()> {
+        let packet = self.encoder.encode()?;
+        self.buffered_frames = 0;
 
-            // set status to Ready
-            status_cache.insert(
-                job.hash_kind.clone(),
-                job.hash.clone(),
-                TranscodeStatus::Ready {
-                    local_path: final_path,
-                    file_size,
-                },
-            );
+        // the number of frames up to and including the last frame in this packet
+        // this is measured in frames, so mono and stereo increase at the same rate
+        self.encoded_frames += self.chunk_frames as u64;
+        let granule_position = self.encoded_frames;
+
+        if let Some((progress, first_chunk, total_chunks)) = &self.progress {
+            let encoded_chunks = self.encoded_frames / self.chunk_frames as u64;
+            progress.set_encoded(encoded_chunks - first_chunk, total_chunks - first_chunk);
+        }
+
+        // now that there's a newer packet, the previous one isn't the end of the stream
+        if let Some((packet, granule_position)) =
+            self.pending_packet.replace((packet, granule_position))
+        {
+            self.packet_writer
+                .write_packet(
+                    packet,
+                    self.serial,
+                    ogg::PacketWriteEndInfo::NormalPacket,
+                    granule_position,
+                )
+                .context("failed to write packet")?;
         }
 
-        // worker shut down
         Ok(())
     }
+
+    /// Encodes the final chunk and writes the end of the stream.
+    ///
+    /// Returns the inner writer.
+    fn finish(mut self) -> anyhow::Result<W> {
+        // opus always requires a full chunk of input but we might not have enough remaining
+        // samples, so zero-pad the final chunk
+        if self.buffered_frames > 0 {
+            for _ in self.buffered_frames..self.chunk_frames {
+                self.encoder.push_silent_frame();
+            }
+            self.encode_chunk()?;
+        }
+
+        if let Some((packet, _)) = self.pending_packet.take() {
+            // for end-trimming, the granule position of the final packet is the total number of input frames
+            // this may be less than the position of the final frame in the final packet
+            // this allows the player to trim the padding samples from the final chunk
+            let granule_position = self.total_frames;
+
+            self.packet_writer
+                .write_packet(
+                    packet,
+                    self.serial,
+                    ogg::PacketWriteEndInfo::EndStream,
+                    granule_position,
+                )
+                .context("failed to write packet")?;
+        }
+
+        Ok(self.packet_writer.into_inner())
+    }
 }
 
-/// Counts the number of threads of execution that are in a region.
+/// Describes how channels are split into Opus streams.
 ///
-/// This is used to track how many worker threads are currently working.
-#[derive(Debug, Clone)]
-struct RegionCounter(Arc<AtomicU64>);
+/// See RFC 7845 section 5.1.1.
+#[derive(Debug)]
+struct ChannelMapping {
+    /// The channel mapping family written to the OpusHead.
+    family: u8,
+    stream_count: usize,
+    coupled_count: usize,
+    /// The decoded stream channel for each output channel, in Vorbis channel
+    /// order. This is empty for family 0.
+    mapping: Vec<u8>,
+
+    /// The input channels encoded by each stream, in stream channel order.
+    stream_channels: Vec<Vec<usize>>,
+}
 
-impl RegionCounter {
-    /// Creates a new RegionCounter.
-    pub fn new() -> Self {
-        Self(Arc::new(AtomicU64::new(0)))
+impl ChannelMapping {
+    /// Creates the channel mapping for a channel count.
+    ///
+    /// Mono and stereo use family 0 with a single stream. Surround uses
+    /// family 1 with the Vorbis channel layouts.
+    fn new(channel_count: usize) -> anyhow::Result<Self> {
+        match channel_count {
+            1 => {
+                return Ok(Self {
+                    family: 0,
+                    stream_count: 1,
+                    coupled_count: 0,
+                    mapping: Vec::new(),
+                    stream_channels: vec![vec![0]],
+                });
+            }
+            2 => {
+                return Ok(Self {
+                    family: 0,
+                    stream_count: 1,
+                    coupled_count: 1,
+                    mapping: Vec::new(),
+                    stream_channels: vec![vec![0, 1]],
+                });
+            }
+            _ => {}
+        }
+
+        // stream counts and mapping tables from RFC 7845 section 5.1.1.2
+        let (stream_count, coupled_count, mapping): (usize, usize, &[u8]) = match channel_count {
+            3 => (2, 1, &[0, 2, 1]),
+            4 => (2, 2, &[0, 1, 2, 3]),
+            5 => (3, 2, &[0, 4, 1, 2, 3]),
+            6 => (4, 2, &[0, 4, 1, 2, 3, 5]),
+            7 => (4, 3, &[0, 4, 1, 2, 3, 5, 6]),
+            8 => (5, 3, &[0, 6, 1, 2, 3, 4, 5, 7]),
+            _ => anyhow::bail!("unsupported channel count: {}", channel_count),
+        };
+
+        // symphonia orders channels like WAVE (FL, FR, FC, LFE, BL, BR, SL, SR), but
+        // the Vorbis order puts the center between the fronts and the LFE last
+        // this is the input channel for each channel in Vorbis order
+        let vorbis_order: &[usize] = match channel_count {
+            3 => &[0, 2, 1],
+            4 => &[0, 1, 2, 3],
+            5 => &[0, 2, 1, 3, 4],
+            6 => &[0, 2, 1, 4, 5, 3],
+            7 => &[0, 2, 1, 5, 6, 4, 3],
+            8 => &[0, 2, 1, 6, 7, 4, 5, 3],
+            _ => unreachable!(),
+        };
+
+        // coupled streams decode to two channels each, followed by one channel
+        // for each uncoupled stream
+        let stream_channels = (0..stream_count)
+            .map(|stream| {
+                let decoded_channels = if stream < coupled_count {
+                    vec![stream * 2, stream * 2 + 1]
+                } else {
+                    vec![coupled_count + stream]
+                };
+
+                decoded_channels
+                    .into_iter()
+                    .map(|decoded_channel| {
+                        let vorbis_channel = mapping
+                            .iter()
+                            .position(|&m| m as usize == decoded_channel)
+                            .expect("mapping table should contain every decoded channel");
+                        vorbis_order[vorbis_channel]
+                    })
+                    .collect()
+            })
+            .collect();
+
+        Ok(Self {
+            family: 1,
+            stream_count,
+            coupled_count,
+            mapping: mapping.to_vec(),
+            stream_channels,
+        })
     }
 
-    /// Gets the current count.
-    pub fn count(&self) -> u64 {
-        self.0.load(Ordering::Relaxed)
+    /// Returns the bitrate for a stream.
+    fn stream_bitrate(&self, stream: usize, profile: &TranscodeProfile) -> u32 {
+        if self.stream_count == 1 || stream < self.coupled_count {
+            profile.bitrate
+        } else {
+            (profile.bitrate / 2).max(TranscodeProfile::MIN_BITRATE)
+        }
+    }
+}
+
+/// Encodes each stream of a channel mapping with its own Opus encoder and
+/// combines the results into multistream packets.
+struct MultistreamEncoder {
+    encoders: Vec<opus::Encoder>,
+    stream_channels: Vec<Vec<usize>>,
+
+    /// Interleaved input samples for each stream.
+    input_bufs: Vec<Vec<f32>>,
+}
+
+impl MultistreamEncoder {
+    fn new(mapping: &ChannelMapping, profile: &TranscodeProfile) -> anyhow::Result<Self> {
+        let mut encoders = Vec::with_capacity(mapping.stream_count);
+        for (stream, channels) in mapping.stream_channels.iter().enumerate() {
+            let mut encoder = opus::Encoder::new(
+                48000,
+                match channels.len() {
+                    1 => opus::Channels::Mono,
+                    _ => opus::Channels::Stereo,
+                },
+                profile.application.into(),
+            )
+            .context("failed to create opus encoder")?;
+            encoder
+                .set_bitrate(opus::Bitrate::Bits(
+                    mapping.stream_bitrate(stream, profile) as i32
+                ))
+                .context("failed to set opus bitrate")?;
+            encoder
+                .set_vbr(profile.bitrate_mode != TranscodeBitrateMode::Cbr)
+                .context("failed to set opus vbr")?;
+            encoder
+                .set_vbr_constraint(profile.bitrate_mode == TranscodeBitrateMode::ConstrainedVbr)
+                .context("failed to set opus vbr constraint")?;
+            encoder
+                .set_complexity(profile.complexity as i32)
+                .context("failed to set opus complexity")?;
+
+            encoders.push(encoder);
+        }
+
+        Ok(Self {
+            encoders,
+            stream_channels: mapping.stream_channels.clone(),
+
+            input_bufs: vec![Vec::new(); mapping.stream_count],
+        })
+    }
+
+    /// Returns the total number of channels.
+    fn channel_count(&self) -> usize {
+        self.stream_channels
+            .iter()
+            .map(|channels| channels.len())
+            .sum()
+    }
+
+    /// Returns the encoder lookahead in frames.
+    ///
+    /// All streams use the same settings, so they have the same lookahead.
+    fn lookahead(&self) -> anyhow::Result<usize> {
+        let lookahead = self.encoders[0]
+            .get_lookahead()
+            .context("failed to get opus encoder lookahead")?;
+
+        Ok(lookahead as usize)
+    }
+
+    /// Buffers one frame of planar samples.
+    fn push_frame<S: AsRef<[f32]>>(&mut self, samples: &[S], index: usize) {
+        // interleave samples since opus needs interleaved input
+        // TODO: profile + explore SIMD for this
+        for (input_buf, channels) in self.input_bufs.iter_mut().zip(&self.stream_channels) {
+            for &channel in channels {
+                input_buf.push(samples[channel].as_ref()[index]);
+            }
+        }
+    }
+
+    /// Buffers one silent frame.
+    fn push_silent_frame(&mut self) {
+        for (input_buf, channels) in self.input_bufs.iter_mut().zip(&self.stream_channels) {
+            input_buf.extend(std::iter::repeat_n(0.0, channels.len()));
+        }
+    }
+
+    /// Encodes the buffered chunk into a single packet.
+    fn encode(&mut self) -> anyhow::Result<Vec<u8>> {
+        let mut packet = Vec::new();
+
+        let last_stream = self.encoders.len() - 1;
+        for (stream, (encoder, input_buf)) in self
+            .encoders
+            .iter_mut()
+            .zip(&mut self.input_bufs)
+            .enumerate()
+        {
+            // allocate chunk output buffer
+            // encode_float uses the length (not capacity) as max_data_size
+            // length comes from recommended max_data_size in opus documentation
+            let mut output_buf = vec![0; 4000];
+
+            // call encoder with chunk input buffer and chunk output buffer
+            let output_len = encoder
+                .encode_float(input_buf, &mut output_buf)
+                .context("failed to encode chunk")?;
+            output_buf.truncate(output_len);
+
+            input_buf.clear();
+
+            // every stream except the last uses self-delimited framing so the
+            // decoder can find where the next stream starts
+            if stream == last_stream {
+                packet.extend(output_buf);
+            } else {
+                write_self_delimited_packet(&output_buf, &mut packet)?;
+            }
+        }
+
+        Ok(packet)
+    }
+}
+
+/// Downmixes stereo or surround audio to stereo or mono.
+#[derive(Debug)]
+struct Downmix {
+    /// The left and right gains for each input channel.
+    gains: Vec<(f32, f32)>,
+}
+
+impl Downmix {
+    /// Creates a downmix for input with the given channel count, in WAVE
+    /// channel order.
+    fn new(channel_count: usize) -> anyhow::Result<Self> {
+        const C: f32 = std::f32::consts::FRAC_1_SQRT_2;
+
+        // ITU-R BS.775 coefficients, the LFE channel is dropped
+        let gains: &[(f32, f32)] = match channel_count {
+            // FL, FR, only used for mono output
+            2 => &[(1.0, 0.0), (0.0, 1.0)],
+            // FL, FR, FC
+            3 => &[(1.0, 0.0), (0.0, 1.0), (C, C)],
+            // FL, FR, BL, BR
+            4 => &[(1.0, 0.0), (0.0, 1.0), (C, 0.0), (0.0, C)],
+            // FL, FR, FC, BL, BR
+            5 => &[(1.0, 0.0), (0.0, 1.0), (C, C), (C, 0.0), (0.0, C)],
+            // FL, FR, FC, LFE, BL, BR
+            6 => &[
+                (1.0, 0.0),
+                (0.0, 1.0),
+                (C, C),
+                (0.0, 0.0),
+                (C, 0.0),
+                (0.0, C),
+            ],
+            // FL, FR, FC, LFE, BC, SL, SR
+            7 => &[
+                (1.0, 0.0),
+                (0.0, 1.0),
+                (C, C),
+                (0.0, 0.0),
+                (0.5, 0.5),
+                (C, 0.0),
+                (0.0, C),
+            ],
+            // FL, FR, FC, LFE, BL, BR, SL, SR
+            8 => &[
+                (1.0, 0.0),
+                (0.0, 1.0),
+                (C, C),
+                (0.0, 0.0),
+                (C, 0.0),
+                (0.0, C),
+                (C, 0.0),
+                (0.0, C),
+            ],
+            _ => anyhow::bail!("unsupported channel count for downmix: {}", channel_count),
+        };
+
+        // normalize so that the mix can't clip
+        let total: f32 = gains.iter().map(|(left, _)| left).sum();
+        let gains = gains
+            .iter()
+            .map(|(left, right)| (left / total, right / total))
+            .collect();
+
+        Ok(Self { gains })
     }
 
-    /// Enters the region and increments the count, returning a guard that
-    /// decrements the count when dropped at the end of the region.
-    pub fn entered(&self) -> RegionCounterGuard {
-        RegionCounterGuard::new(self)
+    /// Mixes planar input frames and appends them to the output, which has
+    /// either two channels or one.
+    fn process(&self, input: &[Vec<f32>], output: &mut [Vec<f32>]) {
+        let frames = input[0].len();
+
+        for i in 0..frames {
+            let mut left = 0.0;
+            let mut right = 0.0;
+            for (channel, (left_gain, right_gain)) in input.iter().zip(&self.gains) {
+                left += channel[i] * left_gain;
+                right += channel[i] * right_gain;
+            }
+
+            match output {
+                [mono] => mono.push((left + right) / 2.0),
+                [output_left, output_right] => {
+                    output_left.push(left);
+                    output_right.push(right);
+                }
+                _ => unreachable!("downmix output must be mono or stereo"),
+            }
+        }
     }
 }
 
-struct RegionCounterGuard<'a>(&'a RegionCounter);
+/// Lossy codecs that are passed through instead of transcoded when their
+/// bitrate is low enough.
+const PASSTHROUGH_CODECS: [AudioCodecId; 4] =
+    [CODEC_ID_OPUS, CODEC_ID_VORBIS, CODEC_ID_MP3, CODEC_ID_AAC];
 
-impl<'a> RegionCounterGuard<'a> {
-    fn new(counter: &'a RegionCounter) -> Self {
-        counter.0.fetch_add(1, Ordering::Relaxed);
-        Self(counter)
+/// Checks if a file should be passed through as-is instead of transcoded.
+///
+/// Lossy sources with an average bitrate that already fits the profile are
+/// passed through, since re-encoding them would use CPU and only lose quality.
+/// Files that need processing, like downmixing or loudness normalization, are
+/// always transcoded.
+///
+/// With FLAC output, FLAC sources and all lossy sources are passed through,
+/// since lossless output can't improve lossy sources.
+///
+/// Files with sidecar lyrics are never passed through, so the lyrics can be
+/// embedded in the output. Files with art that the art settings would change
+/// aren't either, see `art_passes_through`.
+///
+/// Copies keep the source's tags in its own container, where players read
+/// them from, so the tags aren't mapped like they are for transcodes.
+///
+/// Returns the extension of the file's container if it should be passed
+/// through.
+pub fn passthrough_extension(
+    path: &Path,
+    profile: &TranscodeProfile,
+) -> anyhow::Result<Option<String>> {
+    if profile.format == TranscodeFormat::Opus && profile.normalize_loudness {
+        return Ok(None);
     }
-}
 
-impl Drop for RegionCounterGuard<'_> {
-    fn drop(&mut self) {
-        self.0.0.fetch_sub(1, Ordering::Relaxed);
+    if sidecar_lyrics_path(path).is_some() {
+        return Ok(None);
     }
-}
 
-/// Transcode a file.
-///
-/// Returns the file size of the output file.
-fn transcode(input_path: &Path, output_path: &Path) -> anyhow::Result<u64> {
-    let input_file = File::open(input_path).context("failed to open input file")?;
+    let Some(extension) = path.extension() else {
+        return Ok(None);
+    };
+    let extension = extension
+        .to_str()
+        .context("invalid file extension")?
+        .to_ascii_lowercase();
 
-    let mss = MediaSourceStream::new(Box::new(input_file), Default::default());
+    let src = std::fs::File::open(path).context("failed to open file")?;
+    let file_size = src.metadata().context("failed to get file metadata")?.len();
+
+    let mss = MediaSourceStream::new(Box::new(src), Default::default());
 
     let mut hint = Hint::new();
-    if let Some(extension) = input_path.extension() {
-        hint.with_extension(extension.to_str().context("invalid file extension")?);
-    }
+    hint.with_extension(&extension);
 
     let mut format = symphonia::default::get_probe()
         .probe(&hint, mss, Default::default(), Default::default())
-        .context("failed to probe file format")?;
+        .context("failed to probe file")?;
+
+    let art_passes_through = match format.metadata().skip_to_latest() {
+        Some(metadata) => art_passes_through(metadata.visuals(), path, &profile.art),
+        None => art_passes_through(&[], path, &profile.art),
+    };
+    if !art_passes_through {
+        return Ok(None);
+    }
 
     // get the default audio track
     let audio_track = format
         .default_track(TrackType::Audio)
         .context("failed to get default audio track")?;
-    let audio_track_id = audio_track.id;
-
-    // get codec parameters for the audio track
-    let codec_params = audio_track
+    let audio_codec_params = audio_track
         .codec_params
         .as_ref()
-        .context("failed to get codec parameters")?;
-    let audio_codec_params = codec_params
+        .context("failed to get codec parameters")?
         .audio()
         .context("codec parameters are not audio")?;
 
-    // get channel count and sample rate from codec parameters
+    if profile.format == TranscodeFormat::Flac {
+        let codec = audio_codec_params.codec;
+        let passthrough = codec == CODEC_ID_FLAC || PASSTHROUGH_CODECS.contains(&codec);
+        return Ok(passthrough.then_some(extension));
+    }
+
+    if !PASSTHROUGH_CODECS.contains(&audio_codec_params.codec) {
+        return Ok(None);
+    }
+
+    // files that would be downmixed are transcoded
     let channel_count = audio_codec_params
         .channels
         .as_ref()
         .context("failed to get channel count from codec params")?
         .count();
-    let sample_rate = audio_codec_params
-        .sample_rate
-        .context("failed to get sample rate from codec params")? as usize;
+    if profile.output_channel_count(channel_count)? != channel_count {
+        return Ok(None);
+    }
 
-    let mut decoder = symphonia::default::get_codecs()
-        .make_audio_decoder(audio_codec_params, &Default::default())
-        .context("failed to create decoder")?;
+    // the average bitrate can't be known without the duration
+    let (Some(time_base), Some(num_frames)) = (audio_track.time_base, audio_track.num_frames)
+    else {
+        return Ok(None);
+    };
+    let duration = time_base.calc_time(num_frames);
+    let duration_secs = duration.seconds as f64 + duration.frac;
+    if duration_secs <= 0.0 {
+        return Ok(None);
+    }
 
-    // decode the audio track into planar samples
-    let mut original_samples: Vec<Vec<f32>> = vec![Vec::new(); channel_count];
-    loop {
-        // read next packet
-        let packet = match format.next_packet() {
-            Ok(Some(packet)) => packet,
+    // the file size includes tags and cover art, so this slightly overestimates
+    let bitrate = file_size as f64 * 8.0 / duration_secs;
+    if bitrate > profile.total_bitrate(channel_count) as f64 {
+        return Ok(None);
+    }
 
-            // end of track
-            Ok(None) => break,
+    Ok(Some(extension))
+}
 
-            Err(e) => {
-                return Err(e).context("failed to read packet");
-            }
-        };
+/// Checks if a copy of a file would have the same art as a transcode, apart
+/// from the image format.
+///
+/// Files with art aren't copied if art isn't embedded or the art is larger
+/// than the art size. Files without art aren't copied if they have a sidecar
+/// cover, since it's embedded in transcodes.
+fn art_passes_through(visuals: &[Visual], path: &Path, options: &TranscodeArtOptions) -> bool {
+    if visuals.is_empty() {
+        return !options.embed || sidecar_cover_path(path).is_none();
+    }
 
-        // skip packets from other tracks
-        if packet.track_id() != audio_track_id {
-            continue;
-        }
+    options.embed
+        && visuals.iter().all(|visual| {
+            ImageReader::new(Cursor::new(&visual.data[..]))
+                .with_guessed_format()
+                .expect("cursor io never fails")
+                .into_dimensions()
+                .is_ok_and(|(width, height)| {
+                    width <= options.max_size && height <= options.max_size
+                })
+        })
+}
 
-        // decode packet
-        let audio_buf = decoder.decode(&packet).context("failed to decode packet")?;
+#[cfg(test)]
+mod tests {
+    use std::{sync::atomic::AtomicBool, time::Duration};
+
+    use super::*;
 
-        // copy to output buffer
-        // symphonia only lets us copy to vecs/slices, which replaces instead of extending
-        // we need to manually resize each channel and then copy to mut slices of the new extended areas
-        let mut output_slices = Vec::with_capacity(channel_count);
-        for channel in &mut original_samples {
-            let curr_len = channel.len();
-            let new_len = curr_len + audio_buf.frames();
-            channel.resize(new_len, 0.0);
-            output_slices.push(&mut channel[curr_len..new_len]);
+    fn test_item(hash: u8) -> TranscodeItem {
+        TranscodeItem {
+            hash_kind: "test".to_string(),
+            hash: vec![hash],
+            local_path: PathBuf::from("test.ogg"),
+            content: TranscodeContent::Music,
+            cue: None,
         }
-        audio_buf.copy_to_slice_planar(&mut output_slices);
     }
 
-    // construct the encoder before resampling to determine the lookahead
-    let mut encoder = opus::Encoder::new(
-        48000,
-        match channel_count {
-            1 => opus::Channels::Mono,
-            2 => opus::Channels::Stereo,
-            _ => anyhow::bail!("unsupported channel count: {}", channel_count),
-        },
-        opus::Application::Audio,
-    )
-    .context("failed to create opus encoder")?;
-    encoder
-        .set_bitrate(opus::Bitrate::Bits(128000))
-        .context("failed to set opus bitrate")?;
+    fn join_timeout<T>(timeout: std::time::Duration, thread: std::thread::JoinHandle<T>) -> T {
+        let now = std::time::Instant::now();
 
-    let lookahead_frames = encoder
-        .get_lookahead()
-        .context("failed to get opus encoder lookahead")? as usize;
+        while now.elapsed() < timeout {
+            if thread.is_finished() {
+                return thread.join().unwrap();
+            }
+            std::thread::sleep(std::time::Duration::from_millis(10));
+        }
 
-    // resample to 48k if needed
-    // also pad the start with zeros to account for encoder lookahead. doing
-    // this now allows the encoding logic to be simpler and more efficient.
-    let mut resampled_samples = if sample_rate != 48000 {
-        let mut resampler = FftFixedIn::<f32>::new(
-            sample_rate,
-            48000,
-            1024, // arbitrary
-            4,    // arbitrary
-            channel_count,
-        )
-        .context("failed to create resampler")?;
+        panic!("thread timed out");
+    }
 
-        let delay = resampler.output_delay();
+    fn fixture_path(name: &str) -> PathBuf {
+        PathBuf::from(env!("CARGO_MANIFEST_DIR"))
+            .join("fixtures")
+            .join(name)
+    }
 
-        let original_frames = original_samples[0].len();
+    /// Transcodes a fixture to a temporary file and returns the output path.
+    fn transcode_fixture(name: &str, profile: &TranscodeProfile) -> PathBuf {
+        let output_path = std::env::temp_dir().join(format!(
+            "musicopy-test-{}-{}.ogg",
+            std::process::id(),
+            name.replace('.', "-")
+        ));
+
+        transcode(
+            &fixture_path(name),
+            None,
+            &output_path,
+            profile,
+            &TranscodeProgress::default(),
+            &TranscodeInterrupt::default(),
+        )
+        .unwrap();
 
-        // number of frames after resampling, including zero-padding for encoder lookahead
-        let new_frames = (original_frames * 48000 / sample_rate) + lookahead_frames;
+        output_path
+    }
 
-        // pre-allocate output buffer with enough capacity
-        // TODO: we might need a little more than this, should check its final capacity to see if it gets resized usually
-        let mut resampled_samples: Vec<Vec<f32>> =
-            vec![Vec::with_capacity(new_frames + delay); channel_count];
+    /// Reads all packets of an Ogg file.
+    fn read_ogg_packets(path: &Path) -> Vec<ogg::Packet> {
+        let mut reader = ogg::PacketReader::new(File::open(path).unwrap());
 
-        // pad start with zeros
-        for channel in resampled_samples.iter_mut() {
-            channel.resize(lookahead_frames, 0.0);
+        let mut packets = Vec::new();
+        while let Some(packet) = reader.read_packet().unwrap() {
+            packets.push(packet);
         }
+        packets
+    }
 
-        // allocate chunk input slices vec and chunk output buffer
-        let mut input_slices: Vec<&[f32]> = vec![&[]; channel_count];
-        let mut output_buf = resampler.output_buffer_allocate(true);
+    /// Reads the user comments from the OpusTags packet of an Ogg Opus file.
+    fn read_opus_comments(path: &Path) -> Vec<String> {
+        let packets = read_ogg_packets(path);
+        tags::parse_opus_tags(&packets[1].data).unwrap()
+    }
 
-        // resample in chunks
-        let mut pos = 0;
-        loop {
-            // get number of frames needed for next chunk
-            let frames_needed = resampler.input_frames_next();
+    #[test]
+    fn test_evict_least_recently_used() {
+        let dir = std::env::temp_dir().join(format!("musicopy-test-{}-evict", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
 
-            // check if we have enough frames for a full chunk
-            if pos + frames_needed > original_frames {
-                break;
-            }
+        let status_cache = TranscodeStatusCache::new();
+        let mut sources = HashMap::new();
+        for hash in 1..=3u8 {
+            let local_path = dir.join(format!("test-0{hash}.ogg"));
+            std::fs::write(&local_path, vec![0; 100]).unwrap();
 
-            // copy reference to slice of original buffer to input slices vec
-            for i in 0..channel_count {
-                input_slices[i] = &original_samples[i][pos..(pos + frames_needed)];
-            }
+            status_cache.insert(
+                "test".to_string(),
+                vec![hash],
+                TranscodeStatus::Ready {
+                    local_path,
+                    file_size: 100,
+                    passthrough: false,
+                },
+            );
+            // file 1 was used most recently
+            status_cache.set_last_used(
+                "test".to_string(),
+                vec![hash],
+                SystemTime::UNIX_EPOCH + Duration::from_secs(10 - hash as u64),
+            );
 
-            // call resampler with chunk input slices vec and chunk output buffer
-            let (input_frames, output_frames) = resampler
-                .process_into_buffer(&input_slices, &mut output_buf, None)
-                .expect("bad inputs to resampler");
+            sources.insert(("test".to_string(), vec![hash]), test_item(hash));
+        }
 
-            // copy chunk output buffer to resampled samples
-            for i in 0..channel_count {
-                resampled_samples[i].extend_from_slice(&output_buf[i][0..output_frames]);
+        let mut evicted = HashMap::new();
+        TranscodePool::evict(&status_cache, &dir, &sources, &mut evicted, 150);
+
+        // the two least recently used files are evicted to fit in the limit
+        assert_eq!(status_cache.ready_size(), 100);
+        assert!(dir.join("test-01.ogg").exists());
+        assert!(!dir.join("test-02.ogg").exists());
+        assert!(!dir.join("test-03.ogg").exists());
+
+        // and they're waiting to be requested again
+        assert_eq!(evicted.len(), 2);
+        assert!(matches!(
+            *status_cache.get("test", &[3]).unwrap(),
+            TranscodeStatus::Waiting {
+                estimated_size: Some(100)
             }
+        ));
+        assert_eq!(status_cache.waiting_counter().load(Ordering::Relaxed), 2);
+        assert_eq!(status_cache.ready_counter().load(Ordering::Relaxed), 1);
+
+        // which is remembered across restarts
+        let markers = TranscodePool::read_evicted_markers(&dir, &TranscodeStatusCache::new());
+        assert_eq!(
+            markers,
+            HashSet::from([("test".to_string(), vec![2]), ("test".to_string(), vec![3])])
+        );
 
-            // increment position by number of input frames consumed
-            pos += input_frames;
-        }
+        let _ = std::fs::remove_dir_all(&dir);
+    }
 
-        // resample final chunk with remaining frames
-        if pos < original_frames {
-            // copy reference to remaining frames in original samples to input buffer
-            for i in 0..channel_count {
-                input_slices[i] = &original_samples[i][pos..original_frames];
-            }
+    #[test]
+    fn test_evict_pinned() {
+        let dir =
+            std::env::temp_dir().join(format!("musicopy-test-{}-evict-pinned", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
 
-            let (_input_frames, output_frames) = resampler
-                .process_partial_into_buffer(Some(&input_slices), &mut output_buf, None)
-                .expect("bad inputs to resampler");
+        let status_cache = TranscodeStatusCache::new();
+        let mut sources = HashMap::new();
+        for hash in 1..=2u8 {
+            let local_path = dir.join(format!("test-0{hash}.ogg"));
+            std::fs::write(&local_path, vec![0; 100]).unwrap();
 
-            // copy chunk output buffer to resampled samples
-            for i in 0..channel_count {
-                resampled_samples[i].extend_from_slice(&output_buf[i][0..output_frames]);
-            }
+            status_cache.insert(
+                "test".to_string(),
+                vec![hash],
+                TranscodeStatus::Ready {
+                    local_path,
+                    file_size: 100,
+                    passthrough: false,
+                },
+            );
+            status_cache.set_last_used(
+                "test".to_string(),
+                vec![hash],
+                SystemTime::UNIX_EPOCH + Duration::from_secs(hash as u64),
+            );
+
+            sources.insert(("test".to_string(), vec![hash]), test_item(hash));
         }
 
-        // continue feeding zeros to the resampler until we have enough frames
-        // this ensures we account for resample delay and push everything through its internal buffer
-        while resampled_samples[0].len() < new_frames + delay {
-            let (_input_frames, output_frames) = resampler
-                .process_partial_into_buffer(None::<&[&[f32]]>, &mut output_buf, None)
-                .expect("bad inputs to resampler");
+        // file 1 is least recently used, but a server job is ready to send it
+        let pin = status_cache.pin("test", &[1]);
+        let mut evicted = HashMap::new();
 
-            // copy chunk output buffer to resampled samples
-            for i in 0..channel_count {
-                resampled_samples[i].extend_from_slice(&output_buf[i][0..output_frames]);
-            }
-        }
+        // file 2 is being sent, so nothing can be evicted yet
+        let lock = status_cache.rewrite_lock("test", &[2]);
+        let guard = lock.try_read().unwrap();
+        TranscodePool::evict(&status_cache, &dir, &sources, &mut evicted, 100);
+        assert!(dir.join("test-02.ogg").exists());
 
-        // remove resample delay frames from the start and truncate to new frame count
-        // TODO: can we do this without a copy from .drain()?
-        for channel in resampled_samples.iter_mut() {
-            channel.drain(0..delay);
-            channel.truncate(new_frames);
-        }
+        drop(guard);
+        TranscodePool::evict(&status_cache, &dir, &sources, &mut evicted, 100);
 
-        resampled_samples
-    } else {
-        // we don't need to resample, but we still need to pad the start with zeros
+        assert!(dir.join("test-01.ogg").exists());
+        assert!(!dir.join("test-02.ogg").exists());
 
-        let original_frames = original_samples[0].len();
+        // unpinned transcodes can be evicted
+        drop(pin);
+        TranscodePool::evict(&status_cache, &dir, &sources, &mut evicted, 0);
+        assert!(!dir.join("test-01.ogg").exists());
+
+        let _ = std::fs::remove_dir_all(&dir);
+    }
 
-        let mut resampled_samples = vec![Vec::new(); channel_count];
-        for i in 0..channel_count {
-            resampled_samples[i].resize(lookahead_frames + original_frames, 0.0);
-            resampled_samples[i][lookahead_frames..].copy_from_slice(&original_samples[i][..]);
+    #[test]
+    fn test_read_transcodes_dir_manifest() {
+        let dir = std::env::temp_dir().join(format!(
+            "musicopy-test-{}-read-transcodes-dir",
+            std::process::id()
+        ));
+        std::fs::create_dir_all(&dir).unwrap();
+
+        let profile = TranscodeProfile::default();
+        for hash in 1..=4u8 {
+            let local_path = dir.join(format!("test-0{hash}.ogg"));
+            std::fs::write(&local_path, vec![hash; 100]).unwrap();
+
+            // file 4 has no manifest
+            if hash != 4 {
+                let mut manifest =
+                    TranscodeManifest::new(&local_path, &profile, "test", &[hash], None).unwrap();
+                manifest.revision = hash as u64;
+                manifest.write(&local_path).unwrap();
+            }
         }
 
-        resampled_samples
-    };
+        // file 2 is truncated and file 3 is corrupted
+        std::fs::write(dir.join("test-02.ogg"), vec![2; 50]).unwrap();
+        std::fs::write(dir.join("test-03.ogg"), vec![0; 100]).unwrap();
 
-    // interleave samples since opus needs interleaved input
-    // TODO: profile + explore SIMD for this
-    let interleaved_samples = if channel_count == 2 {
-        let mut interleaved_samples = vec![0.0; resampled_samples[0].len() * channel_count];
+        let status_cache = TranscodeStatusCache::new();
+        TranscodePool::read_transcodes_dir(&dir, &profile, &status_cache);
 
-        for i in 0..resampled_samples[0].len() {
-            for j in 0..channel_count {
-                interleaved_samples[i * channel_count + j] = resampled_samples[j][i];
-            }
+        // only the valid transcodes are served, the rest are removed
+        // file 3 has the right size, so only its checksum shows the damage
+        for hash in [1, 3] {
+            assert!(matches!(
+                *status_cache.get("test", &[hash]).unwrap(),
+                TranscodeStatus::Ready { .. }
+            ));
+        }
+        // the revision is remembered
+        assert_eq!(status_cache.revision("test", &[1]), 1);
+        for hash in [2, 4] {
+            assert!(status_cache.get("test", &[hash]).is_none());
+            assert!(!dir.join(format!("test-0{hash}.ogg")).exists());
+            assert!(!dir.join(format!("test-0{hash}.ogg.manifest")).exists());
         }
 
-        interleaved_samples
-    } else if channel_count == 1 {
-        resampled_samples.swap_remove(0)
-    } else {
-        unreachable!();
-    };
+        assert_eq!(
+            TranscodePool::verify_checksums(&status_cache),
+            [(("test".to_string(), vec![3]), dir.join("test-03.ogg"), 3)]
+        );
 
-    let mut output_file = File::create(output_path).context("failed to create output file")?;
+        // a different profile's transcodes are outdated
+        let other_profile = TranscodeProfile {
+            bitrate: profile.bitrate + 1,
+            ..profile
+        };
+        let status_cache = TranscodeStatusCache::new();
+        TranscodePool::read_transcodes_dir(&dir, &other_profile, &status_cache);
+        assert!(status_cache.get("test", &[1]).is_none());
+        assert!(!dir.join("test-01.ogg").exists());
 
-    let mut packet_writer = ogg::PacketWriter::new(&mut output_file);
+        let _ = std::fs::remove_dir_all(&dir);
+    }
 
-    // we write the number of lookahead frames as pre-skip in the opus header
-    // we added this many zeros to the start of the resampled samples to account for encoder lookahead
-    // players should skip these frames when decoding
-    let preskip_bytes = lookahead_frames.to_le_bytes();
+    #[test]
+    fn test_read_profile_dirs_speech() {
+        let dir = std::env::temp_dir().join(format!(
+            "musicopy-test-{}-read-profile-dirs-speech",
+            std::process::id()
+        ));
+        let profile = TranscodeProfile::default();
+        let speech_profile = profile.for_content(TranscodeContent::Speech);
+        let profile_dir = profile_transcodes_dir(&dir, &profile);
+        let speech_dir = profile_transcodes_dir(&dir, &speech_profile);
+        std::fs::create_dir_all(&profile_dir).unwrap();
+        std::fs::create_dir_all(&speech_dir).unwrap();
+
+        // file 1 is music, file 2 is speech, file 3 is a speech transcode in
+        // the music directory, and file 4 is in both directories
+        let transcodes = [
+            (&profile_dir, &profile, 1u8),
+            (&speech_dir, &speech_profile, 2),
+            (&profile_dir, &speech_profile, 3),
+            (&profile_dir, &profile, 4),
+            (&speech_dir, &speech_profile, 4),
+        ];
+        for (transcode_dir, transcode_profile, hash) in transcodes {
+            let local_path = transcode_dir.join(format!("test-0{hash}.ogg"));
+            std::fs::write(&local_path, vec![hash; 100]).unwrap();
+            TranscodeManifest::new(&local_path, transcode_profile, "test", &[hash], None)
+                .unwrap()
+                .write(&local_path)
+                .unwrap();
+        }
 
-    // input sample rate is always 48000 since we resample to it
-    let rate_bytes = 48000u32.to_le_bytes();
+        let status_cache = TranscodeStatusCache::new();
+        TranscodePool::read_profile_dirs(&dir, &profile, &status_cache);
 
-    #[rustfmt::skip]
-	let opus_head: [u8; 19] = [
-        b'O', b'p', b'u', b's', b'H', b'e', b'a', b'd', // magic signature
-        1, // version, always 1
-        channel_count as u8, // channel count
-        preskip_bytes[0], preskip_bytes[1], // pre-skip
-        rate_bytes[0], rate_bytes[1], rate_bytes[2], rate_bytes[3], // input sample rate
-        0, 0, // output gain
-        0, // channel mapping family
-    ];
+        let local_path = |hash: u8| match status_cache.get("test", &[hash]).as_deref() {
+            Some(TranscodeStatus::Ready { local_path, .. }) => Some(local_path.clone()),
+            _ => None,
+        };
+        assert_eq!(local_path(1), Some(profile_dir.join("test-01.ogg")));
+        assert_eq!(local_path(2), Some(speech_dir.join("test-02.ogg")));
+        assert_eq!(local_path(3), None);
+        assert!(!profile_dir.join("test-03.ogg").exists());
+        assert_eq!(local_path(4), Some(profile_dir.join("test-04.ogg")));
+        assert!(!speech_dir.join("test-04.ogg").exists());
+
+        let _ = std::fs::remove_dir_all(&dir);
+    }
 
-    let (user_comments_len, user_comments_buf) = {
-        let mut len = 0u32;
-        let mut buf = Vec::new();
+    #[test]
+    fn test_status_cache_remove_failed() {
+        let status_cache = TranscodeStatusCache::new();
+        status_cache.insert(
+            "test".to_string(),
+            vec![1],
+            TranscodeStatus::Failed {
+                error: anyhow::anyhow!("failed"),
+            },
+        );
+        status_cache.insert(
+            "test".to_string(),
+            vec![2],
+            TranscodeStatus::Waiting {
+                estimated_size: None,
+            },
+        );
+        assert_eq!(status_cache.failed_counter().load(Ordering::Relaxed), 1);
 
-        if let Some(metadata) = format.metadata().skip_to_latest() {
-            for tag in metadata.tags().iter().flat_map(|t| &t.std) {
-                // TODO: replace =
-                let comment = match tag {
-                    StandardTag::TrackTitle(tag) => Some(format!("TITLE={tag}")),
-                    StandardTag::Album(tag) => Some(format!("ALBUM={tag}")),
-                    StandardTag::TrackNumber(tag) => Some(format!("TRACKNUMBER={tag}")),
-                    StandardTag::Artist(tag) => Some(format!("ARTIST={tag}")),
-                    _ => None,
-                };
+        // other statuses are left alone
+        status_cache.remove_failed("test", &[2]);
+        assert!(status_cache.get("test", &[2]).is_some());
+        assert_eq!(status_cache.waiting_counter().load(Ordering::Relaxed), 1);
 
-                if let Some(s) = comment {
-                    len += 1;
-                    buf.extend((s.len() as u32).to_le_bytes());
-                    buf.extend(s.bytes());
-                }
-            }
+        status_cache.remove_failed("test", &[1]);
+        assert!(status_cache.get("test", &[1]).is_none());
+        assert_eq!(status_cache.failed_counter().load(Ordering::Relaxed), 0);
+    }
 
-            // find front cover visual or first available
-            let mut best_visual = metadata.visuals().first();
-            for visual in metadata.visuals() {
-                if visual.usage == Some(StandardVisualKey::FrontCover) {
-                    best_visual = Some(visual);
-                }
-            }
+    #[test]
+    fn test_region_counter() {
+        let counter = RegionCounter::new();
+        assert_eq!(counter.count(), 0);
 
-            if let Some(visual) = best_visual {
-                let rdr = ImageReader::new(Cursor::new(&visual.data))
-                    .with_guessed_format()
-                    .expect("cursor io never fails");
+        let guard_1 = counter.entered();
+        assert_eq!(counter.count(), 1);
 
-                // convert to jpeg 500x500 90% quality
-                let image_buf = {
-                    let original_image = rdr.decode().context("failed to decode image")?;
+        let guard_2 = counter.entered();
+        assert_eq!(counter.count(), 2);
 
-                    let resized_image = original_image.resize(500, 500, FilterType::Lanczos3);
+        drop(guard_2);
+        assert_eq!(counter.count(), 1);
 
-                    let mut image_buf = vec![];
-                    let mut encoder = JpegEncoder::new_with_quality(&mut image_buf, 90);
-                    encoder
-                        .encode_image(&resized_image)
-                        .context("failed to encode image")?;
+        drop(guard_1);
+        assert_eq!(counter.count(), 0);
+    }
 
-                    image_buf
-                };
+    #[test]
+    fn test_remove_legacy_transcodes() {
+        let dir = std::env::temp_dir().join(format!("musicopy-test-{}-legacy", std::process::id()));
+        let profile_dir = profile_transcodes_dir(&dir, &TranscodeProfile::default());
+        std::fs::create_dir_all(&profile_dir).unwrap();
+
+        let legacy_path = dir.join("xxh3-0102.ogg");
+        let profile_path = profile_dir.join("xxh3-0102.ogg");
+        std::fs::write(&legacy_path, "legacy").unwrap();
+        std::fs::write(&profile_path, "current").unwrap();
+
+        TranscodePool::remove_legacy_transcodes(&dir);
+        let legacy_exists = legacy_path.exists();
+        let profile_exists = profile_path.exists();
+        let _ = std::fs::remove_dir_all(&dir);
+
+        // only transcodes outside of profile directories are deleted
+        assert!(!legacy_exists);
+        assert!(profile_exists);
+    }
 
-                // construct flac picture structure
-                // note that flac uses big endian while vorbis comments use little endian
-                let mut picture = Vec::<u8>::new();
-                picture.extend(&3u32.to_be_bytes()); // picture type (3, front cover)
+    #[test]
+    fn test_transcode_cancel() {
+        let queue = Arc::new(TranscodeQueue::new(TranscodePolicy::Always));
+        queue.extend(vec![test_item(0x01)]);
+        let item = queue.wait(0);
+        let interrupt = queue.interrupt(&item);
 
-                let media_type = "image/jpeg";
-                picture.extend(&(media_type.len() as u32).to_be_bytes());
-                picture.extend(media_type.as_bytes());
+        queue.cancel(
+            [&item]
+                .iter()
+                .map(|i| (i.hash_kind.as_ref(), i.hash.as_slice())),
+        );
 
-                picture.extend(&[0, 0, 0, 0]); // description length
-                picture.extend(&500u32.to_be_bytes()); // width (500px)
-                picture.extend(&500u32.to_be_bytes()); // height (500px)
-                picture.extend(&[0, 0, 0, 0]); // color depth (0, unknown)
-                picture.extend(&[0, 0, 0, 0]); // indexed color count (0, non-indexed)
+        let output_path =
+            std::env::temp_dir().join(format!("musicopy-test-{}-cancel.ogg", std::process::id()));
+        let res = transcode(
+            &fixture_path("tags.flac"),
+            None,
+            &output_path,
+            &TranscodeProfile::default(),
+            &TranscodeProgress::default(),
+            &interrupt,
+        );
+        let _ = std::fs::remove_file(&output_path);
 
-                picture.extend(&(image_buf.len() as u32).to_be_bytes()); // picture data length
-                picture.extend(&image_buf); // picture data
+        assert!(res.unwrap_err().is::<TranscodeCancelled>());
+    }
 
-                // encode picture with base64 for comment
-                let comment = format!(
-                    "METADATA_BLOCK_PICTURE={}",
-                    BASE64_STANDARD.encode(&picture)
-                );
+    #[test]
+    fn test_profile_key_roundtrip() {
+        let profile = TranscodeProfile {
+            format: TranscodeFormat::Opus,
+            bitrate: 96000,
+            bitrate_mode: TranscodeBitrateMode::Cbr,
+            complexity: 5,
+            frame_duration: TranscodeFrameDuration::Ms40,
+            application: TranscodeApplication::Voip,
+            downmix_stereo: false,
+            downmix_mono: false,
+            normalize_loudness: false,
+            art: TranscodeArtOptions::default(),
+        };
 
-                log::debug!(
-                    "adding visual to opus tags, image size = {}, comment size = {}",
-                    image_buf.len(),
-                    comment.len(),
-                );
+        let key = profile.key();
+        assert_eq!(key, "opus-b96000-cbr-c5-f40-voip");
+        assert_eq!(TranscodeProfile::from_key(&key).unwrap(), profile);
 
-                len += 1;
-                buf.extend((comment.len() as u32).to_le_bytes());
-                buf.extend(comment.as_bytes());
-            }
-        }
+        let profile = TranscodeProfile {
+            downmix_stereo: true,
+            normalize_loudness: true,
+            ..profile
+        };
 
-        (len, buf)
-    };
+        let key = profile.key();
+        assert_eq!(key, "opus-b96000-cbr-c5-f40-voip-stereo-norm");
+        assert_eq!(TranscodeProfile::from_key(&key).unwrap(), profile);
 
-    #[rustfmt::skip]
-    let opus_tags = {
-        let mut buf = vec![
-            b'O', b'p', b'u', b's', b'T', b'a', b'g', b's', // magic signature
-            0x08, 0x00, 0x00, 0x00, // vendor string length (8u32 in little-endian)
-            b'm', b'u', b's', b'i', b'c', b'o', b'p', b'y', // vendor string
-        ];
-        buf.extend(user_comments_len.to_le_bytes());
-        buf.extend(user_comments_buf);
-        buf
-    };
+        let mono_profile = TranscodeProfile {
+            downmix_mono: true,
+            ..profile
+        };
 
-    // stream unique serial identifier
-    let serial = 0;
+        let key = mono_profile.key();
+        assert_eq!(key, "opus-b96000-cbr-c5-f40-voip-stereo-mono-norm");
+        assert_eq!(TranscodeProfile::from_key(&key).unwrap(), mono_profile);
 
-    // write opus head and opus tags packets
-    packet_writer
-        .write_packet(&opus_head, serial, ogg::PacketWriteEndInfo::EndPage, 0)
-        .context("failed to write packet")?;
-    packet_writer
-        .write_packet(&opus_tags, serial, ogg::PacketWriteEndInfo::EndPage, 0)
-        .context("failed to write packet")?;
+        let default_key = TranscodeProfile::default().key();
+        assert_eq!(
+            TranscodeProfile::from_key(&default_key).unwrap(),
+            TranscodeProfile::default()
+        );
 
-    // number of frames per chunk (48khz / 1000 * 20ms = 960 frames)
-    // NB: we are calling opus frames 'chunks' to differentiate from sample frames (one sample per channel)
-    let chunk_frames = 48000 / 1000 * 20;
-    let chunk_samples = chunk_frames * channel_count;
+        // flac ignores the opus settings
+        let profile = TranscodeProfile {
+            format: TranscodeFormat::Flac,
+            ..profile
+        };
+        assert_eq!(profile.key(), "flac");
+        assert_eq!(
+            TranscodeProfile::from_key("flac").unwrap(),
+            TranscodeProfile {
+                format: TranscodeFormat::Flac,
+                ..TranscodeProfile::default()
+            }
+        );
+    }
 
-    let interleaved_len = interleaved_samples.len();
+    #[test]
+    fn test_profile_key_art() {
+        let profile = TranscodeProfile {
+            art: TranscodeArtOptions {
+                embed: true,
+                max_size: 1000,
+                format: TranscodeArtFormat::Png,
+                quality: 80,
+                keep_small: true,
+            },
+            ..TranscodeProfile::default()
+        };
 
-    // encode in chunks
-    let mut pos = 0;
-    loop {
-        // check if we have enough samples for a full chunk
-        if pos + chunk_samples > interleaved_len {
-            break;
-        }
+        let key = profile.key();
+        assert_eq!(
+            key,
+            "opus-b128000-cvbr-c10-f20-audio-art1000-png-q80-keepsmall"
+        );
+        assert_eq!(TranscodeProfile::from_key(&key).unwrap(), profile);
+
+        // other art settings are ignored when art is dropped
+        let profile = TranscodeProfile {
+            format: TranscodeFormat::Flac,
+            art: TranscodeArtOptions {
+                embed: false,
+                ..TranscodeArtOptions::default()
+            },
+            ..TranscodeProfile::default()
+        };
 
-        // allocate chunk output buffer
-        // encode_float uses the length (not capacity) as max_data_size
-        // length comes from recommended max_data_size in opus documentation
-        let mut output_buf = vec![0; 4000];
+        let key = profile.key();
+        assert_eq!(key, "flac-noart");
+        assert_eq!(TranscodeProfile::from_key(&key).unwrap(), profile);
 
-        // call encoder with input slice and chunk output buffer
-        let output_len = encoder
-            .encode_float(
-                &interleaved_samples[pos..(pos + chunk_samples)],
-                &mut output_buf,
-            )
-            .context("failed to encode chunk")?;
-        output_buf.truncate(output_len);
+        // art size out of range
+        assert!(TranscodeProfile::from_key("opus-art1").is_err());
+        // quality out of range
+        assert!(TranscodeProfile::from_key("flac-q0").is_err());
+    }
 
-        let end_info = if pos + chunk_samples == interleaved_len {
-            // if this chunk ended exactly at the end of input
-            ogg::PacketWriteEndInfo::EndStream
-        } else {
-            ogg::PacketWriteEndInfo::NormalPacket
-        };
+    /// Encodes a solid color test image.
+    fn test_image(width: u32, height: u32, format: ImageFormat) -> Vec<u8> {
+        let image = DynamicImage::ImageRgb8(image::RgbImage::from_pixel(
+            width,
+            height,
+            image::Rgb([200, 100, 50]),
+        ));
 
-        // the number of frames up to and including the last frame in this packet
-        // this is measured in frames, so mono and stereo increase at the same rate
-        let granule_position = ((pos + chunk_samples) / channel_count) as u64;
-
-        // write packet
-        packet_writer
-            .write_packet(output_buf, serial, end_info, granule_position)
-            .context("failed to write packet")?;
-
-        // increment position by number of samples consumed
-        pos += chunk_samples;
-    }
-
-    // encode final chunk with remaining samples
-    if pos < interleaved_len {
-        // allocate chunk output buffer
-        let mut output_buf = vec![0; 4000];
-
-        // opus always requires a full chunk of input but we don't have enough remaining samples,
-        // so allocate a zero-padded input buffer for the final chunk
-        let mut input_buf = vec![0.0; chunk_samples];
-        input_buf[0..(interleaved_len - pos)]
-            .copy_from_slice(&interleaved_samples[pos..interleaved_len]);
-
-        // call encoder with chunk input buffer and chunk output buffer
-        let output_len = encoder
-            .encode_float(&input_buf, &mut output_buf)
-            .context("failed to encode final chunk")?;
-        output_buf.truncate(output_len);
-
-        // for end-trimming, the granule position of the final packet is the total number of input frames
-        // this may be less than the position of the final frame in the final packet
-        // this allows the player to trim the padding samples from the final chunk
-        let granule_position = (interleaved_len / channel_count) as u64;
-
-        // write packet
-        packet_writer
-            .write_packet(
-                output_buf,
-                serial,
-                ogg::PacketWriteEndInfo::EndStream,
-                granule_position,
-            )
-            .context("failed to write packet")?;
+        let mut buf = Vec::new();
+        image.write_to(&mut Cursor::new(&mut buf), format).unwrap();
+        buf
     }
 
-    let file = packet_writer.into_inner();
-    let file_size = file
-        .seek(SeekFrom::End(0))
-        .context("failed to seek to end of file")?;
+    /// Reads the media type, width, height, and data from a FLAC picture
+    /// structure.
+    fn parse_picture(picture: &[u8]) -> (String, u32, u32, Vec<u8>) {
+        let read_u32 =
+            |offset: usize| u32::from_be_bytes(picture[offset..offset + 4].try_into().unwrap());
 
-    // we did it
-    Ok(file_size)
-}
+        let media_type_len = read_u32(4) as usize;
+        let media_type = String::from_utf8(picture[8..8 + media_type_len].to_vec()).unwrap();
 
-/// Estimates the size of a file after transcoding based on its duration.
-fn estimate_file_size(path: &PathBuf) -> anyhow::Result<u64> {
-    let src = std::fs::File::open(path).context("failed to open file")?;
+        let offset = 8 + media_type_len + 4;
+        let width = read_u32(offset);
+        let height = read_u32(offset + 4);
 
-    let mss = MediaSourceStream::new(Box::new(src), Default::default());
+        let data_len = read_u32(offset + 16) as usize;
+        let data = picture[offset + 20..offset + 20 + data_len].to_vec();
 
-    let mut hint = Hint::new();
-    if let Some(extension) = path.extension() {
-        hint.with_extension(extension.to_str().context("invalid file extension")?);
+        (media_type, width, height, data)
     }
 
-    let mut format = symphonia::default::get_probe()
-        .probe(&hint, mss, Default::default(), Default::default())
-        .context("failed to probe file")?;
+    #[test]
+    fn test_sidecar_cover() {
+        let dir =
+            std::env::temp_dir().join(format!("musicopy-test-{}-sidecar", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+
+        let track_path = dir.join("01 track.flac");
+        assert_eq!(sidecar_cover_path(&track_path), None);
+
+        // names are matched case-insensitively and cover is preferred over folder
+        std::fs::write(
+            dir.join("folder.png"),
+            test_image(100, 50, ImageFormat::Png),
+        )
+        .unwrap();
+        std::fs::write(
+            dir.join("Cover.JPG"),
+            test_image(100, 50, ImageFormat::Jpeg),
+        )
+        .unwrap();
+        std::fs::write(dir.join("cover.txt"), "not an image").unwrap();
+        assert_eq!(sidecar_cover_path(&track_path), Some(dir.join("Cover.JPG")));
+
+        // the sidecar is used when there are no visuals
+        let picture = cover_picture(&[], &track_path, &TranscodeArtOptions::default())
+            .unwrap()
+            .unwrap();
+        let (media_type, width, height, _) = parse_picture(&picture);
+        assert_eq!(media_type, "image/jpeg");
+        assert_eq!((width, height), (500, 250));
+
+        // small images are kept as they are
+        std::fs::remove_file(dir.join("Cover.JPG")).unwrap();
+        let options = TranscodeArtOptions {
+            format: TranscodeArtFormat::Png,
+            keep_small: true,
+            ..TranscodeArtOptions::default()
+        };
+        let picture = cover_picture(&[], &track_path, &options).unwrap().unwrap();
+        let (media_type, width, height, data) = parse_picture(&picture);
+        assert_eq!(media_type, "image/png");
+        assert_eq!((width, height), (100, 50));
+        assert_eq!(data, test_image(100, 50, ImageFormat::Png));
+
+        // art can be dropped
+        let options = TranscodeArtOptions {
+            embed: false,
+            ..TranscodeArtOptions::default()
+        };
+        assert!(cover_picture(&[], &track_path, &options).unwrap().is_none());
 
-    // get the default audio track
-    let audio_track = format
-        .default_track(TrackType::Audio)
-        .context("failed to get default audio track")?;
-    let audio_track_id = audio_track.id;
+        let _ = std::fs::remove_dir_all(&dir);
+    }
 
-    // get time base and number of frames from the audio track
-    let duration_secs = match (audio_track.time_base, audio_track.num_frames) {
-        (Some(time_base), Some(num_frames)) => {
-            let duration = time_base.calc_time(num_frames);
-            duration.seconds as f64 + duration.frac
-        }
+    #[test]
+    fn test_sidecar_lyrics() {
+        let dir = std::env::temp_dir().join(format!(
+            "musicopy-test-{}-sidecar-lyrics",
+            std::process::id()
+        ));
+        std::fs::create_dir_all(&dir).unwrap();
+
+        let track_path = dir.join("01 track.flac");
+        std::fs::copy(fixture_path("tags.flac"), &track_path).unwrap();
+        assert_eq!(sidecar_lyrics_path(&track_path), None);
+        let fingerprint = metadata_fingerprint(&track_path).unwrap();
+
+        // uppercase extensions are matched, other names are ignored
+        std::fs::write(dir.join("02 track.lrc"), "other lyrics").unwrap();
+        std::fs::write(
+            dir.join("01 track.LRC"),
+            "\u{feff}[00:01.00]First line\r\n[00:02.50]Second line\r\n",
+        )
+        .unwrap();
+        assert_eq!(
+            sidecar_lyrics_path(&track_path),
+            Some(dir.join("01 track.LRC"))
+        );
+        assert_eq!(
+            sidecar_lyrics(&track_path).unwrap().as_deref(),
+            Some("[00:01.00]First line\n[00:02.50]Second line")
+        );
 
-        _ => {
-            log::info!(
-                "file missing time_base or num_frames, decoding to find duration: {}",
-                path.display()
-            );
+        // the lyrics are part of the fingerprint, so adding them re-tags transcodes
+        assert_ne!(metadata_fingerprint(&track_path).unwrap(), fingerprint);
 
-            // get codec parameters for the audio track
-            let codec_params = audio_track
-                .codec_params
-                .as_ref()
-                .context("failed to get codec parameters")?;
-            let audio_codec_params = codec_params
-                .audio()
-                .context("codec parameters are not audio")?;
-
-            // get sample rate
-            let sample_rate = audio_codec_params
-                .sample_rate
-                .context("failed to get sample rate from codec params")?;
-
-            let mut decoder = symphonia::default::get_codecs()
-                .make_audio_decoder(audio_codec_params, &Default::default())
-                .context("failed to create decoder")?;
-
-            // decode the audio track and count frames
-            let mut num_frames = 0;
-            loop {
-                // read next packet
-                let packet = match format.next_packet() {
-                    Ok(Some(packet)) => packet,
-
-                    // end of track
-                    Ok(None) => break,
+        // flac sources are encoded again to embed the lyrics
+        let flac_profile = TranscodeProfile {
+            format: TranscodeFormat::Flac,
+            ..TranscodeProfile::default()
+        };
+        assert_eq!(
+            passthrough_extension(&track_path, &flac_profile).unwrap(),
+            None
+        );
 
-                    Err(e) => {
-                        return Err(e).context("failed to read packet");
-                    }
-                };
+        let output_path = dir.join("01 track.ogg");
+        transcode(
+            &track_path,
+            None,
+            &output_path,
+            &TranscodeProfile::default(),
+            &TranscodeProgress::default(),
+            &TranscodeInterrupt::default(),
+        )
+        .unwrap();
+        let comments = read_opus_comments(&output_path);
 
-                // skip packets from other tracks
-                if packet.track_id() != audio_track_id {
-                    continue;
-                }
+        let _ = std::fs::remove_dir_all(&dir);
 
-                // decode packet
-                let audio_buf = decoder.decode(&packet).context("failed to decode packet")?;
+        let lyrics = comments
+            .iter()
+            .filter(|c| c.starts_with("LYRICS="))
+            .collect::<Vec<_>>();
+        assert_eq!(
+            lyrics,
+            ["LYRICS=[00:01.00]First line\n[00:02.50]Second line"]
+        );
+        assert!(comments.iter().any(|c| c == "TITLE=Tag Test = Title"));
+    }
 
-                // count frames
-                num_frames += audio_buf.frames();
-            }
+    #[test]
+    fn test_sidecar_lyrics_lossy() {
+        let dir = std::env::temp_dir().join(format!(
+            "musicopy-test-{}-sidecar-lyrics-lossy",
+            std::process::id()
+        ));
+        std::fs::create_dir_all(&dir).unwrap();
+
+        let track_path = dir.join("01 track.mp3");
+        std::fs::copy(fixture_path("lyrics.mp3"), &track_path).unwrap();
+        let output_path = dir.join("01 track.ogg");
+
+        // a bitrate high enough that the mp3 is passed through
+        let profile = TranscodeProfile {
+            bitrate: 256000,
+            ..TranscodeProfile::default()
+        };
+        assert_eq!(
+            passthrough_extension(&track_path, &profile).unwrap(),
+            Some("mp3".to_string())
+        );
 
-            // convert frames to seconds
-            num_frames as f64 / sample_rate as f64
-        }
-    };
+        // embedded lyrics are copied when transcoding
+        transcode(
+            &track_path,
+            None,
+            &output_path,
+            &profile,
+            &TranscodeProgress::default(),
+            &TranscodeInterrupt::default(),
+        )
+        .unwrap();
+        let embedded_comments = read_opus_comments(&output_path);
+
+        // lossy sources with sidecar lyrics are transcoded to embed them
+        std::fs::write(dir.join("01 track.lrc"), "[00:01.00]Sidecar line").unwrap();
+        let passthrough = passthrough_extension(&track_path, &profile).unwrap();
+        transcode(
+            &track_path,
+            None,
+            &output_path,
+            &profile,
+            &TranscodeProgress::default(),
+            &TranscodeInterrupt::default(),
+        )
+        .unwrap();
+        let sidecar_comments = read_opus_comments(&output_path);
 
-    // estimated size = duration * bitrate (128k), converted to bytes
-    let estimated_size = duration_secs * 128_000.0 / 8.0;
+        let _ = std::fs::remove_dir_all(&dir);
 
-    // add 150 KB for embedded cover art
-    let estimated_size = estimated_size + 150_000.0;
+        let lyrics = |comments: &[String]| {
+            comments
+                .iter()
+                .filter(|c| c.starts_with("LYRICS="))
+                .cloned()
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(
+            lyrics(&embedded_comments),
+            ["LYRICS=First line\nSecond line"]
+        );
+        assert_eq!(passthrough, None);
+        assert_eq!(lyrics(&sidecar_comments), ["LYRICS=[00:01.00]Sidecar line"]);
+    }
 
-    // add 1% for container overhead
-    let estimated_size = estimated_size * 1.01;
+    #[test]
+    fn test_profile_key_invalid() {
+        // unknown codec
+        assert!(TranscodeProfile::from_key("mp3-b128000").is_err());
+        // unknown token
+        assert!(TranscodeProfile::from_key("opus-b128000-x").is_err());
+        // bitrate out of range
+        assert!(TranscodeProfile::from_key("opus-b1000").is_err());
+        // complexity out of range
+        assert!(TranscodeProfile::from_key("opus-c11").is_err());
+        // flac has no settings
+        assert!(TranscodeProfile::from_key("flac-b128000").is_err());
+    }
 
-    Ok(estimated_size as u64)
-}
+    #[test]
+    fn test_channel_mapping_stereo() {
+        let mapping = ChannelMapping::new(2).unwrap();
+        assert_eq!(mapping.family, 0);
+        assert_eq!(mapping.stream_count, 1);
+        assert_eq!(mapping.coupled_count, 1);
+        assert!(mapping.mapping.is_empty());
+        assert_eq!(mapping.stream_channels, vec![vec![0, 1]]);
+    }
 
-#[cfg(test)]
-mod tests {
-    use std::{sync::atomic::AtomicBool, time::Duration};
+    #[test]
+    fn test_channel_mapping_surround() {
+        // 5.1: FL, FR, FC, LFE, BL, BR
+        let mapping = ChannelMapping::new(6).unwrap();
+        assert_eq!(mapping.family, 1);
+        assert_eq!(mapping.stream_count, 4);
+        assert_eq!(mapping.coupled_count, 2);
+        assert_eq!(mapping.mapping, vec![0, 4, 1, 2, 3, 5]);
+        assert_eq!(
+            mapping.stream_channels,
+            vec![vec![0, 1], vec![4, 5], vec![2], vec![3]]
+        );
 
-    use super::*;
+        // 7.1: FL, FR, FC, LFE, BL, BR, SL, SR
+        let mapping = ChannelMapping::new(8).unwrap();
+        assert_eq!(mapping.stream_count, 5);
+        assert_eq!(mapping.coupled_count, 3);
+        assert_eq!(
+            mapping.stream_channels,
+            vec![vec![0, 1], vec![6, 7], vec![4, 5], vec![2], vec![3]]
+        );
 
-    fn test_item(hash: u8) -> TranscodeItem {
-        TranscodeItem {
-            hash_kind: "test".to_string(),
-            hash: vec![hash],
-            local_path: PathBuf::from("test.ogg"),
-        }
+        assert!(ChannelMapping::new(9).is_err());
     }
 
-    fn join_timeout<T>(timeout: std::time::Duration, thread: std::thread::JoinHandle<T>) -> T {
-        let now = std::time::Instant::now();
-
-        while now.elapsed() < timeout {
-            if thread.is_finished() {
-                return thread.join().unwrap();
-            }
-            std::thread::sleep(std::time::Duration::from_millis(10));
-        }
-
-        panic!("thread timed out");
+    #[test]
+    fn test_total_bitrate() {
+        let profile = TranscodeProfile::default();
+        assert_eq!(profile.total_bitrate(1), 128000);
+        assert_eq!(profile.total_bitrate(2), 128000);
+        // 5.1 has two coupled streams and two uncoupled streams
+        assert_eq!(profile.total_bitrate(6), 128000 * 2 + 64000 * 2);
+
+        let profile = TranscodeProfile {
+            downmix_stereo: true,
+            ..profile
+        };
+        assert_eq!(profile.total_bitrate(6), 128000);
     }
 
-    /// Asserts that a condition is true for a given duration.
-    fn assert_duration(timeout: std::time::Duration, condition: impl Fn() -> bool) {
-        let now = std::time::Instant::now();
+    #[test]
+    fn test_profile_for_content() {
+        let profile = TranscodeProfile::default();
+        assert_eq!(profile.for_content(TranscodeContent::Music), profile);
+
+        let speech_profile = profile.for_content(TranscodeContent::Speech);
+        assert_eq!(speech_profile.bitrate, TranscodeProfile::SPEECH_BITRATE);
+        assert_eq!(speech_profile.application, TranscodeApplication::Voip);
+        assert_eq!(speech_profile.output_channel_count(2).unwrap(), 1);
+        assert_eq!(speech_profile.output_channel_count(6).unwrap(), 1);
+        assert_eq!(
+            speech_profile.total_bitrate(6),
+            TranscodeProfile::SPEECH_BITRATE
+        );
 
-        while now.elapsed() < timeout {
-            if !condition() {
-                panic!("condition failed before timeout");
-            }
-            std::thread::sleep(std::time::Duration::from_millis(10));
-        }
+        // lower bitrates are kept
+        let low_profile = TranscodeProfile {
+            bitrate: 16000,
+            ..profile
+        };
+        assert_eq!(
+            low_profile.for_content(TranscodeContent::Speech).bitrate,
+            16000
+        );
+
+        // lossless profiles are used as they are
+        let flac_profile = TranscodeProfile {
+            format: TranscodeFormat::Flac,
+            ..profile
+        };
+        assert_eq!(
+            flac_profile.for_content(TranscodeContent::Speech),
+            flac_profile
+        );
     }
 
     #[test]
-    fn test_assert_duration_success() {
-        let flag = Arc::new(AtomicBool::new(true));
-
-        // set flag to false after 200ms
-        std::thread::spawn({
-            let flag = flag.clone();
-            move || {
-                std::thread::sleep(std::time::Duration::from_millis(200));
-                flag.store(false, Ordering::SeqCst);
-            }
-        });
+    fn test_stereo_downmix() {
+        let downmix = Downmix::new(6).unwrap();
+
+        let input = vec![
+            vec![1.0],  // FL
+            vec![0.0],  // FR
+            vec![1.0],  // FC
+            vec![1.0],  // LFE
+            vec![0.0],  // BL
+            vec![-1.0], // BR
+        ];
+        let mut output = vec![Vec::new(), Vec::new()];
+        downmix.process(&input, &mut output);
 
-        // should remain true for 100ms
-        assert_duration(std::time::Duration::from_millis(100), || {
-            flag.load(Ordering::SeqCst)
-        });
+        let total = 1.0 + 2.0 * std::f32::consts::FRAC_1_SQRT_2;
+        let center = std::f32::consts::FRAC_1_SQRT_2 / total;
+        assert!((output[0][0] - (1.0 / total + center)).abs() < 1e-6);
+        assert!(output[1][0].abs() < 1e-6);
     }
 
     #[test]
-    #[should_panic]
-    fn test_assert_duration_panic() {
-        let flag = Arc::new(AtomicBool::new(true));
+    fn test_mono_downmix() {
+        let downmix = Downmix::new(2).unwrap();
 
-        // set flag to false after 50ms
-        std::thread::spawn({
-            let flag = flag.clone();
-            move || {
-                std::thread::sleep(std::time::Duration::from_millis(50));
-                flag.store(false, Ordering::SeqCst);
-            }
-        });
+        let input = vec![vec![1.0, 0.5], vec![0.0, 0.5]];
+        let mut output = vec![Vec::new()];
+        downmix.process(&input, &mut output);
 
-        // should become false before 100ms and panic
-        assert_duration(std::time::Duration::from_millis(100), || {
-            flag.load(Ordering::SeqCst)
-        });
+        assert_eq!(output, vec![vec![0.5, 0.5]]);
     }
 
     #[test]
-    fn test_region_counter() {
-        let counter = RegionCounter::new();
-        assert_eq!(counter.count(), 0);
+    fn test_verify_transcode() {
+        let source_path = std::env::temp_dir().join(format!(
+            "musicopy-test-{}-verify-source.flac",
+            std::process::id()
+        ));
+        let output_path =
+            std::env::temp_dir().join(format!("musicopy-test-{}-verify.ogg", std::process::id()));
+
+        // one second of 5.1, so both coupled and uncoupled streams are decoded
+        let channels = (0..6)
+            .map(|channel| {
+                (0..44100)
+                    .map(|i| ((i * (channel + 1)) as f64 * 0.01).sin() * 8000.0)
+                    .map(|sample| sample as i32)
+                    .collect::<Vec<_>>()
+            })
+            .collect::<Vec<_>>();
+        let mut encoder = FlacEncoder::new(
+            File::create(&source_path).unwrap(),
+            44100,
+            channels.len(),
+            16,
+            &tags::vorbis_comment_header(&[]),
+            None,
+        )
+        .unwrap();
+        encoder.push(&channels).unwrap();
+        encoder.finish().unwrap();
+
+        let output = transcode(
+            &source_path,
+            None,
+            &output_path,
+            &TranscodeProfile::default(),
+            &TranscodeProgress::default(),
+            &TranscodeInterrupt::default(),
+        )
+        .unwrap();
+        assert!((output.duration_secs - 1.0).abs() < 1e-9);
+        verify_transcode(&output_path, output.duration_secs).unwrap();
 
-        let guard_1 = counter.entered();
-        assert_eq!(counter.count(), 1);
+        // the duration must match the source
+        let err = verify_transcode(&output_path, 2.0).unwrap_err();
+        assert!(format!("{err:#}").contains("duration is"), "{err:#}");
 
-        let guard_2 = counter.entered();
-        assert_eq!(counter.count(), 2);
+        // truncated transcodes are missing audio
+        let data = std::fs::read(&output_path).unwrap();
+        std::fs::write(&output_path, &data[..(data.len() / 2)]).unwrap();
+        let res = verify_transcode(&output_path, output.duration_secs);
 
-        drop(guard_2);
-        assert_eq!(counter.count(), 1);
+        let _ = std::fs::remove_file(&source_path);
+        let _ = std::fs::remove_file(&output_path);
 
-        drop(guard_1);
-        assert_eq!(counter.count(), 0);
+        assert!(res.is_err());
     }
 
     #[test]
-    fn test_queue_wait_after() {
-        let queue = Arc::new(TranscodeQueue::new(TranscodePolicy::Always));
+    fn test_transcode_tags_round_trip() {
+        for name in ["tags.flac", "tags.mp3"] {
+            let output_path = transcode_fixture(name, &TranscodeProfile::default());
+            let comments = read_opus_comments(&output_path);
+            let _ = std::fs::remove_file(&output_path);
+
+            for expected in [
+                "TITLE=Tag Test = Title",
+                "ARTIST=Artist A",
+                "ARTIST=Artist B",
+                "ALBUM=Fixture Album",
+                "ALBUMARTIST=Various Artists",
+                "TRACKNUMBER=3",
+                "TRACKTOTAL=12",
+                "DISCNUMBER=2",
+                "DISCTOTAL=2",
+                "GENRE=Electronic",
+                "MUSICBRAINZ_ALBUMID=0f6b2a3e-6f6e-4a53-9b5c-6c0f3b0d2d1e",
+            ] {
+                assert!(
+                    comments.iter().any(|c| c == expected),
+                    "{name}: missing `{expected}` in {comments:?}"
+                );
+            }
+        }
+    }
 
-        // add to queue
-        let item_1 = test_item(0x01);
-        let item_2 = test_item(0x02);
-        queue.extend(vec![item_1, item_2]);
+    /// Decodes a file to integer samples at its bit depth.
+    fn decode_samples(path: &Path) -> Vec<Vec<i32>> {
+        let mss = MediaSourceStream::new(Box::new(File::open(path).unwrap()), Default::default());
 
-        std::thread::sleep(std::time::Duration::from_millis(100));
+        let mut hint = Hint::new();
+        hint.with_extension(path.extension().unwrap().to_str().unwrap());
 
-        // wait after adding item
-        let thread = std::thread::spawn(move || {
-            let item = queue.wait();
-            assert_eq!(item.hash, [0x01]);
-            let item = queue.wait();
-            assert_eq!(item.hash, [0x02]);
-        });
+        let mut format = symphonia::default::get_probe()
+            .probe(&hint, mss, Default::default(), Default::default())
+            .unwrap();
 
-        join_timeout(std::time::Duration::from_secs(1), thread);
-    }
+        let audio_track = format.default_track(TrackType::Audio).unwrap();
+        let audio_codec_params = audio_track.codec_params.as_ref().unwrap().audio().unwrap();
+        let channel_count = audio_codec_params.channels.as_ref().unwrap().count();
+        let shift = 32 - audio_codec_params.bits_per_sample.unwrap();
 
-    #[test]
-    fn test_queue_wait_before() {
-        let queue = Arc::new(TranscodeQueue::new(TranscodePolicy::Always));
+        let mut decoder = symphonia::default::get_codecs()
+            .make_audio_decoder(audio_codec_params, &Default::default())
+            .unwrap();
 
-        // wait before before item
-        let thread = std::thread::spawn({
-            let queue = queue.clone();
-            move || {
-                let item = queue.wait();
-                assert_eq!(item.hash, vec![0x01]);
-                let item = queue.wait();
-                assert_eq!(item.hash, vec![0x02]);
-            }
-        });
+        let mut samples = vec![Vec::new(); channel_count];
+        while let Some(packet) = format.next_packet().unwrap() {
+            let audio_buf = decoder.decode(&packet).unwrap();
 
-        std::thread::sleep(std::time::Duration::from_millis(100));
+            let mut packet_samples = vec![vec![0i32; audio_buf.frames()]; channel_count];
+            audio_buf.copy_to_slice_planar(&mut packet_samples);
 
-        // add to queue
-        let item_1 = test_item(0x01);
-        let item_2 = test_item(0x02);
-        queue.extend(vec![item_1, item_2]);
+            for (channel, packet_channel) in samples.iter_mut().zip(packet_samples) {
+                channel.extend(packet_channel.iter().map(|s| s >> shift));
+            }
+        }
 
-        join_timeout(std::time::Duration::from_secs(1), thread);
+        samples
     }
 
-    #[test]
-    fn test_queue_wait_parallel() {
-        let queue = Arc::new(TranscodeQueue::new(TranscodePolicy::Always));
-
-        // spawn consumer threads
-        let thread_1 = std::thread::spawn({
-            let queue = queue.clone();
-            move || {
-                queue.wait();
-            }
-        });
-        let thread_2 = std::thread::spawn({
-            let queue = queue.clone();
-            move || {
-                queue.wait();
-            }
-        });
+    /// Reads the Vorbis comments of a FLAC file.
+    fn read_flac_comments(path: &Path) -> Vec<String> {
+        let mss = MediaSourceStream::new(Box::new(File::open(path).unwrap()), Default::default());
 
-        std::thread::sleep(std::time::Duration::from_millis(100));
+        let mut hint = Hint::new();
+        hint.with_extension("flac");
 
-        // add to queue
-        let item_1 = test_item(0x01);
-        let item_2 = test_item(0x02);
-        queue.extend(vec![item_1, item_2]);
+        let mut format = symphonia::default::get_probe()
+            .probe(&hint, mss, Default::default(), Default::default())
+            .unwrap();
 
-        join_timeout(std::time::Duration::from_secs(1), thread_1);
-        join_timeout(std::time::Duration::from_secs(1), thread_2);
+        tags::vorbis_comments(format.metadata().skip_to_latest().unwrap().tags())
     }
 
     #[test]
-    fn test_queue_remove() {
-        let queue = Arc::new(TranscodeQueue::new(TranscodePolicy::Always));
+    fn test_transcode_gapless() {
+        // frames in the source after trimming the delay and padding, see generate.py
+        for (name, source_frames) in [
+            ("gapless.mp3", 16 * 1152 - 576 - 1000),
+            ("gapless.m4a", 16 * 1024 - 2112 - 1000),
+            // no gapless info, nothing is trimmed
+            ("tags.mp3", 16 * 1152),
+        ] {
+            let output_path = transcode_fixture(name, &TranscodeProfile::default());
+            let packets = read_ogg_packets(&output_path);
+            let _ = std::fs::remove_file(&output_path);
+
+            // the output length is the final granule position minus the pre-skip
+            let pre_skip = u16::from_le_bytes([packets[0].data[10], packets[0].data[11]]) as u64;
+            let output_frames = packets.last().unwrap().absgp_page() - pre_skip;
+
+            let expected_frames = source_frames * 48000 / 44100;
+            assert!(
+                output_frames.abs_diff(expected_frames) <= 1,
+                "{name}: expected {expected_frames} frames, got {output_frames}"
+            );
+        }
+    }
 
-        // add to queue
-        let item_1 = test_item(0x01);
-        let item_2 = test_item(0x02);
-        let item_3 = test_item(0x03);
-        queue.extend(vec![item_1.clone(), item_2.clone(), item_3.clone()]);
+    #[test]
+    fn test_trim_to_range() {
+        // a packet at decoded frames 10..14 after two held back frames
+        let mut samples = vec![vec![9.0, 9.0, 1.0, 2.0, 3.0, 4.0]; 2];
+        trim_to_range(&mut samples, 2, 10, (11, Some(13)));
+        assert_eq!(samples, vec![vec![9.0, 9.0, 2.0, 3.0]; 2]);
+
+        // packets before the range are dropped
+        let mut samples = vec![vec![1.0, 2.0]];
+        trim_to_range(&mut samples, 0, 0, (5, None));
+        assert_eq!(samples, vec![Vec::<f32>::new()]);
+    }
 
-        // wait for next
-        let item = queue.wait();
-        assert_eq!(item.hash, vec![0x01]);
+    #[test]
+    fn test_transcode_cue_track() {
+        let track = |start, end| CueTrack {
+            number: 2,
+            title: Some("Cue Title".to_string()),
+            performer: Some("Cue Artist".to_string()),
+            album: None,
+            album_performer: None,
+            start,
+            end,
+        };
 
-        // remove #2 from queue
-        queue.remove(vec![item_2]);
+        // frames of gapless.mp3 after trimming, see test_transcode_gapless
+        let source_frames = 16 * 1152 - 576 - 1000;
+
+        // a track in the middle and a track that ends with the file, in CD
+        // frames of 588 samples at 44.1 kHz
+        for (track, track_frames) in [
+            (track(5, Some(15)), 10 * 588),
+            (track(15, None), source_frames - 15 * 588),
+        ] {
+            let output_path = std::env::temp_dir().join(format!(
+                "musicopy-test-{}-cue-{}.ogg",
+                std::process::id(),
+                track.start
+            ));
+
+            transcode(
+                &fixture_path("gapless.mp3"),
+                Some(&track),
+                &output_path,
+                &TranscodeProfile::default(),
+                &TranscodeProgress::default(),
+                &TranscodeInterrupt::default(),
+            )
+            .unwrap();
+            let packets = read_ogg_packets(&output_path);
+            let comments = read_opus_comments(&output_path);
+            let _ = std::fs::remove_file(&output_path);
+
+            let pre_skip = u16::from_le_bytes([packets[0].data[10], packets[0].data[11]]) as u64;
+            let output_frames = packets.last().unwrap().absgp_page() - pre_skip;
+
+            let expected_frames = track_frames * 48000 / 44100;
+            assert!(
+                output_frames.abs_diff(expected_frames) <= 1,
+                "track at {}: expected {expected_frames} frames, got {output_frames}",
+                track.start
+            );
 
-        // wait for next
-        let item = queue.wait();
-        assert_eq!(item.hash, vec![0x03]);
+            // tagged from the cue sheet
+            assert!(comments.iter().any(|c| c == "TITLE=Cue Title"));
+            assert!(comments.iter().any(|c| c == "ARTIST=Cue Artist"));
+            assert!(comments.iter().any(|c| c == "TRACKNUMBER=2"));
+        }
     }
 
     #[test]
-    fn test_queue_if_requested() {
-        let queue = Arc::new(TranscodeQueue::new(TranscodePolicy::IfRequested));
-
-        // add to queue
-        let item_1 = test_item(0x01);
-        let item_2 = test_item(0x02);
-        let item_3 = test_item(0x03);
-        queue.extend(vec![item_1.clone(), item_2.clone(), item_3.clone()]);
-
-        // spawn consumer thread
-        let thread_1 = std::thread::spawn({
-            let queue = queue.clone();
-            move || queue.wait()
-        });
-
-        // should wait and not receive item
-        assert_duration(Duration::from_millis(100), || !thread_1.is_finished());
-
-        // request #2
-        queue.prioritize(
-            [item_2]
-                .iter()
-                .map(|i| (i.hash_kind.as_ref(), i.hash.as_slice())),
+    fn test_transcode_speech() {
+        let output_path =
+            std::env::temp_dir().join(format!("musicopy-test-{}-speech.ogg", std::process::id()));
+        let profile = TranscodeProfile::default().for_content(TranscodeContent::Speech);
+
+        transcode(
+            &fixture_path("tags.flac"),
+            None,
+            &output_path,
+            &profile,
+            &TranscodeProgress::default(),
+            &TranscodeInterrupt::default(),
+        )
+        .unwrap();
+        let packets = read_ogg_packets(&output_path);
+        let _ = std::fs::remove_file(&output_path);
+
+        // the stereo source is downmixed to mono
+        assert_eq!(packets[0].data[9], 1);
+
+        // the estimate uses the speech bitrate
+        let source_path = fixture_path("tags.flac");
+        assert!(
+            estimate_file_size(&source_path, &profile).unwrap()
+                < estimate_file_size(&source_path, &TranscodeProfile::default()).unwrap()
         );
+    }
 
-        // should receive #2
-        let item = thread_1.join().unwrap();
-        assert_eq!(item.hash, vec![0x02]);
+    #[test]
+    fn test_decode_progress() {
+        let progress = TranscodeProgress::default();
+        let position = Arc::new(AtomicU64::new(0));
+
+        // decoded frames are counted from the start of the range
+        let mut decode_progress =
+            DecodeProgress::new(Some((100, None)), Some(300), (position.clone(), 1000));
+        decode_progress.update(50, &progress);
+        assert_eq!(progress.get(), 0.0);
+        decode_progress.update(200, &progress);
+        assert_eq!(progress.get(), 0.5 * DECODE_PROGRESS);
+
+        // without the length of the track, the read position is used
+        let mut decode_progress =
+            DecodeProgress::new(Some((100, None)), None, (position.clone(), 1000));
+        position.store(200, Ordering::Relaxed);
+        decode_progress.update(100, &progress);
+        assert_eq!(progress.get(), 0.0);
+        position.store(600, Ordering::Relaxed);
+        decode_progress.update(150, &progress);
+        assert_eq!(progress.get(), 0.5 * DECODE_PROGRESS);
+
+        // then the buffered chunks are encoded
+        progress.set_encoded(1, 2);
+        assert_eq!(
+            progress.get(),
+            DECODE_PROGRESS + 0.5 * (1.0 - DECODE_PROGRESS)
+        );
+        progress.set_encoded(0, 0);
+        assert_eq!(progress.get(), 1.0);
+    }
 
-        // spawn another consumer thread
-        let thread_2 = std::thread::spawn({
-            let queue = queue.clone();
-            move || queue.wait()
-        });
+    #[test]
+    fn test_transcode_progress() {
+        let status_cache = TranscodeStatusCache::new();
+        let progress = status_cache.progress("test", &[1]);
+
+        let output_path =
+            std::env::temp_dir().join(format!("musicopy-test-{}-progress.ogg", std::process::id()));
+        transcode(
+            &fixture_path("tags.flac"),
+            None,
+            &output_path,
+            &TranscodeProfile::default(),
+            &progress,
+            &TranscodeInterrupt::default(),
+        )
+        .unwrap();
+        let _ = std::fs::remove_file(&output_path);
+
+        // the progress is shared until the file is ready
+        assert_eq!(status_cache.progress("test", &[1]).get(), 1.0);
+
+        // the mp3 fixture has no xing header with its length
+        let mp3_progress = TranscodeProgress::default();
+        transcode(
+            &fixture_path("tags.mp3"),
+            None,
+            &output_path,
+            &TranscodeProfile::default(),
+            &mp3_progress,
+            &TranscodeInterrupt::default(),
+        )
+        .unwrap();
+        let _ = std::fs::remove_file(&output_path);
+        assert_eq!(mp3_progress.get(), 1.0);
+
+        status_cache.insert(
+            "test".to_string(),
+            vec![1],
+            TranscodeStatus::Ready {
+                local_path: output_path,
+                file_size: 0,
+                passthrough: false,
+            },
+        );
+        assert_eq!(status_cache.progress("test", &[1]).get(), 0.0);
+    }
 
-        // should wait and not receive item
-        assert_duration(Duration::from_millis(100), || !thread_2.is_finished());
+    #[test]
+    fn test_catch_panic() {
+        assert_eq!(catch_panic(|| Ok(1)).unwrap(), 1);
 
-        // request #3
-        queue.prioritize(
-            [item_3]
-                .iter()
-                .map(|i| (i.hash_kind.as_ref(), i.hash.as_slice())),
-        );
+        let e = catch_panic(|| -> anyhow::Result<()> { panic!("malformed file") }).unwrap_err();
+        assert!(e.is::<JobPanicked>());
+        assert_eq!(e.to_string(), "panicked: malformed file");
 
-        // should receive #3
-        let item = thread_2.join().unwrap();
-        assert_eq!(item.hash, vec![0x03]);
+        // errors are passed through
+        let e = catch_panic(|| -> anyhow::Result<()> { anyhow::bail!("failed") }).unwrap_err();
+        assert!(!e.is::<JobPanicked>());
     }
 
     #[test]
-    fn test_queue_change_policy_to_always() {
-        let queue = Arc::new(TranscodeQueue::new(TranscodePolicy::IfRequested));
-
-        // add to queue
-        let item_1 = test_item(0x01);
-        let item_2 = test_item(0x02);
-        let item_3 = test_item(0x03);
-        queue.extend(vec![item_1.clone(), item_2.clone(), item_3.clone()]);
-
-        // spawn consumer thread to wait for 3 items
-        let thread_1 = std::thread::spawn({
-            let queue = queue.clone();
-            move || {
-                queue.wait();
-                queue.wait();
-                queue.wait();
-            }
+    fn test_worker_panic() {
+        let dir = std::env::temp_dir().join(format!("musicopy-test-{}-panic", std::process::id()));
+        let profile = TranscodeProfile::default();
+        std::fs::create_dir_all(profile_transcodes_dir(&dir, &profile)).unwrap();
+
+        let status_cache = TranscodeStatusCache::new();
+        let queue = Arc::new(TranscodeQueue::new(TranscodePolicy::Always));
+        let panic_counter = Arc::new(AtomicU64::new(0));
+        let (event_tx, mut event_rx) = mpsc::unbounded_channel();
+        let (exit_tx, mut exit_rx) = mpsc::unbounded_channel();
+
+        // a job that panics, followed by a job that succeeds
+        let items = [0x01, 0x02].map(|hash| TranscodeItem {
+            local_path: fixture_path("tags.flac"),
+            ..test_item(hash)
         });
+        queue.extend(items.to_vec());
+
+        let context = WorkerContext {
+            transcodes_dir: dir.clone(),
+            status_cache: status_cache.clone(),
+            profile: Arc::new(Mutex::new(profile)),
+            queue: queue.clone(),
+            inprogress_counter: RegionCounter::new(),
+            panic_counter: panic_counter.clone(),
+            background: Arc::new(AtomicBool::new(false)),
+            verify: Arc::new(AtomicBool::new(false)),
+            event_tx,
+            make_output: |job| {
+                if job.item.hash == [0x01] {
+                    panic!("malformed file");
+                }
+                TranscodeWorker::make_output(job)
+            },
+        };
+        TranscodeWorker::new(0, context, exit_tx);
 
-        // should wait and not receive item
-        assert_duration(Duration::from_millis(100), || !thread_1.is_finished());
+        // wait until both jobs are done
+        let thread = std::thread::spawn(move || {
+            let mut hashes = Vec::new();
+            while hashes.len() < 2 {
+                if let TranscodeEvent::Failed { hash, .. } | TranscodeEvent::Ready { hash, .. } =
+                    event_rx.blocking_recv().unwrap()
+                {
+                    hashes.push(hash);
+                }
+            }
+            hashes
+        });
+        let hashes = join_timeout(Duration::from_secs(10), thread);
 
-        // change policy to Always
-        queue.set_policy(TranscodePolicy::Always);
+        let _ = std::fs::remove_dir_all(&dir);
 
-        // should receive items and exit
-        join_timeout(Duration::from_millis(100), thread_1);
+        // the panic fails the job and is counted
+        assert_eq!(hashes, [vec![0x01], vec![0x02]]);
+        let failed = status_cache.get("test", &[0x01]).unwrap();
+        match &*failed {
+            TranscodeStatus::Failed { error } => assert!(error.is::<JobPanicked>()),
+            status => panic!("unexpected status: {status:?}"),
+        }
+        assert_eq!(panic_counter.load(Ordering::Relaxed), 1);
+        assert_eq!(queue.running_count(), 0);
+
+        // the worker keeps taking jobs
+        assert!(matches!(
+            &*status_cache.get("test", &[0x02]).unwrap(),
+            TranscodeStatus::Ready { .. }
+        ));
+        assert!(exit_rx.try_recv().is_err());
     }
 
     #[test]
-    fn test_queue_change_policy_to_if_requested() {
-        let queue = Arc::new(TranscodeQueue::new(TranscodePolicy::Always));
-
-        // add to queue
-        let item_1 = test_item(0x01);
-        let item_2 = test_item(0x02);
-        let item_3 = test_item(0x03);
-        queue.extend(vec![item_1.clone(), item_2.clone(), item_3.clone()]);
+    fn test_transcode_corrupt() {
+        for name in ["corrupt.flac", "corrupt.mp3", "corrupt.wav"] {
+            let output_path = std::env::temp_dir().join(format!(
+                "musicopy-test-{}-{}",
+                std::process::id(),
+                name.replace('.', "-")
+            ));
+
+            // corrupt files fail without taking down the thread
+            let res = catch_panic(|| {
+                transcode(
+                    &fixture_path(name),
+                    None,
+                    &output_path,
+                    &TranscodeProfile::default(),
+                    &TranscodeProgress::default(),
+                    &TranscodeInterrupt::default(),
+                )
+            });
+            assert!(res.is_err(), "{name} transcoded");
+
+            let res = catch_panic(|| {
+                transcode_flac(
+                    &fixture_path(name),
+                    None,
+                    &output_path,
+                    &TranscodeProfile::default(),
+                    &TranscodeProgress::default(),
+                    &TranscodeInterrupt::default(),
+                )
+            });
+            assert!(res.is_err(), "{name} transcoded to flac");
 
-        // should receive some item
-        queue.wait();
+            let _ = std::fs::remove_file(&output_path);
+        }
+    }
 
-        // change policy to IfRequested
-        queue.set_policy(TranscodePolicy::IfRequested);
+    #[test]
+    fn test_retag_transcode() {
+        let source_path = std::env::temp_dir().join(format!(
+            "musicopy-test-{}-retag-source.flac",
+            std::process::id()
+        ));
+        let output_path =
+            std::env::temp_dir().join(format!("musicopy-test-{}-retag.ogg", std::process::id()));
+        std::fs::copy(fixture_path("tags.flac"), &source_path).unwrap();
+
+        let profile = TranscodeProfile::default();
+        transcode(
+            &source_path,
+            None,
+            &output_path,
+            &profile,
+            &TranscodeProgress::default(),
+            &TranscodeInterrupt::default(),
+        )
+        .unwrap();
+        let packets = read_ogg_packets(&output_path);
+        let comments = read_opus_comments(&output_path);
+
+        // the fingerprint is stable until the tags change
+        let fingerprint = metadata_fingerprint(&source_path).unwrap();
+        assert_eq!(metadata_fingerprint(&source_path).unwrap(), fingerprint);
+
+        let data = std::fs::read(&source_path).unwrap();
+        let pos = data
+            .windows(13)
+            .position(|w| w == b"Fixture Album")
+            .unwrap();
+        let mut data = data;
+        data[pos..(pos + 13)].copy_from_slice(b"Changed Album");
+        std::fs::write(&source_path, data).unwrap();
+        assert_ne!(metadata_fingerprint(&source_path).unwrap(), fingerprint);
+
+        let file_size = retag_transcode(&output_path, &source_path, None, &profile).unwrap();
+        assert_eq!(file_size, std::fs::metadata(&output_path).unwrap().len());
+
+        let new_packets = read_ogg_packets(&output_path);
+        let new_comments = read_opus_comments(&output_path);
+        let _ = std::fs::remove_file(&source_path);
+        let _ = std::fs::remove_file(&output_path);
+
+        assert!(new_comments.iter().any(|c| c == "ALBUM=Changed Album"));
+        assert!(!new_comments.iter().any(|c| c == "ALBUM=Fixture Album"));
+
+        // the gains and the audio are kept
+        let gains = |comments: &[String]| {
+            comments
+                .iter()
+                .filter(|c| c.starts_with("R128_"))
+                .cloned()
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(gains(&new_comments), gains(&comments));
+        assert_eq!(new_packets[0].data, packets[0].data);
+        assert_eq!(new_packets.len(), packets.len());
+        for (new_packet, packet) in new_packets[2..].iter().zip(&packets[2..]) {
+            assert_eq!(new_packet.data, packet.data);
+            assert_eq!(new_packet.absgp_page(), packet.absgp_page());
+        }
+    }
 
-        // spawn consumer thread to wait for 2 more items
-        let thread_1 = std::thread::spawn({
-            let queue = queue.clone();
-            move || {
-                queue.wait();
-                queue.wait();
-            }
-        });
+    #[test]
+    fn test_transcode_flac_lossless() {
+        let input_path = fixture_path("tags.wav");
+        let output_path = std::env::temp_dir().join(format!(
+            "musicopy-test-{}-tags-wav.flac",
+            std::process::id()
+        ));
+
+        transcode_flac(
+            &input_path,
+            None,
+            &output_path,
+            &TranscodeProfile::default(),
+            &TranscodeProgress::default(),
+            &TranscodeInterrupt::default(),
+        )
+        .unwrap();
 
-        // should wait and not receive item
-        assert_duration(Duration::from_millis(100), || !thread_1.is_finished());
+        let input_samples = decode_samples(&input_path);
+        let output_samples = decode_samples(&output_path);
+        let _ = std::fs::remove_file(&output_path);
 
-        // request all
-        queue.prioritize(
-            [item_1, item_2, item_3]
-                .iter()
-                .map(|i| (i.hash_kind.as_ref(), i.hash.as_slice())),
-        );
+        assert!(!input_samples[0].is_empty());
+        assert_eq!(input_samples, output_samples);
+    }
 
-        // should receive items and exit
-        join_timeout(Duration::from_millis(100), thread_1);
+    #[test]
+    fn test_transcode_flac_cue_track_seek() {
+        // a 16-bit stereo wav that's long enough to seek past the preroll
+        let sample_rate = 44100u32;
+        let frame_count = 3 * sample_rate as usize;
+        let data = (0..frame_count * 2)
+            .flat_map(|i| ((i * 7919 % 65536) as u16).to_le_bytes())
+            .collect::<Vec<_>>();
+        let mut wav = Vec::new();
+        wav.extend(b"RIFF");
+        wav.extend((36 + data.len() as u32).to_le_bytes());
+        wav.extend(b"WAVEfmt ");
+        wav.extend(16u32.to_le_bytes());
+        wav.extend(1u16.to_le_bytes());
+        wav.extend(2u16.to_le_bytes());
+        wav.extend(sample_rate.to_le_bytes());
+        wav.extend((sample_rate * 4).to_le_bytes());
+        wav.extend(4u16.to_le_bytes());
+        wav.extend(16u16.to_le_bytes());
+        wav.extend(b"data");
+        wav.extend((data.len() as u32).to_le_bytes());
+        wav.extend(&data);
+
+        let input_path =
+            std::env::temp_dir().join(format!("musicopy-test-{}-cue-seek.wav", std::process::id()));
+        let output_path = input_path.with_extension("flac");
+        std::fs::write(&input_path, wav).unwrap();
+        let input_samples = decode_samples(&input_path);
+
+        // the track starts 2.5 seconds in, in CD frames of 588 samples
+        let track = CueTrack {
+            number: 2,
+            title: None,
+            performer: None,
+            album: None,
+            album_performer: None,
+            start: 187,
+            end: None,
+        };
+        transcode_flac(
+            &input_path,
+            Some(&track),
+            &output_path,
+            &TranscodeProfile::default(),
+            &TranscodeProgress::default(),
+            &TranscodeInterrupt::default(),
+        )
+        .unwrap();
+        let output_samples = decode_samples(&output_path);
+        let _ = std::fs::remove_file(&input_path);
+        let _ = std::fs::remove_file(&output_path);
+
+        // the track's range is kept bit-perfect after seeking
+        let expected_samples = input_samples
+            .iter()
+            .map(|channel| channel[187 * 588..].to_vec())
+            .collect::<Vec<_>>();
+        assert_eq!(output_samples, expected_samples);
     }
 
     #[test]
-    fn test_queue_ready_count() {
-        let queue = Arc::new(TranscodeQueue::new(TranscodePolicy::Always));
+    fn test_transcode_flac_cue_track() {
+        let input_path = fixture_path("tags.wav");
+        let input_samples = decode_samples(&input_path);
+
+        let track = |start, end| CueTrack {
+            number: 2,
+            title: Some("Cue Title".to_string()),
+            performer: None,
+            album: None,
+            album_performer: None,
+            start,
+            end,
+        };
 
-        // should have 0 ready
-        assert_eq!(queue.ready_counter.load(Ordering::SeqCst), 0);
+        // a track in the middle and a track that ends with the file, in CD
+        // frames of 588 samples at 44.1 kHz
+        for (track, range) in [
+            (track(5, Some(10)), 5 * 588..10 * 588),
+            (track(10, None), 10 * 588..input_samples[0].len()),
+        ] {
+            let output_path = std::env::temp_dir().join(format!(
+                "musicopy-test-{}-cue-{}.flac",
+                std::process::id(),
+                track.start
+            ));
+
+            transcode_flac(
+                &input_path,
+                Some(&track),
+                &output_path,
+                &TranscodeProfile::default(),
+                &TranscodeProgress::default(),
+                &TranscodeInterrupt::default(),
+            )
+            .unwrap();
+            let output_samples = decode_samples(&output_path);
+            let comments = read_flac_comments(&output_path);
+            let _ = std::fs::remove_file(&output_path);
 
-        // add to queue
-        let item_1 = test_item(0x01);
-        let item_2 = test_item(0x02);
-        let item_3 = test_item(0x03);
-        queue.extend(vec![item_1.clone(), item_2.clone(), item_3.clone()]);
+            // the track's range is kept bit-perfect
+            let expected_samples = input_samples
+                .iter()
+                .map(|channel| channel[range.clone()].to_vec())
+                .collect::<Vec<_>>();
+            assert_eq!(output_samples, expected_samples);
 
-        // should have 3 ready
-        assert_eq!(queue.ready_counter.load(Ordering::SeqCst), 3);
+            // tagged from the cue sheet
+            assert!(comments.iter().any(|c| c == "TITLE=Cue Title"));
+            assert!(comments.iter().any(|c| c == "TRACKNUMBER=2"));
+        }
+    }
 
-        // should receive some item
-        queue.wait();
+    #[test]
+    fn test_passthrough_flac_profile() {
+        let profile = TranscodeProfile {
+            format: TranscodeFormat::Flac,
+            ..TranscodeProfile::default()
+        };
 
-        // should have 2 ready
-        assert_eq!(queue.ready_counter.load(Ordering::SeqCst), 2);
+        // flac and lossy sources are passed through, pcm is encoded
+        assert_eq!(
+            passthrough_extension(&fixture_path("tags.flac"), &profile).unwrap(),
+            Some("flac".to_string())
+        );
+        assert_eq!(
+            passthrough_extension(&fixture_path("tags.mp3"), &profile).unwrap(),
+            Some("mp3".to_string())
+        );
+        assert_eq!(
+            passthrough_extension(&fixture_path("tags.wav"), &profile).unwrap(),
+            None
+        );
 
-        // change policy to IfRequested
-        queue.set_policy(TranscodePolicy::IfRequested);
+        // flac is never passed through to opus
+        assert_eq!(
+            passthrough_extension(&fixture_path("tags.flac"), &TranscodeProfile::default())
+                .unwrap(),
+            None
+        );
+    }
 
-        // should have 0 ready
-        assert_eq!(queue.ready_counter.load(Ordering::SeqCst), 0);
+    #[test]
+    fn test_passthrough_art() {
+        let profile = TranscodeProfile {
+            format: TranscodeFormat::Flac,
+            ..TranscodeProfile::default()
+        };
+        let path = fixture_path("art.mp3");
 
-        // request #2
-        queue.prioritize(
-            [item_2]
-                .iter()
-                .map(|i| (i.hash_kind.as_ref(), i.hash.as_slice())),
+        // the 64x64 cover fits the art size
+        assert_eq!(
+            passthrough_extension(&path, &profile).unwrap(),
+            Some("mp3".to_string())
         );
 
-        // should have 1 ready
-        assert_eq!(queue.ready_counter.load(Ordering::SeqCst), 1);
-
-        // should receive some item
-        queue.wait();
+        // larger art would be scaled down, and dropped art would be kept
+        for art in [
+            TranscodeArtOptions {
+                max_size: 32,
+                ..profile.art
+            },
+            TranscodeArtOptions {
+                embed: false,
+                ..profile.art
+            },
+        ] {
+            let profile = TranscodeProfile { art, ..profile };
+            assert_eq!(passthrough_extension(&path, &profile).unwrap(), None);
+        }
 
-        // should have 0 ready
-        assert_eq!(queue.ready_counter.load(Ordering::SeqCst), 0);
+        // files without art can be passed through without embedding art
+        let profile = TranscodeProfile {
+            art: TranscodeArtOptions {
+                embed: false,
+                ..profile.art
+            },
+            ..profile
+        };
+        assert_eq!(
+            passthrough_extension(&fixture_path("tags.mp3"), &profile).unwrap(),
+            Some("mp3".to_string())
+        );
     }
 }
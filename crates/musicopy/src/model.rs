@@ -9,7 +9,7 @@
 
 use std::sync::{
     Arc,
-    atomic::{AtomicU64, Ordering},
+    atomic::{AtomicU32, AtomicU64, Ordering},
 };
 
 /// A counter that can be updated across the FFI boundary.
@@ -42,3 +42,28 @@ impl From<&Arc<AtomicU64>> for CounterModel {
         Self(counter.clone())
     }
 }
+
+/// A fraction from 0 to 1 that can be updated across the FFI boundary.
+///
+/// Like CounterModel, a ProgressModel wraps a shared reference to an atomic,
+/// which holds the bits of an f32.
+#[derive(Debug, uniffi::Object)]
+pub struct ProgressModel(Arc<AtomicU32>);
+
+#[uniffi::export]
+impl ProgressModel {
+    #[uniffi::constructor]
+    pub fn new(progress: f32) -> Self {
+        Self(Arc::new(AtomicU32::new(progress.to_bits())))
+    }
+
+    pub fn get(&self) -> f32 {
+        f32::from_bits(self.0.load(Ordering::Relaxed))
+    }
+}
+
+impl From<&Arc<AtomicU32>> for ProgressModel {
+    fn from(progress: &Arc<AtomicU32>) -> Self {
+        Self(progress.clone())
+    }
+}
@@ -14,9 +14,9 @@ use crate::{
     fs::{OpenMode, TreeFile, TreePath},
     library::{
         Library, LibraryCommand,
-        transcode::{TranscodeStatus, TranscodeStatusCache},
+        transcode::{TranscodePin, TranscodeProgress, TranscodeStatus, TranscodeStatusCache},
     },
-    model::CounterModel,
+    model::{CounterModel, ProgressModel},
 };
 use anyhow::Context;
 use dashmap::DashMap;
@@ -49,11 +49,17 @@ use tokio_util::{
     sync::CancellationToken,
 };
 
+/// The smallest change in transcode progress that's sent to clients.
+const TRANSCODE_PROGRESS_STEP: f32 = 0.01;
+
 /// Model of progress for a transfer job.
 #[derive(Debug, Clone, uniffi::Enum)]
 pub enum TransferJobProgressModel {
     Requested,
-    Transcoding,
+    Transcoding {
+        /// Fraction of the file transcoded so far.
+        progress: Arc<ProgressModel>,
+    },
     Ready,
     InProgress {
         started_at: u64,
@@ -222,6 +228,11 @@ pub enum NodeCommand {
 enum NodeEvent {
     FilesRequested(Vec<(String, Vec<u8>)>),
 
+    /// Sent when a server starts sending a file.
+    TransferStarted,
+    /// Sent when a server stops sending a file.
+    TransferFinished,
+
     RecentServersChanged,
 
     ServerOpened {
@@ -257,6 +268,33 @@ enum NodeEvent {
     },
 }
 
+/// Sends TransferStarted when created and TransferFinished when dropped, so
+/// the transfer is counted as finished even if it fails.
+struct TransferActivityGuard {
+    event_tx: mpsc::UnboundedSender<NodeEvent>,
+}
+
+impl TransferActivityGuard {
+    fn new(event_tx: mpsc::UnboundedSender<NodeEvent>) -> Self {
+        if let Err(e) = event_tx.send(NodeEvent::TransferStarted) {
+            log::warn!(
+                "TransferActivityGuard::new: failed to send NodeEvent::TransferStarted: {e:#}"
+            );
+        }
+        Self { event_tx }
+    }
+}
+
+impl Drop for TransferActivityGuard {
+    fn drop(&mut self) {
+        if let Err(e) = self.event_tx.send(NodeEvent::TransferFinished) {
+            log::warn!(
+                "TransferActivityGuard::drop: failed to send NodeEvent::TransferFinished: {e:#}"
+            );
+        }
+    }
+}
+
 /// An update to a server model.
 enum ServerModelUpdate {
     Accept,
@@ -597,6 +635,18 @@ impl Node {
                             }
                         }
 
+                        NodeEvent::TransferStarted => {
+                            if let Err(e) = library.send(LibraryCommand::TransferStarted) {
+                                error!("NodeEvent::TransferStarted: failed to send to library: {e:#}");
+                            }
+                        }
+
+                        NodeEvent::TransferFinished => {
+                            if let Err(e) = library.send(LibraryCommand::TransferFinished) {
+                                error!("NodeEvent::TransferFinished: failed to send to library: {e:#}");
+                            }
+                        }
+
                         NodeEvent::RecentServersChanged => {
                             self.update_model(NodeModelUpdate::UpdateRecentServers);
                         }
@@ -807,9 +857,12 @@ impl Node {
                                 let job = entry.value();
 
                                 let (progress, file_size) = match &job.progress {
-                                    ServerTransferJobProgress::Transcoding { .. } => {
-                                        (TransferJobProgressModel::Transcoding, None)
-                                    }
+                                    ServerTransferJobProgress::Transcoding { progress, .. } => (
+                                        TransferJobProgressModel::Transcoding {
+                                            progress: Arc::new(progress.model()),
+                                        },
+                                        None,
+                                    ),
 
                                     ServerTransferJobProgress::Ready { file_size, .. } => {
                                         (TransferJobProgressModel::Ready, Some(*file_size))
@@ -989,9 +1042,12 @@ impl Node {
                                         (TransferJobProgressModel::Requested, None)
                                     }
 
-                                    ClientTransferJobProgress::Transcoding => {
-                                        (TransferJobProgressModel::Transcoding, None)
-                                    }
+                                    ClientTransferJobProgress::Transcoding { progress } => (
+                                        TransferJobProgressModel::Transcoding {
+                                            progress: Arc::new(progress.model()),
+                                        },
+                                        None,
+                                    ),
 
                                     ClientTransferJobProgress::Ready { file_size } => {
                                         (TransferJobProgressModel::Ready, Some(*file_size))
@@ -1132,7 +1188,7 @@ struct Protocol {
 }
 
 impl Protocol {
-    const ALPN: &'static [u8] = b"musicopy/0";
+    const ALPN: &'static [u8] = b"musicopy/1";
 
     fn new(
         db: Arc<Mutex<Database>>,
@@ -1216,6 +1272,11 @@ struct IndexItem {
     hash: Vec<u8>,
 
     file_size: FileSize,
+
+    /// How many times the transcode was changed without changing the hash,
+    /// like when it's re-tagged. Clients store it with downloaded files so
+    /// they can tell when their copies are out of date.
+    revision: u64,
 }
 
 /// An update to an item in the index.
@@ -1227,14 +1288,31 @@ enum IndexUpdateItem {
 
         file_size: FileSize,
     },
+    /// The transcode was changed without changing the hash, so downloaded
+    /// copies are out of date.
+    Retagged {
+        hash_kind: String,
+        hash: Vec<u8>,
+
+        file_size: FileSize,
+        revision: u64,
+    },
 }
 
 /// A job that changed status.
 #[derive(Debug, Clone, Serialize, Deserialize)]
 enum JobStatusItem {
     Transcoding,
-    Ready { file_size: u64 },
-    Failed { error: String },
+    Ready {
+        file_size: u64,
+    },
+    Failed {
+        error: String,
+    },
+    /// The fraction of the file transcoded so far, sent while transcoding.
+    TranscodeProgress {
+        progress: f32,
+    },
 }
 
 /// A message sent by the server end of a connection on the control stream.
@@ -1262,7 +1340,15 @@ struct TransferRequest {
 #[derive(Debug, Clone, Serialize, Deserialize)]
 enum TransferResponse {
     /// The job is ready to be downloaded and will be sent by the server.
-    Ok { file_size: u64 },
+    ///
+    /// The extension is the file's container, since passthrough files aren't
+    /// always Ogg. The revision is the transcode's revision when it was read,
+    /// see `IndexItem::revision`.
+    Ok {
+        file_size: u64,
+        extension: String,
+        revision: u64,
+    },
     /// The job was unable to be downloaded.
     Error { error: String },
 }
@@ -1280,9 +1366,20 @@ struct ServerTransferJob {
 #[derive(Debug)]
 enum ServerTransferJobProgress {
     /// The server is waiting for the file to be transcoded.
-    Transcoding { hash_kind: String, hash: Vec<u8> },
+    Transcoding {
+        hash_kind: String,
+        hash: Vec<u8>,
+        progress: TranscodeProgress,
+    },
     /// The server is ready to send the file.
-    Ready { local_path: PathBuf, file_size: u64 },
+    Ready {
+        hash_kind: String,
+        hash: Vec<u8>,
+        local_path: PathBuf,
+        file_size: u64,
+        /// Keeps the transcode from being evicted before it's sent.
+        pin: TranscodePin,
+    },
     /// The server has started sending the file.
     InProgress {
         started_at: u64,
@@ -1519,14 +1616,21 @@ impl Server {
             let transcode_status_cache = self.transcode_status_cache.clone();
             let event_tx = self.event_tx.clone();
             async move {
+                // the last progress sent to the client for each job
+                let mut sent_progress = HashMap::new();
+
                 loop {
                     let mut ready_jobs = Vec::new();
                     let mut failed_jobs = Vec::new();
+                    let mut progress_changes = Vec::new();
 
                     // check for jobs with Transcoding status
                     for job in jobs.iter() {
-                        if let ServerTransferJobProgress::Transcoding { hash_kind, hash } =
-                            &job.value().progress
+                        if let ServerTransferJobProgress::Transcoding {
+                            hash_kind,
+                            hash,
+                            progress,
+                        } = &job.value().progress
                         {
                             // get transcode status
                             let Some(status) = transcode_status_cache.get(hash_kind, hash) else {
@@ -1535,15 +1639,34 @@ impl Server {
 
                             match &*status {
                                 TranscodeStatus::Waiting { .. } => {
-                                    // job is still queued
+                                    // job is still queued or in progress
+                                    let progress = progress.get();
+                                    let last_sent =
+                                        sent_progress.get(job.key()).copied().unwrap_or(0.0);
+                                    if progress - last_sent >= TRANSCODE_PROGRESS_STEP
+                                        || progress < last_sent
+                                    {
+                                        sent_progress.insert(*job.key(), progress);
+                                        progress_changes.push((
+                                            *job.key(),
+                                            JobStatusItem::TranscodeProgress { progress },
+                                        ));
+                                    }
                                 }
 
                                 // if transcode status is Ready, set job status to Ready
                                 TranscodeStatus::Ready {
                                     local_path,
                                     file_size,
+                                    ..
                                 } => {
-                                    ready_jobs.push((*job.key(), local_path.clone(), *file_size));
+                                    ready_jobs.push((
+                                        *job.key(),
+                                        hash_kind.clone(),
+                                        hash.clone(),
+                                        local_path.clone(),
+                                        *file_size,
+                                    ));
                                 }
 
                                 // if transcode status is Failed, set job status to Failed
@@ -1563,24 +1686,36 @@ impl Server {
                         }
                     }
 
-                    // create status changes for ready jobs
-                    let ready_jobs =
-                        ready_jobs
-                            .into_iter()
-                            .map(|(job_id, local_path, file_size)| {
-                                // set job status to Ready
-                                // needs to happen outside the loop, since jobs.iter() already holds the entry's lock
-                                jobs.alter(&job_id, |_, mut job| {
-                                    job.progress = ServerTransferJobProgress::Ready {
-                                        local_path,
-                                        file_size,
-                                    };
-                                    job
-                                });
+                    // progress is shared with the model, so only status changes update it
+                    let jobs_changed = !ready_jobs.is_empty() || !failed_jobs.is_empty();
+                    for (job_id, ..) in &ready_jobs {
+                        sent_progress.remove(job_id);
+                    }
+                    for (job_id, _) in &failed_jobs {
+                        sent_progress.remove(job_id);
+                    }
 
-                                (job_id, JobStatusItem::Ready { file_size })
+                    // create status changes for ready jobs
+                    let ready_jobs = ready_jobs.into_iter().map(
+                        |(job_id, hash_kind, hash, local_path, file_size)| {
+                            // set job status to Ready
+                            // needs to happen outside the loop, since jobs.iter() already holds the entry's lock
+                            let pin = transcode_status_cache.pin(&hash_kind, &hash);
+                            jobs.alter(&job_id, |_, mut job| {
+                                job.progress = ServerTransferJobProgress::Ready {
+                                    hash_kind,
+                                    hash,
+                                    local_path,
+                                    file_size,
+                                    pin,
+                                };
+                                job
                             });
 
+                            (job_id, JobStatusItem::Ready { file_size })
+                        },
+                    );
+
                     // create status changes for failed jobs
                     let failed_jobs = failed_jobs.into_iter().map(|(job_id, error)| {
                         let error_string = format!("{error}");
@@ -1600,7 +1735,10 @@ impl Server {
                         )
                     });
 
-                    let status_changes = ready_jobs.chain(failed_jobs).collect::<HashMap<_, _>>();
+                    let status_changes = ready_jobs
+                        .chain(failed_jobs)
+                        .chain(progress_changes)
+                        .collect::<HashMap<_, _>>();
                     if !status_changes.is_empty() {
                         // send status changes to client via ServerCommand::ServerMessage
                         if let Err(e) = tx.send(ServerCommand::ServerMessage(
@@ -1608,7 +1746,9 @@ impl Server {
                         )) {
                             log::warn!("transcode watcher failed to send JobStatus message: {e}");
                         }
+                    }
 
+                    if jobs_changed {
                         // update model
                         event_tx
                             .send(NodeEvent::ServerChanged {
@@ -1691,7 +1831,11 @@ impl Server {
 
                                             // create job
                                             self.jobs.insert(item.job_id, ServerTransferJob {
-                                                progress: ServerTransferJobProgress::Transcoding { hash_kind: file.hash_kind.clone(), hash: file.hash.clone() },
+                                                progress: ServerTransferJobProgress::Transcoding {
+                                                    hash_kind: file.hash_kind.clone(),
+                                                    hash: file.hash.clone(),
+                                                    progress: self.transcode_status_cache.progress(&file.hash_kind, &file.hash),
+                                                },
                                                 file_node_id: item.node_id,
                                                 file_root: item.root,
                                                 file_path: item.path,
@@ -1706,7 +1850,11 @@ impl Server {
 
                                                 // create job
                                                 self.jobs.insert(item.job_id, ServerTransferJob {
-                                                    progress: ServerTransferJobProgress::Transcoding { hash_kind: file.hash_kind.clone(), hash: file.hash.clone() },
+                                                    progress: ServerTransferJobProgress::Transcoding {
+                                                    hash_kind: file.hash_kind.clone(),
+                                                    hash: file.hash.clone(),
+                                                    progress: self.transcode_status_cache.progress(&file.hash_kind, &file.hash),
+                                                },
                                                     file_node_id: item.node_id,
                                                     file_root: item.root,
                                                     file_path: item.path,
@@ -1715,14 +1863,17 @@ impl Server {
                                                 (item.job_id, JobStatusItem::Transcoding)
                                             }
 
-                                            TranscodeStatus::Ready { local_path, file_size } => {
+                                            TranscodeStatus::Ready { local_path, file_size, .. } => {
                                                 // file is already transcoded
 
                                                 // create job
                                                 self.jobs.insert(item.job_id, ServerTransferJob {
                                                     progress: ServerTransferJobProgress::Ready {
+                                                        hash_kind: file.hash_kind.clone(),
+                                                        hash: file.hash.clone(),
                                                         local_path: local_path.clone(),
                                                         file_size: *file_size,
+                                                        pin: self.transcode_status_cache.pin(&file.hash_kind, &file.hash),
                                                     },
                                                     file_node_id: item.node_id,
                                                     file_root: item.root,
@@ -1786,6 +1937,7 @@ impl Server {
                     match accept_result {
                         Ok((mut send, mut recv)) => {
                             let jobs = self.jobs.clone();
+                            let transcode_status_cache = self.transcode_status_cache.clone();
                             let event_tx = self.event_tx.clone();
                             tokio::spawn(async move {
                                 // receive transfer request with job id
@@ -1799,19 +1951,52 @@ impl Server {
                                     postcard::from_bytes(&transfer_req_buf).context("failed to deserialize transfer request")?;
 
                                 // check job status
-                                let (transfer_res, ready) = {
+                                let ready = {
                                     let Some(job) = jobs.get(&transfer_req.job_id) else {
                                         anyhow::bail!("transfer request job id not found: {}", transfer_req.job_id);
                                     };
 
                                     match &job.progress {
-                                        ServerTransferJobProgress::Ready { local_path, file_size } => {
-                                            (TransferResponse::Ok { file_size: *file_size }, Some((local_path.clone(), *file_size)))
+                                        ServerTransferJobProgress::Ready { hash_kind, hash, local_path, .. } => {
+                                            Some((hash_kind.clone(), hash.clone(), local_path.clone()))
                                         }
-                                        _ => {
-                                            (TransferResponse::Error { error: "job not ready".to_string() }, None)
+                                        _ => None,
+                                    }
+                                };
+
+                                // read file to buffer before responding, holding the rewrite lock so
+                                // it isn't changed in place halfway through and the size sent to the
+                                // client matches the contents
+                                // TODO: stream instead of reading into memory?
+                                let (transfer_res, ready) = match ready {
+                                    Some((hash_kind, hash, local_path)) => {
+                                        let (file_content, revision) = {
+                                            let lock = transcode_status_cache.rewrite_lock(&hash_kind, &hash);
+                                            let _guard = lock.read().await;
+                                            let file_content = tokio::fs::read(&local_path).await;
+                                            (file_content, transcode_status_cache.revision(&hash_kind, &hash))
+                                        };
+
+                                        match file_content {
+                                            Ok(file_content) => {
+                                                let extension = local_path
+                                                    .extension()
+                                                    .and_then(|extension| extension.to_str())
+                                                    .unwrap_or("ogg")
+                                                    .to_string();
+                                                let file_size = file_content.len() as u64;
+                                                (TransferResponse::Ok { file_size, extension, revision }, Some((hash_kind, hash, file_content)))
+                                            }
+                                            Err(e) => {
+                                                // TODO: set job to failed
+                                                log::error!("failed to read file at {}: {e:#}", local_path.display());
+                                                (TransferResponse::Error { error: "failed to read file".to_string() }, None)
+                                            }
                                         }
                                     }
+                                    None => {
+                                        (TransferResponse::Error { error: "job not ready".to_string() }, None)
+                                    }
                                 };
 
                                 // send transfer response
@@ -1825,15 +2010,10 @@ impl Server {
                                     .context("failed to write transfer response")?;
 
                                 // TODO: could maybe be nicer
-                                let Some((local_path, file_size)) = ready else {
+                                let Some((hash_kind, hash, file_content)) = ready else {
                                     return Ok(());
                                 };
-
-                                // check local file exists
-                                if !local_path.exists() {
-                                    // TODO: set job to failed and respond with error
-                                    anyhow::bail!("file at local_path does not exist: {}", local_path.display());
-                                }
+                                let file_size = file_content.len() as u64;
 
                                 let sent_counter = Arc::new(AtomicU64::new(0));
 
@@ -1847,15 +2027,17 @@ impl Server {
                                     job
                                 });
 
+                                // mark transcode as recently used so it isn't evicted from the cache
+                                transcode_status_cache.touch(&hash_kind, &hash);
+
                                 // update model
                                 event_tx.send(NodeEvent::ServerChanged {
                                     node_id: remote_node_id,
                                     update: ServerModelUpdate::UpdateTransferJobs,
                                 }).expect("failed to send ServerModelUpdate::UpdateTransferJobs");
 
-                                // read file to buffer
-                                // TODO: stream instead of reading into memory?
-                                let file_content = tokio::fs::read(local_path).await?;
+                                // let the transcode pool yield to the transfer in background mode
+                                let _activity_guard = TransferActivityGuard::new(event_tx.clone());
 
                                 // TODO: handle errors during send
                                 let mut send_progress = WriteProgress::new(sent_counter.clone(), send);
@@ -1888,6 +2070,32 @@ impl Server {
                     let mut updates = Vec::new();
 
                     for item in index.iter_mut() {
+                        // if the transcode was re-tagged since the client last heard
+                        let revision = self.transcode_status_cache.revision(&item.hash_kind, &item.hash);
+                        if revision != item.revision {
+                            let file_size = self
+                                .transcode_status_cache
+                                .get(&item.hash_kind, &item.hash)
+                                .and_then(|entry| match &*entry {
+                                    TranscodeStatus::Ready { file_size, .. } => Some(FileSize::Actual(*file_size)),
+                                    _ => None,
+                                })
+                                .unwrap_or(item.file_size);
+
+                            // store client's view so we don't send the same update again
+                            item.revision = revision;
+                            item.file_size = file_size;
+
+                            updates.push(IndexUpdateItem::Retagged {
+                                hash_kind: item.hash_kind.clone(),
+                                hash: item.hash.clone(),
+
+                                file_size,
+                                revision,
+                            });
+                            continue;
+                        }
+
                         // if the client doesn't have the actual file size
                         if !matches!(item.file_size, FileSize::Actual(_)) {
                             // try to get file size from transcode status cache
@@ -1958,6 +2166,10 @@ impl Server {
                     })
                     .unwrap_or(FileSize::Unknown);
 
+                let revision = self
+                    .transcode_status_cache
+                    .revision(&file.hash_kind, &file.hash);
+
                 IndexItem {
                     node_id: file.node_id,
                     root: file.root,
@@ -1967,6 +2179,7 @@ impl Server {
                     hash: file.hash,
 
                     file_size,
+                    revision,
                 }
             })
             .collect::<Vec<_>>();
@@ -1991,7 +2204,7 @@ enum ClientTransferJobProgress {
     /// The client sent the request and is waiting for its status.
     Requested,
     /// The client is waiting for the file to be transcoded.
-    Transcoding,
+    Transcoding { progress: TranscodeProgress },
     /// The client is ready to download the file.
     Ready { file_size: u64 },
     /// The client has started downloading the file.
@@ -2128,8 +2341,24 @@ impl Client {
                                     .context("failed to deserialize transfer response")?;
 
                             // check transfer response
-                            let file_size = match transfer_res {
-                                TransferResponse::Ok { file_size } => file_size,
+                            let (file_size, extension, revision) = match transfer_res {
+                                TransferResponse::Ok {
+                                    file_size,
+                                    extension,
+                                    revision,
+                                } => {
+                                    // don't let the server write arbitrary paths
+                                    if !extension.is_empty()
+                                        && extension.chars().all(|c| c.is_ascii_alphanumeric())
+                                    {
+                                        (file_size, extension, revision)
+                                    } else {
+                                        log::warn!(
+                                            "invalid extension in transfer response: {extension:?}"
+                                        );
+                                        (file_size, "ogg".to_string(), revision)
+                                    }
+                                }
                                 TransferResponse::Error { error } => {
                                     // set job status to Failed
                                     jobs.alter(&job_id, |_, mut job| {
@@ -2168,7 +2397,7 @@ impl Client {
                                 let mut local_path =
                                     TreePath::new(download_directory, root_dir_name.into());
                                 local_path.push(&file_path);
-                                local_path.set_extension("ogg");
+                                local_path.set_extension(&extension);
                                 local_path
                             };
 
@@ -2203,6 +2432,9 @@ impl Client {
                                         path: &file_path,
                                         local_tree: local_path.root(),
                                         local_path: &local_path.path(),
+                                        cue_path: None,
+                                        cue_track: None,
+                                        revision,
                                     },
                                 )
                                 .context("failed to insert remote file in database")?;
@@ -2547,6 +2779,31 @@ impl Client {
                             match message {
                                 ServerMessage::Index(new_index) => {
                                     log::info!("received index with {} items", new_index.len());
+
+                                    // forget downloaded files that were changed on the server since
+                                    // they were downloaded, like while we weren't connected
+                                    {
+                                        let db = self.db.lock().unwrap();
+                                        let outdated_files = new_index
+                                            .iter()
+                                            .filter(|item| {
+                                                db.get_file_by_node_root_path(item.node_id, &item.root, &item.path)
+                                                    .ok()
+                                                    .flatten()
+                                                    .is_some_and(|file| file.revision != item.revision)
+                                            })
+                                            .map(|item| (item.node_id, item.root.clone(), item.path.clone()))
+                                            .collect::<Vec<_>>();
+
+                                        if !outdated_files.is_empty() {
+                                            log::info!("{} downloaded files are out of date", outdated_files.len());
+
+                                            if let Err(e) = db.remove_files_by_node_root_path(outdated_files.into_iter()) {
+                                                log::warn!("failed to remove outdated files from database: {e:#}");
+                                            }
+                                        }
+                                    }
+
                                     {
                                         let mut index = self.index.lock().unwrap();
                                         *index = Some(new_index);
@@ -2561,6 +2818,10 @@ impl Client {
 
                                 ServerMessage::IndexUpdate(updates) => {
                                     log::info!("received index update with {} items", updates.len());
+
+                                    // downloaded files that were re-tagged on the server
+                                    let mut outdated_files = Vec::new();
+
                                     {
                                         let mut index = self.index.lock().unwrap();
                                         if let Some(index) = index.as_mut() {
@@ -2574,6 +2835,16 @@ impl Client {
                                                             }
                                                         }
                                                     }
+                                                    IndexUpdateItem::Retagged { hash_kind, hash, file_size, revision } => {
+                                                        for item in index.iter_mut() {
+                                                            if item.hash_kind == hash_kind && item.hash == hash {
+                                                                item.file_size = file_size;
+                                                                item.revision = revision;
+
+                                                                outdated_files.push((item.node_id, item.root.clone(), item.path.clone()));
+                                                            }
+                                                        }
+                                                    }
                                                 }
                                             }
                                         } else {
@@ -2581,6 +2852,17 @@ impl Client {
                                         }
                                     }
 
+                                    // forget re-tagged files so they're shown as not downloaded
+                                    // and downloaded again, replacing the outdated copies
+                                    if !outdated_files.is_empty() {
+                                        log::info!("{} downloaded files were re-tagged on the server", outdated_files.len());
+
+                                        let db = self.db.lock().unwrap();
+                                        if let Err(e) = db.remove_files_by_node_root_path(outdated_files.into_iter()) {
+                                            log::warn!("failed to remove re-tagged files from database: {e:#}");
+                                        }
+                                    }
+
                                     // update model
                                     self.event_tx.send(NodeEvent::ClientChanged {
                                         node_id: remote_node_id,
@@ -2589,15 +2871,32 @@ impl Client {
                                 }
 
                                 ServerMessage::JobStatus(status_changes) => {
+                                    // progress updates don't change the model, since it reads the shared progress
+                                    let mut jobs_changed = false;
+
                                     for (job_id, status) in status_changes {
+                                        if !matches!(status, JobStatusItem::TranscodeProgress { .. }) {
+                                            jobs_changed = true;
+                                        }
+
                                         match status {
                                             JobStatusItem::Transcoding => {
                                                 // set job status to Transcoding
                                                 self.jobs.alter(&job_id, |_, mut job| {
-                                                    job.progress = ClientTransferJobProgress::Transcoding;
+                                                    job.progress = ClientTransferJobProgress::Transcoding {
+                                                        progress: TranscodeProgress::default(),
+                                                    };
                                                     job
                                                 });
                                             },
+                                            JobStatusItem::TranscodeProgress { progress } => {
+                                                // update the shared progress if the job is still transcoding
+                                                if let Some(ClientTransferJobProgress::Transcoding { progress: job_progress }) =
+                                                    self.jobs.get(&job_id).as_deref().map(|job| &job.progress)
+                                                {
+                                                    job_progress.set(progress);
+                                                }
+                                            },
                                             JobStatusItem::Ready { file_size } => {
                                                 // set job status to Ready
                                                 self.jobs.alter(&job_id, |_, mut job| {
@@ -2619,10 +2918,12 @@ impl Client {
                                     }
 
                                     // update model
-                                    self.event_tx.send(NodeEvent::ClientChanged {
-                                        node_id: remote_node_id,
-                                        update: ClientModelUpdate::UpdateTransferJobs,
-                                    }).expect("failed to send ClientModelUpdate::UpdateTransferJobs");
+                                    if jobs_changed {
+                                        self.event_tx.send(NodeEvent::ClientChanged {
+                                            node_id: remote_node_id,
+                                            update: ClientModelUpdate::UpdateTransferJobs,
+                                        }).expect("failed to send ClientModelUpdate::UpdateTransferJobs");
+                                    }
                                 }
 
                                 _ => {
@@ -2659,7 +2960,7 @@ impl Client {
 }
 
 /// Returns the current system time in seconds since the Unix epoch.
-fn unix_epoch_now_secs() -> u64 {
+pub(crate) fn unix_epoch_now_secs() -> u64 {
     SystemTime::now()
         .duration_since(SystemTime::UNIX_EPOCH)
         .unwrap_or_default()